@@ -0,0 +1,64 @@
+//! Hashes a multi-GB temp file serially and with the hashing overlapped with
+//! reading, and prints the throughput of each.
+//!
+//! `cargo bench --bench hash`, with `HASH_BENCH_SIZE` (bytes, default 2 GiB)
+//! and `HASH_BENCH_THREADS` (default 4) to change the setup. Build with
+//! `--features blake3` to include BLAKE3 hashed across the threads.
+
+use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use buggy_client::HashAlgo;
+
+fn setting(name: &str, default: usize) -> usize {
+    env::var(name).ok().and_then(|value| value.parse().ok()).unwrap_or(default)
+}
+
+fn write_file(path: &Path, size: usize) -> std::io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let mut block = vec![0u8; 1024 * 1024];
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut written = 0;
+    while written < size {
+        for byte in block.iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *byte = state as u8;
+        }
+        let len = block.len().min(size - written);
+        out.write_all(&block[..len])?;
+        written += len;
+    }
+    out.flush()
+}
+
+fn time(label: &str, size: usize, hash: impl FnOnce() -> std::io::Result<String>) -> String {
+    let started = Instant::now();
+    let digest = hash().expect("hashing the temp file failed");
+    let secs = started.elapsed().as_secs_f64();
+    println!("{:<32} {:>8.2}s {:>10.1} MiB/s", label, secs, size as f64 / 1024.0 / 1024.0 / secs);
+    digest
+}
+
+fn main() {
+    let size = setting("HASH_BENCH_SIZE", 2 * 1024 * 1024 * 1024);
+    let threads = setting("HASH_BENCH_THREADS", 4);
+    let path = env::temp_dir().join(format!("buggy-client-hash-bench-{}.bin", std::process::id()));
+    write_file(&path, size).expect("cannot write the temp file");
+    println!("Hashing {} bytes, {} threads for the overlapped runs", size, threads);
+
+    let mut algos = vec![HashAlgo::Sha256];
+    algos.extend(HashAlgo::ALL.iter().copied().filter(|algo| algo.name() == "blake3"));
+    for algo in algos {
+        // One untimed pass so both runs read from the page cache.
+        algo.digest_file(&path, 1).expect("hashing the temp file failed");
+        let serial = time(&format!("{} serial", algo.name()), size, || algo.digest_file(&path, 1));
+        let overlapped = time(&format!("{} overlapped", algo.name()), size, || algo.digest_file(&path, threads));
+        assert_eq!(serial, overlapped, "the overlapped {} digest differs", algo.name());
+    }
+    std::fs::remove_file(&path).expect("cannot remove the temp file");
+}
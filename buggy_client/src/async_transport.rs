@@ -0,0 +1,286 @@
+//! Tokio transport used when the `async` feature is enabled.
+//!
+//! Scheduling and retries stay in the downloader, and responses are parsed
+//! by the same [`Responses`] as with the thread transport; this module only
+//! replaces the blocking worker threads with tasks on a runtime, bounded by a
+//! semaphore so `--threads` becomes the number of in-flight requests.
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use socket2::SockRef;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+
+use crate::cancel::POLL_INTERVAL;
+use crate::downloader::{Job, Outcome, WorkerContext};
+use crate::http::{connect_timeout, group_request, log_stale, timed_out_after, BatchReport, ConnectFailed,
+                  ProgressBatch, RangeResult, ResponseTooLarge, Responses, SpeedCheck, StaleConnection};
+use crate::stream::Stream;
+
+/// Either kind of tokio stream, behind one type.
+trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+/// Starts a dispatcher thread that pulls jobs off the channel and runs each
+/// one as a task. It returns once the job channel is closed and every task
+/// has reported back.
+pub(crate) fn spawn_dispatcher(
+    context: Arc<WorkerContext>,
+    jobs: crossbeam_channel::Receiver<Job>,
+    results: crossbeam_channel::Sender<Outcome>,
+    workers: usize,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_multi_thread().enable_all().build() {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                log::error!("Failed to start the async runtime: {}", e);
+                return;
+            }
+        };
+        let semaphore = Arc::new(Semaphore::new(workers));
+        // Task slots stand in for worker indices, so observers see the same
+        // numbering as with the thread transport.
+        let idle_slots = Arc::new(Mutex::new((0..workers).collect::<Vec<_>>()));
+
+        // A job for another server than the batch before it, which starts the next batch.
+        let mut held = None;
+        while let Some(job) = held.take().or_else(|| jobs.recv().ok()) {
+            context.pause.wait_while_paused(&context.cancel);
+            if context.cancel.is_cancelled() {
+                break;
+            }
+            let mut batch = vec![job];
+            while batch.len() < context.pipeline * context.ranges_per_request {
+                match jobs.try_recv() {
+                    Ok(job) if job.mirror == batch[0].mirror => batch.push(job),
+                    Ok(job) => {
+                        held = Some(job);
+                        break;
+                    }
+                    Err(_) => break,
+                }
+            }
+            let permit = match runtime.block_on(Arc::clone(&semaphore).acquire_owned()) {
+                Ok(permit) => permit,
+                Err(_) => break,
+            };
+            // Holding a permit guarantees a free slot.
+            let index = idle_slots.lock().unwrap().pop().expect("a slot per permit");
+            let context = Arc::clone(&context);
+            let results = results.clone();
+            let idle_slots = Arc::clone(&idle_slots);
+
+            runtime.spawn(async move {
+                // The requests run in their own task so that a panic in them
+                // or in an observer fails only the job it hit, and the slot
+                // comes back.
+                let outcomes = Arc::new(Mutex::new(Vec::new()));
+                let started = Arc::new(AtomicUsize::new(0));
+                let request = tokio::spawn({
+                    let context = Arc::clone(&context);
+                    let batch = batch.clone();
+                    let outcomes = Arc::clone(&outcomes);
+                    let started = Arc::clone(&started);
+                    async move {
+                        make_range_requests(&context, index, &batch, &started, &mut |job, elapsed, result| {
+                            let outcome = Outcome::from_response(job.clone(), &context, index, elapsed, result);
+                            outcomes.lock().unwrap().push(outcome);
+                        }).await;
+                    }
+                });
+                let failure = request.await.err();
+                let mut outcomes = std::mem::take(&mut *outcomes.lock().unwrap());
+                let reported = outcomes.len();
+                let mut rest = batch.into_iter().enumerate().skip(reported);
+                if let (Some(e), Some((_, job))) = (failure, rest.next()) {
+                    outcomes.push(match e.is_panic() {
+                        true => Outcome::panicked(job, &*e.into_panic()),
+                        false => Outcome::panicked(job, &e.to_string()),
+                    });
+                }
+                let started = started.load(Ordering::Relaxed);
+                outcomes.extend(rest.map(|(at, job)| Outcome::Requeued { job, started: at < started }));
+                idle_slots.lock().unwrap().push(index);
+                drop(permit);
+                for outcome in outcomes {
+                    let _ = results.send(outcome);
+                }
+            });
+        }
+
+        // Wait for the tasks still in flight before tearing the runtime down.
+        let _ = runtime.block_on(semaphore.acquire_many(workers as u32));
+    })
+}
+
+/// Async counterpart of `make_range_requests`, with the same timeouts, the
+/// same handling of a read timeout after partial data, the same pipelining
+/// and the same requests for several ranges.
+async fn make_range_requests(context: &WorkerContext, worker: usize, jobs: &[Job], started: &AtomicUsize,
+                             done: &mut (dyn FnMut(&Job, Duration, RangeResult) + Send)) {
+    let mirror = &context.mirrors[jobs[0].mirror];
+    let keep_alive = context.pool.keep_alive() && mirror.keep_alive();
+    let ranges = if mirror.multi_range() { context.ranges_per_request } else { 1 };
+    let per_connection = if keep_alive { jobs.len() } else { ranges };
+    for batch in jobs.chunks(per_connection) {
+        let mut report = BatchReport::new(context, worker, batch, started, &mut *done);
+        let mut fresh = false;
+        loop {
+            match pipeline(context, &mut report, ranges, keep_alive, fresh).await {
+                Err(e) if e.is::<StaleConnection>() => {
+                    log_stale(&report);
+                    fresh = true;
+                }
+                result => {
+                    report.finish(result.err());
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn pipeline(context: &WorkerContext, report: &mut BatchReport<'_>, ranges: usize, keep_alive: bool,
+                  fresh: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let timeouts = &context.timeouts;
+    let jobs = report.unanswered();
+    report.start_group(1);
+    let mut checkout = match (!fresh).then(|| context.pool.take(jobs[0].mirror)).flatten() {
+        Some(checkout) => checkout,
+        None => {
+            let stream = connect(context, &jobs[0]).await.map_err(ConnectFailed)?;
+            context.pool.opened(jobs[0].mirror, stream)
+        }
+    };
+    let mut responses = Responses::new(checkout.reused(), context.max_response);
+    // The pool keeps std streams; this one shares the socket for the requests.
+    checkout.stream().set_nonblocking(true)?;
+    let mut stream: Box<dyn AsyncStream> = match checkout.stream().try_clone()? {
+        Stream::Tcp(stream) => Box::new(TcpStream::from_std(stream)?),
+        #[cfg(unix)]
+        Stream::Unix(stream) => Box::new(tokio::net::UnixStream::from_std(stream)?),
+    };
+
+    let requests: String = jobs.chunks(ranges).map(|group| group_request(context, group, keep_alive)).collect();
+    timeout(timeouts.write, stream.write_all(requests.as_bytes())).await
+        .map_err(|_| "write timed out")?
+        .map_err(|e| responses.failed(e.into()))?;
+
+    let mut buffer = vec![0u8; context.read_buffer];
+    for group in jobs.chunks(ranges) {
+        report.start_group(group.len());
+        let mut progress = ProgressBatch::new(context, report.worker, &group[0]);
+        progress.add(responses.begin(group));
+        read_response(context, &mut *stream, &mut buffer, &mut responses, &mut progress).await
+            .map_err(|e| responses.failed(e))?;
+        if !responses.answer(context, report, group.len(), progress)? {
+            return Ok(());
+        }
+    }
+    drop(stream);
+    if let Some((timeout, remaining)) = responses.drained() {
+        checkout.server_limits(timeout, remaining);
+        checkout.release(jobs.len().div_ceil(ranges));
+    }
+    Ok(())
+}
+
+async fn read_response(context: &WorkerContext, stream: &mut dyn AsyncStream, buffer: &mut [u8],
+                       responses: &mut Responses, progress: &mut ProgressBatch<'_>)
+                       -> Result<(), Box<dyn std::error::Error>> {
+    let timeouts = &context.timeouts;
+    let mut last_data = Instant::now();
+    let mut speed = SpeedCheck::new(context.low_speed);
+
+    while !responses.complete() {
+        if context.cancel.is_cancelled() {
+            return Err("cancelled".into());
+        }
+        match timeout(timeouts.poll(), stream.read(buffer)).await {
+            Ok(Ok(0)) => break,
+            Ok(Ok(n)) => {
+                last_data = Instant::now();
+                progress.add(responses.feed(&buffer[..n]));
+                speed.add(n)?;
+                if let Some(limiter) = context.limiter.as_deref() {
+                    let until = Instant::now() + limiter.reserve(n);
+                    while !context.cancel.is_cancelled() && Instant::now() < until {
+                        tokio::time::sleep(until.saturating_duration_since(Instant::now()).min(POLL_INTERVAL)).await;
+                    }
+                }
+                if responses.too_large() {
+                    return Err(Box::new(ResponseTooLarge));
+                }
+            }
+            Ok(Err(e)) => return Err(Box::new(e)),
+            Err(_) => {
+                speed.add(0)?;
+                if timeouts.idle(!responses.received().is_empty()).is_none_or(|limit| last_data.elapsed() < limit) {
+                    continue;
+                }
+                if !responses.received().is_empty() {
+                    timed_out_after(responses.received())?;
+                    break;
+                }
+                return Err(Box::new(io::Error::new(io::ErrorKind::TimedOut, "read timed out")));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Opens a connection to the server of `job`, trying each of its addresses,
+/// or to its Unix socket if there is one.
+async fn connect(context: &WorkerContext, job: &Job) -> io::Result<Stream> {
+    let mirror = &context.mirrors[job.mirror];
+    if let Some(path) = mirror.endpoint.unix_socket() {
+        return connect_unix(context, job, path).await;
+    }
+    let mut connection = Err(io::Error::new(io::ErrorKind::NotFound, "no address to connect to"));
+    for address in mirror.endpoint.addresses()? {
+        match within(connect_timeout(context), TcpStream::connect(address)).await {
+            Some(Ok(stream)) => {
+                mirror.endpoint.connected(address);
+                mirror.endpoint.tune(SockRef::from(&stream))?;
+                return Ok(stream.into_std()?.into());
+            }
+            Some(Err(e)) => connection = Err(e),
+            None => connection = Err(io::Error::new(io::ErrorKind::TimedOut, "connection timed out")),
+        }
+    }
+    connection
+}
+
+#[cfg(unix)]
+async fn connect_unix(context: &WorkerContext, job: &Job, path: &std::path::Path) -> io::Result<Stream> {
+    match within(connect_timeout(context), tokio::net::UnixStream::connect(path)).await {
+        Some(Ok(stream)) => {
+            context.mirrors[job.mirror].endpoint.connected_unix();
+            Ok(stream.into_std()?.into())
+        }
+        Some(Err(e)) => Err(e),
+        None => Err(io::Error::new(io::ErrorKind::TimedOut, "connection timed out")),
+    }
+}
+
+/// Awaits `future` for at most `limit`, or for as long as it takes without
+/// one. `None` if the time ran out.
+async fn within<F: std::future::Future>(limit: Option<Duration>, future: F) -> Option<F::Output> {
+    match limit {
+        Some(limit) => timeout(limit, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_context: &WorkerContext, _job: &Job, _path: &std::path::Path) -> io::Result<Stream> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, crate::endpoint::UNIX_SOCKETS_UNSUPPORTED))
+}
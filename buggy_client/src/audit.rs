@@ -0,0 +1,141 @@
+//! Checking a local copy against the server without downloading it anew.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use crate::downloader::{SeekSink, Sink};
+
+/// Compares each chunk the server sends with the same bytes of a local
+/// file instead of writing it, collecting the ranges where they differ.
+/// Bytes past the end of the file differ, as do any it has past the end of
+/// the resource. With `fix` the server's bytes are written over the
+/// differing ones and the file is cut to the size of the resource, which
+/// needs the file open for writing as well as reading.
+///
+/// ```no_run
+/// use std::fs::File;
+///
+/// use buggy_client::{AuditSink, Downloader};
+///
+/// let downloader = Downloader::builder().path("/data.bin").build()?;
+/// let mut audit = AuditSink::new(File::open("data.bin")?, false)?;
+/// downloader.download(&mut audit)?;
+/// for range in audit.differences() {
+///     println!("bytes {}..{} differ", range.start, range.end);
+/// }
+/// # Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+/// ```
+pub struct AuditSink {
+    file: File,
+    fix: bool,
+    local_len: u64,
+    differences: Vec<Range<u64>>,
+    buffer: Vec<u8>,
+}
+
+impl AuditSink {
+    /// Compares the resource with `file`, opened for writing as well with `fix`.
+    ///
+    /// ```no_run
+    /// use std::fs::OpenOptions;
+    ///
+    /// use buggy_client::AuditSink;
+    ///
+    /// let file = OpenOptions::new().read(true).write(true).open("data.bin")?;
+    /// let audit = AuditSink::new(file, true)?;
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn new(file: File, fix: bool) -> io::Result<Self> {
+        let local_len = file.metadata()?.len();
+        Ok(AuditSink { file, fix, local_len, differences: Vec::new(), buffer: Vec::new() })
+    }
+
+    /// The byte ranges that differ so far, in order, with neighbours merged.
+    ///
+    /// ```
+    /// use std::fs::OpenOptions;
+    ///
+    /// use buggy_client::{AuditSink, Sink};
+    ///
+    /// let path = std::env::temp_dir().join("buggy-client-audit-example");
+    /// let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?;
+    /// let mut audit = AuditSink::new(file, false)?;
+    /// audit.write_at(0, b"new")?;
+    /// assert_eq!(audit.differences(), vec![0..3]);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn differences(&self) -> Vec<Range<u64>> {
+        let mut sorted = self.differences.clone();
+        sorted.sort_by_key(|range| range.start);
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(sorted.len());
+        for range in sorted {
+            match merged.last_mut() {
+                Some(last) if last.end >= range.start => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        merged
+    }
+
+    /// Records `range` and, when fixing, writes the server's `data` for it.
+    fn differs(&mut self, range: Range<u64>, data: &[u8]) -> io::Result<()> {
+        if self.fix {
+            SeekSink(&self.file).write_at(range.start, data)?;
+        }
+        self.differences.push(range);
+        Ok(())
+    }
+}
+
+impl Sink for AuditSink {
+    fn set_size(&mut self, size: u64) -> io::Result<()> {
+        if self.local_len > size {
+            self.differences.push(size..self.local_len);
+            if self.fix {
+                self.file.set_len(size)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        // Only what the file held at the start counts, not what a fix added.
+        let available = usize::try_from(self.local_len.saturating_sub(offset)).unwrap_or(usize::MAX).min(data.len());
+        self.buffer.resize(available, 0);
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut local = 0;
+        while local < available {
+            match self.file.read(&mut self.buffer[local..])? {
+                0 => break,
+                n => local += n,
+            }
+        }
+        // Runs of differing bytes, each reported as one range.
+        let mut at = 0;
+        while at < local {
+            if data[at] == self.buffer[at] {
+                at += 1;
+                continue;
+            }
+            let end = (at..local).find(|&i| data[i] == self.buffer[i]).unwrap_or(local);
+            self.differs(offset + at as u64..offset + end as u64, &data[at..end])?;
+            at = end;
+        }
+        if local < data.len() {
+            self.differs(offset + local as u64..offset + data.len() as u64, &data[local..])?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.fix {
+            self.file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn discards(&self) -> bool {
+        true
+    }
+}
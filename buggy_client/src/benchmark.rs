@@ -0,0 +1,187 @@
+//! Load-test figures for repeated downloads whose data is thrown away.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use buggy_client::{CancellationToken, DownloadObserver, Summary};
+
+use crate::progress::format_bytes;
+
+/// Observer that times every request across any number of downloads and
+/// reports requests per second, throughput overall and per worker, request
+/// time percentiles and errors by kind.
+///
+/// Chunk events are passed on to the observer given to
+/// [`forward_to`](Self::forward_to), so a progress display keeps running
+/// from one download to the next; events about a whole download are not.
+pub struct Benchmark {
+    started: Instant,
+    forward: Option<Arc<dyn DownloadObserver>>,
+    stop: Option<(usize, CancellationToken)>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Worker and start time of each request in flight, by chunk id.
+    running: HashMap<usize, (usize, Instant)>,
+    durations: Vec<Duration>,
+    failed: usize,
+    bytes: usize,
+    worker_bytes: BTreeMap<usize, usize>,
+    errors: BTreeMap<&'static str, usize>,
+    passes: usize,
+}
+
+impl Benchmark {
+    pub fn new() -> Self {
+        Benchmark {
+            started: Instant::now(),
+            forward: None,
+            stop: None,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Passes chunk events on to `observer` as well.
+    pub fn forward_to(mut self, observer: Arc<dyn DownloadObserver>) -> Self {
+        self.forward = Some(observer);
+        self
+    }
+
+    /// Cancels `token` once `bytes` have been received in total.
+    pub fn stop_after(mut self, bytes: usize, token: CancellationToken) -> Self {
+        self.stop = Some((bytes, token));
+        self
+    }
+
+    /// Everything measured since the benchmark was created, as lines of text.
+    pub fn report(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let elapsed = self.started.elapsed().as_secs_f64().max(f64::EPSILON);
+        let requests = state.durations.len();
+        let mut lines = vec![
+            format!("Benchmark: {} in {:.2}s, {} full download{}", format_bytes(state.bytes as f64), elapsed,
+                    state.passes, if state.passes == 1 { "" } else { "s" }),
+            format!("Requests: {} ({:.1}/s), {} failed", requests, requests as f64 / elapsed, state.failed),
+            format!("Throughput: {}/s", format_bytes(state.bytes as f64 / elapsed)),
+        ];
+        for (worker, bytes) in &state.worker_bytes {
+            lines.push(format!("Thread #{:2}: {} ({}/s)", worker, format_bytes(*bytes as f64),
+                               format_bytes(*bytes as f64 / elapsed)));
+        }
+        if requests > 0 {
+            let mut durations = state.durations.clone();
+            durations.sort_unstable();
+            let percentile = |p: f64| durations[((requests - 1) as f64 * p).round() as usize].as_secs_f64() * 1000.0;
+            lines.push(format!("Request time: p50 {:.0}ms, p90 {:.0}ms, p99 {:.0}ms, max {:.0}ms",
+                               percentile(0.5), percentile(0.9), percentile(0.99), percentile(1.0)));
+        }
+        let errors: Vec<_> = state.errors.iter().map(|(kind, count)| format!("{} {}", kind, count)).collect();
+        lines.push(format!("Errors: {}", if errors.is_empty() { "none".to_string() } else { errors.join(", ") }));
+        lines.push(format!("Connections: {} opened, none reused; every request uses its own connection", requests));
+        lines.join("\n")
+    }
+
+    /// Takes the request for `chunk_id` off the running list and records how
+    /// long it took.
+    fn request_done(&self, state: &mut State, chunk_id: usize) -> Option<usize> {
+        let (worker, started) = state.running.remove(&chunk_id)?;
+        state.durations.push(started.elapsed());
+        Some(worker)
+    }
+}
+
+impl Default for Benchmark {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rough kind of a chunk error, from its message.
+fn error_kind(error: &str) -> &'static str {
+    let error = error.to_ascii_lowercase();
+    if error.starts_with("could not connect") {
+        "connect"
+    } else if error.contains("timed out") || error.contains("temporarily unavailable") {
+        "timeout"
+    } else if error.contains("checksum") {
+        "checksum"
+    } else if error.contains("status") {
+        "status"
+    } else {
+        "transfer"
+    }
+}
+
+impl DownloadObserver for Benchmark {
+    fn attempt_started(&self, attempt: usize, max_attempts: usize) {
+        // Chunk ids start over with every download.
+        self.state.lock().unwrap().running.clear();
+        if let Some(forward) = &self.forward {
+            forward.attempt_started(attempt, max_attempts);
+        }
+    }
+
+    fn max_tries(&self, tries: usize) {
+        if let Some(forward) = &self.forward {
+            forward.max_tries(tries);
+        }
+    }
+
+    fn chunk_started(&self, worker: usize, chunk_id: usize, range: Range<usize>, attempt: usize) {
+        self.state.lock().unwrap().running.insert(chunk_id, (worker, Instant::now()));
+        if let Some(forward) = &self.forward {
+            forward.chunk_started(worker, chunk_id, range, attempt);
+        }
+    }
+
+    fn bytes_received(&self, worker: usize, chunk_id: usize, bytes: usize) {
+        if let Some(forward) = &self.forward {
+            forward.bytes_received(worker, chunk_id, bytes);
+        }
+    }
+
+    fn chunk_finished(&self, chunk_id: usize, bytes: usize) {
+        let total = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(worker) = self.request_done(&mut state, chunk_id) {
+                *state.worker_bytes.entry(worker).or_default() += bytes;
+            }
+            state.bytes += bytes;
+            state.bytes
+        };
+        if let Some((limit, token)) = &self.stop {
+            if total >= *limit {
+                token.cancel();
+            }
+        }
+        if let Some(forward) = &self.forward {
+            forward.chunk_finished(chunk_id, bytes);
+        }
+    }
+
+    fn chunk_failed(&self, chunk_id: usize, attempt: usize, error: &str, retry_in: Option<Duration>) {
+        {
+            let mut state = self.state.lock().unwrap();
+            self.request_done(&mut state, chunk_id);
+            state.failed += 1;
+            *state.errors.entry(error_kind(error)).or_default() += 1;
+        }
+        if let Some(forward) = &self.forward {
+            forward.chunk_failed(chunk_id, attempt, error, retry_in);
+        }
+    }
+
+    fn concurrency_changed(&self, limit: usize) {
+        if let Some(forward) = &self.forward {
+            forward.concurrency_changed(limit);
+        }
+    }
+
+    fn download_finished(&self, _summary: &Summary) {
+        self.state.lock().unwrap().passes += 1;
+    }
+}
@@ -0,0 +1,185 @@
+//! A directory of chunks kept across runs, so ranges of a resource fetched
+//! once are read back from disk while the server still has the same version.
+
+use std::fs::{self, File};
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+
+/// A chunk in the cache: bytes `range` of the resource, whose sha256 is
+/// `hash`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CacheEntry {
+    pub(crate) range: Range<usize>,
+    hash: String,
+    path: PathBuf,
+}
+
+/// Chunks are kept as `<dir>/<key>/<start>-<end>-<sha256>.bin`, with the end
+/// exclusive. The key stands for the host, path and validator of the
+/// resource, which `<key>/resource` lists for whoever looks. Reading an entry
+/// marks it as used, and [`ChunkCache::trim`] removes the least recently used
+/// ones first.
+pub(crate) struct ChunkCache {
+    dir: PathBuf,
+    max_size: Option<u64>,
+}
+
+impl ChunkCache {
+    /// Opens `dir`, creating it and its parents if missing. `max_size` caps
+    /// the bytes kept in it.
+    pub(crate) fn open(dir: &Path, max_size: Option<u64>) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(ChunkCache { dir: dir.to_path_buf(), max_size })
+    }
+
+    /// The key for the resource at `path` on `server`, in the version the
+    /// ETag or Last-Modified date `validator` stands for.
+    pub(crate) fn key(server: &str, path: &str, validator: &str) -> String {
+        let digest = format!("{:x}", Sha256::digest(format!("{}\n{}\n{}\n", server, path, validator)));
+        digest[..32].to_string()
+    }
+
+    /// The entries saved under `key`, by offset, leaving out any that overlap
+    /// one before them.
+    pub(crate) fn entries(&self, key: &str) -> io::Result<Vec<CacheEntry>> {
+        let dir = self.dir.join(key);
+        let listing = match fs::read_dir(&dir) {
+            Ok(listing) => listing,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut entries = Vec::new();
+        for file in listing {
+            let path = file?.path();
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            if let Some((range, hash)) = parse_entry_name(name) {
+                entries.push(CacheEntry { range, hash, path });
+            }
+        }
+        entries.sort_by_key(|entry| entry.range.start);
+        let mut end = 0;
+        entries.retain(|entry| {
+            let keep = entry.range.start >= end;
+            end = end.max(entry.range.end);
+            keep
+        });
+        Ok(entries)
+    }
+
+    /// The bytes of `entry`, marking it as just used. `None` when they no
+    /// longer match the hash they were saved with, in which case the entry
+    /// is removed.
+    pub(crate) fn read(&self, entry: &CacheEntry) -> io::Result<Option<Vec<u8>>> {
+        let data = fs::read(&entry.path)?;
+        if data.len() != entry.range.len() || format!("{:x}", Sha256::digest(&data)) != entry.hash {
+            log::warn!("Cached chunk '{}' is corrupt, removing it", entry.path.display());
+            fs::remove_file(&entry.path)?;
+            return Ok(None);
+        }
+        if let Err(e) = File::options().write(true).open(&entry.path)
+            .and_then(|file| file.set_modified(SystemTime::now())) {
+            log::debug!("Cannot mark '{}' as used: {}", entry.path.display(), e);
+        }
+        Ok(Some(data))
+    }
+
+    /// Saves `data` as the bytes from `start` on of the resource under
+    /// `key`, which `resource` describes, through a temporary name so that
+    /// an entry is only ever complete.
+    pub(crate) fn store(&self, key: &str, resource: &str, start: usize, data: &[u8]) -> io::Result<()> {
+        let dir = self.dir.join(key);
+        if !dir.exists() {
+            fs::create_dir_all(&dir)?;
+            fs::write(dir.join("resource"), resource)?;
+        }
+        let name = format!("{}-{}-{:x}", start, start + data.len(), Sha256::digest(data));
+        let partial = dir.join(format!("{}.part", name));
+        fs::write(&partial, data)?;
+        fs::rename(&partial, dir.join(format!("{}.bin", name)))
+    }
+
+    /// Removes the least recently used entries until the cache fits in its
+    /// maximum size, and returns how many were removed.
+    pub(crate) fn trim(&self) -> io::Result<usize> {
+        let max_size = match self.max_size {
+            Some(max_size) => max_size,
+            None => return Ok(0),
+        };
+        let mut files = Vec::new();
+        for (path, size) in cached_files(&self.dir)? {
+            let used = fs::metadata(&path)?.modified()?;
+            files.push((used, path, size));
+        }
+        let mut total: u64 = files.iter().map(|(_, _, size)| size).sum();
+        files.sort();
+        let mut removed = 0;
+        for (_, path, size) in files {
+            if total <= max_size {
+                break;
+            }
+            fs::remove_file(&path)?;
+            total -= size;
+            removed += 1;
+        }
+        if removed > 0 {
+            log::info!("Removed {} chunks from the cache to keep it under {} bytes", removed, max_size);
+        }
+        Ok(removed)
+    }
+}
+
+/// Removes every chunk cached in `dir` along with the directories that held
+/// them, and returns how many there were and their total size.
+pub(crate) fn purge_cache(dir: &Path) -> io::Result<(usize, u64)> {
+    let files = cached_files(dir)?;
+    let bytes = files.iter().map(|(_, size)| size).sum();
+    for (path, _) in &files {
+        fs::remove_file(path)?;
+    }
+    for resource in fs::read_dir(dir)? {
+        let resource = resource?.path();
+        if resource.join("resource").is_file() {
+            fs::remove_file(resource.join("resource"))?;
+            // Anything else in it was not put there by the cache.
+            let _ = fs::remove_dir(&resource);
+        }
+    }
+    Ok((files.len(), bytes))
+}
+
+/// The entry files under `dir`, and leftover partial ones, with their sizes.
+fn cached_files(dir: &Path) -> io::Result<Vec<(PathBuf, u64)>> {
+    let mut files = Vec::new();
+    for resource in fs::read_dir(dir)? {
+        let resource = resource?.path();
+        if !resource.join("resource").is_file() {
+            continue;
+        }
+        for file in fs::read_dir(&resource)? {
+            let file = file?;
+            let name = file.file_name();
+            let name = name.to_str().unwrap_or_default();
+            let stem = name.strip_suffix(".bin").or_else(|| name.strip_suffix(".part"));
+            if stem.is_some_and(|stem| parse_entry_name(&format!("{}.bin", stem)).is_some()) {
+                files.push((file.path(), file.metadata()?.len()));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// The range and hash in a `<start>-<end>-<sha256>.bin` name.
+fn parse_entry_name(name: &str) -> Option<(Range<usize>, String)> {
+    let mut parts = name.strip_suffix(".bin")?.splitn(3, '-');
+    let start = parts.next()?.parse().ok()?;
+    let end = parts.next()?.parse().ok()?;
+    let hash = parts.next()?;
+    if start >= end || hash.len() != 64 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some((start..end, hash.to_string()))
+}
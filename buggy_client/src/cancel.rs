@@ -0,0 +1,148 @@
+//! Stopping a download from another thread.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often blocked workers and the scheduler look at the token.
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Cheap, clonable handle that aborts the download it was given to. Every
+/// clone controls the same download.
+///
+/// ```no_run
+/// use std::thread;
+/// use std::time::Duration;
+///
+/// use buggy_client::{CancellationToken, DownloadError, Downloader};
+///
+/// let token = CancellationToken::new();
+/// let downloader = Downloader::builder().cancellation(token.clone()).build()?;
+/// let stopper = thread::spawn(move || {
+///     thread::sleep(Duration::from_secs(10));
+///     token.cancel();
+/// });
+/// match downloader.download(&mut Vec::new()) {
+///     Err(DownloadError::Cancelled(cancelled)) => println!("stopped after {} bytes", cancelled.bytes),
+///     result => println!("{:?}", result.map(|summary| summary.bytes)),
+/// }
+/// stopper.join().unwrap();
+/// # Ok::<(), String>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    /// A token that is not cancelled and has no deadline.
+    ///
+    /// ```
+    /// use buggy_client::CancellationToken;
+    ///
+    /// assert!(!CancellationToken::new().is_cancelled());
+    /// ```
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes the token cancel itself at `deadline`, for this handle and the
+    /// clones made from it afterwards. A download stopped that way is not a
+    /// [`Cancelled`] error: it returns what it has, with
+    /// `Summary::timed_out` set.
+    ///
+    /// ```
+    /// use std::time::{Duration, Instant};
+    ///
+    /// use buggy_client::CancellationToken;
+    ///
+    /// let token = CancellationToken::new().with_deadline(Instant::now() + Duration::from_secs(60));
+    /// assert!(!token.expired());
+    /// ```
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Whether the deadline has passed.
+    ///
+    /// ```
+    /// use std::time::Instant;
+    ///
+    /// use buggy_client::CancellationToken;
+    ///
+    /// let token = CancellationToken::new().with_deadline(Instant::now());
+    /// assert!(token.expired());
+    /// assert!(token.is_cancelled());
+    /// ```
+    pub fn expired(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// How long until the deadline, if there is one.
+    pub(crate) fn time_left(&self) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Asks the download to stop. Workers notice within about 100ms, or once
+    /// a pending connect attempt times out.
+    ///
+    /// ```
+    /// use buggy_client::CancellationToken;
+    ///
+    /// let token = CancellationToken::new();
+    /// let clone = token.clone();
+    /// clone.cancel();
+    /// assert!(token.is_cancelled());
+    /// ```
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether the token was cancelled or its deadline has passed.
+    ///
+    /// ```
+    /// use buggy_client::CancellationToken;
+    ///
+    /// let token = CancellationToken::new();
+    /// assert!(!token.is_cancelled());
+    /// token.cancel();
+    /// assert!(token.is_cancelled());
+    /// ```
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst) || self.expired()
+    }
+}
+
+/// Error returned by `Downloader::download` when its token was cancelled.
+/// Nothing is written to the sink.
+///
+/// ```no_run
+/// use buggy_client::{CancellationToken, DownloadError, Downloader};
+///
+/// let token = CancellationToken::new();
+/// token.cancel();
+/// let downloader = Downloader::builder().cancellation(token).build()?;
+/// if let Err(DownloadError::Cancelled(cancelled)) = downloader.download(&mut Vec::new()) {
+///     println!("{} chunks were in", cancelled.chunks);
+/// }
+/// # Ok::<(), String>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Cancelled {
+    /// Bytes of the chunks that had completed.
+    pub bytes: usize,
+    /// Number of chunks that had completed.
+    pub chunks: usize,
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Download cancelled after {} bytes in {} chunks", self.bytes, self.chunks)
+    }
+}
+
+impl std::error::Error for Cancelled {}
@@ -0,0 +1,81 @@
+//! Rolling CRC32C checkpoints over the assembled output.
+
+/// CRC32C of the assembled prefix, recorded at the end of each window.
+///
+/// ```no_run
+/// use buggy_client::Downloader;
+///
+/// let downloader = Downloader::builder().crc_window(1 << 20).build()?;
+/// let summary = downloader.download(&mut Vec::new())?;
+/// for checkpoint in summary.checkpoints.unwrap_or_default() {
+///     println!("{}..{} crc32c {:08x}", checkpoint.start, checkpoint.end, checkpoint.crc);
+/// }
+/// # Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Checkpoint {
+    /// Offset of the first byte in the window.
+    pub start: usize,
+    /// Offset just past the last byte in the window.
+    pub end: usize,
+    /// CRC32C of everything from offset 0 up to `end`.
+    pub crc: u32,
+    /// Chunks that supplied bytes to the window.
+    pub chunk_ids: Vec<usize>,
+}
+
+/// Rolling CRC32C over the contiguous prefix, fed chunk by chunk in order.
+pub(crate) struct CrcCheckpoints {
+    window: usize,
+    crc: u32,
+    offset: usize,
+    window_start: usize,
+    chunk_ids: Vec<usize>,
+    entries: Vec<Checkpoint>,
+}
+
+impl CrcCheckpoints {
+    pub(crate) fn new(window: usize) -> Self {
+        CrcCheckpoints {
+            window,
+            crc: 0,
+            offset: 0,
+            window_start: 0,
+            chunk_ids: Vec::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    pub(crate) fn update(&mut self, chunk_id: usize, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.chunk_ids.last() != Some(&chunk_id) {
+                self.chunk_ids.push(chunk_id);
+            }
+            let take = (self.window_start + self.window - self.offset).min(data.len());
+            self.crc = crc32c::crc32c_append(self.crc, &data[..take]);
+            self.offset += take;
+            data = &data[take..];
+            if self.offset == self.window_start + self.window {
+                self.checkpoint();
+            }
+        }
+    }
+
+    fn checkpoint(&mut self) {
+        self.entries.push(Checkpoint {
+            start: self.window_start,
+            end: self.offset,
+            crc: self.crc,
+            chunk_ids: std::mem::take(&mut self.chunk_ids),
+        });
+        self.window_start = self.offset;
+    }
+
+    pub(crate) fn finish(mut self) -> Vec<Checkpoint> {
+        if self.offset > self.window_start {
+            self.checkpoint();
+        }
+        self.entries
+    }
+}
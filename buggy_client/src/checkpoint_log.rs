@@ -0,0 +1,38 @@
+//! The CRC checkpoints of a download written next to the output, and read
+//! back to find where a written file stopped matching them.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+use buggy_client::Checkpoint;
+
+/// Writes one `start-end crc chunk,ids` line per checkpoint after a header
+/// recording the window size.
+pub fn write_checkpoint_log(path: &str, window: usize, checkpoints: &[Checkpoint]) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "# crc32c window={}", window)?;
+    for checkpoint in checkpoints {
+        let ids: Vec<String> = checkpoint.chunk_ids.iter().map(|id| id.to_string()).collect();
+        writeln!(writer, "{}-{} {:08x} {}", checkpoint.start, checkpoint.end, checkpoint.crc, ids.join(","))?;
+    }
+    writer.flush()
+}
+
+/// Streams the written output from disk and returns the first window whose
+/// rolling CRC no longer matches the one recorded during assembly.
+pub fn find_divergence<'a>(path: &Path, checkpoints: &'a [Checkpoint]) -> std::io::Result<Option<&'a Checkpoint>> {
+    let mut reader = std::io::BufReader::new(File::open(path)?);
+    let mut buffer = Vec::new();
+    let mut crc = 0;
+    for checkpoint in checkpoints {
+        buffer.clear();
+        let len = (checkpoint.end - checkpoint.start) as u64;
+        reader.by_ref().take(len).read_to_end(&mut buffer)?;
+        crc = crc32c::crc32c_append(crc, &buffer);
+        if buffer.len() as u64 != len || crc != checkpoint.crc {
+            return Ok(Some(checkpoint));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,35 @@
+//! `sha256sum`-style checksum listings, as `--checksum-file` reads them.
+
+use std::path::Path;
+
+/// Picks the expected hash out of a `sha256sum`-style listing, matching the
+/// entry by file name when more than one file is listed.
+pub fn parse_checksum_file(text: &str, filename: Option<&str>) -> Result<String, String> {
+    let text = text.trim_start_matches('\u{feff}');
+    let mut entries = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (hash, name) = match line.split_once(char::is_whitespace) {
+            Some((hash, name)) => (hash, name.trim_start().trim_start_matches('*')),
+            None => (line, ""),
+        };
+        entries.push((hash.to_lowercase(), name.trim()));
+    }
+
+    let base_name = |name: &str| Path::new(name).file_name().map(|n| n.to_string_lossy().to_string());
+    match (entries.as_slice(), filename) {
+        ([], _) => Err("Checksum file contains no entries".to_string()),
+        ([(hash, _)], _) => Ok(hash.clone()),
+        (_, Some(filename)) => {
+            let wanted = base_name(filename);
+            entries.iter()
+                .find(|(_, name)| base_name(name) == wanted)
+                .map(|(hash, _)| hash.clone())
+                .ok_or_else(|| format!("Checksum file has no entry for '{}'", filename))
+        }
+        (_, None) => Err("Checksum file lists several files, use -o to pick the matching entry".to_string()),
+    }
+}
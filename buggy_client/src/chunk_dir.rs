@@ -0,0 +1,292 @@
+//! A directory holding every chunk as it was received, each in its own file
+//! with the response head next to it, instead of an assembled output.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::hash::HashAlgo;
+use crate::message::Response;
+use crate::json::json_string;
+
+/// Everything recorded about one chunk besides its bytes.
+pub(crate) struct ChunkRecord<'a> {
+    pub(crate) chunk_id: usize,
+    /// The bytes the chunk holds, as offsets in the resource.
+    pub(crate) range: Range<usize>,
+    pub(crate) head: &'a Response,
+    pub(crate) attempt: usize,
+    pub(crate) worker: usize,
+    /// The server that sent it, as `host:port`.
+    pub(crate) server: String,
+    /// When the request was sent, relative to the start of the download.
+    pub(crate) started: Duration,
+    pub(crate) duration: Duration,
+}
+
+pub(crate) struct ChunkDir {
+    dir: PathBuf,
+}
+
+impl ChunkDir {
+    /// Opens `dir`, creating it and its parents if missing.
+    pub(crate) fn create(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        Ok(ChunkDir { dir: dir.to_path_buf() })
+    }
+
+    /// `chunk-<id>-<start>-<end>`, with the end exclusive.
+    fn stem(chunk_id: usize, range: &Range<usize>) -> String {
+        format!("chunk-{}-{}-{}", chunk_id, range.start, range.end)
+    }
+
+    /// The chunks a previous run left.
+    pub(crate) fn existing(&self) -> io::Result<Vec<(usize, Range<usize>)>> {
+        list_chunks(&self.dir)
+    }
+
+    /// Writes the chunk's `.meta` and then its `.bin`, the data through a
+    /// temporary name so that a `.bin` is only ever complete.
+    pub(crate) fn save(&self, record: &ChunkRecord, data: &[u8]) -> io::Result<()> {
+        let stem = Self::stem(record.chunk_id, &record.range);
+        let mut headers = Vec::new();
+        for (name, value) in record.head.headers.iter() {
+            headers.push(format!("[{}, {}]", json_string(name), json_string(value)));
+        }
+        let meta = format!("{{\"chunk\": {}, \"start\": {}, \"end\": {}, \"status\": {}, \"headers\": [{}], \
+                            \"attempt\": {}, \"worker\": {}, \"server\": {}, \"started\": {:.3}, \
+                            \"duration\": {:.3}}}\n",
+                           record.chunk_id, record.range.start, record.range.end, record.head.status,
+                           headers.join(", "), record.attempt, record.worker, json_string(&record.server),
+                           record.started.as_secs_f64(), record.duration.as_secs_f64());
+        fs::write(self.dir.join(format!("{}.meta", stem)), meta)?;
+        let partial = self.dir.join(format!("{}.part", stem));
+        fs::write(&partial, data)?;
+        fs::rename(&partial, self.dir.join(format!("{}.bin", stem)))
+    }
+
+    /// Writes `manifest.json`, listing the file of every chunk the data from
+    /// `start` on is made of, in order, and the ranges no chunk covers yet.
+    /// `size` counts from `start` and is `None` while the end is unknown.
+    pub(crate) fn write_manifest(&self, start: usize, size: Option<usize>, chunks: &[(usize, Range<usize>)],
+                                 missing: &[Range<usize>]) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(self.dir.join("manifest.json"))?);
+        writeln!(out, "{{")?;
+        writeln!(out, "  \"start\": {},", start)?;
+        let size = size.map_or("null".to_string(), |size| size.to_string());
+        writeln!(out, "  \"size\": {},", size)?;
+        write!(out, "  \"chunks\": [")?;
+        for (index, (chunk_id, range)) in chunks.iter().enumerate() {
+            let separator = if index == 0 { "" } else { "," };
+            write!(out, "{}\n    {{\"chunk\": {}, \"start\": {}, \"end\": {}, \"file\": \"{}.bin\"}}",
+                   separator, chunk_id, range.start, range.end, Self::stem(*chunk_id, range))?;
+        }
+        if !chunks.is_empty() {
+            write!(out, "\n  ")?;
+        }
+        writeln!(out, "],")?;
+        let missing: Vec<_> = missing.iter().map(|range| format!("[{}, {}]", range.start, range.end)).collect();
+        writeln!(out, "  \"missing\": [{}]", missing.join(", "))?;
+        writeln!(out, "}}")?;
+        out.flush()
+    }
+}
+
+/// The chunks saved in `dir`, read from the names of their data files and
+/// sorted by offset. Files still being written have another extension and
+/// are left out.
+fn list_chunks(dir: &Path) -> io::Result<Vec<(usize, Range<usize>)>> {
+    let mut chunks = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let parsed = name.to_str()
+            .and_then(|name| name.strip_prefix("chunk-")?.strip_suffix(".bin"))
+            .and_then(|stem| {
+                let mut numbers = stem.splitn(3, '-').map(|number| number.parse::<usize>().ok());
+                let (id, start, end) = (numbers.next()??, numbers.next()??, numbers.next()??);
+                (start < end).then_some((id, start..end))
+            });
+        chunks.extend(parsed);
+    }
+    chunks.sort_by_key(|(_, range)| (range.start, range.end));
+    Ok(chunks)
+}
+
+/// The value of a `"key": value` line in a manifest written by
+/// [`ChunkDir::write_manifest`].
+fn manifest_value<'a>(text: &'a str, key: &str) -> Option<&'a str> {
+    let prefix = format!("\"{}\":", key);
+    text.lines().find_map(|line| line.trim().strip_prefix(prefix.as_str()))
+        .map(|value| value.trim().trim_end_matches(','))
+}
+
+/// The chunk files of a `--chunks-dir` directory, checked to fit together
+/// before they are assembled into one file.
+///
+/// ```no_run
+/// use std::fs::File;
+/// use std::path::Path;
+///
+/// use buggy_client::{HashAlgo, SavedChunks};
+///
+/// let chunks = SavedChunks::open(Path::new("chunks"), Some(Path::new("chunks/manifest.json")))?;
+/// if chunks.missing.is_empty() {
+///     let (bytes, digest) = chunks.write_to(&mut File::create("data.bin")?, HashAlgo::Sha256)?;
+///     println!("{} bytes, sha256 {}", bytes, digest);
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct SavedChunks {
+    /// The file of every chunk and the bytes it holds, in offset order.
+    files: Vec<(PathBuf, Range<usize>)>,
+    /// Where the data starts in the resource.
+    pub start: usize,
+    /// Where it ends, if the manifest knew.
+    pub end: Option<usize>,
+    /// Ranges between `start` and `end` that no chunk file covers, including
+    /// the ends of files that are shorter than their names say.
+    pub missing: Vec<Range<usize>>,
+    /// The server a chunk was fetched from, as `host:port`, if any says.
+    pub server: Option<String>,
+}
+
+impl SavedChunks {
+    /// Lists the chunk files in `dir`. The start and size of the data come
+    /// from `manifest`, else the data is taken to start at byte 0 and end with
+    /// the last chunk. Overlapping chunks are an error, gaps are `missing`.
+    ///
+    /// ```no_run
+    /// use std::path::Path;
+    ///
+    /// use buggy_client::SavedChunks;
+    ///
+    /// let chunks = SavedChunks::open(Path::new("chunks"), None)?;
+    /// println!("data from byte {} to {:?}", chunks.start, chunks.end);
+    /// # Ok::<(), String>(())
+    /// ```
+    pub fn open(dir: &Path, manifest: Option<&Path>) -> Result<Self, String> {
+        let listed = list_chunks(dir).map_err(|e| format!("Cannot read chunk directory '{}': {}", dir.display(), e))?;
+        let (mut start, mut end) = (0, None);
+        if let Some(path) = manifest {
+            let text = fs::read_to_string(path)
+                .map_err(|e| format!("Cannot read manifest '{}': {}", path.display(), e))?;
+            let number = |key: &str| match manifest_value(&text, key) {
+                Some("null") => Ok(None),
+                Some(value) => value.parse::<usize>().map(Some)
+                    .map_err(|_| format!("Manifest '{}' has an invalid {}: {}", path.display(), key, value)),
+                None => Err(format!("Manifest '{}' has no {}", path.display(), key)),
+            };
+            start = number("start")?.unwrap_or(0);
+            end = number("size")?.map(|size| start + size);
+        }
+
+        let mut files = Vec::new();
+        let mut missing = Vec::new();
+        let mut covered = start;
+        for (chunk_id, range) in listed {
+            let path = dir.join(format!("{}.bin", ChunkDir::stem(chunk_id, &range)));
+            if range.end <= start || end.is_some_and(|end| range.start >= end) {
+                continue;
+            }
+            if range.start < covered {
+                return Err(format!("Chunk file {} overlaps the chunk before it, which ends at byte {}",
+                                   path.display(), covered));
+            }
+            if range.start > covered {
+                missing.push(covered..range.start);
+            }
+            let len = fs::metadata(&path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?.len();
+            let held = range.start..range.end.min(range.start + len as usize);
+            if held.end < range.end {
+                missing.push(held.end..range.end);
+            }
+            covered = range.end;
+            if !held.is_empty() {
+                files.push((path, held));
+            }
+        }
+        if let Some(end) = end.filter(|&end| covered < end) {
+            missing.push(covered..end);
+        }
+        let end = end.or(Some(covered).filter(|_| !files.is_empty()));
+
+        let server = files.first().and_then(|(path, _)| fs::read_to_string(path.with_extension("meta")).ok())
+            .and_then(|meta| {
+                let value = meta.split_once("\"server\": \"")?.1;
+                Some(value[..value.find('"')?].to_string())
+            });
+        Ok(SavedChunks { files, start, end, missing, server })
+    }
+
+    /// How many chunk files there are.
+    ///
+    /// ```no_run
+    /// use std::path::Path;
+    ///
+    /// use buggy_client::SavedChunks;
+    ///
+    /// let chunks = SavedChunks::open(Path::new("chunks"), None)?;
+    /// println!("{} chunk files", chunks.len());
+    /// # Ok::<(), String>(())
+    /// ```
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Whether there are no chunk files at all.
+    ///
+    /// ```
+    /// use buggy_client::SavedChunks;
+    ///
+    /// let dir = std::env::temp_dir().join("buggy-client-no-chunks");
+    /// std::fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+    /// assert!(SavedChunks::open(&dir, None)?.is_empty());
+    /// # Ok::<(), String>(())
+    /// ```
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    /// Copies the chunks to `out` in order, hashing them with `algo`, and
+    /// returns the bytes written and the digest. A file that was cut short
+    /// since [`SavedChunks::open`] is an error.
+    ///
+    /// ```no_run
+    /// use std::path::Path;
+    ///
+    /// use buggy_client::{HashAlgo, SavedChunks};
+    ///
+    /// let chunks = SavedChunks::open(Path::new("chunks"), None)?;
+    /// let mut data = Vec::new();
+    /// let (bytes, digest) = chunks.write_to(&mut data, HashAlgo::Crc32c)?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn write_to(&self, out: &mut impl Write, algo: HashAlgo) -> io::Result<(usize, String)> {
+        let mut hasher = algo.hasher();
+        let mut buffer = vec![0u8; 64 * 1024];
+        let mut written = 0;
+        for (path, range) in &self.files {
+            let mut reader = BufReader::new(File::open(path)?).take(range.len() as u64);
+            let mut copied = 0;
+            loop {
+                let n = reader.read(&mut buffer)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..n]);
+                out.write_all(&buffer[..n])?;
+                copied += n;
+            }
+            if copied < range.len() {
+                let message = format!("{} shrank to {} bytes while it was merged", path.display(), copied);
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, message));
+            }
+            written += copied;
+        }
+        Ok((written, hasher.finalize_hex()))
+    }
+}
@@ -0,0 +1,178 @@
+//! Chunks handed to the caller as they arrive, instead of written to a sink.
+
+use std::io;
+use std::ops::Range;
+use std::thread::{self, JoinHandle};
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+
+use crate::downloader::{Downloader, Sink, Summary};
+use crate::error::DownloadError;
+
+/// Bytes of the resource starting at `offset`.
+///
+/// ```no_run
+/// use buggy_client::Downloader;
+///
+/// for chunk in Downloader::builder().build()?.chunks() {
+///     let chunk = chunk?;
+///     println!("{} bytes at {}", chunk.data.len(), chunk.offset);
+/// }
+/// # Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Chunk {
+    /// Where the bytes start in the resource.
+    pub offset: usize,
+    /// The bytes as received.
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    /// The bytes of the resource this chunk holds.
+    ///
+    /// ```no_run
+    /// use buggy_client::Downloader;
+    ///
+    /// for chunk in Downloader::builder().build()?.chunks() {
+    ///     println!("got bytes {:?}", chunk?.range());
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+    /// ```
+    pub fn range(&self) -> Range<usize> {
+        self.offset..self.offset + self.data.len()
+    }
+}
+
+/// Sink that passes every write on to a [`Chunks`] iterator.
+struct ChunkSender {
+    chunks: Sender<Chunk>,
+    ordered: bool,
+}
+
+impl Sink for ChunkSender {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.chunks.send(Chunk { offset: offset as usize, data: data.to_vec() })
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the chunk iterator was dropped"))
+    }
+
+    fn sequential(&self) -> bool {
+        self.ordered
+    }
+
+    fn discards(&self) -> bool {
+        true
+    }
+}
+
+/// Iterator over the chunks of a download running on its own thread.
+///
+/// Only a few chunks are queued ahead of the caller: while it doesn't keep
+/// up, the download stops sending new requests. Retried ranges never show
+/// up here; once the last chunk is out, a failed download yields its error
+/// and the iteration ends. Dropping the iterator early makes the download
+/// fail on its next write and stop.
+///
+/// ```no_run
+/// use std::io::Write;
+///
+/// use buggy_client::Downloader;
+///
+/// let mut out = std::io::stdout().lock();
+/// let mut chunks = Downloader::builder().build()?.chunks_ordered();
+/// for chunk in &mut chunks {
+///     out.write_all(&chunk?.data)?;
+/// }
+/// println!("{:?}", chunks.summary().map(|summary| summary.duration));
+/// # Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+/// ```
+pub struct Chunks {
+    chunks: Receiver<Chunk>,
+    download: Option<JoinHandle<Result<Summary, DownloadError>>>,
+    summary: Option<Summary>,
+}
+
+impl Chunks {
+    /// How the download went, once the iterator has returned `None`.
+    ///
+    /// ```no_run
+    /// use buggy_client::Downloader;
+    ///
+    /// let mut chunks = Downloader::builder().build()?.chunks();
+    /// assert!(chunks.summary().is_none());
+    /// chunks.by_ref().for_each(drop);
+    /// let received = chunks.summary().map_or(0, |summary| summary.bytes);
+    /// # Ok::<(), String>(())
+    /// ```
+    pub fn summary(&self) -> Option<&Summary> {
+        self.summary.as_ref()
+    }
+}
+
+impl Iterator for Chunks {
+    type Item = Result<Chunk, DownloadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Ok(chunk) = self.chunks.recv() {
+            return Some(Ok(chunk));
+        }
+        let result = match self.download.take()?.join() {
+            Ok(result) => result,
+            Err(_) => Err(DownloadError::Internal("the download thread panicked".to_string())),
+        };
+        match result {
+            Ok(summary) => {
+                self.summary = Some(summary);
+                None
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl Downloader {
+    /// Downloads on a background thread and yields each chunk as soon as it
+    /// arrives, in whatever order the requests complete. The data is not
+    /// hashed, so an expected hash is an error.
+    ///
+    /// ```no_run
+    /// use buggy_client::Downloader;
+    ///
+    /// let mut received = 0;
+    /// for chunk in Downloader::builder().build()?.chunks() {
+    ///     received += chunk?.data.len();
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+    /// ```
+    pub fn chunks(&self) -> Chunks {
+        self.spawn_chunks(false)
+    }
+
+    /// Like [`chunks`](Self::chunks), but yields the chunks strictly by
+    /// offset, each starting where the previous one ended. Chunks that arrive
+    /// early are held back, and new ranges are only requested a bounded
+    /// distance past the last one yielded.
+    ///
+    /// ```no_run
+    /// use buggy_client::Downloader;
+    ///
+    /// let mut next = 0;
+    /// for chunk in Downloader::builder().build()?.chunks_ordered() {
+    ///     let chunk = chunk?;
+    ///     assert_eq!(chunk.offset, next);
+    ///     next = chunk.range().end;
+    /// }
+    /// # Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+    /// ```
+    pub fn chunks_ordered(&self) -> Chunks {
+        self.spawn_chunks(true)
+    }
+
+    fn spawn_chunks(&self, ordered: bool) -> Chunks {
+        let (sender, receiver) = bounded(self.max_concurrency());
+        let downloader = self.clone();
+        let download = thread::spawn(move || downloader.download(&mut ChunkSender { chunks: sender, ordered }));
+        Chunks { chunks: receiver, download: Some(download), summary: None }
+    }
+}
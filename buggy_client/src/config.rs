@@ -0,0 +1,91 @@
+//! Config files: TOML files of top-level keys that give command line options
+//! defaults.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use toml::{Spanned, Value};
+
+/// One `key = value` line of a config file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigEntry {
+    pub key: String,
+    pub value: ConfigValue,
+    /// Line number, from 1.
+    pub line: usize,
+}
+
+/// A TOML value. Tables and dates are not supported.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigValue {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<ConfigValue>),
+}
+
+impl ConfigValue {
+    /// What kind of value this is, for error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ConfigValue::String(_) => "a string",
+            ConfigValue::Integer(_) => "an integer",
+            ConfigValue::Float(_) => "a float",
+            ConfigValue::Boolean(_) => "a boolean",
+            ConfigValue::Array(_) => "an array",
+        }
+    }
+}
+
+/// Formats the value as it would be typed on the command line: strings
+/// without quotes, arrays comma-separated.
+impl fmt::Display for ConfigValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigValue::String(text) => f.write_str(text),
+            ConfigValue::Integer(number) => write!(f, "{}", number),
+            ConfigValue::Float(number) => write!(f, "{}", number),
+            ConfigValue::Boolean(flag) => write!(f, "{}", flag),
+            ConfigValue::Array(values) => {
+                let values: Vec<_> = values.iter().map(ToString::to_string).collect();
+                f.write_str(&values.join(", "))
+            }
+        }
+    }
+}
+
+/// Parses a TOML file whose keys are all at the top level. Strings,
+/// integers, floats, booleans and arrays of them are understood; tables and
+/// dates are errors. Keys keep their spelling, so `chunk_size` and
+/// `chunk-size` are told apart by the caller. Entries come in file order.
+pub fn parse_config(text: &str) -> Result<Vec<ConfigEntry>, String> {
+    let text = text.trim_start_matches('\u{feff}');
+    let line_of = |offset: usize| text[..offset.min(text.len())].matches('\n').count() + 1;
+    let table: BTreeMap<String, Spanned<Value>> = toml::from_str(text).map_err(|e| match e.span() {
+        Some(span) => format!("line {}: {}", line_of(span.start), e.message().trim_end()),
+        None => e.message().trim_end().to_string(),
+    })?;
+    let mut entries = table.into_iter()
+        .map(|(key, value)| {
+            let line = line_of(value.span().start);
+            let value = config_value(value.into_inner()).map_err(|e| format!("line {}: '{}' {}", line, key, e))?;
+            Ok(ConfigEntry { key, value, line })
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    entries.sort_by_key(|entry| entry.line);
+    Ok(entries)
+}
+
+fn config_value(value: Value) -> Result<ConfigValue, String> {
+    Ok(match value {
+        Value::String(text) => ConfigValue::String(text),
+        Value::Integer(number) => ConfigValue::Integer(number),
+        Value::Float(number) => ConfigValue::Float(number),
+        Value::Boolean(flag) => ConfigValue::Boolean(flag),
+        Value::Array(values) => ConfigValue::Array(values.into_iter().map(config_value).collect::<Result<_, _>>()?),
+        Value::Datetime(_) => return Err("is a date; put it in quotes".to_string()),
+        Value::Table(_) => return Err("is a table; tables are not supported, put every option at the top level"
+            .to_string()),
+    })
+}
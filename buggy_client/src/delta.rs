@@ -0,0 +1,82 @@
+//! Delta updates: copying the ranges an older copy of the resource shares
+//! with the server instead of downloading them again.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use sha2::{Digest, Sha256};
+
+/// The sha256 of each block of the resource, and of the whole of it when
+/// known, as a `.hashes` sidecar or the `X-Chunk-Checksum` headers tell them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct BlockHashes {
+    pub(crate) blocks: Vec<(Range<usize>, String)>,
+    pub(crate) whole: Option<String>,
+}
+
+impl BlockHashes {
+    /// Reads a sidecar: a `<start>-<end> <sha256>` line for each block, with
+    /// the end exclusive, and optionally a `sha256 <hex>` line for the whole
+    /// file. Blank lines and `#` comments are skipped.
+    pub(crate) fn parse(text: &str) -> Result<Self, String> {
+        let mut hashes = BlockHashes::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &str| format!("line {}: {}", number + 1, reason);
+            let (key, hash) = line.split_once(char::is_whitespace)
+                .ok_or_else(|| invalid("expected a range and a hash"))?;
+            let hash = hash.trim().to_ascii_lowercase();
+            if hash.len() != 64 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                return Err(invalid("expected a sha256 in hex"));
+            }
+            if key.eq_ignore_ascii_case("sha256") {
+                hashes.whole = Some(hash);
+                continue;
+            }
+            let range = key.split_once('-')
+                .and_then(|(start, end)| Some(start.parse::<usize>().ok()?..end.parse::<usize>().ok()?))
+                .filter(|range| range.start < range.end)
+                .ok_or_else(|| invalid("expected a range such as 0-1048576"))?;
+            hashes.blocks.push((range, hash));
+        }
+        Ok(hashes)
+    }
+
+    /// The blocks that lie within the first `len` bytes, in order, leaving
+    /// out any that overlap one before them.
+    pub(crate) fn within(&self, len: usize) -> Vec<(Range<usize>, String)> {
+        let mut blocks: Vec<_> = self.blocks.iter().filter(|(range, _)| range.end <= len).cloned().collect();
+        blocks.sort_by_key(|(range, _)| range.start);
+        let mut end = 0;
+        blocks.retain(|(range, _)| {
+            let keep = range.start >= end;
+            end = end.max(range.end);
+            keep
+        });
+        blocks
+    }
+}
+
+/// Hands `reuse` the offset and data of every block of `base` that has the
+/// listed hash, reading one block at a time. Blocks past the end of `base`
+/// are left out.
+pub(crate) fn matching_blocks(base: &mut File, blocks: &[(Range<usize>, String)],
+                              mut reuse: impl FnMut(usize, Vec<u8>) -> io::Result<()>) -> io::Result<()> {
+    for (range, hash) in blocks {
+        let mut data = vec![0; range.len()];
+        base.seek(SeekFrom::Start(range.start as u64))?;
+        match base.read_exact(&mut data) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        if format!("{:x}", Sha256::digest(&data)) == *hash {
+            reuse(range.start, data)?;
+        }
+    }
+    Ok(())
+}
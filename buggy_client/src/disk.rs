@@ -0,0 +1,29 @@
+//! Free space on the filesystem the output goes to.
+
+use std::io;
+use std::path::Path;
+
+/// Bytes an unprivileged process can still write on the filesystem holding
+/// `path`, or `None` where that can't be asked.
+#[cfg(unix)]
+pub(crate) fn available_space(path: &Path) -> io::Result<Option<u64>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let stats = unsafe { stats.assume_init() };
+    // The field types differ between platforms; on some they are u64 already.
+    #[allow(clippy::unnecessary_cast)]
+    let available = stats.f_bavail as u64 * stats.f_frsize as u64;
+    Ok(Some(available))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn available_space(_path: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
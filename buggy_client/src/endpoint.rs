@@ -0,0 +1,243 @@
+//! Resolving the server's host name and picking an address that accepts
+//! connections.
+
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use socket2::{SockRef, TcpKeepalive};
+
+use crate::message::format_authority;
+use crate::proxy::Proxy;
+use crate::stream::Stream;
+
+/// Which kind of address to try first when a host resolves to both.
+///
+/// ```
+/// use buggy_client::{AddressFamily, Downloader};
+///
+/// let builder = Downloader::builder().host("localhost").prefer_family(AddressFamily::Ipv6);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AddressFamily {
+    /// IPv4 addresses first.
+    Ipv4,
+    /// IPv6 addresses first.
+    Ipv6,
+}
+
+impl AddressFamily {
+    /// Parses `ipv4` or `ipv6`, or just `4` or `6`.
+    ///
+    /// ```
+    /// use buggy_client::AddressFamily;
+    ///
+    /// assert_eq!(AddressFamily::parse("IPv4"), Ok(AddressFamily::Ipv4));
+    /// assert_eq!(AddressFamily::parse("6"), Ok(AddressFamily::Ipv6));
+    /// assert!(AddressFamily::parse("ipx").is_err());
+    /// ```
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "ipv4" | "4" => Ok(AddressFamily::Ipv4),
+            "ipv6" | "6" => Ok(AddressFamily::Ipv6),
+            _ => Err(format!("Unknown address family '{}': expected ipv4 or ipv6", name)),
+        }
+    }
+
+    fn contains(self, address: &SocketAddr) -> bool {
+        match self {
+            AddressFamily::Ipv4 => address.is_ipv4(),
+            AddressFamily::Ipv6 => address.is_ipv6(),
+        }
+    }
+}
+
+/// Options applied to every connection right after it is made; anything
+/// unset keeps the OS default.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct SocketOptions {
+    pub(crate) nodelay: bool,
+    pub(crate) recv_buffer: Option<usize>,
+    pub(crate) keepalive: Option<Duration>,
+}
+
+/// A host and port, resolved on first use. Connections try every address
+/// with its own timeout, starting with the last one that worked, so later
+/// connections don't wait on an address that is known to be dead.
+///
+/// With a proxy or a Unix socket the host and port only name the server in
+/// requests; every connection goes to the proxy or the socket.
+pub(crate) struct Endpoint {
+    host: String,
+    port: u16,
+    proxy: Option<(String, u16)>,
+    unix_socket: Option<PathBuf>,
+    prefer: Option<AddressFamily>,
+    socket: SocketOptions,
+    resolved: Mutex<Vec<SocketAddr>>,
+    chosen: Mutex<Option<SocketAddr>>,
+    tuned: AtomicBool,
+}
+
+impl Endpoint {
+    pub(crate) fn new(host: &str, port: u16, prefer: Option<AddressFamily>, socket: SocketOptions) -> Self {
+        let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+        Endpoint {
+            host: host.to_string(),
+            port,
+            proxy: None,
+            unix_socket: None,
+            prefer,
+            socket,
+            resolved: Mutex::new(Vec::new()),
+            chosen: Mutex::new(None),
+            tuned: AtomicBool::new(false),
+        }
+    }
+
+    /// Sends every connection to `proxy` instead.
+    pub(crate) fn through_proxy(mut self, proxy: Option<&Proxy>) -> Self {
+        self.proxy = proxy.map(|proxy| (proxy.host.clone(), proxy.port));
+        self
+    }
+
+    /// Sends every connection to the Unix socket at `path` instead.
+    pub(crate) fn through_unix_socket(mut self, path: Option<PathBuf>) -> Self {
+        self.unix_socket = path;
+        self
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn unix_socket(&self) -> Option<&Path> {
+        self.unix_socket.as_deref()
+    }
+
+    /// The server as `host:port`.
+    pub(crate) fn authority(&self) -> String {
+        format_authority(&self.host, self.port)
+    }
+
+    /// The addresses to try in order: the one that worked last, then the
+    /// rest with the preferred family first. They are the proxy's if there
+    /// is one.
+    pub(crate) fn addresses(&self) -> io::Result<Vec<SocketAddr>> {
+        let (host, port) = self.proxy.as_ref().map_or((&self.host, self.port), |(host, port)| (host, *port));
+        let mut resolved = self.resolved.lock().unwrap();
+        if resolved.is_empty() {
+            let mut addresses: Vec<_> = (host.as_str(), port).to_socket_addrs()?.collect();
+            if let Some(family) = self.prefer {
+                addresses.sort_by_key(|address| !family.contains(address));
+            }
+            if addresses.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::NotFound,
+                                          format!("'{}' did not resolve to any address", host)));
+            }
+            log::debug!("{} resolved to {:?}", host, addresses);
+            *resolved = addresses;
+        }
+        let mut addresses = resolved.clone();
+        if let Some(chosen) = *self.chosen.lock().unwrap() {
+            addresses.retain(|address| *address != chosen);
+            addresses.insert(0, chosen);
+        }
+        Ok(addresses)
+    }
+
+    /// Records that `address` accepted a connection.
+    pub(crate) fn connected(&self, address: SocketAddr) {
+        let mut chosen = self.chosen.lock().unwrap();
+        if *chosen != Some(address) {
+            match &self.proxy {
+                Some((host, port)) => log::info!("Connected to {} through proxy {} at {}", self.host,
+                                                 format_authority(host, *port), address),
+                None => log::info!("Connected to {} at {}", self.host, address),
+            }
+            *chosen = Some(address);
+        }
+    }
+
+    /// Applies the socket options to a fresh connection. The values in
+    /// effect are logged once, with a warning if the OS clamped the receive
+    /// buffer below the requested size.
+    pub(crate) fn tune(&self, socket: SockRef<'_>) -> io::Result<()> {
+        let options = &self.socket;
+        if options.nodelay {
+            socket.set_nodelay(true)?;
+        }
+        if let Some(size) = options.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(time) = options.keepalive {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+        if !self.tuned.swap(true, Ordering::Relaxed) {
+            let recv_buffer = socket.recv_buffer_size()?;
+            let keepalive = options.keepalive.map_or("OS default".to_string(), |time| format!("after {}s", time.as_secs()));
+            log::debug!("Socket options: nodelay {}, receive buffer {} bytes, keepalive {}",
+                        socket.nodelay()?, recv_buffer, keepalive);
+            if let Some(size) = options.recv_buffer.filter(|&size| recv_buffer < size) {
+                log::warn!("The OS clamped the {} byte receive buffer to {} bytes", size, recv_buffer);
+            }
+        }
+        Ok(())
+    }
+
+    /// Records that the Unix socket accepted a connection.
+    pub(crate) fn connected_unix(&self) {
+        if !self.tuned.swap(true, Ordering::Relaxed) {
+            log::info!("Connected to {} through {}", self.host, self.unix_socket.as_ref().unwrap().display());
+        }
+    }
+
+    /// Connects to the first address that accepts within `timeout`, or to
+    /// the Unix socket if there is one. `None` waits as long as the system
+    /// does.
+    pub(crate) fn connect(&self, timeout: Option<Duration>) -> io::Result<Stream> {
+        if let Some(path) = &self.unix_socket {
+            return self.connect_unix(path, timeout);
+        }
+        let mut last_error = None;
+        for address in self.addresses()? {
+            let connected = match timeout {
+                Some(timeout) => TcpStream::connect_timeout(&address, timeout),
+                None => TcpStream::connect(address),
+            };
+            match connected {
+                Ok(stream) => {
+                    self.connected(address);
+                    self.tune(SockRef::from(&stream))?;
+                    return Ok(stream.into());
+                }
+                Err(e) => {
+                    log::debug!("Connecting to {} failed: {}", address, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address to connect to")))
+    }
+
+    #[cfg(unix)]
+    fn connect_unix(&self, path: &Path, timeout: Option<Duration>) -> io::Result<Stream> {
+        use socket2::{Domain, SockAddr, Socket, Type};
+        let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+        match timeout {
+            Some(timeout) => socket.connect_timeout(&SockAddr::unix(path)?, timeout)?,
+            None => socket.connect(&SockAddr::unix(path)?)?,
+        }
+        self.connected_unix();
+        Ok(Stream::Unix(std::os::fd::OwnedFd::from(socket).into()))
+    }
+
+    #[cfg(not(unix))]
+    fn connect_unix(&self, _path: &Path, _timeout: Option<Duration>) -> io::Result<Stream> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, UNIX_SOCKETS_UNSUPPORTED))
+    }
+}
+
+/// Why `--unix-socket` fails on targets without Unix domain sockets.
+pub(crate) const UNIX_SOCKETS_UNSUPPORTED: &str = "Unix domain sockets are only supported on Unix";
@@ -0,0 +1,342 @@
+//! The error a download fails with, one variant for each way it can fail so
+//! callers can tell them apart without reading the message.
+
+use std::fmt;
+use std::io;
+use std::ops::Range;
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::cancel::Cancelled;
+use crate::downloader::{ChunkError, ErrorPhase, ResourceChanged, TooLarge, MAX_TAIL_REQUESTS};
+use crate::http::ConnectFailed;
+
+/// Error returned by [`Downloader`](crate::Downloader) requests. Failures of
+/// single chunks that a retry made up for only show up in the summary; the
+/// variants from [`DownloadError::Resolve`] to [`DownloadError::Network`]
+/// describe them there, and end a download as the `source` of a
+/// [`DownloadError::ChunkFailed`].
+///
+/// ```no_run
+/// use buggy_client::{DownloadError, Downloader};
+///
+/// match Downloader::builder().build()?.download(&mut Vec::new()) {
+///     Ok(summary) => println!("{} bytes", summary.bytes),
+///     Err(DownloadError::Connect { addr, .. }) => eprintln!("{} is down", addr),
+///     Err(e @ DownloadError::ChunkFailed { .. }) => eprintln!("gave up: {}", e.cause().name()),
+///     Err(e) => eprintln!("{}", e),
+/// }
+/// # Ok::<(), String>(())
+/// ```
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum DownloadError {
+    /// The options, the sink or the resource do not allow the download;
+    /// nothing was fetched.
+    #[error("{0}")]
+    Unsupported(String),
+    /// The host name could not be resolved.
+    #[error("Cannot resolve '{host}': {source}")]
+    Resolve {
+        /// The host name.
+        host: String,
+        /// Why the lookup failed.
+        source: io::Error,
+    },
+    /// No connection could be opened.
+    #[error("could not connect to {addr}: {source}")]
+    Connect {
+        /// The server, as `host:port`.
+        addr: String,
+        /// Why the connect failed.
+        source: io::Error,
+    },
+    /// Nothing came within the timeout.
+    #[error("{}", if *.phase == ErrorPhase::Connect { "connect timed out" } else { "timed out waiting for data" })]
+    Timeout {
+        /// [`ErrorPhase::Connect`] for the connect timeout, else that of a
+        /// read.
+        phase: ErrorPhase,
+    },
+    /// A transfer stayed below the lowest speed allowed for too long.
+    #[error("transfer stalled at {speed} bytes/s for {}s, below the limit of {limit} bytes/s", .time.as_secs_f64())]
+    Stalled {
+        /// The speed it had, in bytes/s.
+        speed: u64,
+        /// The lowest speed allowed, in bytes/s.
+        limit: u64,
+        /// How long it was that slow.
+        time: Duration,
+    },
+    /// The server answered with a status that carries no data.
+    #[error("Server answered {}with status {code}", .chunk.map_or(String::new(), |chunk| format!("the request for chunk {} ", chunk)))]
+    HttpStatus {
+        /// The status code.
+        code: u16,
+        /// The chunk asked for, if the request was for one.
+        chunk: Option<usize>,
+    },
+    /// The server answered 429 Too Many Requests.
+    #[error("429 Too Many Requests{}", .retry_after.map_or(String::new(), |wait| format!(", retry after {}s", wait.as_secs())))]
+    Throttled {
+        /// How long the server asked to wait, if it said.
+        retry_after: Option<Duration>,
+    },
+    /// A chunk kept being cut short, even after the most tail requests
+    /// allowed.
+    #[error("still {} bytes short after {MAX_TAIL_REQUESTS} tail requests", .expected - .got)]
+    Truncated {
+        /// The chunk.
+        chunk: usize,
+        /// Bytes the last tail request asked for.
+        expected: usize,
+        /// Bytes it brought.
+        got: usize,
+    },
+    /// The read timed out in the body of a response that had more to come.
+    #[error("the server stopped sending after {got}{} body bytes", .expected.map_or(String::new(), |len| format!(" of {}", len)))]
+    CutShort {
+        /// Length of the body, if the server said.
+        expected: Option<usize>,
+        /// Body bytes that came.
+        got: usize,
+    },
+    /// Data that does not match the checksum sent with it.
+    #[error("Chunk checksum mismatch: expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        /// The checksum the server sent.
+        expected: String,
+        /// The checksum of the data.
+        actual: String,
+    },
+    /// A response that could not be understood, or does not fit the request.
+    #[error("{0}")]
+    Protocol(String),
+    /// The connection failed after it was opened.
+    #[error("{0}")]
+    Network(io::Error),
+    /// A chunk failed for good and that ended the download: it ran out of
+    /// retries with fail-fast, or the retry budget ran out.
+    #[error("{}", chunk_failed(*.chunk, .range, *.phase, *.attempt, *.budget, .source))]
+    ChunkFailed {
+        /// The chunk.
+        chunk: usize,
+        /// Byte range it asked for.
+        range: Range<usize>,
+        /// The phase its last try failed in.
+        phase: ErrorPhase,
+        /// Which try in that phase it was, counting from 1.
+        attempt: usize,
+        /// The retry budget, when that is what ran out.
+        budget: Option<usize>,
+        /// Its last failure.
+        source: Box<DownloadError>,
+    },
+    /// Byte ranges nobody could fetch. `Downloader::download` returns it
+    /// only for streaming sinks, which cannot write past the hole; other
+    /// sinks get a [`Summary`](crate::Summary) with `missing` set.
+    #[error("{}", incomplete(.missing_ranges))]
+    Incomplete {
+        /// The ranges, in order.
+        missing_ranges: Vec<Range<usize>>,
+    },
+    /// The resource is larger than allowed.
+    #[error(transparent)]
+    TooLarge(#[from] TooLarge),
+    /// The resource changed on the server during the download.
+    #[error(transparent)]
+    ResourceChanged(#[from] ResourceChanged),
+    /// The cancellation token was cancelled.
+    #[error(transparent)]
+    Cancelled(#[from] Cancelled),
+    /// Reading or writing local data: the sink, a delta base, the cache or
+    /// a chunks directory.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// A failure of the client itself, such as a worker thread that died.
+    #[error("{0}")]
+    Internal(String),
+}
+
+impl DownloadError {
+    /// Sorts out the error a request failed with. `addr` names the server
+    /// for a connect that failed.
+    pub(crate) fn from_transfer(error: Box<dyn std::error::Error>, addr: impl FnOnce() -> String) -> Self {
+        let error = match error.downcast::<DownloadError>() {
+            Ok(error) => return *error,
+            Err(error) => error,
+        };
+        let timed_out = |error: &io::Error| matches!(error.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock);
+        let error = match error.downcast::<ConnectFailed>() {
+            Ok(failed) if timed_out(&failed.0) => return DownloadError::Timeout { phase: ErrorPhase::Connect },
+            Ok(failed) => return DownloadError::Connect { addr: addr(), source: failed.0 },
+            Err(error) => error,
+        };
+        match error.downcast::<io::Error>() {
+            Ok(error) if timed_out(&error) => DownloadError::Timeout { phase: ErrorPhase::Transfer },
+            Ok(error) => DownloadError::Network(*error),
+            Err(error) => DownloadError::Protocol(error.to_string()),
+        }
+    }
+
+    /// What the error comes down to, which the summary groups failed
+    /// requests by.
+    ///
+    /// ```
+    /// use buggy_client::{DownloadError, ErrorCause};
+    ///
+    /// let error = DownloadError::HttpStatus { code: 503, chunk: None };
+    /// assert_eq!(error.cause(), ErrorCause::HttpStatus(503));
+    /// ```
+    pub fn cause(&self) -> ErrorCause {
+        match self {
+            DownloadError::Connect { source, .. } if source.kind() == io::ErrorKind::ConnectionRefused => {
+                ErrorCause::ConnectRefused
+            }
+            DownloadError::Resolve { .. } | DownloadError::Connect { .. } => ErrorCause::Connect,
+            DownloadError::Timeout { .. } | DownloadError::CutShort { .. } => ErrorCause::Timeout,
+            DownloadError::Stalled { .. } => ErrorCause::Stalled,
+            DownloadError::HttpStatus { code, .. } => ErrorCause::HttpStatus(*code),
+            DownloadError::Throttled { .. } => ErrorCause::Throttled,
+            DownloadError::Truncated { .. } => ErrorCause::Truncated,
+            DownloadError::ChecksumMismatch { .. } => ErrorCause::ChecksumMismatch,
+            DownloadError::Protocol(_) | DownloadError::Network(_) => ErrorCause::Transfer,
+            DownloadError::ChunkFailed { source, .. } => source.cause(),
+            _ => ErrorCause::Other,
+        }
+    }
+
+    /// The last failure of the chunk that ended the download, as a summary
+    /// would list it, for a [`DownloadError::ChunkFailed`].
+    ///
+    /// ```
+    /// use buggy_client::DownloadError;
+    ///
+    /// assert!(DownloadError::Protocol("bad chunk".to_string()).chunk_error().is_none());
+    /// ```
+    pub fn chunk_error(&self) -> Option<ChunkError> {
+        match self {
+            DownloadError::ChunkFailed { chunk, range, phase, attempt, source, .. } => Some(ChunkError {
+                chunk_id: *chunk,
+                range: range.clone(),
+                attempt: *attempt,
+                phase: *phase,
+                cause: source.cause(),
+                message: source.to_string(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The phase whose retries a failed request counts against.
+    ///
+    /// ```
+    /// use buggy_client::{DownloadError, ErrorPhase};
+    ///
+    /// let error = DownloadError::Throttled { retry_after: None };
+    /// assert_eq!(error.phase(), ErrorPhase::Throttled);
+    /// ```
+    pub fn phase(&self) -> ErrorPhase {
+        match self {
+            DownloadError::Resolve { .. } | DownloadError::Connect { .. } => ErrorPhase::Connect,
+            DownloadError::Timeout { phase } | DownloadError::ChunkFailed { phase, .. } => *phase,
+            DownloadError::Throttled { .. } => ErrorPhase::Throttled,
+            _ => ErrorPhase::Transfer,
+        }
+    }
+}
+
+/// What a failed request came down to, as told by [`DownloadError::cause`].
+///
+/// ```no_run
+/// use buggy_client::{Downloader, ErrorCause};
+///
+/// let summary = Downloader::builder().build()?.download(&mut Vec::new())?;
+/// let refused = summary.errors.iter().filter(|error| error.cause == ErrorCause::ConnectRefused).count();
+/// # Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[non_exhaustive]
+pub enum ErrorCause {
+    /// The server refused the connection.
+    ConnectRefused,
+    /// The host could not be resolved or reached.
+    Connect,
+    /// Nothing came within a timeout.
+    Timeout,
+    /// The transfer was slower than allowed.
+    Stalled,
+    /// The server answered with this status.
+    HttpStatus(u16),
+    /// The server answered 429 Too Many Requests.
+    Throttled,
+    /// Responses kept being cut short.
+    Truncated,
+    /// The data did not match the checksum sent with it.
+    ChecksumMismatch,
+    /// The connection broke off, or the response made no sense.
+    Transfer,
+    /// Anything else.
+    Other,
+}
+
+impl ErrorCause {
+    /// A short name for machine-readable output, like `timeout` or
+    /// `http_503`.
+    ///
+    /// ```
+    /// use buggy_client::ErrorCause;
+    ///
+    /// assert_eq!(ErrorCause::HttpStatus(503).name(), "http_503");
+    /// assert_eq!(ErrorCause::ConnectRefused.name(), "connect_refused");
+    /// ```
+    pub fn name(self) -> String {
+        match self {
+            ErrorCause::ConnectRefused => "connect_refused".to_string(),
+            ErrorCause::Connect => "connect".to_string(),
+            ErrorCause::Timeout => "timeout".to_string(),
+            ErrorCause::Stalled => "stalled".to_string(),
+            ErrorCause::HttpStatus(code) => format!("http_{}", code),
+            ErrorCause::Throttled => "throttled".to_string(),
+            ErrorCause::Truncated => "truncated".to_string(),
+            ErrorCause::ChecksumMismatch => "checksum_mismatch".to_string(),
+            ErrorCause::Transfer => "transfer".to_string(),
+            ErrorCause::Other => "other".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCause::ConnectRefused => f.write_str("connection refused"),
+            ErrorCause::Connect => f.write_str("could not connect"),
+            ErrorCause::Timeout => f.write_str("timed out"),
+            ErrorCause::Stalled => f.write_str("stalled below the speed limit"),
+            ErrorCause::HttpStatus(code) => write!(f, "HTTP status {}", code),
+            ErrorCause::Throttled => f.write_str("429 Too Many Requests"),
+            ErrorCause::Truncated => f.write_str("cut short again and again"),
+            ErrorCause::ChecksumMismatch => f.write_str("chunk checksum mismatch"),
+            ErrorCause::Transfer => f.write_str("connection broke off or bad response"),
+            ErrorCause::Other => f.write_str("other"),
+        }
+    }
+}
+
+fn chunk_failed(chunk: usize, range: &Range<usize>, phase: ErrorPhase, attempt: usize, budget: Option<usize>,
+                source: &DownloadError) -> String {
+    match budget {
+        Some(budget) => format!("Retry budget of {} used up: chunk {} (bytes {}-{}) failed: {}", budget, chunk,
+                                range.start, range.end, source),
+        None => format!("Chunk {} (bytes {}-{}) failed on {} attempt {}: {}", chunk, range.start, range.end,
+                        phase.as_str(), attempt, source),
+    }
+}
+
+fn incomplete(missing: &[Range<usize>]) -> String {
+    let bytes: usize = missing.iter().map(|range| range.len()).sum();
+    let ranges: Vec<_> = missing.iter().take(5).map(|range| format!("{}-{}", range.start, range.end)).collect();
+    format!("Download incomplete: {} bytes in {} ranges are missing ({}{})", bytes, missing.len(), ranges.join(", "),
+            if missing.len() > 5 { ", ..." } else { "" })
+}
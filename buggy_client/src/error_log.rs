@@ -0,0 +1,40 @@
+//! Appends every chunk error to a log as it happens.
+
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::downloader::ChunkError;
+
+/// One line per chunk error, written as soon as the error is seen so a long
+/// run leaves a record even if the terminal scrollback is gone.
+pub(crate) struct ErrorLog {
+    /// The writer, or `None` once a write failed and the log was given up.
+    out: Mutex<Option<Box<dyn Write + Send>>>,
+}
+
+impl ErrorLog {
+    pub(crate) fn new(out: impl Write + Send + 'static) -> Self {
+        ErrorLog { out: Mutex::new(Some(Box::new(out))) }
+    }
+
+    /// Appends `error`, marked `gave_up` if the chunk is not tried again.
+    /// The first failed write is reported once and turns the log off; it
+    /// never fails the download.
+    pub(crate) fn record(&self, error: &ChunkError, gave_up: bool) {
+        let mut out = self.out.lock().unwrap();
+        let writer = match out.as_mut() {
+            Some(writer) => writer,
+            None => return,
+        };
+        let ts = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0);
+        let written = writeln!(writer, "{:.3} phase={} chunk={} bytes={}-{} attempt={}{} error={}",
+                               ts, error.phase.as_str(), error.chunk_id, error.range.start, error.range.end,
+                               error.attempt, if gave_up { " gave_up" } else { "" }, error.message.replace('\n', " "))
+            .and_then(|()| writer.flush());
+        if let Err(e) = written {
+            log::warn!("Cannot write to the error log, no more errors will be recorded: {}", e);
+            *out = None;
+        }
+    }
+}
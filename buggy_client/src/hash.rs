@@ -0,0 +1,452 @@
+//! Whole-file digests, taken inline or on threads of their own.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+
+use crossbeam_channel::{Receiver, Sender};
+
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+
+/// Hashes the file at `path` without reading it into memory.
+pub(crate) fn hash_file(path: &Path, algo: HashAlgo) -> std::io::Result<String> {
+    let mut reader = std::io::BufReader::new(File::open(path)?);
+    let mut hasher = algo.hasher();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher.finalize_hex())
+}
+
+/// How much [`hash_file_with`] reads at a time.
+const READ_BLOCK: usize = 4 * 1024 * 1024;
+
+/// Like [`hash_file`], but with `threads` threads hashing beside the one
+/// reading the file in large blocks. See [`Hashing`] for what the threads
+/// do; the digest is the same as [`hash_file`] gives.
+pub(crate) fn hash_file_with(path: &Path, algo: HashAlgo, threads: usize) -> std::io::Result<String> {
+    if threads <= 1 {
+        return hash_file(path, algo);
+    }
+    let mut file = File::open(path)?;
+    let mut hashing = Hashing::new(algo, threads);
+    loop {
+        let mut block = hashing.spare_block().unwrap_or_default();
+        block.resize(READ_BLOCK, 0);
+        let mut filled = 0;
+        while filled < block.len() {
+            match file.read(&mut block[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        if filled == 0 {
+            break;
+        }
+        block.truncate(filled);
+        hashing.update_owned(block);
+    }
+    Ok(hashing.finalize_hex())
+}
+
+/// A [`Hasher`] that, given more than one thread, runs on a thread of its
+/// own fed through a short queue, so hashing overlaps with whatever produces
+/// the data. SHA-256 and the other serial digests can only use that one
+/// thread, and give the usual digest; BLAKE3 also spreads each block over
+/// `threads` threads.
+pub(crate) enum Hashing {
+    Inline(Hasher),
+    /// Blocks go to the worker through `blocks`, and come back through
+    /// `spent` once hashed to be filled again.
+    Background { blocks: Sender<Vec<u8>>, spent: Receiver<Vec<u8>>, worker: JoinHandle<String> },
+}
+
+impl Hashing {
+    /// Blocks queued for the background thread before `update` waits.
+    const QUEUE: usize = 4;
+
+    pub(crate) fn new(algo: HashAlgo, threads: usize) -> Self {
+        if threads <= 1 || algo == HashAlgo::None {
+            return Hashing::Inline(algo.hasher());
+        }
+        let (blocks, queue) = crossbeam_channel::bounded::<Vec<u8>>(Self::QUEUE);
+        let (done, spent) = crossbeam_channel::bounded::<Vec<u8>>(Self::QUEUE);
+        let worker = thread::Builder::new().name("hash".to_string()).spawn(move || {
+            let mut hasher = algo.hasher();
+            #[cfg(feature = "blake3")]
+            if let Hasher::Blake3(blake3) = &mut hasher {
+                match rayon::ThreadPoolBuilder::new().num_threads(threads).build() {
+                    Ok(pool) => {
+                        pool.install(|| queue.iter().for_each(|block| {
+                            blake3.update_rayon(&block);
+                            let _ = done.try_send(block);
+                        }));
+                        return hasher.finalize_hex();
+                    }
+                    Err(e) => log::warn!("Cannot start {} hashing threads, hashing on one: {}", threads, e),
+                }
+            }
+            for block in queue {
+                hasher.update(&block);
+                let _ = done.try_send(block);
+            }
+            hasher.finalize_hex()
+        });
+        match worker {
+            Ok(worker) => Hashing::Background { blocks, spent, worker },
+            Err(e) => {
+                log::warn!("Cannot start a hashing thread, hashing inline: {}", e);
+                Hashing::Inline(algo.hasher())
+            }
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Hashing::Inline(hasher) => hasher.update(data),
+            Hashing::Background { .. } => {
+                let mut block = self.spare_block().unwrap_or_default();
+                block.clear();
+                block.extend_from_slice(data);
+                self.update_owned(block);
+            }
+        }
+    }
+
+    /// Like [`Hashing::update`], without copying `data` for the background thread.
+    pub(crate) fn update_owned(&mut self, data: Vec<u8>) {
+        match self {
+            Hashing::Inline(hasher) => hasher.update(&data),
+            // The worker only stops early by panicking, which `finalize_hex` passes on.
+            Hashing::Background { blocks, .. } => {
+                let _ = blocks.send(data);
+            }
+        }
+    }
+
+    /// A block the background thread is done with, to fill again instead
+    /// of allocating another.
+    pub(crate) fn spare_block(&self) -> Option<Vec<u8>> {
+        match self {
+            Hashing::Inline(_) => None,
+            Hashing::Background { spent, .. } => spent.try_recv().ok(),
+        }
+    }
+
+    /// Waits for the background thread to hash everything queued.
+    pub(crate) fn finalize_hex(self) -> String {
+        match self {
+            Hashing::Inline(hasher) => hasher.finalize_hex(),
+            Hashing::Background { blocks, worker, .. } => {
+                drop(blocks);
+                worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+            }
+        }
+    }
+}
+
+/// Digest used for the whole-file hash and for `--verify`.
+///
+/// ```
+/// use buggy_client::{Downloader, HashAlgo};
+///
+/// let builder = Downloader::builder().hash_algo(HashAlgo::Sha512);
+/// assert_eq!(HashAlgo::Sha512.label(), "SHA-512");
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HashAlgo {
+    /// SHA-256, the default.
+    Sha256,
+    /// SHA-512.
+    Sha512,
+    /// SHA-1, for servers that only publish it.
+    Sha1,
+    /// MD5, for servers that only publish it.
+    Md5,
+    /// BLAKE3, with the `blake3` feature.
+    #[cfg(feature = "blake3")]
+    Blake3,
+    /// CRC-32 as used by gzip and zip, not a cryptographic hash.
+    Crc32,
+    /// CRC-32C, hardware accelerated on most CPUs, not a cryptographic hash.
+    Crc32c,
+    /// 64-bit XXH3, faster still on long inputs, not a cryptographic hash.
+    #[cfg(feature = "xxh3")]
+    Xxh3,
+    /// No digest at all; the hash is empty and cannot be verified.
+    None,
+}
+
+impl HashAlgo {
+    /// The cryptographic hashes compiled in.
+    ///
+    /// ```
+    /// use buggy_client::HashAlgo;
+    ///
+    /// assert!(HashAlgo::ALL.iter().all(|algo| algo.is_cryptographic()));
+    /// ```
+    pub const ALL: &'static [HashAlgo] = &[
+        HashAlgo::Sha256,
+        HashAlgo::Sha512,
+        HashAlgo::Sha1,
+        HashAlgo::Md5,
+        #[cfg(feature = "blake3")]
+        HashAlgo::Blake3,
+    ];
+
+    /// The fast, non-cryptographic choices, and skipping the hash.
+    ///
+    /// ```
+    /// use buggy_client::HashAlgo;
+    ///
+    /// assert!(HashAlgo::CHECKSUMS.contains(&HashAlgo::Crc32c));
+    /// ```
+    pub const CHECKSUMS: &'static [HashAlgo] = &[
+        HashAlgo::Crc32,
+        HashAlgo::Crc32c,
+        #[cfg(feature = "xxh3")]
+        HashAlgo::Xxh3,
+        HashAlgo::None,
+    ];
+
+    /// The digest of the file at `path`, read in blocks rather than all at
+    /// once, with `threads` threads hashing beside the reader as
+    /// [`DownloaderBuilder::hash_threads`](crate::DownloaderBuilder::hash_threads) describes.
+    ///
+    /// ```
+    /// use buggy_client::HashAlgo;
+    ///
+    /// let path = std::env::temp_dir().join("buggy-client-digest-example");
+    /// std::fs::write(&path, b"hello")?;
+    /// assert_eq!(HashAlgo::Crc32.digest_file(&path, 1)?, "3610a686");
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn digest_file(self, path: &Path, threads: usize) -> std::io::Result<String> {
+        hash_file_with(path, self, threads)
+    }
+
+    /// Parses a `--hash-algo` name: sha256, sha512, sha1, md5 or blake3.
+    ///
+    /// ```
+    /// use buggy_client::HashAlgo;
+    ///
+    /// assert_eq!(HashAlgo::parse("SHA1"), Ok(HashAlgo::Sha1));
+    /// assert!(HashAlgo::parse("crc32").is_err());
+    /// ```
+    pub fn parse(name: &str) -> Result<Self, String> {
+        if let Some(algo) = Self::ALL.iter().find(|algo| algo.name().eq_ignore_ascii_case(name)) {
+            return Ok(*algo);
+        }
+        if name.eq_ignore_ascii_case("blake3") {
+            return Err("blake3 support is not compiled in, rebuild with --features blake3".to_string());
+        }
+        Err(format!("Unknown hash algorithm '{}', expected one of sha256, sha512, sha1, md5, blake3", name))
+    }
+
+    /// Parses a `--checksum` name: crc32, crc32c, xxh3 or none.
+    ///
+    /// ```
+    /// use buggy_client::HashAlgo;
+    ///
+    /// assert_eq!(HashAlgo::parse_checksum("crc32c"), Ok(HashAlgo::Crc32c));
+    /// assert!(HashAlgo::parse_checksum("sha256").is_err());
+    /// ```
+    pub fn parse_checksum(name: &str) -> Result<Self, String> {
+        if let Some(algo) = Self::CHECKSUMS.iter().find(|algo| algo.name().eq_ignore_ascii_case(name)) {
+            return Ok(*algo);
+        }
+        if name.eq_ignore_ascii_case("xxh3") {
+            return Err("xxh3 support is not compiled in, rebuild with --features xxh3".to_string());
+        }
+        Err(format!("Unknown checksum '{}', expected one of crc32, crc32c, xxh3, none", name))
+    }
+
+    /// Whether the digest guards against deliberate tampering, not just
+    /// transfer errors.
+    ///
+    /// ```
+    /// use buggy_client::HashAlgo;
+    ///
+    /// assert!(HashAlgo::Md5.is_cryptographic());
+    /// assert!(!HashAlgo::Crc32.is_cryptographic());
+    /// ```
+    pub fn is_cryptographic(self) -> bool {
+        !Self::CHECKSUMS.contains(&self)
+    }
+
+    /// The name [`HashAlgo::parse`] reads, such as `sha256`.
+    ///
+    /// ```
+    /// use buggy_client::HashAlgo;
+    ///
+    /// assert_eq!(HashAlgo::Sha256.name(), "sha256");
+    /// assert_eq!(HashAlgo::parse(HashAlgo::Md5.name()), Ok(HashAlgo::Md5));
+    /// ```
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Sha512 => "sha512",
+            HashAlgo::Sha1 => "sha1",
+            HashAlgo::Md5 => "md5",
+            #[cfg(feature = "blake3")]
+            HashAlgo::Blake3 => "blake3",
+            HashAlgo::Crc32 => "crc32",
+            HashAlgo::Crc32c => "crc32c",
+            #[cfg(feature = "xxh3")]
+            HashAlgo::Xxh3 => "xxh3",
+            HashAlgo::None => "none",
+        }
+    }
+
+    /// The name to show people, such as `SHA-256`.
+    ///
+    /// ```
+    /// use buggy_client::HashAlgo;
+    ///
+    /// assert_eq!(HashAlgo::Sha256.label(), "SHA-256");
+    /// ```
+    pub fn label(self) -> &'static str {
+        match self {
+            HashAlgo::Sha256 => "SHA-256",
+            HashAlgo::Sha512 => "SHA-512",
+            HashAlgo::Sha1 => "SHA-1",
+            HashAlgo::Md5 => "MD5",
+            #[cfg(feature = "blake3")]
+            HashAlgo::Blake3 => "BLAKE3",
+            HashAlgo::Crc32 => "CRC-32",
+            HashAlgo::Crc32c => "CRC-32C",
+            #[cfg(feature = "xxh3")]
+            HashAlgo::Xxh3 => "XXH3-64",
+            HashAlgo::None => "No hash",
+        }
+    }
+
+    /// How many hex digits a digest has, 0 for [`HashAlgo::None`].
+    ///
+    /// ```
+    /// use buggy_client::HashAlgo;
+    ///
+    /// assert_eq!(HashAlgo::Sha256.hex_len(), 64);
+    /// assert_eq!(HashAlgo::Crc32c.hex_len(), 8);
+    /// ```
+    pub fn hex_len(self) -> usize {
+        match self {
+            HashAlgo::Sha256 => 64,
+            HashAlgo::Sha512 => 128,
+            HashAlgo::Sha1 => 40,
+            HashAlgo::Md5 => 32,
+            #[cfg(feature = "blake3")]
+            HashAlgo::Blake3 => 64,
+            HashAlgo::Crc32 | HashAlgo::Crc32c => 8,
+            #[cfg(feature = "xxh3")]
+            HashAlgo::Xxh3 => 16,
+            HashAlgo::None => 0,
+        }
+    }
+
+    /// Rejects an expected digest that this algorithm can never produce.
+    ///
+    /// ```
+    /// use buggy_client::HashAlgo;
+    ///
+    /// assert!(HashAlgo::Crc32.check_digest("3610a686").is_ok());
+    /// assert!(HashAlgo::Sha256.check_digest("3610a686").is_err());
+    /// ```
+    pub fn check_digest(self, expected: &str) -> Result<(), String> {
+        if self == HashAlgo::None {
+            return Err("An expected hash cannot be checked when hashing is turned off".to_string());
+        }
+        if !expected.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("Expected hash '{}' is not a hex string", expected));
+        }
+        if expected.len() == self.hex_len() {
+            return Ok(());
+        }
+        let mut message = format!("Expected hash is {} hex digits but {} produces {}, length mismatch",
+                                  expected.len(), self.name(), self.hex_len());
+        let candidates: Vec<_> = Self::ALL.iter().chain(Self::CHECKSUMS)
+            .filter(|algo| algo.hex_len() == expected.len())
+            .map(|algo| if algo.is_cryptographic() { format!("--hash-algo {}", algo.name()) }
+                        else { format!("--checksum {}", algo.name()) })
+            .collect();
+        if !candidates.is_empty() {
+            message.push_str(&format!(", did you mean {}", candidates.join(" or ")));
+        }
+        Err(message)
+    }
+
+    pub(crate) fn hasher(self) -> Hasher {
+        match self {
+            HashAlgo::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgo::Sha512 => Hasher::Sha512(Sha512::new()),
+            HashAlgo::Sha1 => Hasher::Sha1(Sha1::new()),
+            HashAlgo::Md5 => Hasher::Md5(Md5::new()),
+            #[cfg(feature = "blake3")]
+            HashAlgo::Blake3 => Hasher::Blake3(Box::default()),
+            HashAlgo::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+            HashAlgo::Crc32c => Hasher::Crc32c(0),
+            #[cfg(feature = "xxh3")]
+            HashAlgo::Xxh3 => Hasher::Xxh3(Box::default()),
+            HashAlgo::None => Hasher::None,
+        }
+    }
+}
+
+pub(crate) enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Sha1(Sha1),
+    Md5(Md5),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+    Crc32(crc32fast::Hasher),
+    Crc32c(u32),
+    #[cfg(feature = "xxh3")]
+    Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+    None,
+}
+
+impl Hasher {
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+            Hasher::Sha1(hasher) => hasher.update(data),
+            Hasher::Md5(hasher) => hasher.update(data),
+            #[cfg(feature = "blake3")]
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            Hasher::Crc32(hasher) => hasher.update(data),
+            Hasher::Crc32c(crc) => *crc = crc32c::crc32c_append(*crc, data),
+            #[cfg(feature = "xxh3")]
+            Hasher::Xxh3(hasher) => hasher.update(data),
+            Hasher::None => {}
+        }
+    }
+
+    pub(crate) fn finalize_hex(self) -> String {
+        match self {
+            Hasher::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+            Hasher::Sha512(hasher) => format!("{:x}", hasher.finalize()),
+            Hasher::Sha1(hasher) => format!("{:x}", hasher.finalize()),
+            Hasher::Md5(hasher) => format!("{:x}", hasher.finalize()),
+            #[cfg(feature = "blake3")]
+            Hasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+            Hasher::Crc32(hasher) => format!("{:08x}", hasher.finalize()),
+            Hasher::Crc32c(crc) => format!("{:08x}", crc),
+            #[cfg(feature = "xxh3")]
+            Hasher::Xxh3(hasher) => format!("{:016x}", hasher.digest()),
+            Hasher::None => String::new(),
+        }
+    }
+}
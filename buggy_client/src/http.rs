@@ -0,0 +1,877 @@
+//! The HTTP/1.1 transport: range requests over pooled connections, pipelined
+//! on request, body decoding and per-chunk checksums. The messages themselves are built
+//! and parsed in [`crate::message`].
+
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+use crate::cancel::POLL_INTERVAL;
+use crate::downloader::{ErrorPhase, Job, WorkerContext};
+use crate::endpoint::Endpoint;
+use crate::error::DownloadError;
+use crate::message::{parse_response_head, split_byteranges, Headers, Response};
+
+/// Connect, read and write timeouts applied to every connection. `None`
+/// waits as long as it takes.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Timeouts {
+    pub(crate) connect: Option<Duration>,
+    /// From sending a request to the first byte of its response.
+    pub(crate) first_byte: Option<Duration>,
+    /// Between two reads once the response has started.
+    pub(crate) read: Option<Duration>,
+    pub(crate) write: Duration,
+}
+
+impl Timeouts {
+    /// How long a response may go without data: until its first byte if
+    /// `started` is false, else between reads.
+    pub(crate) fn idle(&self, started: bool) -> Option<Duration> {
+        if started { self.read } else { self.first_byte }
+    }
+
+    /// How long a single blocking read may wait, for the requests that
+    /// read a whole response in one go.
+    pub(crate) fn blocking_read(&self) -> Option<Duration> {
+        self.first_byte.zip(self.read).map(|(first_byte, read)| first_byte.max(read))
+    }
+
+    /// How long to wait on a socket before looking at the cancellation
+    /// token and the idle timer again.
+    pub(crate) fn poll(&self) -> Duration {
+        [self.first_byte, self.read].into_iter().flatten().fold(POLL_INTERVAL, Duration::min)
+    }
+}
+
+/// A transfer slower than `limit` bytes per second over a whole `time` is
+/// taken for stalled.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct LowSpeed {
+    pub(crate) limit: u64,
+    pub(crate) time: Duration,
+}
+
+/// Measures one transfer against a [`LowSpeed`] limit, in windows of its
+/// `time` that each have to reach the limit on average.
+pub(crate) struct SpeedCheck {
+    low_speed: Option<LowSpeed>,
+    since: Instant,
+    bytes: u64,
+}
+
+impl SpeedCheck {
+    pub(crate) fn new(low_speed: Option<LowSpeed>) -> Self {
+        SpeedCheck { low_speed, since: Instant::now(), bytes: 0 }
+    }
+
+    /// Counts `bytes` more, 0 for a read that timed out, and fails once a
+    /// window closes below the limit.
+    pub(crate) fn add(&mut self, bytes: usize) -> Result<(), DownloadError> {
+        let Some(low_speed) = self.low_speed else {
+            return Ok(());
+        };
+        self.bytes += bytes as u64;
+        let elapsed = self.since.elapsed();
+        if elapsed < low_speed.time {
+            return Ok(());
+        }
+        let speed = self.bytes as f64 / elapsed.as_secs_f64();
+        if speed < low_speed.limit as f64 {
+            return Err(DownloadError::Stalled { speed: speed as u64, limit: low_speed.limit, time: low_speed.time });
+        }
+        self.since = Instant::now();
+        self.bytes = 0;
+        Ok(())
+    }
+}
+
+/// The connect timeout, cut short by a deadline that comes sooner. Reads
+/// need no such care, as they look at the cancellation token between polls.
+pub(crate) fn connect_timeout(context: &WorkerContext) -> Option<Duration> {
+    let left = context.cancel.time_left().map(|left| left.max(Duration::from_millis(1)));
+    match (context.timeouts.connect, left) {
+        (Some(timeout), Some(left)) => Some(timeout.min(left)),
+        (timeout, left) => timeout.or(left),
+    }
+}
+
+/// Logs the request line and headers of a chunk request at debug level.
+pub(crate) fn log_request(job: &Job, request: &str) {
+    log::debug!("chunk {} request: {}", job.chunk_id, request.trim_end().replace("\r\n", ", "));
+}
+
+/// What a range request came back with: the body and the parsed head.
+pub(crate) type RangeResult = Result<(Vec<u8>, Response), Box<dyn std::error::Error>>;
+
+/// Tells the observer a worker is now on `job`.
+pub(crate) fn chunk_started(context: &WorkerContext, worker: usize, job: &Job) {
+    context.observer.chunk_started(worker, job.chunk_id, job.offset..job.offset + job.len, job.try_number());
+}
+
+/// Sends the range requests for `jobs`, all to the same server, and hands
+/// each result to `done` once it is in, with how long it took. Up to the
+/// ranges per request go into one request while the server answers those
+/// with multipart/byteranges. With keep-alive the requests are pipelined:
+/// written back to back on one connection, with the responses read in
+/// order. A response that cannot be parsed or ends short takes the
+/// connection down, and the jobs behind it fail with [`Unanswered`].
+/// Without keep-alive each request gets its own connection. `started`
+/// counts the jobs the observer was told about, for a caller left to report
+/// the rest after a panic.
+#[cfg(not(feature = "async"))]
+pub(crate) fn make_range_requests(context: &WorkerContext, worker: usize, jobs: &[Job], started: &AtomicUsize,
+                                  done: &mut (dyn FnMut(&Job, Duration, RangeResult) + Send)) {
+    let mirror = &context.mirrors[jobs[0].mirror];
+    let keep_alive = context.pool.keep_alive() && mirror.keep_alive();
+    let ranges = if mirror.multi_range() { context.ranges_per_request } else { 1 };
+    let per_connection = if keep_alive { jobs.len() } else { ranges };
+    for batch in jobs.chunks(per_connection) {
+        let mut report = BatchReport::new(context, worker, batch, started, &mut *done);
+        let mut fresh = false;
+        loop {
+            match pipeline(context, &mut report, ranges, keep_alive, fresh) {
+                Err(e) if e.is::<StaleConnection>() => {
+                    log_stale(&report);
+                    fresh = true;
+                }
+                result => {
+                    report.finish(result.err());
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Sends the requests for the jobs of `report` still unanswered, `ranges`
+/// jobs to a request, on one connection and reads their responses, stopping
+/// early after one that leaves the connection unusable. An error is the one
+/// of the first job whose response was being read, or [`StaleConnection`].
+/// A `fresh` connection is opened even if the pool has one.
+#[cfg(not(feature = "async"))]
+fn pipeline(context: &WorkerContext, report: &mut BatchReport, ranges: usize, keep_alive: bool, fresh: bool)
+    -> Result<(), Box<dyn std::error::Error>> {
+    let timeouts = &context.timeouts;
+    let jobs = report.unanswered();
+    let mirror = &context.mirrors[jobs[0].mirror];
+    report.start_group(1);
+    let connect = || mirror.endpoint.connect(connect_timeout(context));
+    let checkout = match fresh {
+        true => connect().map(|stream| context.pool.opened(jobs[0].mirror, stream)),
+        false => context.pool.checkout(jobs[0].mirror, connect),
+    };
+    let mut checkout = checkout.map_err(ConnectFailed)?;
+    let mut responses = Responses::new(checkout.reused(), context.max_response);
+    let mut stream = checkout.stream();
+    
+    // Short reads let the worker notice a cancellation; the real read
+    // timeout is enforced by tracking how long the socket has been idle.
+    stream.set_read_timeout(Some(timeouts.poll()))?;
+    stream.set_write_timeout(Some(timeouts.write))?;
+    
+    let requests: String = jobs.chunks(ranges).map(|group| group_request(context, group, keep_alive)).collect();
+    if let Err(e) = stream.write_all(requests.as_bytes()) {
+        return Err(responses.failed(e.into()));
+    }
+    
+    let mut buffer = vec![0u8; context.read_buffer];
+    for group in jobs.chunks(ranges) {
+        report.start_group(group.len());
+        let mut progress = ProgressBatch::new(context, report.worker, &group[0]);
+        progress.add(responses.begin(group));
+        read_response(context, &mut stream, &mut buffer, &mut responses, &mut progress)
+            .map_err(|e| responses.failed(e))?;
+        if !responses.answer(context, report, group.len(), progress)? {
+            return Ok(());
+        }
+    }
+    if let Some((timeout, remaining)) = responses.drained() {
+        checkout.server_limits(timeout, remaining);
+        checkout.release(jobs.len().div_ceil(ranges));
+    }
+    Ok(())
+}
+
+/// The responses to the requests pipelined on one connection, parsed in
+/// order from the bytes read off it. No I/O happens here, so both
+/// transports share it: they [`begin`](Self::begin) a response, hand it
+/// each read with [`feed`](Self::feed) until it is
+/// [`complete`](Self::complete) or the server stops sending, then
+/// [`answer`](Self::answer) the jobs it was for.
+pub(crate) struct Responses {
+    /// The response being read, and whatever came after it.
+    received: Vec<u8>,
+    /// Its length, once the head says.
+    framed: Option<usize>,
+    /// Whether the connection served a response before this one.
+    served: bool,
+    /// Longest a response may grow, from `--max-size`.
+    max_len: Option<usize>,
+    /// The keep-alive timeout and requests left that the last response gave.
+    limits: (Option<u64>, Option<usize>),
+}
+
+impl Responses {
+    pub(crate) fn new(served: bool, max_len: Option<usize>) -> Self {
+        Responses { received: Vec::new(), framed: None, served, max_len, limits: (None, None) }
+    }
+
+    /// Starts on the response to `group`. Returns how many of its bytes came
+    /// in with the one before.
+    pub(crate) fn begin(&mut self, group: &[Job]) -> usize {
+        self.received.reserve(group.iter().map(|job| job.len).sum::<usize>() + 1024);
+        self.framed = framed_len(&self.received);
+        self.framed.map_or(self.received.len(), |len| len.min(self.received.len()))
+    }
+
+    /// Adds the bytes of one read. Returns how many of them belong to the
+    /// current response.
+    pub(crate) fn feed(&mut self, data: &[u8]) -> usize {
+        let before = self.received.len();
+        self.received.extend_from_slice(data);
+        self.framed = self.framed.or_else(|| framed_len(&self.received));
+        self.framed.map_or(data.len(), |len| len.saturating_sub(before).min(data.len()))
+    }
+
+    /// Whether the current response is as long as its head says.
+    pub(crate) fn complete(&self) -> bool {
+        self.framed.is_some_and(|len| self.received.len() >= len)
+    }
+
+    /// Whether the current response, still unfinished, has outgrown the limit.
+    pub(crate) fn too_large(&self) -> bool {
+        !self.complete() && self.max_len.is_some_and(|limit| self.received.len() > limit)
+    }
+
+    /// The bytes of the current response so far.
+    pub(crate) fn received(&self) -> &[u8] {
+        &self.received
+    }
+
+    /// `error` from writing the requests or reading the current response,
+    /// as [`stale_or`] sees it.
+    pub(crate) fn failed(&self, error: Box<dyn std::error::Error>) -> Box<dyn std::error::Error> {
+        stale_or(self.served, &self.received, error)
+    }
+
+    /// Ends the current response and hands it to the next `len` jobs of
+    /// `report`, keeping whatever was read past its end for the next one.
+    /// Returns whether the connection can carry the next response, or
+    /// [`StaleConnection`] when a kept-alive one closed before answering.
+    pub(crate) fn answer(&mut self, context: &WorkerContext, report: &mut BatchReport, len: usize,
+                         progress: ProgressBatch) -> Result<bool, Box<dyn std::error::Error>> {
+        if self.served && self.received.is_empty() {
+            return Err(Box::new(StaleConnection));
+        }
+        // Whatever was read past the end belongs to the next response.
+        let next = match self.framed {
+            Some(len) if self.received.len() > len => self.received.split_off(len),
+            _ => Vec::new(),
+        };
+        let response = std::mem::replace(&mut self.received, next);
+        let result = split_response(&response);
+        if let Ok((_, head)) = &result {
+            context.mirrors[report.unanswered()[0].mirror].answered(head);
+            self.limits = head.headers.keep_alive();
+        }
+        let usable = result.is_ok() && reusable(&response, self.framed);
+        drop(progress);
+        answer_group(context, report, len, result);
+        self.served = true;
+        Ok(usable)
+    }
+
+    /// The keep-alive limits of the last response, once every response was
+    /// read and nothing came after them, when the connection can be reused.
+    pub(crate) fn drained(&self) -> Option<(Option<u64>, Option<usize>)> {
+        self.received.is_empty().then_some(self.limits)
+    }
+}
+
+/// `error` as a [`StaleConnection`] when it is the end of a connection that
+/// `served` a response before, met before any byte of the next one.
+fn stale_or(served: bool, response: &[u8], error: Box<dyn std::error::Error>)
+    -> Box<dyn std::error::Error> {
+    let closed = error.downcast_ref::<io::Error>().is_some_and(|e| matches!(e.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe));
+    match served && closed && response.is_empty() {
+        true => Box::new(StaleConnection),
+        false => error,
+    }
+}
+
+/// Logs the requests of `report` going out again after a stale connection.
+pub(crate) fn log_stale(report: &BatchReport) {
+    let job = &report.unanswered()[0];
+    log::debug!("chunk {}: {} closed the kept-alive connection, sending again on a new one", job.chunk_id,
+                report.context.mirrors[job.mirror].endpoint.authority());
+}
+
+/// Reports the jobs of one batch in order: each to the observer when its
+/// response is about to be read, and its result to `done`.
+pub(crate) struct BatchReport<'a> {
+    context: &'a WorkerContext,
+    pub(crate) worker: usize,
+    pub(crate) jobs: &'a [Job],
+    started: usize,
+    /// Counts the started jobs across the batches of one call.
+    total_started: &'a AtomicUsize,
+    answered: usize,
+    since: Instant,
+    done: &'a mut (dyn FnMut(&Job, Duration, RangeResult) + Send),
+}
+
+impl<'a> BatchReport<'a> {
+    pub(crate) fn new(context: &'a WorkerContext, worker: usize, jobs: &'a [Job], total_started: &'a AtomicUsize,
+                      done: &'a mut (dyn FnMut(&Job, Duration, RangeResult) + Send)) -> Self {
+        BatchReport { context, worker, jobs, started: 0, total_started, answered: 0, since: Instant::now(), done }
+    }
+
+    /// The jobs still waiting for their result.
+    pub(crate) fn unanswered(&self) -> &'a [Job] {
+        &self.jobs[self.answered..]
+    }
+
+    /// Tells the observer about the jobs up to the `count`th.
+    pub(crate) fn start(&mut self, count: usize) {
+        let jobs = self.jobs;
+        for job in jobs.iter().take(count).skip(self.started) {
+            chunk_started(self.context, self.worker, job);
+            self.total_started.fetch_add(1, Ordering::Relaxed);
+        }
+        self.started = self.started.max(count);
+    }
+
+    /// Tells the observer about the next `len` jobs, whose response comes next.
+    pub(crate) fn start_group(&mut self, len: usize) {
+        self.start(self.answered + len);
+    }
+
+    /// Hands the next job its result.
+    pub(crate) fn answer(&mut self, result: RangeResult) {
+        self.start(self.answered + 1);
+        self.deliver(result);
+    }
+
+    /// Sends the next job again: its response never came, or did not serve it.
+    pub(crate) fn requeue(&mut self) {
+        let started = self.answered < self.started;
+        self.deliver(Err(Box::new(Unanswered { started })));
+    }
+
+    fn deliver(&mut self, result: RangeResult) {
+        let jobs = self.jobs;
+        (self.done)(&jobs[self.answered], self.since.elapsed(), result);
+        self.answered += 1;
+        self.since = Instant::now();
+    }
+
+    /// Ends the batch: `error` goes to the next job, and the jobs still
+    /// unanswered after it are sent again.
+    pub(crate) fn finish(mut self, error: Option<Box<dyn std::error::Error>>) {
+        if let Some(e) = error.filter(|_| self.answered < self.jobs.len()) {
+            self.answer(Err(e));
+        }
+        while self.answered < self.jobs.len() {
+            self.requeue();
+        }
+    }
+}
+
+/// The request for one group of jobs: a single range, or several to be
+/// answered with a multipart/byteranges body.
+pub(crate) fn group_request(context: &WorkerContext, group: &[Job], keep_alive: bool) -> String {
+    let mirror = &context.mirrors[group[0].mirror];
+    let ranges: Vec<_> = group.iter().map(|job| {
+        let start = context.origin + job.offset;
+        (start, start + job.len)
+    }).collect();
+    let request = mirror.template.build_ranges(&ranges, group[0].if_range.as_deref(), keep_alive);
+    log_request(&group[0], &request);
+    request
+}
+
+/// Hands the response to a group of `len` jobs out to them. With several,
+/// each gets the part of a multipart/byteranges response that covers its
+/// start. A job left out is past the end of the file when it starts beyond
+/// the size the parts give, or they give none, and is sent again otherwise.
+/// A server that answers with anything else gets one range per request from
+/// then on: the first job keeps a 200 or a single 206, and the others are
+/// sent again.
+fn answer_group(context: &WorkerContext, report: &mut BatchReport, len: usize, result: RangeResult) {
+    let jobs = report.jobs;
+    let group = &jobs[report.answered..report.answered + len];
+    let (body, head) = match result {
+        Ok(response) if len > 1 => response,
+        result => {
+            report.answer(result);
+            (1..len).for_each(|_| report.requeue());
+            return;
+        }
+    };
+    let mirror = &context.mirrors[group[0].mirror];
+    let parts = match head.headers.get("content-type").filter(|_| head.status == 206) {
+        Some(content_type) => split_byteranges(content_type, &body),
+        None => None,
+    };
+    let parts = match parts {
+        Some(Ok(parts)) => parts,
+        Some(Err(e)) => {
+            report.answer(Err(e.into()));
+            (1..len).for_each(|_| report.requeue());
+            return;
+        }
+        None => {
+            // A 400 is how some servers reject the request, not the end of the file.
+            match head.status {
+                200 | 206 => {
+                    mirror.refused_multi_range();
+                    report.answer(Ok((body, head)));
+                }
+                400 => {
+                    mirror.refused_multi_range();
+                    report.start(report.answered + 1);
+                    report.requeue();
+                }
+                _ => report.answer(Ok((body, head))),
+            }
+            (1..len).for_each(|_| report.requeue());
+            return;
+        }
+    };
+    let total = parts.iter().find_map(|(headers, _)| headers.content_range().and_then(|(_, _, total)| total));
+    for job in group {
+        let offset = (context.origin + job.offset) as u64;
+        let part = parts.iter().find(|(headers, _)| {
+            headers.content_range().is_some_and(|(first, last, _)| first <= offset && offset <= last)
+        });
+        match part {
+            Some((headers, data)) => report.answer(Ok((data.clone(), part_head(&head, headers, data.len())))),
+            None if total.is_none_or(|total| offset >= total) => {
+                let unsatisfiable = Response { status: 416, version: head.version, headers: Headers::new(),
+                                               body_offset: 0 };
+                report.answer(Ok((Vec::new(), unsatisfiable)));
+            }
+            None => report.requeue(),
+        }
+    }
+}
+
+/// The head of a multipart response as seen by one part: its own range,
+/// type and length in place of those of the whole body.
+fn part_head(head: &Response, part: &Headers, len: usize) -> Response {
+    let mut headers = Headers::new();
+    for (name, value) in head.headers.iter() {
+        if !matches!(name, "content-type" | "content-length" | "content-range") {
+            headers.append(name, value);
+        }
+    }
+    for (name, value) in part.iter().filter(|(name, _)| *name != "content-length") {
+        headers.append(name, value);
+    }
+    headers.append("content-length", &len.to_string());
+    Response { status: 206, version: head.version, headers, body_offset: 0 }
+}
+
+/// Reads the current response of `responses`, which may already hold its
+/// first bytes, until it is as long as its head says or the server stops
+/// sending.
+#[cfg(not(feature = "async"))]
+fn read_response(context: &WorkerContext, stream: &mut impl Read, buffer: &mut [u8], responses: &mut Responses,
+                 progress: &mut ProgressBatch) -> Result<(), Box<dyn std::error::Error>> {
+    let timeouts = &context.timeouts;
+    let mut last_data = Instant::now();
+    let mut speed = SpeedCheck::new(context.low_speed);
+    
+    while !responses.complete() {
+        if context.cancel.is_cancelled() {
+            return Err("cancelled".into());
+        }
+        match stream.read(buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                last_data = Instant::now();
+                progress.add(responses.feed(&buffer[..n]));
+                speed.add(n)?;
+                if let Some(limiter) = context.limiter.as_deref() {
+                    limiter.consume(n, &context.cancel);
+                }
+                if responses.too_large() {
+                    return Err(Box::new(ResponseTooLarge));
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut => {
+                speed.add(0)?;
+                if timeouts.idle(!responses.received().is_empty()).is_none_or(|limit| last_data.elapsed() < limit) {
+                    continue;
+                }
+                if !responses.received().is_empty() {
+                    timed_out_after(responses.received())?;
+                    break;
+                }
+                return Err(Box::new(e));
+            }
+            Err(e) => return Err(Box::new(e)),
+        }
+    }
+    Ok(())
+}
+
+/// The length of the response at the start of `response`, head included,
+/// once the head is in and its Content-Length or status says where the body
+/// ends. `None` until then, or when the body runs to the end of the
+/// connection.
+fn framed_len(response: &[u8]) -> Option<usize> {
+    let head = parse_response_head(response).ok()??;
+    let body = match head.status {
+        100..=199 | 204 | 304 => 0,
+        _ => usize::try_from(head.headers.content_length()?).ok()?,
+    };
+    Some(head.body_offset + body)
+}
+
+/// Whether the connection that brought `response`, of length `framed`, can
+/// carry another request: the response ended exactly where its head said
+/// and the server did not ask to close. An HTTP/1.0 server closes unless it
+/// says keep-alive.
+fn reusable(response: &[u8], framed: Option<usize>) -> bool {
+    framed == Some(response.len())
+        && parse_response_head(response).ok().flatten().is_some_and(|head| {
+            let says = |token: &str| head.headers.get_all("connection").iter()
+                .any(|value| value.split(',').any(|option| option.trim().eq_ignore_ascii_case(token)));
+            !says("close") && (head.version > 0 || says("keep-alive"))
+        })
+}
+
+/// Whether a read timeout after the first bytes of `response` may end it:
+/// only a body without a Content-Length, from an HTTP/1.0 server or one that
+/// said `Connection: close`, runs until the server stops sending. Any other
+/// response was cut short, and is an error so that the chunk is fetched
+/// again rather than taken as complete.
+pub(crate) fn timed_out_after(response: &[u8]) -> Result<(), DownloadError> {
+    let Ok(Some(head)) = parse_response_head(response) else {
+        return Err(DownloadError::Timeout { phase: ErrorPhase::Transfer });
+    };
+    let expected = head.headers.content_length().map(|len| len as usize);
+    let closes = head.version == 0 || head.headers.get_all("connection").iter()
+        .any(|value| value.split(',').any(|option| option.trim().eq_ignore_ascii_case("close")));
+    if expected.is_none() && closes {
+        return Ok(());
+    }
+    Err(DownloadError::CutShort { expected, got: response.len() - head.body_offset })
+}
+
+/// Longest a worker holds back received bytes from the observer.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Collects the byte counts of single reads so the observer hears about a
+/// chunk a few times rather than after every read. Whatever is left is
+/// reported when the batch is dropped.
+pub(crate) struct ProgressBatch<'a> {
+    context: &'a WorkerContext,
+    worker: usize,
+    chunk_id: usize,
+    step: usize,
+    pending: usize,
+    last_report: Instant,
+}
+
+impl<'a> ProgressBatch<'a> {
+    pub(crate) fn new(context: &'a WorkerContext, worker: usize, job: &Job) -> Self {
+        ProgressBatch {
+            context,
+            worker,
+            chunk_id: job.chunk_id,
+            step: (job.len / 4).max(1),
+            pending: 0,
+            last_report: Instant::now(),
+        }
+    }
+
+    pub(crate) fn add(&mut self, bytes: usize) {
+        self.pending += bytes;
+        if self.pending >= self.step || self.last_report.elapsed() >= PROGRESS_INTERVAL {
+            self.report();
+        }
+    }
+
+    fn report(&mut self) {
+        if self.pending > 0 {
+            self.context.observer.bytes_received(self.worker, self.chunk_id, self.pending);
+            self.pending = 0;
+        }
+        self.last_report = Instant::now();
+    }
+}
+
+impl Drop for ProgressBatch<'_> {
+    fn drop(&mut self) {
+        self.report();
+    }
+}
+
+/// Splits a raw response into its decoded body and parsed head.
+fn split_response(response: &[u8]) -> Result<(Vec<u8>, Response), Box<dyn std::error::Error>> {
+    // A dropped connection is a failure to retry, not the end of the file:
+    // past the end the server still answers with headers and an empty body.
+    if response.is_empty() {
+        return Err("connection closed before a response was received".into());
+    }
+    
+    let head = parse_response_head(response)?
+        .ok_or_else(|| format!("connection closed after {} bytes, before the end of the response head",
+                               response.len()))?;
+    let mut body = response[head.body_offset..].to_vec();
+
+    if let Some(encoding) = head.headers.get("content-encoding") {
+        // Content-Length counts the encoded bytes, so a short transfer has to
+        // be caught before decoding changes the length.
+        if let Some(expected) = head.headers.content_length() {
+            if (body.len() as u64) < expected {
+                return Err(format!("Truncated {} body: got {} of {} bytes", encoding, body.len(), expected).into());
+            }
+        }
+        body = decode_body(encoding, &body)?;
+    }
+
+    if let Some(checksum) = head.headers.get("x-chunk-checksum") {
+        verify_chunk_checksum(checksum, &body)?;
+    }
+    
+    Ok((body, head))
+}
+
+/// A request that failed before it reached the server, retried on its own
+/// budget since a restarting server fails every request at once.
+#[derive(Debug)]
+pub(crate) struct ConnectFailed(pub(crate) io::Error);
+
+impl std::fmt::Display for ConnectFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "could not connect: {}", self.0)
+    }
+}
+
+impl std::error::Error for ConnectFailed {}
+
+/// A response that grew past the size limit, which proves the resource is
+/// larger than allowed however it was cut.
+#[derive(Debug)]
+pub(crate) struct ResponseTooLarge;
+
+impl std::fmt::Display for ResponseTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "response passed the size limit")
+    }
+}
+
+impl std::error::Error for ResponseTooLarge {}
+
+/// A request whose response never came, as it was pipelined behind one that
+/// took the connection down, or did not serve it, as the server ignored a
+/// request for several ranges. It is sent again without counting a try.
+#[derive(Debug)]
+pub(crate) struct Unanswered {
+    /// Whether the observer was told the job started.
+    pub(crate) started: bool,
+}
+
+impl std::fmt::Display for Unanswered {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the request was not answered")
+    }
+}
+
+impl std::error::Error for Unanswered {}
+
+/// A kept-alive connection the server closed before answering, which it may
+/// do at any time between requests. The requests go out again on a new
+/// connection without counting a try.
+#[derive(Debug)]
+pub(crate) struct StaleConnection;
+
+impl std::fmt::Display for StaleConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the server closed the connection before answering")
+    }
+}
+
+impl std::error::Error for StaleConnection {}
+
+/// Extracts the hex digest from a `sha256=<hex>` checksum header value.
+pub(crate) fn parse_chunk_checksum(value: &str) -> Option<String> {
+    match value.split_once('=') {
+        Some((algo, hex)) if algo.trim().eq_ignore_ascii_case("sha256") => Some(hex.trim().to_lowercase()),
+        _ => None,
+    }
+}
+
+/// Checks the body against an `X-Chunk-Checksum: sha256=<hex>` value.
+/// Algorithms other than sha256 are not checked.
+fn verify_chunk_checksum(checksum: &str, body: &[u8]) -> Result<(), DownloadError> {
+    let expected = match parse_chunk_checksum(checksum) {
+        Some(expected) => expected,
+        None => return Ok(()),
+    };
+    let actual = format!("{:x}", Sha256::digest(body));
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(DownloadError::ChecksumMismatch { expected, actual })
+    }
+}
+
+fn decode_body(encoding: &str, body: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+
+    let mut decoded = Vec::with_capacity(body.len() * 2);
+    match encoding.to_ascii_lowercase().as_str() {
+        "identity" => decoded.extend_from_slice(body),
+        "gzip" | "x-gzip" => {
+            GzDecoder::new(body).read_to_end(&mut decoded)?;
+        }
+        // "deflate" is meant to be zlib-wrapped, but some servers send a raw stream.
+        "deflate" => {
+            if ZlibDecoder::new(body).read_to_end(&mut decoded).is_err() {
+                decoded.clear();
+                DeflateDecoder::new(body).read_to_end(&mut decoded)?;
+            }
+        }
+        other => return Err(format!("Unsupported Content-Encoding: {}", other).into()),
+    }
+    Ok(decoded)
+}
+
+/// Sends `request` on a fresh connection and returns the whole body and the
+/// head, whatever the status.
+pub(crate) fn send_request(endpoint: &Endpoint, request: &str, timeouts: &Timeouts)
+    -> Result<(Vec<u8>, Response), Box<dyn std::error::Error>> {
+    let mut stream = endpoint.connect(timeouts.connect).map_err(ConnectFailed)?;
+    stream.set_read_timeout(timeouts.blocking_read())?;
+    stream.set_write_timeout(Some(timeouts.write))?;
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    split_response(&response)
+}
+
+/// Sends a HEAD `request` on a fresh connection and returns the head of the
+/// answer, whatever the status. There is no body to decode or check.
+pub(crate) fn send_head(endpoint: &Endpoint, request: &str, timeouts: &Timeouts)
+    -> Result<Response, Box<dyn std::error::Error>> {
+    let mut stream = endpoint.connect(timeouts.connect).map_err(ConnectFailed)?;
+    stream.set_read_timeout(timeouts.blocking_read())?;
+    stream.set_write_timeout(Some(timeouts.write))?;
+    stream.write_all(request.as_bytes())?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    if response.is_empty() {
+        return Err("connection closed before a response was received".into());
+    }
+    Ok(parse_response_head(&response)?.ok_or("connection closed before the end of the response head")?)
+}
+
+/// Like [`send_request`], but failing on non-2xx statuses.
+pub(crate) fn fetch_response(endpoint: &Endpoint, request: &str, timeouts: &Timeouts)
+    -> Result<(Vec<u8>, Response), Box<dyn std::error::Error>> {
+    let (body, head) = send_request(endpoint, request, timeouts)?;
+    if !(200..300).contains(&head.status) {
+        return Err(DownloadError::HttpStatus { code: head.status, chunk: None }.into());
+    }
+    Ok((body, head))
+}
+
+/// Sends a plain GET and returns the whole body, failing on non-2xx statuses.
+pub(crate) fn fetch_document(endpoint: &Endpoint, request: &str, timeouts: &Timeouts)
+    -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    fetch_response(endpoint, request, timeouts).map(|(body, _)| body)
+}
+
+/// The file name a `Content-Disposition` value suggests, preferring the
+/// RFC 5987 `filename*` form over the plain `filename`.
+pub(crate) fn parse_content_disposition(value: &str) -> Option<String> {
+    let mut plain = None;
+    for param in value.split(';').skip(1) {
+        let (name, raw) = match param.split_once('=') {
+            Some((name, raw)) => (name.trim().to_ascii_lowercase(), raw.trim()),
+            None => continue,
+        };
+        if name == "filename*" {
+            // charset'language'percent-encoded
+            if let Some(name) = raw.splitn(3, '\'').nth(2).and_then(percent_decode) {
+                return Some(name);
+            }
+        }
+        if name == "filename" {
+            plain = Some(raw.trim_matches('"').replace("\\\"", "\""));
+        }
+    }
+    plain
+}
+
+/// Decodes `%XX` escapes, returning `None` for malformed ones or invalid UTF-8.
+pub(crate) fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = text.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Reduces a name from the server to a plain file name in the current
+/// directory: anything up to the last path separator is dropped, and empty,
+/// `.` and `..` names are rejected.
+pub(crate) fn sanitize_filename(name: &str) -> Option<String> {
+    let name: String = name.rsplit(['/', '\\']).next()?.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim();
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+    Some(name.to_string())
+}
+
+/// Splits `http://host[:port]/path` into its parts, where the host may be a
+/// bracketed IPv6 literal; anything else is taken
+/// as a path on the download server.
+pub(crate) fn parse_location(location: &str, host: &str, port: u16) -> Result<(String, u16, String), String> {
+    let rest = match location.strip_prefix("http://") {
+        Some(rest) => rest,
+        None if location.starts_with('/') => return Ok((host.to_string(), port, location.to_string())),
+        None => return Err(format!("Unsupported location '{}': expected an http:// URL or a /path", location)),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    let (url_host, url_port) = match authority.strip_prefix('[') {
+        Some(bracketed) => {
+            let (url_host, rest) = bracketed.split_once(']')
+                .ok_or_else(|| format!("Unclosed '[' in '{}'", location))?;
+            (url_host, rest.strip_prefix(':'))
+        }
+        None => match authority.rsplit_once(':') {
+            Some((url_host, url_port)) => (url_host, Some(url_port)),
+            None => (authority, None),
+        },
+    };
+    let url_port = match url_port {
+        Some(url_port) => url_port.parse::<u16>().map_err(|e| format!("Invalid port in '{}': {}", location, e))?,
+        None => 80,
+    };
+    Ok((url_host.to_string(), url_port, path.to_string()))
+}
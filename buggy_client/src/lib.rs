@@ -0,0 +1,64 @@
+//! Parallel range downloader for HTTP servers that truncate large responses.
+//!
+//! A [`Downloader`] is configured through [`DownloaderBuilder`] and fetches
+//! the resource in chunks over several connections, retrying failed ranges
+//! and re-requesting the tail of truncated ones, then writes the assembled
+//! data into a [`Sink`]:
+//!
+//! ```no_run
+//! use buggy_client::Downloader;
+//!
+//! let downloader = Downloader::builder().host("127.0.0.1").port(8080).build()?;
+//! let mut data = Vec::new();
+//! let summary = downloader.download(&mut data)?;
+//! println!("{} bytes, sha256 {}", summary.bytes, summary.hash);
+//! # Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+//! ```
+
+#![deny(missing_docs)]
+
+mod audit;
+mod cache;
+mod cancel;
+mod checkpoint;
+mod chunk_dir;
+mod chunks;
+mod delta;
+mod disk;
+mod downloader;
+mod endpoint;
+mod error;
+mod error_log;
+mod hash;
+mod http;
+mod json;
+mod message;
+mod mirror;
+mod mmap;
+mod observer;
+mod pause;
+mod pool;
+mod proxy;
+mod rate;
+mod state;
+mod stream;
+
+#[cfg(feature = "async")]
+mod async_transport;
+
+pub use audit::AuditSink;
+pub use cancel::{CancellationToken, Cancelled};
+pub use checkpoint::Checkpoint;
+pub use chunk_dir::SavedChunks;
+pub use chunks::{Chunk, Chunks};
+pub use downloader::{ChunkError, ChunkTiming, Discard, Downloader, DownloaderBuilder, ErrorGroup, ErrorPhase, Freshness,
+                     Plan, ResourceChanged, SeekSink, Sink, StreamSink, Summary, TooLarge};
+pub use endpoint::AddressFamily;
+pub use error::{DownloadError, ErrorCause};
+pub use hash::HashAlgo;
+pub use message::RangeStyle;
+pub use mirror::MirrorStats;
+pub use mmap::MmapSink;
+pub use observer::DownloadObserver;
+pub use pause::PauseToken;
+pub use pool::PoolStats;
@@ -0,0 +1,99 @@
+//! The command line client's `log` backend.
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::progress::TerminalProgress;
+
+/// Filters records the way `RUST_LOG` describes and prints them above the
+/// progress bars while some are drawn, so log lines do not tear them.
+pub struct Logger {
+    /// `module=level` directives; `None` applies to every module.
+    directives: Vec<(Option<String>, LevelFilter)>,
+    bars: Mutex<Option<Arc<TerminalProgress>>>,
+    stdout: bool,
+}
+
+impl Logger {
+    /// Reads directives from `RUST_LOG` when it is set, otherwise logs every
+    /// module at `default`.
+    pub fn from_env(default: LevelFilter) -> Self {
+        let directives = match std::env::var("RUST_LOG") {
+            Ok(spec) if !spec.trim().is_empty() => parse_directives(&spec),
+            _ => vec![(None, default)],
+        };
+        Logger { directives, bars: Mutex::new(None), stdout: false }
+    }
+
+    /// Writes to stdout instead of stderr.
+    pub fn stdout(mut self, stdout: bool) -> Self {
+        self.stdout = stdout;
+        self
+    }
+
+    /// Installs the logger for the rest of the process.
+    pub fn install(self) -> Result<&'static Logger, log::SetLoggerError> {
+        let max_level = self.directives.iter().map(|(_, level)| *level).max().unwrap_or(LevelFilter::Off);
+        let logger: &'static Logger = Box::leak(Box::new(self));
+        log::set_logger(logger)?;
+        log::set_max_level(max_level);
+        Ok(logger)
+    }
+
+    /// Prints through `bars` from now on.
+    pub fn print_above(&self, bars: Arc<TerminalProgress>) {
+        *self.bars.lock().unwrap() = Some(bars);
+    }
+
+    /// The most specific directive matching `target` decides its level.
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.directives.iter()
+            .filter(|(module, _)| module.as_deref().is_none_or(|module| {
+                target == module || target.starts_with(&format!("{}::", module))
+            }))
+            .max_by_key(|(module, _)| module.as_ref().map_or(0, String::len))
+            .map_or(LevelFilter::Off, |(_, level)| *level)
+    }
+}
+
+/// Parses `RUST_LOG` style directives: `level`, `module` or `module=level`,
+/// separated by commas. Unknown levels are skipped.
+fn parse_directives(spec: &str) -> Vec<(Option<String>, LevelFilter)> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .filter_map(|directive| match directive.split_once('=') {
+            Some((module, level)) => level.trim().parse().ok().map(|level| (Some(module.trim().to_string()), level)),
+            None => match directive.parse::<LevelFilter>() {
+                Ok(level) => Some((None, level)),
+                Err(_) => Some((Some(directive.to_string()), LevelFilter::Trace)),
+            },
+        })
+        .collect()
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!("[{} {}] {}", record.level(), record.target(), record.args());
+        if self.stdout {
+            println!("{}", line);
+        } else if let Some(bars) = self.bars.lock().unwrap().as_ref() {
+            bars.println(&line);
+        } else {
+            eprintln!("{}", line);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
@@ -1,380 +1,2605 @@
-use std::io::{Read, Write, BufWriter};
-use std::net::TcpStream;
+// The command line's own modules, kept out of the library's API.
+mod benchmark;
+mod checkpoint_log;
+mod checksum;
+mod config;
+mod json;
+mod logger;
+mod manifest;
+mod progress;
+mod report;
+mod units;
+
+use std::collections::BTreeMap;
+use std::io::{BufWriter, ErrorKind, IsTerminal, Read, Write};
 use std::time::{Instant, Duration};
-use std::sync::{Arc, Mutex};
 use std::thread;
-use std::collections::HashSet;
-use std::fs::File;
-use std::path::Path;
-use sha2::{Sha256, Digest};
-use clap::{App, Arg};
-use indicatif::{ProgressBar, ProgressStyle, MultiProgress};
-
-struct Chunk {
-    id: usize,
-    data: Vec<u8>,
-}
-
-impl Clone for Chunk {
-    fn clone(&self) -> Self {
-        Chunk {
-            id: self.id,
-            data: self.data.clone(),
-        }
-    }
-}
-
-fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let matches = App::new("HTTP Downloader")
-        .version("1.0")
-        .about("Downloads files from a buggy HTTP server")
-        .arg(Arg::with_name("host")
-            .short('h')
-            .long("host")
-            .value_name("HOST")
-            .help("Server hostname or IP address")
-            .default_value("127.0.0.1"))
-        .arg(Arg::with_name("port")
-            .short('p')
-            .long("port")
-            .value_name("PORT")
-            .help("Server port")
-            .default_value("8080"))
-        .arg(Arg::with_name("chunk-size")
-            .short('c')
-            .long("chunk-size")
-            .value_name("SIZE")
-            .help("Chunk size in KiB")
-            .default_value("64"))
-        .arg(Arg::with_name("threads")
-            .short('t')
-            .long("threads")
-            .value_name("NUM")
-            .help("Number of concurrent downloads")
-            .default_value("4"))
-        .arg(Arg::with_name("output")
-            .short('o')
-            .long("output")
-            .value_name("FILE")
-            .help("Save downloaded data to FILE")
-            .takes_value(true))
-        .arg(Arg::with_name("verify")
-            .short('v')
-            .long("verify")
-            .value_name("HASH")
-            .help("Verify SHA-256 hash of downloaded data")
-            .takes_value(true))
-        .arg(Arg::with_name("verbose")
-            .long("verbose")
-            .help("Enable verbose output with detailed error messages"))
-        .get_matches();
-
-    let host = matches.value_of("host")
-        .ok_or_else(|| "Missing host argument")?;
-    let port = matches.value_of("port")
-        .ok_or_else(|| "Missing port argument")?
-        .parse::<u16>()
-        .map_err(|e| format!("Invalid port number: {}", e))?;
-    let chunk_size = matches.value_of("chunk-size")
-        .ok_or_else(|| "Missing chunk-size argument")?
-        .parse::<usize>()
-        .map_err(|e| format!("Invalid chunk size: {}", e))?
-        * 1024;
-    let concurrent_downloads = matches.value_of("threads")
-        .ok_or_else(|| "Missing threads argument")?
-        .parse::<usize>()
-        .map_err(|e| format!("Invalid thread count: {}", e))?;
-    let output_file = matches.value_of("output");
-    let verify_hash = matches.value_of("verify");
-    let verbose = matches.is_present("verbose");
-
-    let multi_progress = MultiProgress::new();
-    let total_progress = multi_progress.add(ProgressBar::new(0));
-    total_progress.set_style(ProgressStyle::default_bar()
-        .template("[{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
-        .progress_chars("#>-"));
-    
-    let thread_bars: Vec<_> = (0..concurrent_downloads).map(|i| {
-        let pb = multi_progress.add(ProgressBar::new(chunk_size as u64));
-        pb.set_style(ProgressStyle::default_bar()
-            .template(&format!("Thread #{:2} [{{wide_bar:.green/white}}] {{bytes}}/{{total_bytes}}", i))
-            .progress_chars("=> "));
-        pb.set_position(0);
-        Arc::new(Mutex::new(pb))
-    }).collect();
-    
-    let _progress_handle = thread::spawn(move || {
-        multi_progress.join().unwrap();
+use std::fs::{File, OpenOptions, TryLockError};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::ffi::OsString;
+use clap::builder::BoolishValueParser;
+use clap::error::{ContextKind, ContextValue, ErrorKind as ClapErrorKind};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use log::LevelFilter;
+use buggy_client::{AddressFamily, AuditSink, CancellationToken, Cancelled, ChunkTiming, Discard, DownloadError,
+                   DownloadObserver, Downloader, DownloaderBuilder, ErrorCause, ErrorPhase, Freshness, HashAlgo,
+                   MmapSink, PauseToken, Plan, RangeStyle, SavedChunks, StreamSink, Summary};
+
+use benchmark::Benchmark;
+use checkpoint_log::{find_divergence, write_checkpoint_log};
+use checksum::parse_checksum_file;
+use config::{parse_config, ConfigEntry, ConfigValue};
+use logger::Logger;
+use manifest::{parse_manifest, ManifestEntry};
+use progress::{JsonProgress, PlainProgress, TerminalProgress};
+use report::{Report, RunResult};
+use units::{parse_rate, parse_size};
+
+/// Set by `--progress-format json`, when stderr carries nothing but JSON events.
+static JSON_EVENTS: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--quiet`, when only errors are printed.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Set by `-o -`, when stdout carries nothing but the downloaded data.
+static DATA_ON_STDOUT: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--json`, when stdout carries nothing but the result object.
+static JSON_RESULT: AtomicBool = AtomicBool::new(false);
+
+/// What the download produced, for the `--json` result.
+static RESULT: Mutex<Option<RunResult>> = Mutex::new(None);
+
+/// Prints a status line to stdout unless `--quiet` was given, or to stderr
+/// while stdout carries the data or the result.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            if DATA_ON_STDOUT.load(Ordering::Relaxed) || JSON_RESULT.load(Ordering::Relaxed) {
+                eprintln!($($arg)*);
+            } else {
+                println!($($arg)*);
+            }
+        }
+    };
+}
+
+/// Prints a diagnostic to stderr, or to stdout while stderr is reserved for
+/// JSON events and stdout is free.
+macro_rules! diag {
+    ($($arg:tt)*) => {
+        if JSON_EVENTS.load(Ordering::Relaxed) && !DATA_ON_STDOUT.load(Ordering::Relaxed)
+            && !JSON_RESULT.load(Ordering::Relaxed) {
+            println!($($arg)*);
+        } else {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+fn main() {
+    match run() {
+        Ok(()) => print_result(0, None),
+        Err(e) => {
+            diag!("Error: {}", e);
+            let code = exit_code(e.as_ref());
+            print_result(code, Some(e.as_ref()));
+            std::process::exit(code);
+        }
+    }
+}
+
+/// Prints the `--json` result for a run ending with `code`, once.
+fn print_result(code: i32, error: Option<&(dyn std::error::Error + 'static)>) {
+    static PRINTED: AtomicBool = AtomicBool::new(false);
+    if !JSON_RESULT.load(Ordering::Relaxed) || PRINTED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let mut result = RESULT.lock().unwrap().take().unwrap_or_default();
+    result.status = match code {
+        0 => "ok",
+        VERIFY_EXIT_CODE => "verification_failed",
+        INCOMPLETE_EXIT_CODE => "incomplete",
+        INTERRUPTED_EXIT_CODE => "cancelled",
+        _ => "failed",
+    }.to_string();
+    result.exit_code = code;
+    result.error = error.map(|error| error.to_string());
+    // A chunk that ended the download has no summary to be counted in.
+    if let Some(error) = error.and_then(|error| error.downcast_ref::<DownloadError>()?.chunk_error()) {
+        result.errors.push(error);
+        result.chunks.total += 1;
+        result.chunks.failed += 1;
+    }
+    // A failed run leaves nothing in place.
+    if code != 0 {
+        result.output = None;
+    }
+    println!("{}", result.to_json());
+}
+
+/// Ends the run with `code` at once, printing the `--json` result first.
+fn exit_now(code: i32, error: String) -> ! {
+    print_result(code, Some(&Exit::new(code, error)));
+    std::process::exit(code);
+}
+
+/// The documented exit code for an error that ends the run. Scripts depend
+/// on these, so a code never changes meaning.
+fn exit_code(error: &(dyn std::error::Error + 'static)) -> i32 {
+    if let Some(exit) = error.downcast_ref::<Exit>() {
+        exit.code
+    } else if let Some(error) = error.downcast_ref::<DownloadError>() {
+        match error {
+            DownloadError::ChunkFailed { phase: ErrorPhase::Connect, .. } => CONNECT_EXIT_CODE,
+            DownloadError::ChunkFailed { .. } | DownloadError::Incomplete { .. } => INCOMPLETE_EXIT_CODE,
+            DownloadError::Io(_) => OUTPUT_EXIT_CODE,
+            _ => 1,
+        }
+    } else if error.is::<std::io::Error>() {
+        OUTPUT_EXIT_CODE
+    } else {
+        1
+    }
+}
+
+/// An error that the command line itself raises, with its exit code.
+#[derive(Debug)]
+struct Exit {
+    code: i32,
+    message: String,
+}
+
+impl Exit {
+    fn new(code: i32, message: impl Into<String>) -> Self {
+        Exit { code, message: message.into() }
+    }
+
+    /// For options that parse but don't fit together, as the builder finds.
+    fn usage(message: String) -> Self {
+        Exit::new(USAGE_EXIT_CODE, message)
+    }
+}
+
+impl std::fmt::Display for Exit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Exit {}
+
+/// The command line options, also the valid keys of a config file.
+#[derive(Parser)]
+#[command(name = "HTTP Downloader", version = "1.0", about = "Downloads files from a buggy HTTP server",
+          long_about = None, disable_help_flag = true,
+          after_help = "Every option can also be set with the environment variable shown next to it, such as \
+                        BUGGY_CLIENT_CHUNK_SIZE=64. The command line wins over the environment, which wins over the \
+                        config file, which wins over the defaults.")]
+struct Args {
+    /// TOML file with option defaults; without it ./buggy-client.toml or ~/.config/buggy-client/config.toml is
+    /// read if present
+    #[arg(long, env = "BUGGY_CLIENT_CONFIG", value_name = "FILE")]
+    config: Option<String>,
+
+    /// Print help
+    #[arg(long, action = ArgAction::Help)]
+    help: Option<bool>,
+
+    /// Server hostname or IP address, IPv6 with or without brackets, optionally with :PORT; repeat it to spread
+    /// the chunks over mirrors
+    #[arg(short = 'h', long, env = "BUGGY_CLIENT_HOST", value_name = "HOST", default_value = "127.0.0.1")]
+    host: Vec<String>,
+
+    /// Server port
+    #[arg(short, long, env = "BUGGY_CLIENT_PORT", value_name = "PORT", value_parser = parse_port,
+          default_value = "8080")]
+    port: u16,
+
+    /// Try ipv4 or ipv6 addresses first when the host resolves to both
+    #[arg(long, env = "BUGGY_CLIENT_PREFER_FAMILY", value_name = "FAMILY", value_parser = AddressFamily::parse)]
+    prefer_family: Option<AddressFamily>,
+
+    /// Connect to the Unix domain socket at PATH instead of over TCP; --host still names the server in requests
+    /// (Unix only)
+    #[arg(long, env = "BUGGY_CLIENT_UNIX_SOCKET", value_name = "PATH",
+          conflicts_with_all = ["port", "prefer_family", "proxy", "noproxy"])]
+    unix_socket: Option<String>,
+
+    /// HTTP proxy as [http://][USER:PASSWORD@]HOST[:PORT], port 1080 by default, instead of http_proxy or
+    /// all_proxy from the environment; an empty URL turns the proxy off
+    #[arg(short = 'x', long, env = "BUGGY_CLIENT_PROXY", value_name = "URL")]
+    proxy: Option<String>,
+
+    /// Hosts to reach without the proxy instead of no_proxy from the environment: names that also cover their
+    /// subdomains, IP addresses and CIDR ranges, separated by commas, or * for all
+    #[arg(long, env = "BUGGY_CLIENT_NOPROXY", value_name = "LIST")]
+    noproxy: Option<String>,
+
+    /// Chunk size in KiB, or with a unit like 512k or 1MiB, from 1k to 1g
+    #[arg(short, long, env = "BUGGY_CLIENT_CHUNK_SIZE", value_name = "SIZE", value_parser = kib(1, MAX_KIB),
+          default_value = "64")]
+    chunk_size: usize,
+
+    /// Number of concurrent downloads
+    #[arg(short, long, env = "BUGGY_CLIENT_THREADS", value_name = "NUM", value_parser = at_least(1),
+          default_value = "4")]
+    threads: usize,
+
+    /// Fewest concurrent downloads to back off to when the server fails [default: 1]
+    #[arg(long, env = "BUGGY_CLIENT_MIN_THREADS", value_name = "NUM", value_parser = at_least(1))]
+    min_threads: Option<usize>,
+
+    /// Most concurrent downloads to ramp up to [default: --threads]
+    #[arg(long, env = "BUGGY_CLIENT_MAX_THREADS", value_name = "NUM", value_parser = at_least(1))]
+    max_threads: Option<usize>,
+
+    /// Requests to send over one connection before closing it, 1 for a new connection per chunk
+    #[arg(long, env = "BUGGY_CLIENT_MAX_REQUESTS_PER_CONNECTION", value_name = "NUM", value_parser = at_least(1),
+          default_value = "100")]
+    max_requests_per_connection: usize,
+
+    /// Requests each thread sends back to back on one connection before reading the responses
+    #[arg(long, env = "BUGGY_CLIENT_PIPELINE", value_name = "NUM", value_parser = at_least(1), default_value = "1",
+          conflicts_with = "http_1_0")]
+    pipeline: usize,
+
+    /// Chunks to ask for in one request, answered with a multipart/byteranges body
+    #[arg(long, env = "BUGGY_CLIENT_RANGES_PER_REQUEST", value_name = "NUM", value_parser = at_least(1),
+          default_value = "1")]
+    ranges_per_request: usize,
+
+    /// Ask for each chunk with a Range header, or with query parameters like ?offset=0&length=65536 for servers
+    /// that ignore the header: header or query
+    #[arg(long, env = "BUGGY_CLIENT_RANGE_STYLE", value_name = "STYLE", value_parser = RangeStyle::parse,
+          default_value = "header")]
+    range_style: RangeStyle,
+
+    /// Names of the query parameters --range-style query puts the offset and the length in, instead of offset
+    /// and length
+    #[arg(long, env = "BUGGY_CLIENT_RANGE_PARAMS", value_name = "OFFSET,LENGTH", value_parser = parse_range_params)]
+    range_params: Option<(String, String)>,
+
+    /// Grow or shrink each worker's chunk size to keep chunks near 2s
+    #[arg(long, env = "BUGGY_CLIENT_ADAPTIVE_CHUNKS")]
+    adaptive_chunks: bool,
+
+    /// Smallest chunk size in KiB, or with a k/m suffix, for --adaptive-chunks
+    #[arg(long, env = "BUGGY_CLIENT_MIN_CHUNK_SIZE", value_name = "KIB", value_parser = kib(1, MAX_KIB),
+          default_value = "16", requires = "adaptive_chunks")]
+    min_chunk_size: usize,
+
+    /// Largest chunk size in KiB, or with a k/m suffix, for --adaptive-chunks
+    #[arg(long, env = "BUGGY_CLIENT_MAX_CHUNK_SIZE", value_name = "KIB", value_parser = kib(1, MAX_KIB),
+          default_value = "4096", requires = "adaptive_chunks")]
+    max_chunk_size: usize,
+
+    /// Save downloaded data to FILE, or write it to stdout with -
+    #[arg(short, long, env = "BUGGY_CLIENT_OUTPUT", value_name = "FILE")]
+    output: Option<String>,
+
+    /// Verify the hash of downloaded data (see --hash-algo)
+    #[arg(short, long, env = "BUGGY_CLIENT_VERIFY", value_name = "HASH")]
+    verify: Option<String>,
+
+    /// User-Agent header to send [default: buggy-client/<version>]
+    #[arg(long, env = "BUGGY_CLIENT_USER_AGENT", value_name = "AGENT")]
+    user_agent: Option<String>,
+
+    /// Limit total download speed in bytes/s, with optional k/m/g suffix
+    #[arg(long, env = "BUGGY_CLIENT_LIMIT_RATE", value_name = "RATE", value_parser = parse_rate)]
+    limit_rate: Option<u64>,
+
+    /// Stop after SECS, or a duration like 10min, size probe and repair rounds included, keeping what
+    /// --chunks-dir or --continue can resume from, and exit as incomplete
+    #[arg(long, env = "BUGGY_CLIENT_MAX_TIME", value_name = "SECS", value_parser = parse_secs,
+          conflicts_with_all = ["benchmark", "benchmark_forever"])]
+    max_time: Option<Duration>,
+
+    /// Drop and retry a chunk whose transfer stays below RATE bytes/s, with optional k/m/g suffix, for
+    /// --speed-time
+    #[arg(long, env = "BUGGY_CLIENT_SPEED_LIMIT", value_name = "RATE", value_parser = parse_rate)]
+    speed_limit: Option<u64>,
+
+    /// How long, in seconds or a duration like 1min, a chunk may stay below --speed-limit [default: 30]
+    #[arg(long, env = "BUGGY_CLIENT_SPEED_TIME", value_name = "SECS", value_parser = parse_secs,
+          requires = "speed_limit")]
+    speed_time: Option<Duration>,
+
+    /// Give up on a connect after SECS, or a duration like 500ms; 0 or none waits for the system [default: 3]
+    #[arg(long, env = "BUGGY_CLIENT_CONNECT_TIMEOUT", value_name = "SECS", value_parser = parse_timeout)]
+    connect_timeout: Option<Timeout>,
+
+    /// Retry a request the server has not started answering after SECS, or a duration like 1min; 0 or none
+    /// waits forever [default: 5]
+    #[arg(long, env = "BUGGY_CLIENT_FIRST_BYTE_TIMEOUT", value_name = "SECS", value_parser = parse_timeout)]
+    first_byte_timeout: Option<Timeout>,
+
+    /// Retry a response that sends no data for SECS, or a duration like 500ms, once it has started; 0 or none
+    /// waits forever [default: 5]
+    #[arg(long, env = "BUGGY_CLIENT_IDLE_TIMEOUT", value_name = "SECS", value_parser = parse_timeout)]
+    idle_timeout: Option<Timeout>,
+
+    /// Abort once more than SIZE bytes arrive, with optional k/m/g suffix
+    #[arg(long, env = "BUGGY_CLIENT_MAX_SIZE", value_name = "SIZE", value_parser = parse_size)]
+    max_size: Option<usize>,
+
+    /// Download only bytes START up to END of the file, with optional k/m/g suffixes; 1g- runs to the end
+    #[arg(long, env = "BUGGY_CLIENT_RANGE", value_name = "START-END", value_parser = parse_range,
+          conflicts_with = "manifest")]
+    range: Option<(usize, Option<usize>)>,
+
+    /// Read responses through a SIZE buffer, with optional k/m/g suffix [default: chunk size up to 256k]
+    #[arg(long, env = "BUGGY_CLIENT_READ_BUFFER", value_name = "SIZE", value_parser = parse_size)]
+    read_buffer: Option<usize>,
+
+    /// Retry a chunk up to NUM times when the connection fails, separately from transfer errors
+    #[arg(long, env = "BUGGY_CLIENT_CONNECT_RETRIES", value_name = "NUM", value_parser = at_least(0),
+          default_value = "5")]
+    connect_retries: usize,
+
+    /// Abort after NUM retries across all chunks [default: 20 plus one per chunk received]
+    #[arg(long, env = "BUGGY_CLIENT_RETRY_BUDGET", value_name = "NUM", value_parser = at_least(0))]
+    retry_budget: Option<usize>,
+
+    /// Disable Nagle's algorithm on every connection
+    #[arg(long, env = "BUGGY_CLIENT_TCP_NODELAY")]
+    tcp_nodelay: bool,
+
+    /// Socket receive buffer size in bytes, with optional k/m/g suffix
+    #[arg(long, env = "BUGGY_CLIENT_RECV_BUFFER", value_name = "SIZE", value_parser = parse_size)]
+    recv_buffer: Option<usize>,
+
+    /// Send TCP keepalive probes after SECS of idle time, or a duration like 2min
+    #[arg(long, env = "BUGGY_CLIENT_TCP_KEEPALIVE", value_name = "SECS", value_parser = positive_secs)]
+    tcp_keepalive: Option<Duration>,
+
+    /// Ask the server for a gzip/deflate compressed response
+    #[arg(long, env = "BUGGY_CLIENT_COMPRESS")]
+    compress: bool,
+
+    /// Send HTTP/1.0 requests without a Host header, one per connection, for servers that reject 1.1
+    #[arg(long = "http-1.0", env = "BUGGY_CLIENT_HTTP_1_0")]
+    http_1_0: bool,
+
+    /// Extra request header as "Name: value" (can be repeated)
+    #[arg(short = 'H', long, env = "BUGGY_CLIENT_HEADER", value_name = "HEADER", value_parser = parse_header)]
+    header: Vec<(String, String)>,
+
+    /// Read the expected hash from a sha256sum-style checksum file
+    #[arg(long, env = "BUGGY_CLIENT_VERIFY_FILE", value_name = "PATH", conflicts_with_all = ["verify", "verify_url"])]
+    verify_file: Option<String>,
+
+    /// Fetch a sha256sum-style checksum file from URL or a path on the server
+    #[arg(long, env = "BUGGY_CLIENT_VERIFY_URL", value_name = "URL", conflicts_with = "verify")]
+    verify_url: Option<String>,
+
+    /// Abort unless the server's Content-Type is TYPE, or starts with it if TYPE ends in /
+    #[arg(long, env = "BUGGY_CLIENT_EXPECT_CONTENT_TYPE", value_name = "TYPE")]
+    expect_content_type: Option<String>,
+
+    /// Find the file size with one-byte requests first, to plan every chunk and show a total
+    #[arg(long, env = "BUGGY_CLIENT_PROBE_SIZE")]
+    probe_size: bool,
+
+    /// Hash algorithm: sha256, sha512, sha1, md5 or blake3
+    #[arg(long, env = "BUGGY_CLIENT_HASH_ALGO", value_name = "ALGO", value_parser = HashAlgo::parse,
+          default_value = "sha256")]
+    hash_algo: HashAlgo,
+
+    /// Use a fast non-cryptographic checksum instead: crc32, crc32c, xxh3, or none to skip hashing
+    #[arg(long, env = "BUGGY_CLIENT_CHECKSUM", value_name = "KIND", value_parser = HashAlgo::parse_checksum,
+          conflicts_with = "hash_algo")]
+    checksum: Option<HashAlgo>,
+
+    /// Hash on NUM threads beside the download instead of between chunks; sha256 and the other serial hashes
+    /// use one and give the same digest, blake3 spreads over all of them
+    #[arg(long, env = "BUGGY_CLIENT_HASH_THREADS", value_name = "NUM", value_parser = at_least(1),
+          default_value = "1")]
+    hash_threads: usize,
+
+    /// Download again up to NUM times when --verify, --verify-file or --verify-url fails
+    #[arg(long, env = "BUGGY_CLIENT_VERIFY_RETRIES", value_name = "NUM", value_parser = at_least(0),
+          default_value = "0")]
+    verify_retries: usize,
+
+    /// Record a CRC32C checkpoint every KIB of assembled data, 0 disables
+    #[arg(long, env = "BUGGY_CLIENT_ROLLING_CRC", value_name = "KIB", value_parser = kib(0, MAX_KIB),
+          default_value = "256")]
+    rolling_crc: usize,
+
+    /// Wait up to SECS, or a duration like 5min, for another run writing the same output to finish
+    #[arg(long, env = "BUGGY_CLIENT_WAIT_FOR_LOCK", value_name = "SECS", value_parser = parse_secs)]
+    wait_for_lock: Option<Duration>,
+
+    /// Without -o, only hash the data instead of saving it under the server's file name
+    #[arg(long, env = "BUGGY_CLIENT_NO_AUTO_OUTPUT")]
+    no_auto_output: bool,
+
+    /// Overwrite the output file if it already exists
+    #[arg(long, env = "BUGGY_CLIENT_FORCE")]
+    force: bool,
+
+    /// Download even if the output from an earlier run is still up to date
+    #[arg(long, env = "BUGGY_CLIENT_FORCE_DOWNLOAD")]
+    force_download: bool,
+
+    /// If the output file exists, keep its bytes and append the rest of the file after them
+    #[arg(long = "continue", env = "BUGGY_CLIENT_CONTINUE",
+          conflicts_with_all = ["range", "chunks_dir", "manifest", "mmap", "restart_on_change", "benchmark",
+                                "benchmark_forever"])]
+    continue_output: bool,
+
+    /// Write the download to <output>.part and list the chunks on disk in <output>.state every CHUNKS chunks, so
+    /// a run that was killed or stopped resumes from there; 0 turns it off
+    #[arg(long, env = "BUGGY_CLIENT_CHECKPOINT_INTERVAL", value_name = "CHUNKS", value_parser = at_least(0),
+          default_value = "16")]
+    checkpoint_interval: usize,
+
+    /// Also update <output>.state with the first chunk after SECS, or a duration like 1min, since the last time
+    #[arg(long, env = "BUGGY_CLIENT_CHECKPOINT_TIME", value_name = "SECS", value_parser = positive_secs,
+          default_value = "10")]
+    checkpoint_time: Duration,
+
+    /// Write the output file through a memory map as chunks arrive; implies --probe-size
+    #[arg(long, env = "BUGGY_CLIENT_MMAP")]
+    mmap: bool,
+
+    /// Don't stop when the output's filesystem has less free space than the file needs
+    #[arg(long, env = "BUGGY_CLIENT_NO_SPACE_CHECK")]
+    no_space_check: bool,
+
+    /// Download and discard the file, repeating for a duration like 30s or 5min or until SIZE is received, then
+    /// print throughput, request time percentiles and errors
+    #[arg(long, env = "BUGGY_CLIENT_BENCHMARK", value_name = "DURATION|SIZE",
+          conflicts_with_all = ["output", "manifest", "verify", "verify_file", "verify_url", "mmap"])]
+    benchmark: Option<Option<String>>,
+
+    /// Like --benchmark, but repeat the download until Ctrl+C
+    #[arg(long, env = "BUGGY_CLIENT_BENCHMARK_FOREVER",
+          conflicts_with_all = ["benchmark", "output", "manifest", "verify", "verify_file", "verify_url", "mmap"])]
+    benchmark_forever: bool,
+
+    /// Save every chunk as received to DIR/chunk-<id>-<start>-<end>.bin with a .meta JSON beside it, instead of
+    /// assembling the file; chunks already in DIR are not fetched again
+    #[arg(long, env = "BUGGY_CLIENT_CHUNKS_DIR", value_name = "DIR",
+          conflicts_with_all = ["output", "manifest", "verify", "verify_file", "verify_url", "mmap", "benchmark",
+                                "benchmark_forever"])]
+    chunks_dir: Option<String>,
+
+    /// Keep every chunk fetched in DIR and read back the ones the server's current ETag or Last-Modified date
+    /// still matches instead of fetching them again
+    #[arg(long, env = "BUGGY_CLIENT_CACHE_DIR", value_name = "DIR", conflicts_with_all = ["chunks_dir", "delta_base"])]
+    cache_dir: Option<String>,
+
+    /// After each download, remove the least recently used chunks from --cache-dir until it holds no more than
+    /// SIZE, with optional k/m/g suffix
+    #[arg(long, env = "BUGGY_CLIENT_CACHE_MAX_SIZE", value_name = "SIZE", value_parser = parse_size,
+          requires = "cache_dir")]
+    cache_max_size: Option<usize>,
+
+    /// Copy the chunks whose hashes still match the server's from FILE, an older copy, and download only the
+    /// rest; the hashes come from a .hashes file next to the download or X-Chunk-Checksum headers
+    #[arg(long, env = "BUGGY_CLIENT_DELTA_BASE", value_name = "FILE",
+          conflicts_with_all = ["range", "continue_output", "chunks_dir", "manifest", "benchmark",
+                                "benchmark_forever", "no_auto_output"])]
+    delta_base: Option<String>,
+
+    /// Compare FILE with the server chunk by chunk instead of saving a download, list the byte ranges that
+    /// differ and exit with 5 if any do
+    #[arg(long, env = "BUGGY_CLIENT_AUDIT", value_name = "FILE",
+          conflicts_with_all = ["output", "manifest", "verify", "verify_file", "verify_url", "mmap", "benchmark",
+                                "benchmark_forever", "chunks_dir", "continue_output", "range", "dry_run",
+                                "delta_base"])]
+    audit: Option<String>,
+
+    /// With --audit, write the server's bytes over the ones that differ, then exit with 0
+    #[arg(long, env = "BUGGY_CLIENT_AUDIT_FIX", requires = "audit")]
+    audit_fix: bool,
+
+    /// Probe the size and print the address, chunk plan, retry policy and output, then exit
+    #[arg(long, env = "BUGGY_CLIENT_DRY_RUN", conflicts_with_all = ["manifest", "benchmark", "benchmark_forever"])]
+    dry_run: bool,
+
+    /// Download every '<path> <output> [hash]' line of FILE instead of a single file
+    #[arg(long, env = "BUGGY_CLIENT_MANIFEST", value_name = "FILE",
+          conflicts_with_all = ["output", "verify", "verify_file", "verify_url"])]
+    manifest: Option<String>,
+
+    /// Files from --manifest to download at the same time
+    #[arg(long, env = "BUGGY_CLIENT_PARALLEL_FILES", value_name = "NUM", value_parser = at_least(1),
+          default_value = "1", requires = "manifest")]
+    parallel_files: usize,
+
+    /// Abort at the first chunk that runs out of retries, and stop a --manifest run at the first file that fails
+    #[arg(long, env = "BUGGY_CLIENT_FAIL_FAST")]
+    fail_fast: bool,
+
+    /// Go over the ranges still missing NUM more times, slower and with fresh retries, before giving up
+    #[arg(long, env = "BUGGY_CLIENT_REPAIR_ROUNDS", value_name = "NUM", value_parser = at_least(0),
+          default_value = "2")]
+    repair_rounds: usize,
+
+    /// Start over, up to 3 times, when the file changes on the server during the download instead of failing
+    #[arg(long, env = "BUGGY_CLIENT_RESTART_ON_CHANGE", conflicts_with = "chunks_dir")]
+    restart_on_change: bool,
+
+    /// Append a line to FILE for every chunk error as it happens
+    #[arg(long, env = "BUGGY_CLIENT_ERROR_LOG", value_name = "FILE")]
+    error_log: Option<String>,
+
+    /// Print chunk duration percentiles, per-thread totals and the slowest chunks
+    #[arg(long, env = "BUGGY_CLIENT_STATS")]
+    stats: bool,
+
+    /// Write a JSON report of the run to FILE, even when it fails
+    #[arg(long, env = "BUGGY_CLIENT_REPORT", value_name = "FILE")]
+    report: Option<String>,
+
+    /// Print one JSON object with the result on stdout at the end, and everything else on stderr
+    #[arg(long, env = "BUGGY_CLIENT_JSON",
+          conflicts_with_all = ["manifest", "benchmark", "benchmark_forever", "audit", "dry_run"])]
+    json: bool,
+
+    /// Progress output: human bars, or json events on stderr
+    #[arg(long, env = "BUGGY_CLIENT_PROGRESS_FORMAT", value_name = "FORMAT", value_enum, default_value = "human")]
+    progress_format: ProgressFormat,
+
+    /// Draw bars only when stderr is a terminal (auto), or always (force)
+    #[arg(long, env = "BUGGY_CLIENT_PROGRESS", value_name = "WHEN", value_enum, default_value = "auto")]
+    progress: ProgressBars,
+
+    /// Print a plain stats line every SECS, or a duration like 10s, instead of bars; the default applies when
+    /// stderr is not a terminal
+    #[arg(long, env = "BUGGY_CLIENT_STATS_INTERVAL", value_name = "SECS", value_parser = positive_secs,
+          default_value = "5")]
+    stats_interval: Duration,
+
+    /// Do not draw progress bars, but still print the summary
+    #[arg(long, env = "BUGGY_CLIENT_NO_PROGRESS")]
+    no_progress: bool,
+
+    /// Draw only the total bar and a status line instead of a bar per thread
+    #[arg(long, env = "BUGGY_CLIENT_SIMPLE_PROGRESS", conflicts_with = "no_progress")]
+    simple_progress: bool,
+
+    /// Print nothing but errors
+    #[arg(short, long, env = "BUGGY_CLIENT_QUIET", conflicts_with_all = ["verbose", "simple_progress"])]
+    quiet: bool,
+
+    /// Enable verbose output with detailed error messages; repeat for debug and trace logs
+    #[arg(long, env = "BUGGY_CLIENT_VERBOSE", action = ArgAction::Count)]
+    verbose: u8,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Assemble the chunk files saved with --chunks-dir into one file, hash it and verify it
+    Merge(MergeArgs),
+    /// Manage the chunks kept with --cache-dir
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+}
+
+#[derive(clap::Args)]
+struct MergeArgs {
+    /// Directory the chunks were saved to
+    #[arg(value_name = "DIR")]
+    dir: String,
+
+    /// File to write the assembled data to
+    #[arg(short, long, value_name = "FILE")]
+    output: String,
+
+    /// Manifest giving where the data starts and how long it is [default: DIR/manifest.json]
+    #[arg(long, value_name = "FILE")]
+    manifest: Option<String>,
+
+    /// Verify the hash of the assembled data
+    #[arg(short, long, value_name = "HASH")]
+    verify: Option<String>,
+
+    /// Hash algorithm, if not the one configured for downloads
+    #[arg(long, value_name = "ALGO", value_parser = HashAlgo::parse)]
+    hash_algo: Option<HashAlgo>,
+
+    /// Use a fast non-cryptographic checksum instead: crc32, crc32c or xxh3
+    #[arg(long, value_name = "KIND", value_parser = HashAlgo::parse_checksum, conflicts_with = "hash_algo")]
+    checksum: Option<HashAlgo>,
+
+    /// Overwrite the output file if it already exists
+    #[arg(long)]
+    force: bool,
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Remove every cached chunk
+    Purge {
+        /// Cache directory [default: --cache-dir]
+        #[arg(value_name = "DIR")]
+        dir: Option<String>,
+    },
+}
+
+/// `--progress-format`: how progress is shown.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum ProgressFormat {
+    Human,
+    Json,
+}
+
+/// `--progress`: when to draw bars.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum ProgressBars {
+    Auto,
+    Force,
+}
+
+/// A timeout option's value, `None` for no timeout.
+type Timeout = Option<Duration>;
+
+/// The command line, with flags read from the environment taking 1, yes or on
+/// as well as true.
+fn command() -> Command {
+    Args::command().mut_args(|arg| match arg.get_action() {
+        ArgAction::SetTrue => arg.value_parser(BoolishValueParser::new()),
+        _ => arg,
+    })
+}
+
+/// Parses `args`, naming a value a parser rejected the same way as before
+/// clap 4, which scripts may match on.
+fn parse_args<I, T>(args: I) -> Result<ArgMatches, clap::Error>
+    where I: IntoIterator<Item = T>, T: Into<OsString> + Clone {
+    let mut command = command();
+    command.try_get_matches_from_mut(args).map_err(|error| {
+        let (ClapErrorKind::ValueValidation, Some(ContextValue::String(arg)), Some(ContextValue::String(value))) =
+            (error.kind(), error.get(ContextKind::InvalidArg), error.get(ContextKind::InvalidValue)) else {
+            return error;
+        };
+        let reason = std::error::Error::source(&error).map(ToString::to_string).unwrap_or_default();
+        command.error(ClapErrorKind::ValueValidation, format!("Invalid value \"{}\" for '{}': {}", value, arg, reason))
+    })
+}
+
+/// A config file and the entries read from it.
+struct ConfigFile {
+    path: PathBuf,
+    entries: Vec<ConfigEntry>,
+}
+
+/// Reads the `--config` file, or else the first of `./buggy-client.toml`
+/// and `buggy-client/config.toml` in the user config dir that exists.
+fn load_config(explicit: Option<&str>) -> Result<Option<ConfigFile>, String> {
+    let path = match explicit {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let user_dir = std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()).map(PathBuf::from)
+                .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+            let candidates = [Some(PathBuf::from("buggy-client.toml")),
+                              user_dir.map(|dir| dir.join("buggy-client").join("config.toml"))];
+            match candidates.into_iter().flatten().find(|path| path.is_file()) {
+                Some(path) => path,
+                None => return Ok(None),
+            }
+        }
+    };
+    let text = std::fs::read_to_string(&path)
+        .map_err(|e| format!("Cannot read config file '{}': {}", path.display(), e))?;
+    let entries = parse_config(&text).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(Some(ConfigFile { path, entries }))
+}
+
+/// Options that cannot be set from a config file.
+const NOT_CONFIGURABLE: [&str; 3] = ["config", "help", "version"];
+
+/// Adds the config entries for options not given on the command line and
+/// parses the arguments again, so a flag beats the file and the file beats
+/// the defaults. Returns the new matches and, by option, the file and line
+/// each value from the file came from.
+fn apply_config(config: &ConfigFile, matches: &ArgMatches)
+    -> Result<(ArgMatches, BTreeMap<String, String>), String> {
+    let command = command();
+    let options: Vec<_> = command.get_arguments()
+        .filter(|arg| arg.get_long().is_some() && !NOT_CONFIGURABLE.contains(&arg.get_id().as_str()))
+        .collect();
+    let mut argv = std::env::args_os();
+    let mut args: Vec<_> = argv.next().into_iter().collect();
+    let mut sources = BTreeMap::new();
+    let mut unknown = Vec::new();
+    for entry in &config.entries {
+        let location = format!("{}:{}", config.path.display(), entry.line);
+        let name = entry.key.replace('_', "-");
+        let Some(arg) = options.iter().find(|arg| arg.get_long() == Some(name.as_str())) else {
+            unknown.push(format!("'{}' (line {})", entry.key, entry.line));
+            continue;
+        };
+        let values = config_args(arg, &entry.value).map_err(|e| format!("{}: '{}' {}", location, entry.key, e))?;
+        if !set_outside_config(matches, arg.get_id().as_str()) && !values.is_empty() {
+            args.extend(values.into_iter().map(Into::into));
+            sources.insert(arg.get_id().to_string(), location);
+        }
+    }
+    // The command line goes after the config values so that a subcommand and its arguments still come last.
+    args.extend(argv);
+    if !unknown.is_empty() {
+        let mut valid: Vec<_> = options.iter().filter_map(|arg| arg.get_long()).collect();
+        valid.sort_unstable();
+        diag!("Warning: ignoring unknown key{} {} in '{}'; valid keys are {}", if unknown.len() == 1 { "" } else { "s" },
+              unknown.join(", "), config.path.display(), valid.join(", "));
+    }
+    let matches = parse_args(args)
+        .map_err(|e| format!("With the options from '{}': {}", config.path.display(), e))?;
+    Ok((matches, sources))
+}
+
+/// Value parser for a whole number no smaller than `min`.
+fn at_least(min: usize) -> impl Fn(&str) -> Result<usize, String> + Clone + Send + Sync + 'static {
+    move |raw| raw.parse::<usize>().ok().filter(|&number| number >= min)
+        .ok_or_else(|| format!("expected a whole number of at least {}", min))
+}
+
+/// Largest size, in KiB, that the chunk and buffer size options take.
+const MAX_KIB: usize = 1024 * 1024;
+
+/// Value parser for a size in KiB, or in bytes with a unit as
+/// [`parse_size`] reads it, from `min` to `max` KiB. Returns bytes.
+fn kib(min: usize, max: usize) -> impl Fn(&str) -> Result<usize, String> + Clone + Send + Sync + 'static {
+    move |raw| {
+        let range = format!("from {} to {}", describe_kib(min), describe_kib(max));
+        let bytes = match raw.trim().parse::<usize>() {
+            Ok(kib) => kib.saturating_mul(1024),
+            Err(_) => parse_size(raw)
+                .map_err(|_| format!("expected a number of KiB, or a size like 512k or 1MiB, {}", range))?,
+        };
+        if !(min * 1024..=max * 1024).contains(&bytes) {
+            return Err(format!("must be {}", range));
+        }
+        Ok(bytes)
+    }
+}
+
+/// `kib` KiB in the largest binary unit that divides it.
+fn describe_kib(kib: usize) -> String {
+    match kib {
+        kib if kib > 0 && kib % (1024 * 1024) == 0 => format!("{} GiB", kib / (1024 * 1024)),
+        kib if kib > 0 && kib % 1024 == 0 => format!("{} MiB", kib / 1024),
+        kib => format!("{} KiB", kib),
+    }
+}
+
+/// Reads a window such as `100m-200m` or `1g-`: a start offset and an
+/// exclusive end, both sizes, the end left open when missing.
+fn parse_range(raw: &str) -> Result<(usize, Option<usize>), String> {
+    let (start, end) = raw.split_once('-').ok_or("expected START-END, like 100m-200m or 1g-")?;
+    let offset = |raw: &str| match raw.trim() {
+        "0" => Ok(0),
+        raw => parse_size(raw),
+    };
+    let start = offset(start)?;
+    let end = match end.trim() {
+        "" => None,
+        end => Some(offset(end)?),
+    };
+    if end.is_some_and(|end| end <= start) {
+        return Err("the end must come after the start".to_string());
+    }
+    Ok((start, end))
+}
+
+/// Joins a host and port into `host:port`, bracketing IPv6 literals as
+/// `[::1]:8080`. The host may be given with or without the brackets.
+fn format_authority(host: &str, port: u16) -> String {
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Splits `host:port` or `[v6]:port`; a bare host, including an IPv6
+/// address without brackets, gets `default_port`.
+fn split_host_port(raw: &str, default_port: u16) -> Result<(&str, u16), String> {
+    match raw.rsplit_once(':') {
+        Some((host, port)) if host.ends_with(']') || !host.contains(':') => {
+            let port = parse_port(port).map_err(|e| format!("Invalid host '{}': {}", raw, e))?;
+            Ok((host, port))
+        }
+        _ => Ok((raw, default_port)),
+    }
+}
+
+fn parse_port(raw: &str) -> Result<u16, String> {
+    raw.parse::<u16>().ok().filter(|&port| port != 0).ok_or_else(|| "expected a port from 1 to 65535".to_string())
+}
+
+/// Reads a number of seconds, or a duration such as `500ms`, `30s`, `5min`
+/// or `1h`.
+fn parse_secs(raw: &str) -> Result<Duration, String> {
+    let split = raw.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(raw.len());
+    let (number, unit) = raw.split_at(split);
+    let scale = match unit.to_ascii_lowercase().as_str() {
+        "" | "s" | "sec" => 1.0,
+        "ms" => 0.001,
+        "min" => 60.0,
+        "h" => 3600.0,
+        _ => return Err("expected seconds, or a duration like 500ms, 30s, 5min or 1h".to_string()),
+    };
+    number.parse::<f64>().ok().and_then(|number| Duration::try_from_secs_f64(number * scale).ok())
+        .ok_or_else(|| "expected seconds, or a duration like 500ms, 30s, 5min or 1h".to_string())
+}
+
+/// A timeout in seconds or with a unit, or `0` or `none` for no timeout.
+fn parse_timeout(raw: &str) -> Result<Timeout, String> {
+    if raw.eq_ignore_ascii_case("none") {
+        return Ok(None);
+    }
+    Ok(Some(parse_secs(raw)?).filter(|time| !time.is_zero()))
+}
+
+/// `OFFSET,LENGTH`: the names of the query parameters for ranges.
+fn parse_range_params(raw: &str) -> Result<(String, String), String> {
+    raw.split_once(',').map(|(offset, length)| (offset.trim().to_string(), length.trim().to_string()))
+        .ok_or_else(|| "expected the offset and length parameter names as OFFSET,LENGTH".to_string())
+}
+
+/// Splits a `-H "Name: value"` argument into its name and value.
+fn parse_header(raw: &str) -> Result<(String, String), String> {
+    if raw.contains('\r') || raw.contains('\n') {
+        return Err(format!("Invalid header '{}': must not contain CR or LF", raw.escape_default()));
+    }
+    let (name, value) = raw.split_once(':')
+        .ok_or_else(|| format!("Invalid header '{}': expected \"Name: value\"", raw))?;
+    let name = name.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(format!("Invalid header '{}': bad header name", raw));
+    }
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+fn positive_secs(raw: &str) -> Result<Duration, String> {
+    Some(parse_secs(raw)?).filter(|time| !time.is_zero()).ok_or_else(|| "must be more than zero".to_string())
+}
+
+/// Whether the option was given on the command line or in its environment
+/// variable, either of which beats the config file.
+fn set_outside_config(matches: &ArgMatches, id: &str) -> bool {
+    matches!(matches.value_source(id), Some(ValueSource::CommandLine | ValueSource::EnvVariable))
+}
+
+fn env_source(arg: &Arg) -> String {
+    let name = arg.get_env().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    format!("environment {}", name)
+}
+
+/// The command line arguments that set `arg` to `value`, checking that the
+/// value has the right type: a boolean for a flag, a number where the value
+/// is a count or a size in KiB, and an array only for repeatable options.
+fn config_args(arg: &Arg, value: &ConfigValue) -> Result<Vec<String>, String> {
+    let flag = format!("--{}", arg.get_long().unwrap_or_default());
+    let repeatable = matches!(arg.get_action(), ArgAction::Append | ArgAction::Count);
+    if !arg.get_action().takes_values() {
+        return match value {
+            ConfigValue::Boolean(true) => Ok(vec![flag]),
+            ConfigValue::Boolean(false) => Ok(Vec::new()),
+            // A repeatable flag such as --verbose also takes a count.
+            ConfigValue::Integer(count) if repeatable && *count >= 0 => {
+                Ok(vec![flag; *count as usize])
+            }
+            other => Err(format!("expects true or false, found {}", other.type_name())),
+        };
+    }
+    let values = match value {
+        ConfigValue::Array(values) if repeatable => values.as_slice(),
+        ConfigValue::Array(_) => return Err("expects a single value, found an array".to_string()),
+        value => std::slice::from_ref(value),
+    };
+    let value_name = arg.get_value_names().and_then(|names| names.first()).map_or("", |name| name.as_str());
+    values.iter().map(|value| match (value_name, value) {
+        (_, ConfigValue::Boolean(_) | ConfigValue::Array(_)) => {
+            Err(format!("expects a value, found {}", value.type_name()))
+        }
+        ("NUM" | "PORT", ConfigValue::String(_) | ConfigValue::Float(_)) | ("KIB", ConfigValue::Float(_)) => {
+            Err(format!("expects a whole number, found {}", value.type_name()))
+        }
+        (_, value) => Ok(format!("{}={}", flag, value)),
+    }).collect()
+}
+
+/// Prints every option that has a value and where the value came from: the
+/// command line, a config file line, or the built-in default.
+fn print_config(matches: &ArgMatches, config_sources: &BTreeMap<String, String>) {
+    status!("Configuration:");
+    for arg in command().get_arguments().filter(|arg| !NOT_CONFIGURABLE.contains(&arg.get_id().as_str())) {
+        let (id, Some(long)) = (arg.get_id().as_str(), arg.get_long()) else { continue };
+        let value = match arg.get_action() {
+            ArgAction::SetTrue if matches.get_flag(id) => "true".to_string(),
+            ArgAction::Count if matches.get_count(id) > 1 => matches.get_count(id).to_string(),
+            ArgAction::Count if matches.get_count(id) == 1 => "true".to_string(),
+            action if action.takes_values() => match matches.get_raw(id) {
+                Some(values) => values.map(|value| value.to_string_lossy()).collect::<Vec<_>>().join(", "),
+                None => continue,
+            },
+            _ => continue,
+        };
+        // Proxy credentials stay off the screen.
+        let value = match (id, value.rsplit_once('@')) {
+            ("proxy", Some((_, address))) => format!("***@{}", address),
+            _ => value,
+        };
+        let source = match config_sources.get(id) {
+            Some(location) => location.clone(),
+            None => match matches.value_source(id) {
+                Some(ValueSource::EnvVariable) => env_source(arg),
+                Some(ValueSource::CommandLine) => "command line".to_string(),
+                _ => "default".to_string(),
+            },
+        };
+        status!("    {} = {} ({})", long, value, source);
+    }
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let matches = parse_args(std::env::args_os()).unwrap_or_else(|e| e.exit());
+    let config = load_config(matches.get_one::<String>("config").map(String::as_str)).map_err(Exit::usage)?;
+    let (matches, config_sources) = match &config {
+        Some(config) => apply_config(config, &matches).map_err(Exit::usage)?,
+        None => (matches, BTreeMap::new()),
+    };
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    match &args.command {
+        Some(Commands::Merge(merge)) => return run_merge(&args, merge),
+        Some(Commands::Cache { command }) => return run_cache(&args, command),
+        None => {}
+    }
+    let settings = resolve(&args, &matches)?;
+    let logger = Logger::from_env(settings.log_level)
+        .stdout(settings.json_events && !settings.data_on_stdout && !settings.json_result)
+        .install()?;
+    if settings.verbose && !args.dry_run {
+        print_config(&matches, &config_sources);
+    }
+    let mut builder = configure(&args, &settings)?;
+
+    if let Some(manifest) = &args.manifest {
+        let text = std::fs::read_to_string(manifest)
+            .map_err(|e| format!("Cannot read manifest '{}': {}", manifest, e))?;
+        let entries = parse_manifest(&text)?;
+        let cancel = settings.cancellation();
+        install_interrupt_handler(cancel.clone())?;
+        let builder = builder.cancellation(cancel.clone());
+        let fetch = |entry: &ManifestEntry| {
+            refuse_existing_output(&entry.output, args.force)?;
+            download_entry(&builder, entry, &settings, args.wait_for_lock)
+        };
+        return run_manifest(&entries, args.parallel_files, args.fail_fast, &cancel, fetch);
+    }
+
+    let chunks_dir = args.chunks_dir.as_deref();
+    if let Some(dir) = chunks_dir {
+        builder = builder.chunks_dir(dir);
+    }
+    let delta_base = args.delta_base.as_deref();
+    if let Some(base) = delta_base {
+        builder = builder.delta_base(base);
+    }
+    if let Some(dir) = &args.cache_dir {
+        builder = builder.cache_dir(dir);
+    }
+    if let Some(size) = args.cache_max_size {
+        builder = builder.cache_max_size(size as u64);
+    }
+    if args.dry_run {
+        let downloader = builder.build().map_err(Exit::usage)?;
+        print_plan(&downloader.plan()?, &describe_output(&args, &settings, &downloader));
+        print_config(&matches, &config_sources);
+        return Ok(());
+    }
+    let benchmark_progress = || {
+        progress_for_benchmark(settings.quiet, settings.json_events, args.stats_interval, settings.verbose)
+    };
+    if args.benchmark_forever {
+        return run_benchmark(builder, benchmark_progress(), BenchmarkTarget::Forever);
+    }
+    if let Some(target) = &args.benchmark {
+        let target = target.as_deref().map_or(Ok(BenchmarkTarget::Once), BenchmarkTarget::parse)?;
+        return run_benchmark(builder, benchmark_progress(), target);
+    }
+
+    let auto_output = if settings.output_file.is_none() && !settings.data_on_stdout && chunks_dir.is_none()
+        && !args.no_auto_output && args.audit.is_none() {
+        let name = builder.build().map_err(Exit::usage)?.suggested_filename();
+        status!("Saving to '{}'", name);
+        Some(name)
+    } else {
+        None
+    };
+    let output_file = settings.output_file.or(auto_output.as_deref());
+
+    let verify_hash = hash_to_verify(&args, &builder, output_file)?;
+    if let Some(expected) = &verify_hash {
+        builder = builder.expected_hash(expected.as_str());
+    }
+    if let Some(path) = output_file {
+        prepare_output(path)?;
+    }
+    if let Some(path) = output_file.filter(|_| !args.no_space_check) {
+        let dir = Path::new(path).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        builder = builder.space_check(dir);
+    }
+    // Bytes an earlier, partial download left in the output file.
+    let continued = match output_file {
+        Some(path) if args.continue_output => std::fs::metadata(path).ok().map(|local| local.len()),
+        None if args.continue_output => {
+            return Err(Exit::usage("--continue needs an output file to continue".to_string()).into());
+        }
+        _ => None,
+    };
+    if let Some(existing) = continued {
+        builder = builder.continue_after(existing as usize);
+    }
+    // A part file the next run can resume from, unless the data comes from elsewhere too.
+    let resumable = args.checkpoint_interval > 0 && continued.is_none() && delta_base.is_none()
+        && chunks_dir.is_none() && args.cache_dir.is_none() && !args.mmap;
+    if let Some(path) = output_file.filter(|_| resumable) {
+        builder = builder.state_file(state_path(path)).checkpoint_every(args.checkpoint_interval, args.checkpoint_time);
+    }
+    let cancel = settings.cancellation();
+    let pause = PauseToken::new();
+    let downloader = observe(builder, &args, &settings, logger)
+        .cancellation(cancel.clone())
+        .pause(pause.clone())
+        .build()
+        .map_err(Exit::usage)?;
+    if let Some(path) = &args.audit {
+        install_interrupt_handler(cancel)?;
+        return audit(&downloader, path, args.audit_fix);
+    }
+
+    // A window of the file says nothing about the whole of it.
+    let whole_file = args.range.is_none();
+    if let Some(path) = output_file.filter(|_| whole_file && !args.force_download) {
+        if let Ok(local) = std::fs::metadata(path) {
+            if output_up_to_date(&downloader, path, local.len()) {
+                keep_output(path);
+                return Ok(());
+            }
+        }
+    }
+    // Updating a file from an older copy of itself replaces it.
+    let updating = delta_base.zip(output_file).is_some_and(|(base, output)| {
+        std::fs::canonicalize(base).is_ok_and(|base| std::fs::canonicalize(output).is_ok_and(|output| base == output))
     });
+    let force = args.force || updating;
+    if let Some(path) = output_file.filter(|_| continued.is_none()) {
+        refuse_existing_output(path, force)?;
+    }
+
+    let output_lock = match output_file {
+        Some(path) => match lock_output(&settings, path, &downloader, verify_hash.as_deref(), args.wait_for_lock)? {
+            LockOutcome::Acquired(lock) => Some(lock),
+            LockOutcome::Published => {
+                status!("'{}' was downloaded by another run of the same source", path);
+                keep_output(path);
+                return Ok(());
+            }
+            LockOutcome::Held(holder) => {
+                let message = format!("'{}' is locked by another download ({})", path, lock_holder(holder.as_ref()));
+                diag!("{}", message);
+                exit_now(LOCK_HELD_EXIT_CODE, message);
+            }
+        },
+        None => None,
+    };
+
+    let authorities: Vec<_> = settings.servers.iter().map(|&(host, port)| format_authority(host, port)).collect();
+    status!("Starting download from {}", authorities.join(", "));
+
+    install_interrupt_handler(cancel.clone())?;
+    let keys = watch_keys(pause, cancel);
+    if keys.is_some() {
+        status!("Press p to pause, r to resume, q to stop");
+    }
+
+    let destination = Destination::choose(&args, &settings, output_file, continued, resumable);
+    let result = transfer(&downloader, &destination);
+    drop(keys);
+    let summary = match result {
+        Ok(summary) => summary,
+        Err(e) => return Err(download_failed(&args, &settings, e, destination, output_lock)),
+    };
+    let shortfall = Shortfall::of(&args, &summary);
+    report_summary(&args, &settings, &summary, output_file, shortfall.as_ref())?;
+    print_summary(&args, &settings, &summary);
+    check_download(&args, &settings, &summary, output_file, verify_hash.as_deref(), &destination, shortfall)?;
+    persist(destination, &summary, whole_file)?;
+    if let Some(dir) = chunks_dir {
+        status!("Chunks saved to '{}', listed in its manifest.json", dir);
+    }
+
+    Ok(())
+}
+
+/// The options a run resolves before it builds anything: the servers, how
+/// the data is checked and where the run reports on itself.
+struct Settings<'a> {
+    /// The `--host` servers, the first of them the origin.
+    servers: Vec<(&'a str, u16)>,
+    hash_algo: HashAlgo,
+    crc_window: usize,
+    max_threads: usize,
+    deadline: Option<Instant>,
+    /// `--output`, unless it is `-` for stdout.
+    output_file: Option<&'a str>,
+    data_on_stdout: bool,
+    json_result: bool,
+    json_events: bool,
+    /// Asking for stats lines gets them instead of the bars, unless the bars are forced.
+    stats_requested: bool,
+    plain_progress: bool,
+    quiet: bool,
+    verbose: bool,
+    log_level: LevelFilter,
+}
+
+impl Settings<'_> {
+    /// A token that also cancels the run at the `--max-time` deadline.
+    fn cancellation(&self) -> CancellationToken {
+        match self.deadline {
+            Some(deadline) => CancellationToken::new().with_deadline(deadline),
+            None => CancellationToken::new(),
+        }
+    }
+}
+
+/// Checks the options that only make sense together and notes for
+/// [`print_result`] and the macros where the output goes.
+fn resolve<'a>(args: &'a Args, matches: &ArgMatches)
+    -> Result<Settings<'a>, Box<dyn std::error::Error + Send + Sync>> {
+    // The time limit covers everything from here on.
+    let deadline = args.max_time.map(|limit| Instant::now() + limit);
+    let servers = args.host.iter()
+        .map(|raw| split_host_port(raw, args.port))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(Exit::usage)?;
+    let hash_algo = args.checksum.unwrap_or(args.hash_algo);
+    if hash_algo == HashAlgo::None
+        && (args.verify.is_some() || args.verify_file.is_some() || args.verify_url.is_some()) {
+        return Err(Exit::new(USAGE_EXIT_CODE, "--checksum none skips hashing, so there is nothing to verify against")
+            .into());
+    }
+    // Skipping the hash skips the default checkpoints too.
+    let crc_window = if hash_algo == HashAlgo::None
+        && matches.value_source("rolling_crc") == Some(ValueSource::DefaultValue) {
+        0
+    } else {
+        args.rolling_crc
+    };
+    let data_on_stdout = args.output.as_deref() == Some("-");
+    DATA_ON_STDOUT.store(data_on_stdout, Ordering::Relaxed);
+    let json_result = args.json;
+    if json_result && data_on_stdout {
+        return Err(Exit::usage("--json prints the result on stdout, so the data cannot go there".to_string()).into());
+    }
+    JSON_RESULT.store(json_result, Ordering::Relaxed);
+    let json_events = args.progress_format == ProgressFormat::Json;
+    JSON_EVENTS.store(json_events, Ordering::Relaxed);
+    let stats_requested = matches.value_source("stats_interval") != Some(ValueSource::DefaultValue);
+    let plain_progress = data_on_stdout
+        || (args.progress == ProgressBars::Auto && (stats_requested || !std::io::stderr().is_terminal()));
+    let quiet = args.quiet;
+    QUIET.store(quiet, Ordering::Relaxed);
+    let log_level = match (quiet, args.verbose) {
+        (true, _) => LevelFilter::Error,
+        (false, 0) => LevelFilter::Warn,
+        (false, 1) => LevelFilter::Info,
+        (false, 2) => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    Ok(Settings {
+        servers,
+        hash_algo,
+        crc_window,
+        max_threads: args.max_threads.unwrap_or(args.threads),
+        deadline,
+        output_file: args.output.as_deref().filter(|&path| path != "-"),
+        data_on_stdout,
+        json_result,
+        json_events,
+        stats_requested,
+        plain_progress,
+        quiet,
+        verbose: args.verbose > 0,
+        log_level,
+    })
+}
+
+/// The builder every download of the run starts from: the servers, the
+/// connections and their proxies, and how chunks are fetched and checked.
+fn configure(args: &Args, settings: &Settings) -> Result<DownloaderBuilder, Box<dyn std::error::Error + Send + Sync>> {
+    let (host, port) = settings.servers[0];
+    let mut builder = Downloader::builder()
+        .host(host)
+        .port(port)
+        .chunk_size(args.chunk_size)
+        .concurrency(args.threads)
+        .concurrency_range(args.min_threads.unwrap_or(1), settings.max_threads)
+        .hash_algo(settings.hash_algo)
+        .verify_retries(args.verify_retries)
+        .crc_window(settings.crc_window)
+        .hash_threads(args.hash_threads)
+        .compress(args.compress)
+        .http_1_0(args.http_1_0)
+        .probe_size(args.probe_size || args.mmap);
+    if args.adaptive_chunks {
+        builder = builder.adaptive_chunks(args.min_chunk_size, args.max_chunk_size);
+    }
+    if let Some(user_agent) = &args.user_agent {
+        builder = builder.user_agent(user_agent);
+    }
+    builder = builder.connect_retries(args.connect_retries).fail_fast(args.fail_fast)
+        .repair_rounds(args.repair_rounds).restart_on_change(args.restart_on_change);
+    if let Some(budget) = args.retry_budget {
+        builder = builder.retry_budget(budget);
+    }
+    if args.tcp_nodelay {
+        builder = builder.tcp_nodelay(true);
+    }
+    if let Some(size) = args.read_buffer {
+        builder = builder.read_buffer(size);
+    }
+    if let Some(size) = args.recv_buffer {
+        builder = builder.recv_buffer(size);
+    }
+    if let Some(idle) = args.tcp_keepalive {
+        builder = builder.tcp_keepalive(idle);
+    }
+    if let Some(family) = args.prefer_family {
+        builder = builder.prefer_family(family);
+    }
+    if let Some(path) = &args.unix_socket {
+        builder = builder.unix_socket(path);
+    } else {
+        builder = builder.proxy_from_env();
+        if let Some(url) = &args.proxy {
+            builder = builder.proxy(url);
+        }
+        if let Some(list) = &args.noproxy {
+            builder = builder.no_proxy(list);
+        }
+    }
+    if let Some(content_type) = &args.expect_content_type {
+        builder = builder.expected_content_type(content_type);
+    }
+    if let Some(rate) = args.limit_rate {
+        builder = builder.rate_limit(rate);
+    }
+    if let Some(limit) = args.speed_limit {
+        builder = builder.low_speed(limit, args.speed_time.unwrap_or(Duration::from_secs(30)));
+    }
+    if let Some(timeout) = args.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(timeout) = args.first_byte_timeout {
+        builder = builder.first_byte_timeout(timeout);
+    }
+    if let Some(timeout) = args.idle_timeout {
+        builder = builder.read_timeout(timeout);
+    }
+    builder = builder.max_requests_per_connection(args.max_requests_per_connection).pipeline(args.pipeline)
+        .ranges_per_request(args.ranges_per_request);
+    builder = builder.range_style(match (&args.range_style, &args.range_params) {
+        (RangeStyle::Query { .. }, Some((offset, length))) => {
+            RangeStyle::Query { offset: offset.clone(), length: length.clone() }
+        }
+        (RangeStyle::Header, Some(_)) => {
+            return Err(Exit::usage("--range-params needs --range-style query".to_string()).into())
+        }
+        (style, _) => style.clone(),
+    });
+    if let Some(size) = args.max_size {
+        builder = builder.max_size(size);
+    }
+    for &(host, port) in &settings.servers[1..] {
+        builder = builder.mirror(host, port);
+    }
+    if let Some((start, end)) = args.range {
+        builder = builder.range(start, end);
+    }
+    if let Some(path) = &args.error_log {
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|e| format!("Cannot open error log '{}': {}", path, e))?;
+        builder = builder.error_log(file);
+    }
+    for (name, value) in &args.header {
+        builder = builder.header(name, value);
+    }
+    Ok(builder)
+}
+
+/// Adds the progress display that the options and the terminal call for.
+fn observe(builder: DownloaderBuilder, args: &Args, settings: &Settings, logger: &Logger) -> DownloaderBuilder {
+    if settings.quiet {
+        builder
+    } else if settings.json_events {
+        builder.observer(Arc::new(JsonProgress::new(std::io::stderr(), Duration::from_secs(1))))
+    } else if args.no_progress && !settings.stats_requested {
+        builder.observer(Arc::new(TerminalProgress::without_bars(settings.verbose)))
+    } else if settings.plain_progress {
+        builder.observer(Arc::new(PlainProgress::new(std::io::stderr(), args.stats_interval, settings.verbose)))
+    } else {
+        let progress = if args.simple_progress {
+            Arc::new(TerminalProgress::simple(settings.verbose))
+        } else {
+            Arc::new(TerminalProgress::new(settings.max_threads, settings.verbose))
+        };
+        logger.print_above(Arc::clone(&progress));
+        builder.observer(progress)
+    }
+}
+
+/// Takes the lock on `output` for what `downloader` fetches from the
+/// origin. A file another run published without a hash to check is
+/// compared with what the server has.
+fn lock_output(
+    settings: &Settings,
+    output: &str,
+    downloader: &Downloader,
+    hash: Option<&str>,
+    wait: Option<Duration>,
+) -> Result<LockOutcome, Box<dyn std::error::Error + Send + Sync>> {
+    let (host, port) = settings.servers[0];
+    let metadata = LockMetadata::for_current_run(host, port, downloader.path(), hash);
+    acquire_output_lock(output, &metadata, settings.hash_algo, wait, &|len| output_up_to_date(downloader, output, len))
+}
+
+/// Where a download writes its data.
+enum Destination<'a> {
+    /// The output file itself, after the `len` bytes it already holds.
+    Continue { path: &'a str, len: u64 },
+    /// A temporary file, moved over the output once complete.
+    Temp(TempOutput),
+    /// A temporary file written through a memory map.
+    Mapped(TempOutput),
+    Stdout,
+    /// Nowhere, as the chunks are saved one file each.
+    Discard,
+    /// Memory, only to be hashed.
+    Memory,
+}
+
+impl<'a> Destination<'a> {
+    /// Where the data of a run writing to `output_file`, if it has one,
+    /// goes: after the `continued` bytes it holds, or into a temporary file
+    /// that is `resumable` or not.
+    fn choose(args: &Args, settings: &Settings, output_file: Option<&'a str>, continued: Option<u64>,
+              resumable: bool) -> Self {
+        match (output_file, continued) {
+            (Some(path), Some(len)) => Destination::Continue { path, len },
+            (Some(path), None) if args.mmap => Destination::Mapped(TempOutput::new(path)),
+            (Some(path), None) if resumable => Destination::Temp(TempOutput::resumable(path)),
+            (Some(path), None) => Destination::Temp(TempOutput::new(path)),
+            (None, _) if settings.data_on_stdout => Destination::Stdout,
+            (None, _) if args.chunks_dir.is_some() => Destination::Discard,
+            (None, _) => Destination::Memory,
+        }
+    }
+
+    /// The file the data was written to, if any.
+    fn written(&self) -> Option<&Path> {
+        match self {
+            Destination::Continue { path, .. } => Some(Path::new(path)),
+            Destination::Temp(temp) | Destination::Mapped(temp) => Some(&temp.path),
+            Destination::Stdout | Destination::Discard | Destination::Memory => None,
+        }
+    }
+}
+
+/// Downloads the file into `destination` and syncs what was written.
+fn transfer(downloader: &Downloader, destination: &Destination) -> Result<Summary, DownloadError> {
+    match destination {
+        // A continued file is appended to in place.
+        Destination::Continue { path, len } => {
+            let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+            status!("Continuing '{}' after its {} bytes", path, len);
+            let summary = downloader.download(&mut file)?;
+            // Only the bytes up to the first hole are kept, so the file can
+            // be continued again.
+            if let Some(first) = summary.missing.first() {
+                file.set_len(first.start as u64)?;
+            }
+            file.sync_all()?;
+            Ok(summary)
+        }
+        Destination::Mapped(temp) => {
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&temp.path)?;
+            let summary = downloader.download(&mut MmapSink::new(file.try_clone()?))?;
+            file.sync_all()?;
+            Ok(summary)
+        }
+        Destination::Temp(temp) => {
+            // What an earlier run left in the part file is read back, and anything past the end cut off.
+            let mut file = match temp.state {
+                Some(_) => OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&temp.path)?,
+                None => File::create(&temp.path)?,
+            };
+            let summary = downloader.download(&mut file)?;
+            file.sync_all()?;
+            Ok(summary)
+        }
+        Destination::Stdout => downloader.download(&mut StreamSink::new(std::io::stdout().lock())),
+        Destination::Discard => downloader.download(&mut Discard),
+        Destination::Memory => downloader.download(&mut Vec::new()),
+    }
+}
+
+/// The hash the download is checked against: from `--verify-file`, from
+/// the document at `--verify-url` or given with `--verify`.
+fn hash_to_verify(args: &Args, builder: &DownloaderBuilder, output_file: Option<&str>)
+    -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+    if let Some(path) = &args.verify_file {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read checksum file '{}': {}", path, e))?;
+        Ok(Some(parse_checksum_file(&text, output_file)?))
+    } else if let Some(location) = &args.verify_url {
+        let body = builder.build().map_err(Exit::usage)?.fetch_document(location)
+            .map_err(|e| format!("Cannot fetch checksum file '{}': {}", location, e))?;
+        Ok(Some(parse_checksum_file(&String::from_utf8_lossy(&body), output_file)?))
+    } else {
+        Ok(args.verify.clone())
+    }
+}
+
+/// Reports a download that failed and picks the exit code for it. A
+/// cancellation or a size over the limit exits at once, after the partial
+/// output and the lock are cleaned up.
+fn download_failed(args: &Args, settings: &Settings, error: DownloadError, destination: Destination,
+                   output_lock: Option<OutputLock>) -> Box<dyn std::error::Error + Send + Sync> {
+    if let DownloadError::Cancelled(cancelled) = &error {
+        diag!("\n{}", cancelled);
+        if let Err(e) = write_report(args, settings, "cancelled", None, Some(cancelled.to_string())) {
+            diag!("{}", e);
+        }
+        drop(destination);
+        drop(output_lock);
+        exit_now(INTERRUPTED_EXIT_CODE, cancelled.to_string());
+    }
+    // The report is a side channel; the download error keeps its exit code.
+    if let Err(e) = write_report(args, settings, "failed", None, Some(error.to_string())) {
+        diag!("{}", e);
+    }
+    if matches!(&error, DownloadError::Io(e) if e.kind() == ErrorKind::BrokenPipe) {
+        diag!("Output pipe closed, download stopped");
+        exit_now(BROKEN_PIPE_EXIT_CODE, error.to_string());
+    }
+    if matches!(error, DownloadError::ResourceChanged(_)) && !args.restart_on_change {
+        return format!("{}. Run again for the new version, or pass --restart-on-change to start over on a change",
+                       error).into();
+    }
+    if matches!(error, DownloadError::TooLarge(_)) {
+        diag!("{}", error);
+        drop(destination);
+        drop(output_lock);
+        exit_now(TOO_LARGE_EXIT_CODE, error.to_string());
+    }
+    error.into()
+}
+
+/// Why a download that ran its course did not bring the whole file.
+struct Shortfall {
+    /// The ranges still missing.
+    incomplete: DownloadError,
+    /// How `--max-time` cut the download short, if it did.
+    timed_out: Option<String>,
+}
+
+impl Shortfall {
+    fn of(args: &Args, summary: &Summary) -> Option<Self> {
+        if summary.missing.is_empty() && !summary.timed_out {
+            return None;
+        }
+        let timed_out = summary.timed_out.then(|| {
+            let missing: usize = summary.missing.iter().map(|range| range.len()).sum();
+            let missing = if missing > 0 { format!(", {} bytes are still missing", missing) } else { String::new() };
+            format!("Stopped by --max-time of {:.0}s after downloading {} bytes{}",
+                    args.max_time.unwrap_or_default().as_secs_f64(), summary.bytes, missing)
+        });
+        Some(Shortfall { incomplete: DownloadError::Incomplete { missing_ranges: summary.missing.clone() }, timed_out })
+    }
+}
+
+/// Writes the `--report` file for a download that ran its course, and
+/// notes the result `--json` prints.
+fn report_summary(args: &Args, settings: &Settings, summary: &Summary, output_file: Option<&str>,
+                  shortfall: Option<&Shortfall>) -> Result<(), String> {
+    if let Some(shortfall) = shortfall {
+        let error = shortfall.timed_out.clone().unwrap_or_else(|| shortfall.incomplete.to_string());
+        write_report(args, settings, "incomplete", Some(summary), Some(error))?;
+    } else if summary.verified == Some(false) {
+        let error = "Checksum verification failed".to_string();
+        write_report(args, settings, "verification_failed", Some(summary), Some(error))?;
+    } else {
+        write_report(args, settings, "ok", Some(summary), None)?;
+    }
+    if settings.json_result {
+        let output = output_file.map(str::to_string);
+        *RESULT.lock().unwrap() = Some(RunResult { output, ..RunResult::from_summary(summary) });
+    }
+    Ok(())
+}
+
+/// Writes the checkpoint log, verifies the hash of a complete download and
+/// lists the errors. A download with holes fails here, with the exit code
+/// that says why; it is not moved into place.
+fn check_download(args: &Args, settings: &Settings, summary: &Summary, output_file: Option<&str>,
+                  verify_hash: Option<&str>, destination: &Destination, shortfall: Option<Shortfall>)
+    -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if let (Some(path), Some(checkpoints)) = (output_file, &summary.checkpoints) {
+        write_checkpoint_log(&checkpoint_log_path(path), settings.crc_window, checkpoints)?;
+    }
+    // The hash of a file with holes says nothing.
+    if shortfall.is_none() {
+        verify(summary, verify_hash, args.verify_retries, destination)?;
+    }
+    if !settings.quiet {
+        print_errors(args, settings, summary, output_file);
+    }
+    let Some(shortfall) = shortfall else {
+        return Ok(());
+    };
+    if let Some(message) = shortfall.timed_out {
+        return Err(Exit::new(INCOMPLETE_EXIT_CODE, message).into());
+    }
+    if summary.errors.iter().all(|error| error.phase == ErrorPhase::Connect) && summary.bytes == 0 {
+        let (host, port) = settings.servers[0];
+        let cause = summary.errors.last().map_or("", |error| error.message.as_str());
+        return Err(Exit::new(CONNECT_EXIT_CODE,
+                             format!("Could not connect to {}: {}", format_authority(host, port), cause)).into());
+    }
+    Err(shortfall.incomplete.into())
+}
+
+/// Where `--dry-run` says the data would go.
+fn describe_output(args: &Args, settings: &Settings, downloader: &Downloader) -> String {
+    if settings.data_on_stdout {
+        "standard output".to_string()
+    } else if let Some(dir) = &args.chunks_dir {
+        format!("one file per chunk in '{}'", dir)
+    } else if let Some(path) = settings.output_file {
+        format!("'{}'", path)
+    } else if args.no_auto_output {
+        "nowhere, the data is only hashed".to_string()
+    } else {
+        format!("'{}'", downloader.suggested_filename())
+    }
+}
+
+/// Writes the `--report` file, if one was asked for.
+fn write_report(args: &Args, settings: &Settings, status: &str, summary: Option<&Summary>, error: Option<String>)
+    -> Result<(), String> {
+    let Some(path) = &args.report else {
+        return Ok(());
+    };
+    let (host, port) = settings.servers[0];
+    Report {
+        host,
+        port,
+        path: "/",
+        chunk_size: args.chunk_size,
+        threads: args.threads,
+        status,
+        summary,
+        error,
+        chunk_timings: args.stats,
+    }
+        .write(Path::new(path))
+        .map_err(|e| format!("Cannot write report '{}': {}", path, e))
+}
 
-    println!("Starting download from {}:{}", host, port);
-    
-    let start_time = Instant::now();
-    let chunks = Arc::new(Mutex::new(Vec::<Chunk>::new()));
-    let processed_chunks = Arc::new(Mutex::new(HashSet::new()));
-    let total_bytes = Arc::new(Mutex::new(0_usize));
-    let download_errors = Arc::new(Mutex::new(Vec::<(usize, String)>::new()));
-    let total_progress = Arc::new(Mutex::new(total_progress));
-    
-    let mut next_chunk = 0;
-    let mut eof_reached = false;
-    let mut retry_count = 0;
-    let max_retries = 3; // should also make configurable
-
-    while !eof_reached && retry_count <= max_retries {
-        let mut handles = vec![];
-
-        for i in 0..concurrent_downloads {
-            let chunk_id = next_chunk + i;
-            let start_pos = chunk_id * chunk_size;
-            let end_pos = start_pos + chunk_size;
-            
-            // skip processed chunks
-            if processed_chunks.lock().unwrap().contains(&chunk_id) {
-                continue;
+/// Prints how long the download took, what it fetched and where from.
+fn print_summary(args: &Args, settings: &Settings, summary: &Summary) {
+    let total_time = summary.duration.as_secs_f32();
+    status!("\nDownload completed in {:.2}s", total_time);
+    status!("Total size: {} bytes ({:.2} KiB)", summary.bytes, summary.bytes as f32 / 1024.0);
+    status!("Average speed: {:.2} KiB/s", summary.bytes as f32 / 1024.0 / total_time);
+    if let Some(base) = &args.delta_base {
+        status!("Delta: {} bytes downloaded, {} bytes reused from '{}'", summary.bytes, summary.reused, base);
+    }
+    if summary.restarts > 0 {
+        status!("Restarted: {} times, as the file changed on the server", summary.restarts);
+    }
+    if summary.resumed > 0 {
+        status!("Resumed: {} bytes were already in the part file from an earlier run", summary.resumed);
+    }
+    if args.cache_dir.is_some() {
+        status!("Cache: {} hits, {} misses", summary.cache_hits, summary.cache_misses);
+    }
+    let hash_algo = settings.hash_algo;
+    if args.chunks_dir.is_some() {
+        status!("Hash: skipped, the chunks were not assembled");
+    } else if hash_algo == HashAlgo::None {
+        status!("Hash: skipped (--checksum none)");
+    } else if hash_algo.is_cryptographic() {
+        status!("{} hash: {}", hash_algo.label(), summary.hash);
+    } else {
+        status!("{} checksum (not a cryptographic hash): {}", hash_algo.label(), summary.hash);
+    }
+    if summary.repair_rounds > 0 {
+        status!("Repair: {} chunks rescued in {} rounds", summary.repaired_chunks, summary.repair_rounds);
+    }
+    if summary.mirrors.len() > 1 {
+        for mirror in &summary.mirrors {
+            let dropped = mirror.blacklisted.as_ref().map_or(String::new(), |reason| format!(", dropped: {}", reason));
+            status!("Mirror {}: {} bytes in {} chunks, {} failed requests{}", mirror.authority(), mirror.bytes,
+                    mirror.chunks, mirror.failures, dropped);
+        }
+    }
+    if args.adaptive_chunks {
+        status!("Chunks: {} ({:.2}-{:.2} KiB)", summary.chunks,
+                summary.smallest_chunk as f32 / 1024.0, summary.largest_chunk as f32 / 1024.0);
+    }
+    if args.stats {
+        print_chunk_stats(&summary.chunk_timings);
+        let connections = &summary.connections;
+        status!("Connections: {} created, {} reused, {} evicted", connections.created, connections.reused,
+                connections.evicted);
+    }
+}
+
+/// Checks the hash of a complete download against `expected`, or else
+/// against the one a delta sidecar gave, and on a mismatch points at the
+/// first window of the written data that differs.
+fn verify(summary: &Summary, expected: Option<&str>, retries: usize, destination: &Destination)
+    -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let Some(expected_hash) = expected else {
+        // Without a hash to verify, a delta update still checks the one its sidecar gives.
+        return match summary.verified {
+            Some(true) => {
+                status!("Checksum verification: PASSED ✓ (hash from the delta sidecar)");
+                Ok(())
             }
-            
-            let chunks_clone = Arc::clone(&chunks);
-            let processed_clone = Arc::clone(&processed_chunks);
-            let total_bytes_clone = Arc::clone(&total_bytes);
-            let errors_clone = Arc::clone(&download_errors);
-            let progress_bar = Arc::clone(&thread_bars[i % thread_bars.len()]);
-            let total_pb = Arc::clone(&total_progress);
-            let host = host.to_string();
-            let verbose_flag = verbose;
-            
-            let handle = thread::spawn(move || {
-                progress_bar.lock().unwrap().set_position(0);
-                progress_bar.lock().unwrap().set_length(chunk_size as u64);
-                
-                let mut retry_attempts = 0;
-                let max_chunk_retries = 2;
-                
-                loop {
-                    match make_range_request_with_progress(&host, port, start_pos, end_pos, 
-                                                          &progress_bar) {
-                        Ok((data, headers)) => {                            
-                            if headers.contains("400 Invalid range:") || data.is_empty() {
-                                progress_bar.lock().unwrap().finish();
-                                return Some(chunk_id); // signal EOF
-                            } else {
-                                progress_bar.lock().unwrap().finish();
-                                
-                                {
-                                    let mut total = total_bytes_clone.lock().unwrap();
-                                    *total += data.len();
-                                    total_pb.lock().unwrap().set_position(*total as u64);
-                                }
-                                
-                                chunks_clone.lock().unwrap().push(Chunk {
-                                    id: chunk_id,
-                                    data,
-                                });
-                                
-                                processed_clone.lock().unwrap().insert(chunk_id);
-                                return None;
-                            }
-                        }
-                        Err(e) => {
-                            let error_msg = format!("{}", e);
-                            if verbose_flag {
-                                eprintln!("Error downloading chunk {}: {}", chunk_id, error_msg);
-                            }
-                            
-                            errors_clone.lock().unwrap().push((chunk_id, error_msg.clone()));
-                            
-                            retry_attempts += 1;
-                            if retry_attempts <= max_chunk_retries {
-                                let backoff = Duration::from_millis(50 * (1 << retry_attempts));
-                                if verbose_flag {
-                                    eprintln!("Retrying chunk {} after {}ms", chunk_id, backoff.as_millis());
-                                }
-                                thread::sleep(backoff);
-                                continue;
-                            } else {
-                                if verbose_flag {
-                                    eprintln!("Failed to download chunk {} after {} attempts", 
-                                             chunk_id, retry_attempts);
-                                }
-                                progress_bar.lock().unwrap().finish();
-                                return None;
-                            }
-                        }
-                    }
-                }
-            });
-            
-            handles.push(handle);
-        }
-        
-        let mut batch_eof = false;
-        for handle in handles {
-            match handle.join() {
-                Ok(Some(_eof_chunk_id)) => {
-                    batch_eof = true;
-                    eof_reached = true;
+            Some(false) => {
+                diag!("Checksum verification: FAILED ✗ (hash from the delta sidecar)");
+                Err(Exit::new(VERIFY_EXIT_CODE, "Checksum verification failed").into())
+            }
+            None => Ok(()),
+        };
+    };
+    let attempt = summary.attempts;
+    if summary.verified == Some(true) {
+        if attempt > 1 {
+            status!("Checksum verification: PASSED ✓ (attempt {} of {})", attempt, retries + 1);
+        } else {
+            status!("Checksum verification: PASSED ✓");
+        }
+        return Ok(());
+    }
+    diag!("Checksum verification: FAILED ✗");
+    diag!("Expected: {}", expected_hash);
+    diag!("Actual:   {}", summary.hash);
+    if let (Some(written), Some(checkpoints)) = (destination.written(), &summary.checkpoints) {
+        match find_divergence(written, checkpoints)? {
+            Some(bad) => diag!("First divergent window: bytes {}-{} (chunks {:?})", bad.start, bad.end, bad.chunk_ids),
+            None => diag!("Written output matches all CRC checkpoints; \
+                           the data was already wrong when it was received"),
+        }
+    }
+    if attempt > 1 {
+        return Err(Exit::new(VERIFY_EXIT_CODE, format!("Checksum verification failed after {} attempts", attempt))
+            .into());
+    }
+    Err(Exit::new(VERIFY_EXIT_CODE, "Checksum verification failed").into())
+}
+
+/// Lists the errors the download ran into and the commands that fetch the
+/// ranges still missing.
+fn print_errors(args: &Args, settings: &Settings, summary: &Summary, output_file: Option<&str>) {
+    if !summary.errors.is_empty() {
+        diag!("\n{} errors occurred during download:", summary.errors.len());
+        for group in summary.error_groups() {
+            diag!("  {} × {}, {}", group.count, group.cause, describe_ranges(&group.ranges, 3));
+        }
+        if summary.errors.iter().any(|error| error.cause == ErrorCause::Throttled) {
+            diag!("The server is busy; fewer --threads may go faster");
+        }
+
+        if settings.verbose {
+            for error in &summary.errors {
+                diag!("Chunk {} ({} attempt {}): {}",
+                      error.chunk_id, error.phase.as_str(), error.attempt, error.message);
+            }
+        } else {
+            diag!("Use --verbose for detailed error information");
+        }
+    }
+
+    // Nothing to fetch again in parts when nothing came at all.
+    if !summary.missing.is_empty() && summary.bytes > 0 {
+        let origin = args.range.map_or(0, |(start, _)| start);
+        let missing: Vec<_> = summary.missing.iter().map(|range| origin + range.start..origin + range.end)
+            .collect();
+        diag!("Still missing: {}; to fetch just those bytes again, run:", describe_ranges(&missing, 5));
+        let program = std::env::args().next().unwrap_or_else(|| "buggy_client".to_string());
+        let output = output_file.unwrap_or("download.bin");
+        let (host, port) = settings.servers[0];
+        for range in missing.iter().take(MAX_REFETCH_COMMANDS) {
+            diag!("  {} --host {} --range {}-{} --output {}", shell_quote(&program),
+                  shell_quote(&format_authority(host, port)), range.start, range.end,
+                  shell_quote(&format!("{}.{}-{}", output, range.start, range.end)));
+        }
+        if missing.len() > MAX_REFETCH_COMMANDS {
+            diag!("  ... and {} more ranges; --report FILE lists them all", missing.len() - MAX_REFETCH_COMMANDS);
+        }
+    }
+}
+
+/// Moves a complete download into place and keeps the validators it was
+/// fetched with.
+fn persist(destination: Destination, summary: &Summary, whole_file: bool) -> Result<(), Exit> {
+    match destination {
+        Destination::Temp(temp) | Destination::Mapped(temp) => {
+            let path = temp.output.display().to_string();
+            temp.persist().map_err(|e| Exit::new(OUTPUT_EXIT_CODE,
+                                                 format!("Cannot move the download into place at '{}': {}", path, e)))?;
+            status!("Downloaded data saved to '{}'", path);
+            if whole_file {
+                save_validators(&path, summary);
+            }
+        }
+        Destination::Continue { path, .. } => {
+            status!("Downloaded data appended to '{}'", path);
+            save_validators(path, summary);
+        }
+        Destination::Stdout | Destination::Discard | Destination::Memory => {}
+    }
+    Ok(())
+}
+
+/// Notes `path` as the output of a run that had nothing to download.
+fn keep_output(path: &str) {
+    *RESULT.lock().unwrap() = Some(RunResult { output: Some(path.to_string()), ..RunResult::default() });
+}
+
+/// Most commands printed to fetch missing ranges again.
+const MAX_REFETCH_COMMANDS: usize = 10;
+
+/// The first `limit` of `ranges` as `start-end` pairs, saying how many more
+/// there are.
+fn describe_ranges(ranges: &[Range<usize>], limit: usize) -> String {
+    let bytes: usize = ranges.iter().map(|range| range.len()).sum();
+    let listed: Vec<_> = ranges.iter().take(limit).map(|range| format!("{}-{}", range.start, range.end)).collect();
+    let more = match ranges.len().saturating_sub(limit) {
+        0 => String::new(),
+        more => format!(" and {} more", more),
+    };
+    let plural = if ranges.len() == 1 { "" } else { "s" };
+    format!("{} bytes in {} range{}: {}{}", bytes, ranges.len(), plural, listed.join(", "), more)
+}
+
+/// `word` as a single shell word, in single quotes unless it needs none.
+fn shell_quote(word: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}
+
+/// The first Ctrl+C cancels `cancel` so the download can stop and report; a
+/// second one exits at once.
+fn install_interrupt_handler(cancel: CancellationToken) -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(move || {
+        if cancel.is_cancelled() {
+            restore_terminal();
+            exit_now(INTERRUPTED_EXIT_CODE, "Interrupted".to_string());
+        }
+        cancel.cancel();
+    })
+}
+
+/// Terminal settings of stdin from before `watch_keys`, to put back on exit.
+#[cfg(unix)]
+static SAVED_TERMINAL: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+/// Puts the terminal settings saved by `watch_keys` back in place.
+fn restore_terminal() {
+    #[cfg(unix)]
+    if let Some(saved) = SAVED_TERMINAL.lock().unwrap().take() {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &saved) };
+    }
+}
+
+/// Restores the terminal when dropped.
+struct KeyWatch;
+
+impl Drop for KeyWatch {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+/// When stdin is a terminal, switches it to single key presses without echo
+/// and handles them on a background thread: `p` pauses the download, `r`
+/// resumes it and `q` stops it like Ctrl+C. The terminal is restored when
+/// the returned guard is dropped.
+#[cfg(unix)]
+fn watch_keys(pause: PauseToken, cancel: CancellationToken) -> Option<KeyWatch> {
+    if !std::io::stdin().is_terminal() {
+        return None;
+    }
+    let mut saved = std::mem::MaybeUninit::<libc::termios>::uninit();
+    if unsafe { libc::tcgetattr(libc::STDIN_FILENO, saved.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let saved = unsafe { saved.assume_init() };
+    let mut keys = saved;
+    // Signals stay on, so Ctrl+C still arrives as one.
+    keys.c_lflag &= !(libc::ICANON | libc::ECHO);
+    keys.c_cc[libc::VMIN] = 1;
+    keys.c_cc[libc::VTIME] = 0;
+    if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &keys) } != 0 {
+        return None;
+    }
+    *SAVED_TERMINAL.lock().unwrap() = Some(saved);
+
+    thread::spawn(move || {
+        for key in std::io::stdin().lock().bytes() {
+            match key {
+                Ok(b'p' | b'P') => pause.pause(),
+                Ok(b'r' | b'R') => pause.resume(),
+                Ok(b'q' | b'Q') => {
+                    cancel.cancel();
                     break;
                 }
-                Err(e) => {
-                    if verbose {
-                        eprintln!("Thread panicked: {:?}", e);
-                    }
-                },
-                _ => {}
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+    });
+    Some(KeyWatch)
+}
+
+#[cfg(not(unix))]
+fn watch_keys(_pause: PauseToken, _cancel: CancellationToken) -> Option<KeyWatch> {
+    None
+}
+
+/// When `--benchmark` stops repeating the download.
+enum BenchmarkTarget {
+    /// After one download.
+    Once,
+    Duration(Duration),
+    /// Once this many bytes have been received in total.
+    Bytes(usize),
+    /// Only on Ctrl+C.
+    Forever,
+}
+
+impl BenchmarkTarget {
+    /// Reads a duration such as `30s`, `5min` or `1h`, or else a size for
+    /// [`parse_size`], where `5m` is 5 MiB.
+    fn parse(value: &str) -> Result<Self, String> {
+        // A bare number is a size here, not seconds.
+        if value.ends_with(|c: char| c.is_ascii_alphabetic()) {
+            if let Ok(time) = parse_secs(value) {
+                return Some(time).filter(|time| !time.is_zero()).map(BenchmarkTarget::Duration)
+                    .ok_or_else(|| format!("Invalid benchmark duration '{}'", value));
             }
         }
-        
-        if !batch_eof {
-            let processed = processed_chunks.lock().unwrap();
-            let expected_chunks: HashSet<_> = (next_chunk..(next_chunk + concurrent_downloads)).collect();
-            let missing_chunks: Vec<_> = expected_chunks.difference(&processed).collect();
-            
-            if !missing_chunks.is_empty() {
-                if verbose {
-                    eprintln!("Some chunks failed to download: {:?}", missing_chunks);
+        parse_size(value).map(BenchmarkTarget::Bytes).map_err(|e| format!("{}, or a duration like 30s", e))
+    }
+}
+
+/// The progress display for `--benchmark`. Bars would be redrawn from
+/// scratch by every pass, so it is a plain progress line at most.
+fn progress_for_benchmark(quiet: bool, json_events: bool, interval: Duration, verbose: bool)
+    -> Option<Arc<dyn DownloadObserver>> {
+    if quiet {
+        None
+    } else if json_events {
+        Some(Arc::new(JsonProgress::new(std::io::stderr(), Duration::from_secs(1))))
+    } else {
+        Some(Arc::new(PlainProgress::new(std::io::stderr(), interval, verbose)))
+    }
+}
+
+/// Downloads the file into [`Discard`] until `target` is reached or Ctrl+C,
+/// then prints what [`Benchmark`] measured.
+fn run_benchmark(builder: DownloaderBuilder, progress: Option<Arc<dyn DownloadObserver>>, target: BenchmarkTarget)
+    -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let cancel = CancellationToken::new();
+    let mut benchmark = Benchmark::new();
+    if let Some(progress) = progress {
+        benchmark = benchmark.forward_to(progress);
+    }
+    match target {
+        BenchmarkTarget::Duration(time) => {
+            let cancel = cancel.clone();
+            thread::spawn(move || {
+                thread::sleep(time);
+                cancel.cancel();
+            });
+        }
+        BenchmarkTarget::Bytes(bytes) => benchmark = benchmark.stop_after(bytes, cancel.clone()),
+        BenchmarkTarget::Once | BenchmarkTarget::Forever => {}
+    }
+    let benchmark = Arc::new(benchmark);
+    let downloader = builder.observer(Arc::clone(&benchmark) as Arc<dyn DownloadObserver>)
+        .cancellation(cancel.clone())
+        .build()
+        .map_err(Exit::usage)?;
+    install_interrupt_handler(cancel)?;
+
+    let result = loop {
+        match transfer(&downloader, &Destination::Discard) {
+            Ok(_) if matches!(target, BenchmarkTarget::Once) => break Ok(()),
+            Ok(_) => {}
+            Err(DownloadError::Cancelled(_)) => break Ok(()),
+            Err(e) => break Err(e.into()),
+        }
+    };
+    // The report is the result of the run, so even --quiet prints it.
+    println!("\n{}", benchmark.report());
+    result
+}
+
+/// Compares `path` with the server chunk by chunk and lists the ranges that
+/// differ, writing the server's bytes over them with `fix`.
+fn audit(downloader: &Downloader, path: &str, fix: bool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let file = OpenOptions::new().read(true).write(fix).open(path)
+        .map_err(|e| Exit::usage(format!("Cannot open '{}' to audit: {}", path, e)))?;
+    let mut sink = AuditSink::new(file, fix)?;
+    status!("Auditing '{}'", path);
+    let summary = match downloader.download(&mut sink) {
+        Ok(summary) => summary,
+        Err(e @ DownloadError::Cancelled(_)) => {
+            diag!("\n{}", e);
+            std::process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    // Ranges that could not be fetched were not compared either.
+    if !summary.missing.is_empty() {
+        return Err(Box::new(DownloadError::Incomplete { missing_ranges: summary.missing }));
+    }
+    let differences = sink.differences();
+    let bytes: u64 = differences.iter().map(|range| range.end - range.start).sum();
+    if differences.is_empty() {
+        status!("'{}' matches the server: all {} bytes verified", path, summary.bytes);
+        return Ok(());
+    }
+    for range in &differences {
+        status!("Differs: bytes {}-{}", range.start, range.end);
+    }
+    if fix {
+        status!("Fixed {} bytes in {} ranges of '{}'", bytes, differences.len(), path);
+        return Ok(());
+    }
+    Err(Box::new(Exit::new(VERIFY_EXIT_CODE, format!("'{}' differs from the server in {} bytes over {} ranges; \
+                                                      --audit-fix rewrites them", path, bytes, differences.len()))))
+}
+
+/// How one manifest entry went.
+enum FileResult {
+    /// Downloaded, or `None` when another run published the same file.
+    Done(Option<Box<Summary>>),
+    Failed(String),
+    Skipped,
+}
+
+/// Downloads one manifest entry under its output lock. Missing ranges and a
+/// hash mismatch are errors here, since the run goes on to the next file
+/// either way, and the file is only published whole.
+fn download_entry(
+    builder: &DownloaderBuilder,
+    entry: &ManifestEntry,
+    settings: &Settings,
+    lock_wait: Option<Duration>,
+) -> Result<Option<Summary>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut builder = builder.clone().path(entry.path.as_str());
+    if let Some(hash) = &entry.hash {
+        builder = builder.expected_hash(hash.as_str());
+    }
+    let downloader = builder.build()?;
+    prepare_output(&entry.output)?;
+
+    let _lock = match lock_output(settings, &entry.output, &downloader, entry.hash.as_deref(), lock_wait)? {
+        LockOutcome::Acquired(lock) => lock,
+        LockOutcome::Published => return Ok(None),
+        LockOutcome::Held(holder) => {
+            return Err(format!("locked by another download ({})", lock_holder(holder.as_ref())).into());
+        }
+    };
+    let destination = Destination::Temp(TempOutput::new(&entry.output));
+    let summary = transfer(&downloader, &destination)?;
+    if summary.timed_out {
+        return Err(format!("ran out of time after {} bytes", summary.bytes).into());
+    }
+    if !summary.missing.is_empty() {
+        return Err(DownloadError::Incomplete { missing_ranges: summary.missing }.into());
+    }
+    if summary.verified == Some(false) {
+        return Err(format!("checksum mismatch, got {}", summary.hash).into());
+    }
+    if let Destination::Temp(temp) = destination {
+        temp.persist()?;
+    }
+    Ok(Some(summary))
+}
+
+/// Fetches every entry, `parallel` at a time, then prints a pass/fail table.
+/// Fails unless every file was downloaded.
+fn run_manifest(
+    entries: &[ManifestEntry],
+    parallel: usize,
+    fail_fast: bool,
+    cancel: &CancellationToken,
+    fetch: impl Fn(&ManifestEntry) -> Result<Option<Summary>, Box<dyn std::error::Error + Send + Sync>> + Sync,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let next = AtomicUsize::new(0);
+    let stopped_early = AtomicBool::new(false);
+    let results = Mutex::new((0..entries.len()).map(|_| FileResult::Skipped).collect::<Vec<_>>());
+
+    thread::scope(|scope| {
+        for _ in 0..parallel.min(entries.len()) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                if index >= entries.len() || cancel.is_cancelled() {
+                    break;
                 }
-                retry_count += 1;
-                if retry_count > max_retries {
-                    if verbose {
-                        eprintln!("Max retries reached for batch starting at chunk {}. Moving to next batch.", next_chunk);
+                let entry = &entries[index];
+                let result = match fetch(entry) {
+                    Ok(summary) => {
+                        status!("{} -> {}: done", entry.path, entry.output);
+                        FileResult::Done(summary.map(Box::new))
                     }
-                    next_chunk += concurrent_downloads;
-                    retry_count = 0;
-                }
-            } else {
-                next_chunk += concurrent_downloads;
-                retry_count = 0;
+                    Err(e) if e.is::<Cancelled>() => FileResult::Skipped,
+                    Err(e) => {
+                        diag!("{} -> {}: {}", entry.path, entry.output, e);
+                        if fail_fast {
+                            stopped_early.store(true, Ordering::SeqCst);
+                            cancel.cancel();
+                        }
+                        FileResult::Failed(e.to_string())
+                    }
+                };
+                results.lock().unwrap()[index] = result;
+            });
+        }
+    });
+
+    let results = results.into_inner().unwrap();
+    status!("\n{:<30} {:<8} {:>12} {:>9}", "File", "Result", "Bytes", "Time");
+    for (entry, result) in entries.iter().zip(&results) {
+        match result {
+            FileResult::Done(Some(summary)) => {
+                let verdict = if summary.verified == Some(true) { "PASSED" } else { "OK" };
+                status!("{:<30} {:<8} {:>12} {:>8.2}s", entry.output, verdict, summary.bytes,
+                        summary.duration.as_secs_f32());
             }
+            FileResult::Done(None) => status!("{:<30} {:<8} {:>12} {:>9}", entry.output, "OK", "-", "-"),
+            FileResult::Failed(error) => status!("{:<30} {:<8} {}", entry.output, "FAILED", error),
+            FileResult::Skipped => status!("{:<30} {:<8}", entry.output, "SKIPPED"),
         }
     }
-    
-    total_progress.lock().unwrap().finish_with_message("Download complete!");
-    
-    let mut all_chunks = chunks.lock().unwrap().clone();
-    all_chunks.sort_by_key(|chunk| chunk.id);
-    
-    let mut all_data = Vec::new();
-    for chunk in all_chunks {
-        all_data.extend_from_slice(&chunk.data);
-    }
-    
-    let mut hasher = Sha256::new();
-    hasher.update(&all_data);
-    let result = hasher.finalize();
-    let calculated_hash = format!("{:x}", result);
-    
-    let total_time = start_time.elapsed().as_secs_f32();
-    println!("\nDownload completed in {:.2}s", total_time);
-    println!("Total size: {} bytes ({:.2} KiB)", all_data.len(), all_data.len() as f32 / 1024.0);
-    println!("Average speed: {:.2} KiB/s", all_data.len() as f32 / 1024.0 / total_time);
-    println!("SHA-256 hash: {}", calculated_hash);
-    
-    if let Some(expected_hash) = verify_hash {
-        if expected_hash.to_lowercase() == calculated_hash {
-            println!("Checksum verification: PASSED ✓");
-        } else {
-            eprintln!("Checksum verification: FAILED ✗");
-            eprintln!("Expected: {}", expected_hash);
-            eprintln!("Actual:   {}", calculated_hash);
-            return Err("Checksum verification failed".into());
+
+    if cancel.is_cancelled() && !cancel.expired() && !stopped_early.load(Ordering::SeqCst) {
+        std::process::exit(INTERRUPTED_EXIT_CODE);
+    }
+    let failed = results.iter().filter(|result| !matches!(result, FileResult::Done(_))).count();
+    if failed > 0 && cancel.expired() {
+        return Err(Exit::new(INCOMPLETE_EXIT_CODE, format!("Stopped by --max-time with {} of {} files not downloaded",
+                                                           failed, entries.len())).into());
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} files were not downloaded", failed, entries.len()).into());
+    }
+    Ok(())
+}
+
+/// Prints the `--stats` table: chunk duration percentiles, what each thread
+/// did and the five slowest chunks.
+fn print_chunk_stats(timings: &[ChunkTiming]) {
+    if timings.is_empty() {
+        return;
+    }
+    let mut durations: Vec<f64> = timings.iter().map(|timing| timing.duration.as_secs_f64()).collect();
+    durations.sort_by(f64::total_cmp);
+    let percentile = |p: f64| durations[((durations.len() - 1) as f64 * p).round() as usize];
+    status!("\nChunk durations: min {:.2}s, median {:.2}s, p95 {:.2}s, max {:.2}s",
+            percentile(0.0), percentile(0.5), percentile(0.95), percentile(1.0));
+
+    let mut per_thread = BTreeMap::<usize, (usize, usize, f64)>::new();
+    for timing in timings {
+        let (chunks, bytes, busy) = per_thread.entry(timing.worker).or_default();
+        *chunks += 1;
+        *bytes += timing.range.len();
+        *busy += timing.duration.as_secs_f64();
+    }
+    for (worker, (chunks, bytes, busy)) in per_thread {
+        status!("Thread #{:2}: {} chunks, {:.2} KiB in {:.2}s", worker, chunks, bytes as f32 / 1024.0, busy);
+    }
+
+    let mut slowest: Vec<_> = timings.iter().collect();
+    slowest.sort_by_key(|timing| std::cmp::Reverse(timing.duration));
+    status!("Slowest chunks:");
+    for timing in slowest.iter().take(5) {
+        status!("  Chunk {} (bytes {}-{}): {:.2}s", timing.chunk_id, timing.range.start, timing.range.end,
+                timing.duration.as_secs_f64());
+    }
+}
+
+/// Prints what `--dry-run` found out, with `output` saying where the data
+/// would go.
+fn print_plan(plan: &Plan, output: &str) {
+    status!("Dry run, nothing will be downloaded");
+    let addresses: Vec<_> = plan.addresses.iter().map(ToString::to_string).collect();
+    status!("Address: {}", addresses.join(", then "));
+    status!("Path: {}", plan.path);
+    if !plan.mirrors.is_empty() {
+        status!("Mirrors: {}", plan.mirrors.join(", "));
+    }
+    match plan.range {
+        Some((start, Some(end))) => {
+            status!("Range: bytes {}-{} of the file; offsets below count from {}", start, end, start)
         }
+        Some((start, None)) => status!("Range: byte {} to the end of the file; offsets below count from it", start),
+        None => {}
     }
-    
-    if let Some(path) = output_file {
-        println!("Saving downloaded data to '{}'", path);
-        let file = File::create(Path::new(path))?;
-        let mut writer = BufWriter::new(file);
-        writer.write_all(&all_data)?;
-        writer.flush()?;
-        println!("File saved successfully");
-    }
-    
-    let errors = download_errors.lock().unwrap();
-    if !errors.is_empty() {
-        let error_count = errors.len();
-        eprintln!("\n{} errors occurred during download:", error_count);
-        
-        if verbose {
-            for (chunk_id, error) in errors.iter() {
-                eprintln!("Chunk {}: {}", chunk_id, error);
-            }
-        } else {
-            eprintln!("Use --verbose for detailed error information");
+    match &plan.size {
+        Ok(size) => status!("Size: {} bytes ({:.2} KiB)", size, *size as f32 / 1024.0),
+        Err(e) => status!("Size: unknown, chunks will be requested until one comes back empty ({})", e),
+    }
+    let adaptive = plan.adaptive_chunks
+        .map_or(String::new(), |(min, max)| format!(", adapting between {} and {} bytes", min, max));
+    match (plan.chunks, &plan.last_chunk) {
+        (Some(chunks), Some(last)) => status!("Chunks: {} of {} bytes{}; first bytes {}-{}, last bytes {}-{}",
+                                              chunks, plan.chunk_size, adaptive, plan.first_chunk.start,
+                                              plan.first_chunk.end, last.start, last.end),
+        (Some(_), None) => status!("Chunks: none, the file is empty"),
+        (None, _) => status!("Chunks: {} bytes each{}, first bytes {}-{}, open-ended", plan.chunk_size, adaptive,
+                             plan.first_chunk.start, plan.first_chunk.end),
+    }
+    match plan.thread_range {
+        Some((min, max)) => status!("Threads: {}, adapting between {} and {}", plan.threads, min, max),
+        None => status!("Threads: {}", plan.threads),
+    }
+    let budget = if plan.retry_budget_grows {
+        format!("{} plus one per chunk received", plan.retry_budget)
+    } else {
+        plan.retry_budget.to_string()
+    };
+    let fail_fast = if plan.fail_fast { ", stopping at the first chunk out of retries" } else { "" };
+    status!("Retries: {} per chunk, {} more for connection failures, {} in total{}", plan.retries,
+            plan.connect_retries, budget, fail_fast);
+    if !plan.fail_fast && plan.repair_rounds > 0 {
+        status!("Repair: up to {} more rounds over ranges still missing", plan.repair_rounds);
+    }
+    status!("Output: {}", output);
+}
+
+/// `merge`: checks that the chunk files saved with `--chunks-dir` fit together,
+/// then copies them into the output in order and hashes them on the way.
+/// Missing ranges are listed with the command that fetches them.
+fn run_merge(args: &Args, merge: &MergeArgs) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let dir = Path::new(&merge.dir);
+    let output = merge.output.as_str();
+    let hash_algo = merge.checksum.or(merge.hash_algo).unwrap_or(args.checksum.unwrap_or(args.hash_algo));
+    let verify_hash = merge.verify.as_deref();
+    if let Some(expected) = verify_hash {
+        hash_algo.check_digest(expected).map_err(Exit::usage)?;
+    }
+    refuse_existing_output(output, merge.force).map_err(Exit::usage)?;
+    let manifest = match &merge.manifest {
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(dir.join("manifest.json")).filter(|path| path.exists()),
+    };
+
+    let chunks = SavedChunks::open(dir, manifest.as_deref())?;
+    if !chunks.missing.is_empty() {
+        for range in &chunks.missing {
+            diag!("Missing bytes {}-{} ({} bytes)", range.start, range.end, range.len());
+        }
+        let server = chunks.server.as_deref().unwrap_or("HOST:PORT");
+        diag!("Fetch them with: {} --host {} --chunks-dir {}", env!("CARGO_PKG_NAME"), server, dir.display());
+        diag!("then run the merge again");
+        return Err(DownloadError::Incomplete { missing_ranges: chunks.missing }.into());
+    }
+    if chunks.is_empty() {
+        return Err(Exit::usage(format!("No chunk files in '{}'", dir.display())).into());
+    }
+
+    prepare_output(output)?;
+    let temp = TempOutput::new(output);
+    let mut file = BufWriter::new(File::create(&temp.path)?);
+    let (bytes, hash) = chunks.write_to(&mut file, hash_algo)?;
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    status!("Merged {} chunks, {} bytes", chunks.len(), bytes);
+    if hash_algo.is_cryptographic() {
+        status!("{} hash: {}", hash_algo.label(), hash);
+    } else if hash_algo != HashAlgo::None {
+        status!("{} checksum (not a cryptographic hash): {}", hash_algo.label(), hash);
+    }
+    if let Some(expected) = verify_hash {
+        if expected.to_lowercase() != hash {
+            diag!("Checksum verification: FAILED ✗");
+            diag!("Expected: {}", expected);
+            diag!("Actual:   {}", hash);
+            return Err(Exit::new(VERIFY_EXIT_CODE, "Checksum verification failed").into());
         }
+        status!("Checksum verification: PASSED ✓");
     }
-    
+    temp.persist()?;
+    status!("Output: {}", output);
     Ok(())
 }
 
-fn make_range_request_with_progress(
-    host: &str, 
-    port: u16, 
-    start: usize, 
-    end: usize,
-    progress: &Arc<Mutex<ProgressBar>>,
-) -> Result<(Vec<u8>, String), Box<dyn std::error::Error>> {
-
-    let mut stream = TcpStream::connect_timeout(
-        &format!("{}:{}", host, port).parse()?,
-        Duration::from_secs(3)
-    )?;
-    
-    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
-    stream.set_write_timeout(Some(Duration::from_secs(2)))?;
-    
-    let request = format!(
-        "GET / HTTP/1.1\r\n\
-         Host: {}:{}\r\n\
-         Range: bytes={}-{}\r\n\
-         Connection: close\r\n\
-         \r\n",
-        host, port, start, end
-    );
-    
-    stream.write_all(request.as_bytes())?;
-    
-    let mut response = Vec::with_capacity(end - start + 1024);
-    let mut buffer = [0u8; 8192];
-    let mut total_read = 0;
-    
-    loop {
-        match stream.read(&mut buffer) {
-            Ok(0) => break,
-            Ok(n) => {
-                response.extend_from_slice(&buffer[..n]);
-                total_read += n;
-                progress.lock().unwrap().set_position(total_read as u64);
-            },
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::WouldBlock || e.kind() == std::io::ErrorKind::TimedOut {
-                    if !response.is_empty() {
-                        break;
-                    }
-                }
-                return Err(Box::new(e));
+/// `cache purge`: removes every chunk kept in the cache directory.
+fn run_cache(args: &Args, cache: &CacheCommand) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let CacheCommand::Purge { dir } = cache;
+    let dir = dir.as_deref().or(args.cache_dir.as_deref())
+        .ok_or_else(|| Exit::usage("No cache directory; give one or set --cache-dir".to_string()))?;
+    let (chunks, bytes) = Downloader::builder().cache_dir(dir).build()?.purge_cache()
+        .map_err(|e| format!("Cannot purge the cache '{}': {}", dir, e))?;
+    status!("Removed {} cached chunks, {} bytes, from '{}'", chunks, bytes, dir);
+    Ok(())
+}
+
+/// Fails if `output` exists, unless `force` allows replacing it.
+fn refuse_existing_output(output: &str, force: bool) -> Result<(), String> {
+    if !force && Path::new(output).exists() {
+        return Err(format!("'{}' already exists; pass --force to overwrite it or choose another name", output));
+    }
+    Ok(())
+}
+
+/// Creates the directories `output` goes in, like `mkdir -p`, and makes sure
+/// a file can be created there, so that an unwritable path fails before the
+/// download instead of after it.
+fn prepare_output(output: &str) -> Result<(), Exit> {
+    let path = Path::new(output);
+    let fail = |e: std::io::Error| {
+        let resolved = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+        Exit::new(OUTPUT_EXIT_CODE, format!("Cannot write to '{}': {}", resolved.display(), e))
+    };
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir).map_err(fail)?;
+    }
+    // The temporary file is created next to the output.
+    let probe = TempOutput::new(output);
+    File::create(&probe.path).map_err(fail)?;
+    Ok(())
+}
+
+/// `<output>.tmp-<pid>`, next to the output so renaming it over the output
+/// is atomic. Dropping it without `persist` removes it and leaves the output
+/// as it was.
+struct TempOutput {
+    path: PathBuf,
+    output: PathBuf,
+    /// The state file of a part file that is kept for the next run.
+    state: Option<PathBuf>,
+    persisted: bool,
+}
+
+impl TempOutput {
+    fn new(output: &str) -> Self {
+        TempOutput {
+            path: PathBuf::from(format!("{}.tmp-{}", output, std::process::id())),
+            output: PathBuf::from(output),
+            state: None,
+            persisted: false,
+        }
+    }
+
+    /// `<output>.part`, which is not removed while its state file lists
+    /// what it holds, so the next run can resume it. The output lock keeps
+    /// two runs from writing it at once.
+    fn resumable(output: &str) -> Self {
+        TempOutput {
+            path: PathBuf::from(format!("{}.part", output)),
+            output: PathBuf::from(output),
+            state: Some(PathBuf::from(state_path(output))),
+            persisted: false,
+        }
+    }
+
+    /// Replaces the output with the temporary file.
+    fn persist(mut self) -> std::io::Result<()> {
+        match std::fs::rename(&self.path, &self.output) {
+            Ok(()) => {}
+            // The output can still be on another filesystem, e.g. a bind mount.
+            Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+                std::fs::copy(&self.path, &self.output)?;
+                std::fs::remove_file(&self.path)?;
             }
+            Err(e) => return Err(e),
+        }
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempOutput {
+    fn drop(&mut self) {
+        if !self.persisted && !self.state.as_ref().is_some_and(|state| state.exists()) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// `<output>.validators`, the ETag and Last-Modified date the output was
+/// downloaded with, as header lines.
+fn validators_path(output: &str) -> String {
+    format!("{}.validators", output)
+}
+
+fn save_validators(output: &str, summary: &Summary) {
+    let path = validators_path(output);
+    let mut text = String::new();
+    if let Some(etag) = &summary.etag {
+        text.push_str(&format!("ETag: {}\n", etag));
+    }
+    if let Some(date) = &summary.last_modified {
+        text.push_str(&format!("Last-Modified: {}\n", date));
+    }
+    // Validators of an older download would say the wrong version is current.
+    let result = if text.is_empty() { std::fs::remove_file(&path) } else { std::fs::write(&path, text) };
+    if let Some(e) = result.err().filter(|e| e.kind() != ErrorKind::NotFound) {
+        log::warn!("Cannot save the validators of '{}' to '{}': {}", output, path, e);
+    }
+}
+
+/// Whether `output`, `len` bytes long, is still what the server has: by the
+/// ETag or Last-Modified date it was saved with, else only by its size.
+fn output_up_to_date(downloader: &Downloader, output: &str, len: u64) -> bool {
+    let text = std::fs::read_to_string(validators_path(output)).unwrap_or_default();
+    let header = |name: &str| text.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.eq_ignore_ascii_case(name).then(|| value.trim())
+    });
+    match downloader.check_freshness(header("etag"), header("last-modified")) {
+        Ok(Freshness::Unchanged) => {
+            status!("'{}' is up to date", output);
+            true
+        }
+        Ok(Freshness::Unknown { size: Some(size) }) if size as u64 == len => {
+            status!("'{}' is up to date, judging by its size only: the server sends no ETag or Last-Modified date \
+                     to compare", output);
+            true
+        }
+        Ok(_) => false,
+        Err(e) => {
+            log::warn!("Cannot tell whether '{}' is up to date, downloading it again: {}", output, e);
+            false
+        }
+    }
+}
+
+fn checkpoint_log_path(output: &str) -> String {
+    format!("{}.crc", output)
+}
+
+/// Exit code for invalid options, config files or environment variables,
+/// the same one clap uses for its own errors.
+const USAGE_EXIT_CODE: i32 = 2;
+
+/// Exit code when the server could not be resolved or connected to.
+const CONNECT_EXIT_CODE: i32 = 3;
+
+/// Exit code when some byte ranges could not be downloaded.
+const INCOMPLETE_EXIT_CODE: i32 = 4;
+
+/// Exit code when the hash does not match `--verify`, `--verify-file` or
+/// `--verify-url`.
+const VERIFY_EXIT_CODE: i32 = 5;
+
+/// Exit code when the output cannot be written.
+const OUTPUT_EXIT_CODE: i32 = 6;
+
+/// Exit code after Ctrl+C, as a shell reports for SIGINT.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
+
+/// Exit code when the reader of `-o -` goes away, as a shell reports for SIGPIPE.
+const BROKEN_PIPE_EXIT_CODE: i32 = 141;
+
+/// Exit code used when another run holds the output lock (EX_TEMPFAIL).
+const LOCK_HELD_EXIT_CODE: i32 = 75;
+
+/// Exit code when the file is larger than `--max-size` (EX_DATAERR).
+const TOO_LARGE_EXIT_CODE: i32 = 65;
+
+/// What a run writes into `<output>.lock` so other runs can identify it.
+struct LockMetadata {
+    pid: u32,
+    run_id: String,
+    source: String,
+    hash: Option<String>,
+}
+
+impl LockMetadata {
+    fn for_current_run(host: &str, port: u16, path: &str, hash: Option<&str>) -> Self {
+        let pid = std::process::id();
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        LockMetadata {
+            pid,
+            run_id: format!("{:x}-{:x}", pid, nanos),
+            source: format!("{}{}", format_authority(host, port), path),
+            hash: hash.map(str::to_lowercase),
         }
     }
-    
-    if response.is_empty() {
-        return Ok((Vec::new(), String::new()));
+
+    fn parse(text: &str) -> Option<Self> {
+        let mut pid = None;
+        let mut run_id = None;
+        let mut source = None;
+        let mut hash = None;
+        for line in text.lines() {
+            match line.split_once('=') {
+                Some(("pid", value)) => pid = value.parse().ok(),
+                Some(("run_id", value)) => run_id = Some(value.to_string()),
+                Some(("source", value)) => source = Some(value.to_string()),
+                Some(("hash", value)) => hash = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Some(LockMetadata { pid: pid?, run_id: run_id?, source: source?, hash })
     }
-    
-    let mut headers_end = 0;
-    for i in 0..response.len().saturating_sub(3) {
-        if response[i] == b'\r' && response[i+1] == b'\n' && 
-           response[i+2] == b'\r' && response[i+3] == b'\n' {
-            headers_end = i + 4;
-            break;
+
+    fn render(&self) -> String {
+        let mut text = format!("pid={}\nrun_id={}\nsource={}\n", self.pid, self.run_id, self.source);
+        if let Some(hash) = &self.hash {
+            text.push_str(&format!("hash={}\n", hash));
         }
+        text
+    }
+
+    fn same_content(&self, other: &LockMetadata) -> bool {
+        self.source == other.source
+            && (self.hash.is_none() || other.hash.is_none() || self.hash == other.hash)
+    }
+}
+
+/// Advisory lock on the output path, released when dropped.
+struct OutputLock {
+    file: File,
+}
+
+impl Drop for OutputLock {
+    fn drop(&mut self) {
+        // The lock file is left in place: deleting it would let a waiting run
+        // lock an unlinked inode while a newcomer locks a fresh one.
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+enum LockOutcome {
+    Acquired(OutputLock),
+    Published,
+    /// Another run holds the lock; `None` when it never said which.
+    Held(Option<LockMetadata>),
+}
+
+/// Who holds a lock, as far as its lock file tells.
+fn lock_holder(holder: Option<&LockMetadata>) -> String {
+    match holder {
+        Some(holder) => format!("pid {}, run {}", holder.pid, holder.run_id),
+        None => "which has not written who it is".to_string(),
     }
-    
-    if headers_end == 0 {
-        return Ok((Vec::new(), String::new()));
+}
+
+/// How long a lock held without metadata is waited on when `--wait-for-lock`
+/// is not given, as its holder writes it right after locking.
+const LOCK_METADATA_GRACE: Duration = Duration::from_secs(1);
+
+fn lock_path(output: &str) -> String {
+    format!("{}.lock", output)
+}
+
+/// `<output>.state`, the chunks of `<output>.part` a killed run got on disk.
+fn state_path(output: &str) -> String {
+    format!("{}.state", output)
+}
+
+fn read_lock_metadata(file: &mut File) -> std::io::Result<Option<LockMetadata>> {
+    use std::io::Seek;
+    let mut text = String::new();
+    file.seek(std::io::SeekFrom::Start(0))?;
+    file.read_to_string(&mut text)?;
+    Ok(LockMetadata::parse(&text))
+}
+
+/// Takes the advisory lock next to `output`. If another run holds it and is
+/// fetching the same content, waits for it and checks the file it published:
+/// against the hash either run was given, or else with `up_to_date`, which
+/// gets the length of the file.
+fn acquire_output_lock(
+    output: &str,
+    metadata: &LockMetadata,
+    hash_algo: HashAlgo,
+    wait: Option<Duration>,
+    up_to_date: &dyn Fn(u64) -> bool,
+) -> Result<LockOutcome, Box<dyn std::error::Error + Send + Sync>> {
+    let path = lock_path(output);
+    let started = Instant::now();
+    // The run waited for, with the length and time of the output before it published.
+    let mut waited_for = None::<(LockMetadata, _)>;
+    let stamp = || std::fs::metadata(output).ok().map(|file| (file.len(), file.modified().ok()));
+
+    loop {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        match file.try_lock() {
+            Ok(()) => {
+                if let Some(previous) = read_lock_metadata(&mut file)? {
+                    if previous.pid != metadata.pid {
+                        log::info!("Breaking stale lock left by pid {} (run {})", previous.pid, previous.run_id);
+                    }
+                }
+                let lock = OutputLock { file };
+
+                // A run that failed leaves the output as it was.
+                let published = waited_for.filter(|(_, before)| stamp().is_some_and(|now| Some(now) != *before));
+                if let Some((holder, _)) = published {
+                    let len = std::fs::metadata(output)?.len();
+                    let verified = match metadata.hash.as_ref().or(holder.hash.as_ref()) {
+                        Some(expected) => hash_algo.digest_file(Path::new(output), 1)? == *expected,
+                        None => up_to_date(len),
+                    };
+                    if verified {
+                        return Ok(LockOutcome::Published);
+                    }
+                    log::info!("File published by the other run failed verification, downloading again");
+                }
+
+                let mut file = &lock.file;
+                file.set_len(0)?;
+                file.write_all(metadata.render().as_bytes())?;
+                file.flush()?;
+                return Ok(LockOutcome::Acquired(lock));
+            }
+            Err(TryLockError::WouldBlock) => {
+                let holder = match read_lock_metadata(&mut file)? {
+                    Some(holder) => holder,
+                    // The holder has not written its metadata yet, and may never.
+                    None if started.elapsed() >= wait.unwrap_or(LOCK_METADATA_GRACE) => {
+                        return Ok(LockOutcome::Held(None));
+                    }
+                    None => {
+                        thread::sleep(Duration::from_millis(50));
+                        continue;
+                    }
+                };
+
+                // The lock dies with its holder, so whoever holds it is still running, even if
+                // its pid is from another namespace or has been reused.
+                let timed_out = wait.is_some_and(|limit| started.elapsed() >= limit);
+                if holder.same_content(metadata) && !timed_out {
+                    if waited_for.is_none() {
+                        status!("Another run (pid {}) is downloading the same content, waiting for it", holder.pid);
+                        waited_for = Some((holder, stamp()));
+                    }
+                } else if wait.is_none() || timed_out {
+                    return Ok(LockOutcome::Held(Some(holder)));
+                }
+                thread::sleep(Duration::from_millis(200));
+            }
+            Err(TryLockError::Error(e)) => return Err(Box::new(e)),
+        }
     }
-    
-    let headers = String::from_utf8_lossy(&response[..headers_end]).to_string();
-    let body = response[headers_end..].to_vec();
-    
-    Ok((body, headers))
-}
\ No newline at end of file
+}
@@ -0,0 +1,38 @@
+//! Download manifests: several files fetched from the same server in one run.
+
+/// One file to fetch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Path on the server, starting with `/`.
+    pub path: String,
+    /// Where the file is saved.
+    pub output: String,
+    /// Expected digest in the run's hash algorithm, if any.
+    pub hash: Option<String>,
+}
+
+/// Parses a manifest with one `<path> <output> [hash]` entry per line.
+/// Blank lines and lines starting with `#` are skipped.
+pub fn parse_manifest(text: &str) -> Result<Vec<ManifestEntry>, String> {
+    let mut entries = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let (path, output, hash) = match fields.as_slice() {
+            [path, output] => (path, output, None),
+            [path, output, hash] => (path, output, Some(hash.to_lowercase())),
+            _ => return Err(format!("Manifest line {}: expected '<path> <output> [hash]'", number + 1)),
+        };
+        if !path.starts_with('/') {
+            return Err(format!("Manifest line {}: path '{}' must start with '/'", number + 1, path));
+        }
+        entries.push(ManifestEntry { path: path.to_string(), output: output.to_string(), hash });
+    }
+    if entries.is_empty() {
+        return Err("Manifest contains no entries".to_string());
+    }
+    Ok(entries)
+}
@@ -0,0 +1,504 @@
+//! HTTP/1.1 messages as bytes: building range requests and parsing response
+//! heads. Nothing here does I/O, so the parser can be fed any bytes, split
+//! anywhere.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Joins a host and port into `host:port`, bracketing IPv6 literals as
+/// `[::1]:8080`. The host may be given with or without the brackets.
+pub(crate) fn format_authority(host: &str, port: u16) -> String {
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Where a request says which bytes it wants.
+///
+/// ```
+/// use buggy_client::{Downloader, RangeStyle};
+///
+/// let style = RangeStyle::Query { offset: "from".to_string(), length: "count".to_string() };
+/// let builder = Downloader::builder().range_style(style);
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RangeStyle {
+    /// A `Range: bytes=` header, answered with 206 Partial Content.
+    #[default]
+    Header,
+    /// Query parameters with these names for the offset and the length,
+    /// like `?offset=0&length=65536`, for servers that ignore the Range
+    /// header. The answer is a 200 with just those bytes, and an empty or
+    /// short body past the end of the file.
+    Query {
+        /// Name of the parameter for the first byte.
+        offset: String,
+        /// Name of the parameter for the number of bytes.
+        length: String,
+    },
+}
+
+impl RangeStyle {
+    /// `header`, or `query` with the parameters `offset` and `length`.
+    ///
+    /// ```
+    /// use buggy_client::RangeStyle;
+    ///
+    /// assert_eq!(RangeStyle::parse("header"), Ok(RangeStyle::Header));
+    /// assert!(matches!(RangeStyle::parse("query"), Ok(RangeStyle::Query { .. })));
+    /// ```
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "header" => Ok(RangeStyle::Header),
+            "query" => Ok(RangeStyle::Query { offset: "offset".to_string(), length: "length".to_string() }),
+            _ => Err(format!("Unknown range style '{}': expected header or query", name)),
+        }
+    }
+}
+
+/// Whether `name` can be a header name: a non-empty token of RFC 9110.
+fn is_token(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+/// Pre-rendered request text, so only the range is formatted per chunk.
+pub(crate) struct RequestTemplate {
+    path: String,
+    /// The names of the offset and length parameters when the range goes in
+    /// the query instead of a Range header.
+    query: Option<(String, String)>,
+    /// `http://host:port` before the path when the requests go through a
+    /// proxy, which needs the whole URL; empty otherwise.
+    origin: String,
+    authority: String,
+    /// `HTTP/1.1`, or `HTTP/1.0` for servers that only speak that.
+    version: &'static str,
+    head: String,
+    tail: String,
+    /// The Connection header line for a one-off request, and for one on a
+    /// pooled connection; the same when the user set it.
+    close: String,
+    keep_alive: String,
+}
+
+impl RequestTemplate {
+    /// With `http_1_0` the requests are HTTP/1.0 ones, without the Host and
+    /// Connection headers unless given in `extra_headers`, as a 1.0 server
+    /// closes the connection after every response anyway.
+    pub(crate) fn new(host: &str, port: u16, path: &str, user_agent: &str, compress: bool,
+                      extra_headers: &[(String, String)], http_1_0: bool) -> Result<Self, String> {
+        if !path.starts_with('/') || path.contains(char::is_whitespace) {
+            return Err(format!("Invalid path '{}': must start with '/' and contain no whitespace", path));
+        }
+        if user_agent.contains('\r') || user_agent.contains('\n') {
+            return Err("Invalid User-Agent: must not contain CR or LF".to_string());
+        }
+        let mut host_line = match http_1_0 {
+            true => String::new(),
+            false => format!("Host: {}\r\n", format_authority(host, port)),
+        };
+        let mut user_agent_line = format!("User-Agent: {}\r\n", user_agent);
+        let mut accept_encoding_line = if compress {
+            "Accept-Encoding: gzip, deflate\r\n".to_string()
+        } else {
+            "Accept-Encoding: identity\r\n".to_string()
+        };
+        let mut connection_line = None;
+        let mut tail = String::new();
+
+        for (name, value) in extra_headers {
+            if !is_token(name) {
+                return Err(format!("Invalid header name '{}': must be a token, without spaces, CR, LF or ':'",
+                                   name.escape_default()));
+            }
+            if value.contains(['\r', '\n', '\0']) {
+                return Err(format!("Invalid value for header '{}': must not contain CR, LF or NUL", name));
+            }
+            let line = format!("{}: {}\r\n", name, value);
+            if name.eq_ignore_ascii_case("range") {
+                return Err("The Range header is set by the client and cannot be overridden".to_string());
+            } else if name.eq_ignore_ascii_case("host") {
+                log::warn!("Overriding default Host header with '{}'", value);
+                host_line = line;
+            } else if name.eq_ignore_ascii_case("connection") {
+                log::warn!("Overriding default Connection header with '{}'", value);
+                connection_line = Some(line);
+            } else if name.eq_ignore_ascii_case("user-agent") {
+                user_agent_line = line;
+            } else if name.eq_ignore_ascii_case("accept-encoding") {
+                accept_encoding_line = line;
+            } else {
+                tail.push_str(&line);
+            }
+        }
+
+        tail.insert_str(0, &accept_encoding_line);
+        tail.insert_str(0, &user_agent_line);
+
+        let version = if http_1_0 { "HTTP/1.0" } else { "HTTP/1.1" };
+        let close = match http_1_0 {
+            true => connection_line.clone().unwrap_or_default(),
+            false => connection_line.clone().unwrap_or_else(|| "Connection: close\r\n".to_string()),
+        };
+        Ok(RequestTemplate {
+            path: path.to_string(),
+            query: None,
+            origin: String::new(),
+            authority: format_authority(host, port),
+            version,
+            head: host_line,
+            tail,
+            keep_alive: connection_line.unwrap_or_else(|| "Connection: keep-alive\r\n".to_string()),
+            close,
+        })
+    }
+
+    /// Sends the requests through a proxy: the request line carries the
+    /// whole URL, and the proxy's `authorization` goes along if it has any.
+    pub(crate) fn via_proxy(mut self, authorization: Option<String>) -> Self {
+        self.origin = format!("http://{}", self.authority);
+        if let Some(authorization) = authorization {
+            self.tail.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+        }
+        self
+    }
+
+    /// Asks for the range in the query parameters `offset` and `length`
+    /// instead of a Range header.
+    pub(crate) fn ranges_in_query(mut self, offset: &str, length: &str) -> Result<Self, String> {
+        for name in [offset, length] {
+            if name.is_empty() || name.contains(|c: char| c.is_whitespace() || "&=?#".contains(c)) {
+                return Err(format!("Invalid query parameter name '{}': must not be empty nor contain whitespace, \
+                                    '&', '=', '?' or '#'", name));
+            }
+        }
+        if offset == length {
+            return Err(format!("The offset and the length cannot both go in the query parameter '{}'", offset));
+        }
+        self.query = Some((offset.to_string(), length.to_string()));
+        Ok(self)
+    }
+
+    /// Whether the range goes in the query, so a 200 holds just the bytes
+    /// asked for rather than the whole resource.
+    pub(crate) fn query_ranges(&self) -> bool {
+        self.query.is_some()
+    }
+
+    /// The request for bytes `start` to `end`, sent only if the resource
+    /// still matches `if_range` when given.
+    pub(crate) fn build(&self, start: usize, end: usize, if_range: Option<&str>) -> String {
+        let if_range: Vec<_> = if_range.map(|validator| ("If-Range", validator)).into_iter().collect();
+        self.build_with(start, end, &if_range)
+    }
+
+    /// One request for every `(start, end)` in `ranges`, answered with a
+    /// multipart/byteranges body when there is more than one.
+    pub(crate) fn build_ranges(&self, ranges: &[(usize, usize)], if_range: Option<&str>, keep_alive: bool)
+        -> String {
+        let if_range: Vec<_> = if_range.map(|validator| ("If-Range", validator)).into_iter().collect();
+        self.render("GET", ranges, &if_range, if keep_alive { &self.keep_alive } else { &self.close })
+    }
+
+    /// The request for bytes `start` to `end` with `headers` added.
+    pub(crate) fn build_with(&self, start: usize, end: usize, headers: &[(&str, &str)]) -> String {
+        self.render("GET", &[(start, end)], headers, &self.close)
+    }
+
+    /// A HEAD request for bytes `start` to `end`: the head a GET would get,
+    /// without the body.
+    pub(crate) fn build_head(&self, start: usize, end: usize) -> String {
+        self.render("HEAD", &[(start, end)], &[], &self.close)
+    }
+
+    /// Only the first of `ranges` fits in the query; with ranges in the
+    /// query there is never more than one per request.
+    fn render(&self, method: &str, ranges: &[(usize, usize)], headers: &[(&str, &str)], connection: &str) -> String {
+        let headers: String = headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+        let (target, range_line) = match &self.query {
+            Some((offset, length)) => {
+                let (start, end) = ranges[0];
+                let separator = if self.path.contains('?') { '&' } else { '?' };
+                (format!("{}{}{}={}&{}={}", self.path, separator, offset, start, length, end - start), String::new())
+            }
+            None => {
+                let ranges: Vec<_> = ranges.iter().map(|(start, end)| format!("{}-{}", start, end)).collect();
+                (self.path.clone(), format!("Range: bytes={}\r\n", ranges.join(",")))
+            }
+        };
+        format!("{} {}{} {}\r\n{}{}{}{}{}\r\n", method, self.origin, target, self.version, self.head, range_line,
+                headers, self.tail, connection)
+    }
+
+    /// Plain GET of another path on the same server, without a Range header.
+    pub(crate) fn build_get(&self, path: &str) -> String {
+        format!("GET {}{} {}\r\n{}{}{}\r\n", self.origin, path, self.version, self.head, self.tail, self.close)
+    }
+}
+
+/// The parts of a multipart body, each with its own headers and data.
+pub(crate) type Parts = Vec<(Headers, Vec<u8>)>;
+
+/// The parts of a `multipart/byteranges` body, each with its own headers,
+/// such as the Content-Range saying where it belongs. `None` when
+/// `content_type` is not that.
+pub(crate) fn split_byteranges(content_type: &str, body: &[u8]) -> Option<Result<Parts, String>> {
+    let mut params = content_type.split(';');
+    if !params.next()?.trim().eq_ignore_ascii_case("multipart/byteranges") {
+        return None;
+    }
+    let boundary = params.filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"'))
+        .filter(|boundary| !boundary.is_empty());
+    Some(match boundary {
+        Some(boundary) => split_parts(boundary, body),
+        None => Err("multipart/byteranges response without a boundary".to_string()),
+    })
+}
+
+fn split_parts(boundary: &str, body: &[u8]) -> Result<Parts, String> {
+    let delimiter = format!("\r\n--{}", boundary).into_bytes();
+    // The first delimiter usually starts the body, without a line break before it.
+    let mut rest = match body.strip_prefix(&delimiter[2..]) {
+        Some(rest) => rest,
+        None => {
+            let start = find(body, &delimiter).ok_or("multipart/byteranges body without a boundary line")?;
+            &body[start + delimiter.len()..]
+        }
+    };
+    let mut parts = Vec::new();
+    // After each delimiter comes either `--` for the end or a new part.
+    while !rest.starts_with(b"--") {
+        let end = find(rest, &delimiter).ok_or("multipart/byteranges body ends inside a part")?;
+        let part = &rest[..end];
+        rest = &rest[end + delimiter.len()..];
+        // The rest of the boundary line may hold padding.
+        let mut position = part.iter().position(|&byte| byte == b'\n').ok_or("malformed multipart boundary line")? + 1;
+        let mut headers = Headers::new();
+        loop {
+            let newline = part[position..].iter().position(|&byte| byte == b'\n')
+                .ok_or("multipart part ends inside its headers")? + position;
+            let line = String::from_utf8_lossy(&part[position..newline]);
+            position = newline + 1;
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':')
+                .ok_or_else(|| format!("Malformed multipart header line '{}'", line.escape_default()))?;
+            headers.append(name, value);
+        }
+        parts.push((headers, part[position..].to_vec()));
+    }
+    Ok(parts)
+}
+
+/// Where `needle` first occurs in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Status and headers of a response, and where its body starts.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Response {
+    /// Status code from the status line.
+    pub(crate) status: u16,
+    /// Minor version from the status line: 0 for HTTP/1.0, 1 for HTTP/1.1.
+    pub(crate) version: u8,
+    /// The header fields.
+    pub(crate) headers: Headers,
+    /// Offset of the first body byte in the bytes the head was parsed from.
+    pub(crate) body_offset: usize,
+}
+
+impl Response {
+    /// The status and headers on one line, for logging.
+    pub(crate) fn describe(&self) -> String {
+        let headers: Vec<_> = self.headers.iter().map(|(name, value)| format!("{}: {}", name, value)).collect();
+        format!("status {}, {}", self.status, headers.join(", "))
+    }
+}
+
+/// Header values by case-insensitive name, keeping every value of a repeated
+/// header in the order received.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Headers(BTreeMap<String, Vec<String>>);
+
+impl Headers {
+    /// No headers.
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a value for `name` after any it already has. Whitespace around
+    /// the value is dropped.
+    pub(crate) fn append(&mut self, name: &str, value: &str) {
+        self.0.entry(name.to_ascii_lowercase()).or_default().push(value.trim_matches([' ', '\t']).to_string());
+    }
+
+    /// The first value of `name`.
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.get_all(name).first().map(String::as_str)
+    }
+
+    /// Every value of `name`, in the order received.
+    pub(crate) fn get_all(&self, name: &str) -> &[String] {
+        self.0.get(&name.to_ascii_lowercase()).map_or(&[], Vec::as_slice)
+    }
+
+    /// Every header as a lowercase name and a value, sorted by name.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().flat_map(|(name, values)| values.iter().map(move |value| (name.as_str(), value.as_str())))
+    }
+
+    /// The Content-Length, unless it is missing, malformed or given more than
+    /// once with different values.
+    pub(crate) fn content_length(&self) -> Option<u64> {
+        let mut lengths = self.get_all("content-length").iter().flat_map(|value| value.split(','))
+            .map(|length| length.trim().parse::<u64>().ok());
+        let first = lengths.next()??;
+        lengths.all(|length| length == Some(first)).then_some(first)
+    }
+
+    /// The first and last byte and the total size from a
+    /// `bytes <first>-<last>/<total>` Content-Range, where the total may be
+    /// `*` for unknown. A range that ends before it starts is `None`.
+    pub(crate) fn content_range(&self) -> Option<(u64, u64, Option<u64>)> {
+        let (range, total) = self.get("content-range")?.strip_prefix("bytes ")?.split_once('/')?;
+        let (first, last) = range.split_once('-')?;
+        let (first, last) = (first.trim().parse::<u64>().ok()?, last.trim().parse::<u64>().ok()?);
+        let total = match total.trim() {
+            "*" => None,
+            total => Some(total.parse().ok()?),
+        };
+        (first <= last).then_some((first, last, total))
+    }
+
+    /// What tells this version of the resource from others in `If-Range`:
+    /// the ETag, unless it is weak, else the Last-Modified date.
+    pub(crate) fn validator(&self) -> Option<&str> {
+        self.get("etag").filter(|etag| !etag.starts_with("W/")).or_else(|| self.get("last-modified"))
+    }
+
+    /// How long a `Retry-After` header asks to wait from `now`, given in
+    /// seconds or as an HTTP date. A date already past is no wait at all.
+    pub(crate) fn retry_after(&self, now: SystemTime) -> Option<Duration> {
+        let value = self.get("retry-after")?;
+        match value.parse::<u64>() {
+            Ok(seconds) => Some(Duration::from_secs(seconds)),
+            Err(_) => Some(parse_http_date(value)?.duration_since(now).unwrap_or_default()),
+        }
+    }
+
+    /// The idle timeout in seconds and the requests still allowed that a
+    /// `Keep-Alive: timeout=5, max=100` header announces for the connection,
+    /// each `None` when not given.
+    pub(crate) fn keep_alive(&self) -> (Option<u64>, Option<usize>) {
+        let (mut timeout, mut max) = (None, None);
+        let params = self.get_all("keep-alive").iter().flat_map(|value| value.split(','));
+        for (name, value) in params.filter_map(|param| param.split_once('=')) {
+            let value = value.trim().trim_matches('"');
+            match name.trim().to_ascii_lowercase().as_str() {
+                "timeout" => timeout = value.parse().ok().or(timeout),
+                "max" => max = value.parse().ok().or(max),
+                _ => {}
+            }
+        }
+        (timeout, max)
+    }
+
+    /// Appends a folded continuation line to the last value of `name`.
+    fn continue_last(&mut self, name: &str, text: &str) {
+        if let Some(value) = self.0.get_mut(name).and_then(|values| values.last_mut()) {
+            value.push(' ');
+            value.push_str(text.trim_matches([' ', '\t']));
+        }
+    }
+}
+
+/// Longest response head accepted.
+pub(crate) const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Parses the response head at the start of `bytes`, or returns `Ok(None)`
+/// while the blank line that ends it hasn't arrived, so it can be called
+/// again as more bytes come in. Lines may end in CRLF or a lone LF, and a
+/// header folded onto indented continuation lines is joined with spaces.
+pub(crate) fn parse_response_head(bytes: &[u8]) -> Result<Option<Response>, String> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    let body_offset = loop {
+        let newline = match bytes[start..].iter().position(|&byte| byte == b'\n') {
+            Some(index) => start + index,
+            None if bytes.len() > MAX_HEAD_SIZE => break bytes.len(),
+            None => return Ok(None),
+        };
+        let line = &bytes[start..newline];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        start = newline + 1;
+        if line.is_empty() {
+            break start;
+        }
+        lines.push(line);
+    };
+    if body_offset > MAX_HEAD_SIZE {
+        return Err(format!("Response head is longer than {} bytes", MAX_HEAD_SIZE));
+    }
+
+    let mut lines = lines.into_iter().map(String::from_utf8_lossy);
+    let status_line = lines.next().ok_or("Response has no status line")?;
+    let (version, status) = parse_status_line(&status_line)
+        .ok_or_else(|| format!("Malformed status line '{}'", status_line.escape_default()))?;
+
+    let mut headers = Headers::new();
+    let mut last = None::<String>;
+    for line in lines {
+        if line.starts_with([' ', '\t']) {
+            let name = last.as_ref().ok_or("Response head starts with a continuation line")?;
+            headers.continue_last(name, &line);
+            continue;
+        }
+        let (name, value) = line.split_once(':')
+            .filter(|(name, _)| !name.is_empty() && name.bytes().all(|byte| byte.is_ascii_graphic()))
+            .ok_or_else(|| format!("Malformed header line '{}'", line.escape_default()))?;
+        headers.append(name, value);
+        last = Some(name.to_ascii_lowercase());
+    }
+    Ok(Some(Response { status, version, headers, body_offset }))
+}
+
+/// The minor version and the code from a `HTTP/1.x <code> [reason]` status
+/// line.
+/// Reads an HTTP date in the preferred `Sun, 06 Nov 1994 08:49:37 GMT` form.
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let mut fields = value.split_once(',')?.1.split_whitespace();
+    let day: u64 = fields.next()?.parse().ok()?;
+    let month = fields.next()?;
+    let month = MONTHS.iter().position(|&name| name == month)? as u64 + 1;
+    let year: u64 = fields.next()?.parse().ok()?;
+    let time: Vec<u64> = fields.next()?.split(':').map(|field| field.parse().ok()).collect::<Option<_>>()?;
+    if fields.next() != Some("GMT") || fields.next().is_some() || time.len() != 3 || !(1..=31).contains(&day)
+        || year < 1970 || time[0] > 23 || time[1] > 59 || time[2] > 60 {
+        return None;
+    }
+    // Days since 1970-01-01 of a date in the proleptic Gregorian calendar,
+    // counting years from March so the leap day comes last.
+    let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let days = 365 * year + year / 4 - year / 100 + year / 400 + (153 * month + 2) / 5 + day - 1 - 719_468;
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86_400 + time[0] * 3_600 + time[1] * 60 + time[2]))
+}
+
+fn parse_status_line(line: &str) -> Option<(u8, u16)> {
+    let mut parts = line.splitn(3, ' ');
+    let version = parts.next()?.strip_prefix("HTTP/1.")?;
+    let code = parts.next()?;
+    if code.len() != 3 || !code.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    Some((version.parse().ok()?, code.parse().ok()?))
+}
@@ -0,0 +1,279 @@
+//! Spreading the chunks of one download over several servers that host the
+//! same resource, and keeping count of how each of them does.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use crate::downloader::ResourceChanged;
+use crate::endpoint::Endpoint;
+use crate::message::{format_authority, RequestTemplate, Response};
+
+/// How one mirror did over a download.
+///
+/// ```no_run
+/// use buggy_client::Downloader;
+///
+/// let downloader = Downloader::builder().mirror("10.0.0.2", 8080).build()?;
+/// for mirror in downloader.download(&mut Vec::new())?.mirrors {
+///     println!("{}: {} chunks, {} failures", mirror.authority(), mirror.chunks, mirror.failures);
+/// }
+/// # Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+/// ```
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct MirrorStats {
+    /// Host name or address of the mirror.
+    pub host: String,
+    /// Port of the mirror.
+    pub port: u16,
+    /// Bytes kept from this mirror's responses.
+    pub bytes: usize,
+    /// Responses kept from this mirror.
+    pub chunks: usize,
+    /// Requests to this mirror that failed.
+    pub failures: usize,
+    /// Why the mirror stopped being used, if it did.
+    pub blacklisted: Option<String>,
+}
+
+impl MirrorStats {
+    /// The mirror as `host:port`.
+    ///
+    /// ```no_run
+    /// use buggy_client::Downloader;
+    ///
+    /// let summary = Downloader::builder().host("::1").build()?.download(&mut Vec::new())?;
+    /// assert_eq!(summary.mirrors[0].authority(), "[::1]:8080");
+    /// # Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+    /// ```
+    pub fn authority(&self) -> String {
+        format_authority(&self.host, self.port)
+    }
+}
+
+/// Where a worker sends the requests for one mirror.
+pub(crate) struct Mirror {
+    pub(crate) endpoint: Endpoint,
+    pub(crate) template: RequestTemplate,
+    /// Minor HTTP version of the first response from the server.
+    version: OnceLock<u8>,
+    /// Cleared once the server answers a request for several ranges with
+    /// anything but a multipart/byteranges response.
+    multi_range: AtomicBool,
+}
+
+impl Mirror {
+    pub(crate) fn new(endpoint: Endpoint, template: RequestTemplate) -> Self {
+        Mirror { endpoint, template, version: OnceLock::new(), multi_range: AtomicBool::new(true) }
+    }
+
+    /// Whether a request may ask the server for several ranges at once.
+    pub(crate) fn multi_range(&self) -> bool {
+        self.multi_range.load(Ordering::SeqCst)
+    }
+
+    /// Falls back to one range per request, as the server ignored a request
+    /// for several.
+    pub(crate) fn refused_multi_range(&self) {
+        if self.multi_range.swap(false, Ordering::SeqCst) {
+            log::info!("{} does not answer requests for several ranges, asking for one range at a time",
+                       self.endpoint.authority());
+        }
+    }
+
+    /// Whether to ask the server to keep connections open: not once it
+    /// turned out to speak HTTP/1.0.
+    pub(crate) fn keep_alive(&self) -> bool {
+        self.version.get() != Some(&0)
+    }
+
+    /// Notes the version of a response from the server, logging the first.
+    pub(crate) fn answered(&self, head: &Response) {
+        if self.version.set(head.version).is_ok() {
+            log::debug!("{} answers with HTTP/1.{}", self.endpoint.authority(), head.version);
+            if head.version == 0 {
+                log::debug!("Not keeping connections to {} alive, relying on the close to end bodies \
+                             without a Content-Length", self.endpoint.authority());
+            }
+        }
+    }
+}
+
+/// Weight of the newest response time in a mirror's running average.
+const LATENCY_WEIGHT: f64 = 0.3;
+
+/// Picks the mirror for every request. A mirror is preferred while it answers
+/// quickly and has not failed lately; one that serves something else than the
+/// others is dropped, as long as another one is left.
+pub(crate) struct MirrorPool {
+    stats: Vec<MirrorStats>,
+    /// Running average of response times in seconds, once one arrived.
+    latency: Vec<Option<f64>>,
+    /// Failures since the mirror last delivered, halved by each delivery.
+    recent_failures: Vec<u32>,
+    in_flight: Vec<usize>,
+    /// The resource size each mirror announced in a Content-Range, or the
+    /// size probe found on the host.
+    totals: Vec<Option<u64>>,
+    /// The ETag or Last-Modified date each mirror first answered with.
+    validators: Vec<Option<String>>,
+}
+
+impl MirrorPool {
+    pub(crate) fn new(mirrors: &[(String, u16)]) -> Self {
+        MirrorPool {
+            stats: mirrors.iter().map(|(host, port)| MirrorStats {
+                host: host.clone(),
+                port: *port,
+                bytes: 0,
+                chunks: 0,
+                failures: 0,
+                blacklisted: None,
+            }).collect(),
+            latency: vec![None; mirrors.len()],
+            recent_failures: vec![0; mirrors.len()],
+            in_flight: vec![0; mirrors.len()],
+            totals: vec![None; mirrors.len()],
+            validators: vec![None; mirrors.len()],
+        }
+    }
+
+    /// The mirror for the next request: the one with the shortest expected
+    /// wait, its response time times the requests already queued there,
+    /// doubled for every recent failure. Mirrors not measured yet count as
+    /// average. A retry goes elsewhere than `failed_on` if it can.
+    pub(crate) fn pick(&mut self, failed_on: Option<usize>) -> usize {
+        let usable: Vec<_> = (0..self.stats.len()).filter(|&index| self.stats[index].blacklisted.is_none()).collect();
+        let candidates: Vec<_> = match usable.len() {
+            0 | 1 => usable,
+            _ => usable.into_iter().filter(|&index| Some(index) != failed_on).collect(),
+        };
+        let measured: Vec<f64> = self.latency.iter().flatten().copied().collect();
+        let average = match measured.len() {
+            0 => 1.0,
+            count => measured.iter().sum::<f64>() / count as f64,
+        };
+        let cost = |index: usize| {
+            self.latency[index].unwrap_or(average) * (self.in_flight[index] + 1) as f64
+                * 2_f64.powi(self.recent_failures[index].min(16) as i32)
+        };
+        let best = candidates.into_iter()
+            .min_by(|&a, &b| cost(a).total_cmp(&cost(b)))
+            .unwrap_or(0);
+        self.in_flight[best] += 1;
+        best
+    }
+
+    /// A request handed out by [`MirrorPool::pick`] came back.
+    pub(crate) fn returned(&mut self, mirror: usize) {
+        self.in_flight[mirror] = self.in_flight[mirror].saturating_sub(1);
+    }
+
+    pub(crate) fn delivered(&mut self, mirror: usize, bytes: usize, seconds: f64) {
+        let stats = &mut self.stats[mirror];
+        stats.bytes += bytes;
+        stats.chunks += 1;
+        self.latency[mirror] = Some(match self.latency[mirror] {
+            Some(latency) => latency + (seconds - latency) * LATENCY_WEIGHT,
+            None => seconds,
+        });
+        self.recent_failures[mirror] /= 2;
+    }
+
+    pub(crate) fn failed(&mut self, mirror: usize) {
+        self.stats[mirror].failures += 1;
+        self.recent_failures[mirror] += 1;
+    }
+
+    /// Stops using `mirror` for the rest of the download, unless it is the
+    /// last one left.
+    pub(crate) fn blacklist(&mut self, mirror: usize, reason: String) {
+        let others = self.stats.iter().enumerate()
+            .any(|(index, stats)| index != mirror && stats.blacklisted.is_none());
+        let stats = &mut self.stats[mirror];
+        if stats.blacklisted.is_some() {
+            return;
+        }
+        if !others {
+            log::warn!("Mirror {} {}, but no other mirror is left", stats.authority(), reason);
+            return;
+        }
+        log::warn!("Mirror {} {}, not using it any more", stats.authority(), reason);
+        stats.blacklisted = Some(reason);
+    }
+
+    /// Records the resource size `mirror` announced. Mirrors that disagree
+    /// with most others, or on a tie with the first mirror listed, are
+    /// blacklisted.
+    pub(crate) fn check_total(&mut self, mirror: usize, total: u64) {
+        if self.totals[mirror].replace(total) == Some(total) {
+            return;
+        }
+        let votes = |total: u64| self.totals.iter().filter(|&&other| other == Some(total)).count();
+        // Going through the mirrors in order makes the first one win a tie.
+        let mut agreed = None::<(u64, usize)>;
+        for total in self.totals.iter().flatten().copied() {
+            if agreed.is_none_or(|(_, most)| votes(total) > most) {
+                agreed = Some((total, votes(total)));
+            }
+        }
+        let agreed = match agreed {
+            Some((agreed, _)) => agreed,
+            None => return,
+        };
+        for index in 0..self.totals.len() {
+            if let Some(other) = self.totals[index].filter(|&other| other != agreed) {
+                self.blacklist(index, format!("reports a size of {} bytes where the others report {}", other, agreed));
+            }
+        }
+    }
+
+    /// Compares the resource size in a Content-Range from `mirror` with the
+    /// one it announced before. The resource changed if they differ.
+    pub(crate) fn check_size(&self, mirror: usize, total: u64) -> Result<(), ResourceChanged> {
+        match self.totals[mirror] {
+            Some(known) if known != total => Err(ResourceChanged {
+                server: self.stats[mirror].authority(),
+                before: format!("{} bytes", known),
+                after: Some(format!("{} bytes", total)),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// What requests to `mirror` send as `If-Range`.
+    pub(crate) fn validator(&self, mirror: usize) -> Option<&str> {
+        self.validators[mirror].as_deref()
+    }
+
+    /// Compares the validator in a response from `mirror` with the first one
+    /// it sent. The resource changed if it sends another one, or answers an
+    /// `If-Range` request with the whole resource and none. Returns whether
+    /// the response carried a validator at all.
+    pub(crate) fn check_validator(&mut self, mirror: usize, head: &Response) -> Result<bool, ResourceChanged> {
+        let found = head.headers.validator();
+        let Some(known) = self.validators[mirror].as_deref() else {
+            self.validators[mirror] = found.map(str::to_string);
+            return Ok(found.is_some());
+        };
+        if found == Some(known) || (found.is_none() && head.status != 200) {
+            return Ok(found.is_some());
+        }
+        Err(ResourceChanged {
+            server: self.stats[mirror].authority(),
+            before: known.to_string(),
+            after: found.map(str::to_string),
+        })
+    }
+
+    /// Forgets every validator and size, for a download that starts over on
+    /// a new version of the resource.
+    pub(crate) fn reset_versions(&mut self) {
+        self.validators.iter_mut().for_each(|validator| *validator = None);
+        self.totals.iter_mut().for_each(|total| *total = None);
+    }
+
+    pub(crate) fn into_stats(self) -> Vec<MirrorStats> {
+        self.stats
+    }
+}
@@ -0,0 +1,112 @@
+//! Writing the output through a memory map.
+
+use std::fs::File;
+use std::io;
+
+use memmap2::{MmapMut, MmapOptions};
+
+use crate::downloader::{SeekSink, Sink};
+
+/// Writes into a shared memory map of `file` once the size is known, so each
+/// chunk is copied into the page cache as it arrives instead of being held
+/// until the end, and the hash is read back from the map. Before the size is
+/// known, or where the file cannot be mapped, it writes to the file like the
+/// `File` sink.
+///
+/// Dropping the sink before [`Sink::finish`] truncates the file, so a failed
+/// download doesn't leave a full-length file of zeros behind.
+///
+/// While the map is there the file must keep its length: if anything else,
+/// another process included, truncates it, touching the pages past the new
+/// end raises SIGBUS and kills the process. The output lock keeps other runs
+/// of this client away from it, nothing else does.
+///
+/// ```no_run
+/// use std::fs::OpenOptions;
+///
+/// use buggy_client::{Downloader, MmapSink};
+///
+/// let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open("data.bin")?;
+/// Downloader::builder().build()?.download(&mut MmapSink::new(file))?;
+/// # Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+/// ```
+pub struct MmapSink {
+    file: File,
+    map: Option<MmapMut>,
+    finished: bool,
+}
+
+impl MmapSink {
+    /// Writes into `file`, which must be open for reading as well as
+    /// writing to be mapped.
+    ///
+    /// ```
+    /// use std::fs::OpenOptions;
+    ///
+    /// use buggy_client::MmapSink;
+    ///
+    /// let path = std::env::temp_dir().join("buggy-client-mmap-example");
+    /// let sink = MmapSink::new(OpenOptions::new().read(true).write(true).create(true).truncate(true).open(path)?);
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn new(file: File) -> Self {
+        MmapSink { file, map: None, finished: false }
+    }
+}
+
+impl Sink for MmapSink {
+    fn set_size(&mut self, size: u64) -> io::Result<()> {
+        let len = usize::try_from(size).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "file too large"))?;
+        self.file.set_len(size)?;
+        if len == 0 {
+            return Ok(());
+        }
+        // SAFETY: the map lives no longer than the sink, which owns the file
+        // and only shrinks it after dropping the map. Truncation from
+        // outside is the caveat above.
+        match unsafe { MmapOptions::new().len(len).map_mut(&self.file) } {
+            Ok(map) => self.map = Some(map),
+            Err(e) => log::warn!("Cannot map the output file, writing it normally: {}", e),
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let map = match &mut self.map {
+            Some(map) => &mut map[..],
+            None => return SeekSink(&self.file).write_at(offset, data),
+        };
+        let start = usize::try_from(offset).ok().filter(|&start| start + data.len() <= map.len())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "write past the end of the mapped file"))?;
+        map[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if let Some(map) = &self.map {
+            map.flush()?;
+        }
+        self.finished = true;
+        Ok(())
+    }
+
+    fn written(&self) -> Option<&[u8]> {
+        self.map.as_deref()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        match &self.map {
+            Some(map) => map.flush(),
+            None => self.file.sync_data(),
+        }
+    }
+}
+
+impl Drop for MmapSink {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.map = None;
+            let _ = self.file.set_len(0);
+        }
+    }
+}
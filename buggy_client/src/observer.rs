@@ -0,0 +1,359 @@
+//! Hooks for following a download as it happens.
+
+use std::ops::Range;
+use std::time::Duration;
+
+use crate::downloader::Summary;
+
+/// Receives progress events from a running download. Every method has an
+/// empty default, so an observer only implements what it needs.
+///
+/// `bytes_received` is called from worker threads, everything else from the
+/// thread that called `download`. No download locks are held during a call,
+/// but a slow observer still slows the thread it is called on.
+///
+/// ```no_run
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::sync::Arc;
+///
+/// use buggy_client::{DownloadObserver, Downloader};
+///
+/// #[derive(Default)]
+/// struct Progress {
+///     total: AtomicUsize,
+///     received: AtomicUsize,
+/// }
+///
+/// impl DownloadObserver for Progress {
+///     fn size_known(&self, bytes: usize) {
+///         self.total.store(bytes, Ordering::Relaxed);
+///     }
+///
+///     fn bytes_received(&self, _worker: usize, _chunk_id: usize, bytes: usize) {
+///         let received = self.received.fetch_add(bytes, Ordering::Relaxed) + bytes;
+///         eprint!("\r{} of {} bytes", received, self.total.load(Ordering::Relaxed));
+///     }
+/// }
+///
+/// let downloader = Downloader::builder().probe_size(true).observer(Arc::new(Progress::default())).build()?;
+/// downloader.download(&mut Vec::new())?;
+/// # Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+/// ```
+pub trait DownloadObserver: Send + Sync {
+    /// A pass over the resource begins; `attempt` counts from 1 up to
+    /// `max_attempts`, which is above 1 only with verify retries.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use buggy_client::{DownloadObserver, Downloader};
+    ///
+    /// struct Watch;
+    ///
+    /// impl DownloadObserver for Watch {
+    ///     fn attempt_started(&self, attempt: usize, max_attempts: usize) {
+    ///         if attempt > 1 {
+    ///             eprintln!("pass {} of {}", attempt, max_attempts);
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let builder = Downloader::builder().observer(Arc::new(Watch));
+    /// ```
+    fn attempt_started(&self, _attempt: usize, _max_attempts: usize) {}
+
+    /// A range is given up after `tries` failed requests, counting both the
+    /// failed connects and transfers. Throttled requests are not counted, so
+    /// the `attempt` of a range a server keeps throttling can go past it.
+    ///
+    /// ```
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// use buggy_client::{DownloadObserver, Downloader};
+    ///
+    /// #[derive(Default)]
+    /// struct Watch {
+    ///     tries: AtomicUsize,
+    /// }
+    ///
+    /// impl DownloadObserver for Watch {
+    ///     fn max_tries(&self, tries: usize) {
+    ///         self.tries.store(tries, Ordering::Relaxed);
+    ///     }
+    /// }
+    ///
+    /// let observer = Arc::new(Watch::default());
+    /// let builder = Downloader::builder().observer(observer.clone());
+    /// ```
+    fn max_tries(&self, _tries: usize) {}
+
+    /// The resource is `bytes` long, as found by the size probe before any
+    /// data, or at most that long once a request past the end comes back
+    /// empty. Called again whenever the end moves earlier.
+    ///
+    /// ```
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// use buggy_client::{DownloadObserver, Downloader};
+    ///
+    /// #[derive(Default)]
+    /// struct Watch {
+    ///     size: AtomicUsize,
+    /// }
+    ///
+    /// impl DownloadObserver for Watch {
+    ///     fn size_known(&self, bytes: usize) {
+    ///         self.size.store(bytes, Ordering::Relaxed);
+    ///     }
+    /// }
+    ///
+    /// let observer = Arc::new(Watch::default());
+    /// let builder = Downloader::builder().observer(observer.clone());
+    /// ```
+    fn size_known(&self, _bytes: usize) {}
+
+    /// `bytes` of the resource were already there and are not fetched: the
+    /// prefix an earlier download left, or the blocks of a delta base.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use buggy_client::{DownloadObserver, Downloader};
+    ///
+    /// struct Watch;
+    ///
+    /// impl DownloadObserver for Watch {
+    ///     fn continued(&self, bytes: usize) {
+    ///         eprintln!("{} bytes already there", bytes);
+    ///     }
+    /// }
+    ///
+    /// let builder = Downloader::builder().observer(Arc::new(Watch));
+    /// ```
+    fn continued(&self, _bytes: usize) {}
+
+    /// `worker` began fetching `range` for chunk `chunk_id`; `attempt` counts
+    /// the tries of this range from 1.
+    ///
+    /// ```
+    /// use std::ops::Range;
+    /// use std::sync::Arc;
+    ///
+    /// use buggy_client::{DownloadObserver, Downloader};
+    ///
+    /// struct Watch;
+    ///
+    /// impl DownloadObserver for Watch {
+    ///     fn chunk_started(&self, worker: usize, chunk_id: usize, range: Range<usize>, attempt: usize) {
+    ///         eprintln!("worker {} asks for {:?} (chunk {}, try {})", worker, range, chunk_id, attempt);
+    ///     }
+    /// }
+    ///
+    /// let builder = Downloader::builder().observer(Arc::new(Watch));
+    /// ```
+    fn chunk_started(&self, _worker: usize, _chunk_id: usize, _range: Range<usize>, _attempt: usize) {}
+
+    /// `bytes` more bytes of chunk `chunk_id` arrived on `worker`.
+    ///
+    /// ```
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// use buggy_client::{DownloadObserver, Downloader};
+    ///
+    /// #[derive(Default)]
+    /// struct Watch {
+    ///     received: AtomicUsize,
+    /// }
+    ///
+    /// impl DownloadObserver for Watch {
+    ///     fn bytes_received(&self, _worker: usize, _chunk_id: usize, bytes: usize) {
+    ///         self.received.fetch_add(bytes, Ordering::Relaxed);
+    ///     }
+    /// }
+    ///
+    /// let observer = Arc::new(Watch::default());
+    /// let builder = Downloader::builder().observer(observer.clone());
+    /// ```
+    fn bytes_received(&self, _worker: usize, _chunk_id: usize, _bytes: usize) {}
+
+    /// Chunk `chunk_id` is done and `bytes` of it were kept: 0 for a
+    /// response past the end of the resource or a duplicate.
+    ///
+    /// ```
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// use buggy_client::{DownloadObserver, Downloader};
+    ///
+    /// #[derive(Default)]
+    /// struct Watch {
+    ///     chunks: AtomicUsize,
+    /// }
+    ///
+    /// impl DownloadObserver for Watch {
+    ///     fn chunk_finished(&self, _chunk_id: usize, bytes: usize) {
+    ///         if bytes > 0 {
+    ///             self.chunks.fetch_add(1, Ordering::Relaxed);
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let observer = Arc::new(Watch::default());
+    /// let builder = Downloader::builder().observer(observer.clone());
+    /// ```
+    fn chunk_finished(&self, _chunk_id: usize, _bytes: usize) {}
+
+    /// A request for chunk `chunk_id` failed on its `attempt`th try. `retry_in`
+    /// is the backoff before the next try, `None` once retries are used up.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use std::sync::Arc;
+    ///
+    /// use buggy_client::{DownloadObserver, Downloader};
+    ///
+    /// struct Watch;
+    ///
+    /// impl DownloadObserver for Watch {
+    ///     fn chunk_failed(&self, chunk_id: usize, attempt: usize, error: &str, retry_in: Option<Duration>) {
+    ///         match retry_in {
+    ///             Some(delay) => eprintln!("chunk {} try {}: {}, again in {:?}", chunk_id, attempt, error, delay),
+    ///             None => eprintln!("chunk {} given up: {}", chunk_id, error),
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let builder = Downloader::builder().observer(Arc::new(Watch));
+    /// ```
+    fn chunk_failed(&self, _chunk_id: usize, _attempt: usize, _error: &str, _retry_in: Option<Duration>) {}
+
+    /// The number of requests allowed in flight changed.
+    ///
+    /// ```
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// use buggy_client::{DownloadObserver, Downloader};
+    ///
+    /// #[derive(Default)]
+    /// struct Watch {
+    ///     limit: AtomicUsize,
+    /// }
+    ///
+    /// impl DownloadObserver for Watch {
+    ///     fn concurrency_changed(&self, limit: usize) {
+    ///         self.limit.store(limit, Ordering::Relaxed);
+    ///     }
+    /// }
+    ///
+    /// let observer = Arc::new(Watch::default());
+    /// let builder = Downloader::builder().observer(observer.clone());
+    /// ```
+    fn concurrency_changed(&self, _limit: usize) {}
+
+    /// The download was paused: requests in flight finish, no new ones start.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use buggy_client::{DownloadObserver, Downloader};
+    ///
+    /// struct Watch;
+    ///
+    /// impl DownloadObserver for Watch {
+    ///     fn paused(&self) {
+    ///         eprintln!("paused");
+    ///     }
+    /// }
+    ///
+    /// let builder = Downloader::builder().observer(Arc::new(Watch));
+    /// ```
+    fn paused(&self) {}
+
+    /// The download was resumed after a pause.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use buggy_client::{DownloadObserver, Downloader};
+    ///
+    /// struct Watch;
+    ///
+    /// impl DownloadObserver for Watch {
+    ///     fn resumed(&self) {
+    ///         eprintln!("resumed");
+    ///     }
+    /// }
+    ///
+    /// let builder = Downloader::builder().observer(Arc::new(Watch));
+    /// ```
+    fn resumed(&self) {}
+
+    /// The assembled data did not match the expected hash; `refetch` chunks
+    /// are downloaded again while `kept_bytes` bytes of verified chunks stay.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use buggy_client::{DownloadObserver, Downloader};
+    ///
+    /// struct Watch;
+    ///
+    /// impl DownloadObserver for Watch {
+    ///     fn attempt_failed(&self, attempt: usize, refetch: usize, kept_bytes: usize) {
+    ///         eprintln!("pass {} failed the hash: fetching {} chunks again, keeping {} bytes",
+    ///                   attempt, refetch, kept_bytes);
+    ///     }
+    /// }
+    ///
+    /// let builder = Downloader::builder().observer(Arc::new(Watch));
+    /// ```
+    fn attempt_failed(&self, _attempt: usize, _refetch: usize, _kept_bytes: usize) {}
+
+    /// The resource changed on the server, as `reason` says, and the download
+    /// starts over: everything received so far is dropped.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use buggy_client::{DownloadObserver, Downloader};
+    ///
+    /// struct Watch;
+    ///
+    /// impl DownloadObserver for Watch {
+    ///     fn restarted(&self, reason: &str) {
+    ///         eprintln!("starting over: {}", reason);
+    ///     }
+    /// }
+    ///
+    /// let builder = Downloader::builder().observer(Arc::new(Watch));
+    /// ```
+    fn restarted(&self, _reason: &str) {}
+
+    /// The download is over and `summary` is what it will return.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    ///
+    /// use buggy_client::{DownloadObserver, Downloader, Summary};
+    ///
+    /// struct Watch;
+    ///
+    /// impl DownloadObserver for Watch {
+    ///     fn download_finished(&self, summary: &Summary) {
+    ///         eprintln!("{} bytes in {:?}", summary.bytes, summary.duration);
+    ///     }
+    /// }
+    ///
+    /// let builder = Downloader::builder().observer(Arc::new(Watch));
+    /// ```
+    fn download_finished(&self, _summary: &Summary) {}
+}
+
+/// Observer used when none is set.
+pub(crate) struct NoObserver;
+
+impl DownloadObserver for NoObserver {}
@@ -0,0 +1,106 @@
+//! Holding a download back from another thread without losing its progress.
+
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::cancel::{CancellationToken, POLL_INTERVAL};
+
+/// Cheap, clonable handle that pauses and resumes the download it was given
+/// to. While paused, requests already running finish and no new ones start.
+///
+/// ```no_run
+/// use buggy_client::{Downloader, PauseToken};
+///
+/// let token = PauseToken::new();
+/// let downloader = Downloader::builder().pause(token.clone()).build()?;
+/// let download = std::thread::spawn(move || downloader.download(&mut Vec::new()));
+/// token.pause();
+/// token.resume();
+/// download.join().unwrap()?;
+/// # Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct PauseToken {
+    state: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl PauseToken {
+    /// A token that is not paused.
+    ///
+    /// ```
+    /// use buggy_client::PauseToken;
+    ///
+    /// assert!(!PauseToken::new().is_paused());
+    /// ```
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stops new requests from starting until [`PauseToken::resume`].
+    ///
+    /// ```
+    /// use buggy_client::PauseToken;
+    ///
+    /// let token = PauseToken::new();
+    /// token.clone().pause();
+    /// assert!(token.is_paused());
+    /// ```
+    pub fn pause(&self) {
+        *self.state.0.lock().unwrap() = true;
+    }
+
+    /// Lets a paused download go on.
+    ///
+    /// ```
+    /// use buggy_client::PauseToken;
+    ///
+    /// let token = PauseToken::new();
+    /// token.pause();
+    /// token.resume();
+    /// assert!(!token.is_paused());
+    /// ```
+    pub fn resume(&self) {
+        *self.state.0.lock().unwrap() = false;
+        self.state.1.notify_all();
+    }
+
+    /// Pauses a running download, or resumes a paused one.
+    ///
+    /// ```
+    /// use buggy_client::PauseToken;
+    ///
+    /// let token = PauseToken::new();
+    /// token.toggle();
+    /// assert!(token.is_paused());
+    /// token.toggle();
+    /// assert!(!token.is_paused());
+    /// ```
+    pub fn toggle(&self) {
+        if self.is_paused() {
+            self.resume();
+        } else {
+            self.pause();
+        }
+    }
+
+    /// Whether the download is paused.
+    ///
+    /// ```
+    /// use buggy_client::PauseToken;
+    ///
+    /// let token = PauseToken::new();
+    /// token.pause();
+    /// assert!(token.is_paused());
+    /// ```
+    pub fn is_paused(&self) -> bool {
+        *self.state.0.lock().unwrap()
+    }
+
+    /// Blocks while paused, returning once resumed or once `cancel` fires.
+    pub(crate) fn wait_while_paused(&self, cancel: &CancellationToken) {
+        let (paused, resumed) = &*self.state;
+        let mut paused = paused.lock().unwrap();
+        while *paused && !cancel.is_cancelled() {
+            paused = resumed.wait_timeout(paused, POLL_INTERVAL).unwrap().0;
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Keep-alive connections shared by all workers, so a request reuses a warm
+//! connection to its server whichever worker last used it.
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::stream::Stream;
+
+/// Longest a connection may sit idle before it is closed instead of reused,
+/// short of the 5 seconds many servers wait before closing it themselves.
+const MAX_IDLE: Duration = Duration::from_secs(4);
+
+/// How long before the idle timeout a server announces a connection is
+/// retired, so a request never races the server closing it.
+const TIMEOUT_MARGIN: Duration = Duration::from_secs(1);
+
+/// How the connection pool did over a download.
+///
+/// ```no_run
+/// use buggy_client::Downloader;
+///
+/// let summary = Downloader::builder().build()?.download(&mut Vec::new())?;
+/// let connections = summary.connections;
+/// println!("{} connections for {} requests", connections.created, connections.created + connections.reused);
+/// # Ok::<(), Box<dyn std::error::Error + Send + Sync>>(())
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct PoolStats {
+    /// Connections opened.
+    pub created: usize,
+    /// Requests sent on a connection opened for an earlier one.
+    pub reused: usize,
+    /// Connections closed by the client: after an error, a response that
+    /// left them unusable, too long idle or too many requests, or found
+    /// closed by the server.
+    pub evicted: usize,
+}
+
+impl PoolStats {
+    /// What happened since `earlier` was taken.
+    pub(crate) fn since(self, earlier: PoolStats) -> PoolStats {
+        PoolStats {
+            created: self.created - earlier.created,
+            reused: self.reused - earlier.reused,
+            evicted: self.evicted - earlier.evicted,
+        }
+    }
+}
+
+/// Idle connections by server, at most one per worker.
+pub(crate) struct ConnectionPool {
+    idle: Mutex<Vec<Idle>>,
+    capacity: usize,
+    /// Requests a connection serves before it is closed; 1 turns reuse off.
+    max_requests: usize,
+    created: AtomicUsize,
+    reused: AtomicUsize,
+    evicted: AtomicUsize,
+}
+
+struct Idle {
+    connection: Connection,
+    since: Instant,
+}
+
+/// An open connection to the server at index `mirror`.
+pub(crate) struct Connection {
+    pub(crate) stream: Stream,
+    mirror: usize,
+    served: usize,
+    /// Longest it may sit idle, shorter when the server said so.
+    max_idle: Duration,
+    /// Requests the server said it still takes on it.
+    remaining: Option<usize>,
+}
+
+impl ConnectionPool {
+    pub(crate) fn new(capacity: usize, max_requests: usize) -> Self {
+        ConnectionPool {
+            idle: Mutex::new(Vec::new()),
+            capacity,
+            max_requests,
+            created: AtomicUsize::new(0),
+            reused: AtomicUsize::new(0),
+            evicted: AtomicUsize::new(0),
+        }
+    }
+
+    /// Whether requests should ask the server to keep the connection open.
+    pub(crate) fn keep_alive(&self) -> bool {
+        self.max_requests > 1
+    }
+
+    /// A healthy idle connection to `mirror`, if there is one. Connections
+    /// idle for too long, or closed by the server meanwhile, are dropped.
+    pub(crate) fn take(&self, mirror: usize) -> Option<Checkout<'_>> {
+        loop {
+            let connection = {
+                let mut idle = self.idle.lock().unwrap();
+                let before = idle.len();
+                idle.retain(|entry| entry.since.elapsed() < entry.connection.max_idle);
+                self.evicted.fetch_add(before - idle.len(), Ordering::SeqCst);
+                let index = idle.iter().position(|entry| entry.connection.mirror == mirror)?;
+                idle.swap_remove(index).connection
+            };
+            if healthy(&connection.stream) {
+                self.reused.fetch_add(1, Ordering::SeqCst);
+                return Some(Checkout { pool: self, connection: Some(connection), reused: true });
+            }
+            log::debug!("dropping an idle connection the server has closed");
+            self.evicted.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Takes in a connection just opened to `mirror`.
+    pub(crate) fn opened(&self, mirror: usize, stream: Stream) -> Checkout<'_> {
+        self.created.fetch_add(1, Ordering::SeqCst);
+        let connection = Connection { stream, mirror, served: 0, max_idle: MAX_IDLE, remaining: None };
+        Checkout { pool: self, connection: Some(connection), reused: false }
+    }
+
+    /// An idle connection to `mirror`, or else a new one from `connect`.
+    #[cfg(not(feature = "async"))]
+    pub(crate) fn checkout(&self, mirror: usize, connect: impl FnOnce() -> io::Result<Stream>)
+        -> io::Result<Checkout<'_>> {
+        match self.take(mirror) {
+            Some(checkout) => Ok(checkout),
+            None => Ok(self.opened(mirror, connect()?)),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> PoolStats {
+        PoolStats {
+            created: self.created.load(Ordering::SeqCst),
+            reused: self.reused.load(Ordering::SeqCst),
+            evicted: self.evicted.load(Ordering::SeqCst),
+        }
+    }
+
+    fn give_back(&self, mut connection: Connection, requests: usize) {
+        connection.served += requests;
+        let mut idle = self.idle.lock().unwrap();
+        let spent = connection.served >= self.max_requests || connection.remaining == Some(0);
+        if spent || connection.max_idle.is_zero() || idle.len() >= self.capacity {
+            self.evicted.fetch_add(1, Ordering::SeqCst);
+            return;
+        }
+        idle.push(Idle { connection, since: Instant::now() });
+    }
+}
+
+/// A connection out of the pool for one request. It is closed when dropped,
+/// unless [`Checkout::release`] puts it back.
+pub(crate) struct Checkout<'a> {
+    pool: &'a ConnectionPool,
+    connection: Option<Connection>,
+    reused: bool,
+}
+
+impl Checkout<'_> {
+    pub(crate) fn stream(&self) -> &Stream {
+        &self.connection.as_ref().expect("connection already released").stream
+    }
+
+    /// Whether the connection served an earlier request, so the server may
+    /// have closed it just before this one.
+    pub(crate) fn reused(&self) -> bool {
+        self.reused
+    }
+
+    /// Takes in the limits of a `Keep-Alive` response header: the idle
+    /// `timeout` in seconds and the `remaining` requests the server takes.
+    pub(crate) fn server_limits(&mut self, timeout: Option<u64>, remaining: Option<usize>) {
+        if let Some(connection) = self.connection.as_mut() {
+            if let Some(timeout) = timeout {
+                connection.max_idle = Duration::from_secs(timeout).saturating_sub(TIMEOUT_MARGIN).min(MAX_IDLE);
+            }
+            connection.remaining = remaining.or(connection.remaining);
+        }
+    }
+
+    /// Returns the connection after `requests` responses that left it ready
+    /// for the next request.
+    pub(crate) fn release(mut self, requests: usize) {
+        if let Some(connection) = self.connection.take() {
+            self.pool.give_back(connection, requests);
+        }
+    }
+}
+
+impl Drop for Checkout<'_> {
+    fn drop(&mut self) {
+        if self.connection.is_some() {
+            self.pool.evicted.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Whether an idle connection is still open with nothing unread on it. A
+/// connection the server closed reads as the end of the stream at once.
+fn healthy(stream: &Stream) -> bool {
+    let mut byte = [0; 1];
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let open = matches!(stream.peek(&mut byte), Err(e) if e.kind() == io::ErrorKind::WouldBlock);
+    stream.set_nonblocking(false).is_ok() && open
+}
@@ -0,0 +1,744 @@
+//! The command line client's progress displays: indicatif bars for people,
+//! plain periodic lines for logs and JSON lines for programs.
+
+use std::collections::{HashSet, VecDeque};
+use std::io::Write;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use indicatif::{HumanDuration, MultiProgress, ProgressBar, ProgressStyle};
+
+use buggy_client::{DownloadObserver, Summary};
+
+use crate::json::json_string;
+
+/// Draws a total bar and one bar per worker on the terminal, and in verbose
+/// mode prints every chunk error and retry to stderr.
+pub struct TerminalProgress {
+    /// Taken when the first attempt starts, so nothing is drawn before then.
+    multi_progress: Mutex<Option<MultiProgress>>,
+    total: ProgressBar,
+    workers: Vec<ProgressBar>,
+    /// The chunk each worker bar shows, until it is done.
+    current: Mutex<Vec<Option<usize>>>,
+    /// Most tries a chunk gets, for the try numbers on the worker bars; 0
+    /// until the download says.
+    max_tries: AtomicUsize,
+    /// Line under the total bar with the active and retry counts, in the
+    /// simple mode that has no worker bars.
+    status: Option<ProgressBar>,
+    active: AtomicUsize,
+    retries: AtomicUsize,
+    verbose: bool,
+    /// Recent throughput of the total bar, sampled once a second while the
+    /// bars are drawn.
+    speed: Arc<Mutex<SpeedHistory>>,
+}
+
+impl TerminalProgress {
+    /// Sets up bars for `workers` workers, drawn once the download starts.
+    pub fn new(workers: usize, verbose: bool) -> Self {
+        let multi_progress = MultiProgress::new();
+        let total = total_bar(&multi_progress);
+
+        let workers: Vec<_> = (0..workers).map(|i| {
+            let pb = multi_progress.add(ProgressBar::new(0));
+            pb.set_style(ProgressStyle::default_bar()
+                .template("{prefix} [{wide_bar:.green/white}] {bytes}/{total_bytes} {msg}")
+                .progress_chars("=> "));
+            pb.set_prefix(format!("Thread #{:2}", i));
+            pb
+        }).collect();
+
+        TerminalProgress {
+            multi_progress: Mutex::new(Some(multi_progress)),
+            total,
+            current: Mutex::new(vec![None; workers.len()]),
+            workers,
+            status: None,
+            max_tries: AtomicUsize::new(0),
+            active: AtomicUsize::new(0),
+            retries: AtomicUsize::new(0),
+            verbose,
+            speed: Arc::default(),
+        }
+    }
+
+    /// Draws only the total bar and a status line, however many workers
+    /// there are.
+    pub fn simple(verbose: bool) -> Self {
+        let multi_progress = MultiProgress::new();
+        let total = total_bar(&multi_progress);
+        let status = multi_progress.add(ProgressBar::new(0));
+        status.set_style(ProgressStyle::default_bar().template("{msg}"));
+
+        let progress = TerminalProgress {
+            multi_progress: Mutex::new(Some(multi_progress)),
+            total,
+            workers: Vec::new(),
+            current: Mutex::default(),
+            max_tries: AtomicUsize::new(0),
+            status: Some(status),
+            active: AtomicUsize::new(0),
+            retries: AtomicUsize::new(0),
+            verbose,
+            speed: Arc::default(),
+        };
+        progress.update_status();
+        progress
+    }
+
+    /// Prints the same status and verbose lines as `new` but draws no bars,
+    /// so no thread is spawned to render them.
+    pub fn without_bars(verbose: bool) -> Self {
+        TerminalProgress {
+            multi_progress: Mutex::new(None),
+            total: ProgressBar::hidden(),
+            workers: Vec::new(),
+            current: Mutex::default(),
+            max_tries: AtomicUsize::new(0),
+            status: None,
+            active: AtomicUsize::new(0),
+            retries: AtomicUsize::new(0),
+            verbose,
+            speed: Arc::default(),
+        }
+    }
+
+    /// Prints `line` above the bars while they are drawn, to stderr otherwise.
+    pub fn println(&self, line: &str) {
+        if self.total.is_hidden() || self.total.is_finished() {
+            eprintln!("{}", line);
+        } else {
+            self.total.println(line);
+        }
+    }
+
+    /// Shows `message` on the bar of the worker fetching `chunk_id`, which
+    /// is then done with it.
+    fn release(&self, chunk_id: usize, message: impl FnOnce() -> String) {
+        let mut current = self.current.lock().unwrap();
+        if let Some(worker) = current.iter().position(|&chunk| chunk == Some(chunk_id)) {
+            current[worker] = None;
+            self.workers[worker].set_message(message());
+        }
+    }
+
+    fn update_status(&self) {
+        if let Some(status) = &self.status {
+            status.set_message(format!("{} active workers, {} retries",
+                                       self.active.load(Ordering::SeqCst), self.retries.load(Ordering::SeqCst)));
+        }
+    }
+}
+
+// The speeds, ETA and sparkline go in the prefix, which the total bar has no
+// other use for, as indicatif's own are averaged over the whole download.
+const TOTAL_TEMPLATE: &str = "[{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({prefix}) {msg}";
+
+fn total_bar(multi_progress: &MultiProgress) -> ProgressBar {
+    let total = multi_progress.add(ProgressBar::new(0));
+    total.set_style(total_style(TOTAL_TEMPLATE));
+    total.set_prefix("measuring speed");
+    total
+}
+
+/// Seconds of history the sparkline shows.
+const SPARKLINE_SECONDS: usize = 30;
+/// Seconds the rolling average, and so the ETA, is taken over.
+const AVERAGE_SECONDS: usize = 10;
+
+/// Bytes added to the total bar in each of the last seconds, oldest first.
+#[derive(Default)]
+struct SpeedHistory {
+    samples: VecDeque<u64>,
+    position: u64,
+}
+
+impl SpeedHistory {
+    /// Records how far the bar moved since the last call, a second ago. A
+    /// bar moved back by a restart counts as no progress.
+    fn record(&mut self, position: u64) {
+        if self.samples.len() == SPARKLINE_SECONDS {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(position.saturating_sub(self.position));
+        self.position = position;
+    }
+
+    fn current(&self) -> u64 {
+        self.samples.back().copied().unwrap_or(0)
+    }
+
+    fn average(&self) -> f64 {
+        let recent = self.samples.iter().rev().take(AVERAGE_SECONDS);
+        recent.clone().sum::<u64>() as f64 / recent.count().max(1) as f64
+    }
+
+    /// One block per second, as high as that second's share of the fastest.
+    fn sparkline(&self) -> String {
+        const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+        let peak = self.samples.iter().copied().max().unwrap_or(0).max(1);
+        self.samples.iter().map(|&bytes| BLOCKS[(bytes * 7 / peak) as usize]).collect()
+    }
+
+    /// The speed figures, ETA and sparkline for a bar at `position` of `length`.
+    fn describe(&self, position: u64, length: u64) -> String {
+        let average = self.average();
+        let eta = match length.checked_sub(position) {
+            Some(0) | None => String::new(),
+            Some(_) if average < 1.0 => ", ETA unknown".to_string(),
+            Some(left) => format!(", ETA {}", HumanDuration(Duration::from_secs_f64(left as f64 / average))),
+        };
+        format!("{}/s now, {}/s avg{} {}", format_bytes(self.current() as f64), format_bytes(average), eta,
+                self.sparkline())
+    }
+}
+
+/// Samples the total bar once a second until it finishes or its progress
+/// display is dropped.
+fn sample_speed(total: ProgressBar, speed: Weak<Mutex<SpeedHistory>>) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            let speed = match speed.upgrade() {
+                Some(speed) if !total.is_finished() => speed,
+                _ => break,
+            };
+            let mut speed = speed.lock().unwrap();
+            speed.record(total.position());
+            total.set_prefix(speed.describe(total.position(), total.length()));
+        }
+    });
+}
+
+fn total_style(template: &str) -> ProgressStyle {
+    ProgressStyle::default_bar().template(template).progress_chars("#>-")
+}
+
+impl DownloadObserver for TerminalProgress {
+    fn attempt_started(&self, attempt: usize, max_attempts: usize) {
+        if let Some(multi_progress) = self.multi_progress.lock().unwrap().take() {
+            thread::spawn(move || {
+                multi_progress.join().unwrap();
+            });
+            sample_speed(self.total.clone(), Arc::downgrade(&self.speed));
+        }
+        if max_attempts > 1 {
+            self.println(&format!("Attempt {} of {}", attempt, max_attempts));
+        }
+    }
+
+    fn size_known(&self, bytes: usize) {
+        self.total.set_length(bytes as u64);
+    }
+
+    fn continued(&self, bytes: usize) {
+        self.total.set_position(bytes as u64);
+        // The bytes already there were not received in the first second.
+        self.speed.lock().unwrap().position = bytes as u64;
+    }
+
+    fn max_tries(&self, tries: usize) {
+        self.max_tries.store(tries, Ordering::SeqCst);
+    }
+
+    fn chunk_started(&self, worker: usize, chunk_id: usize, range: Range<usize>, attempt: usize) {
+        if let Some(bar) = self.workers.get(worker) {
+            self.current.lock().unwrap()[worker] = Some(chunk_id);
+            bar.set_position(0);
+            bar.set_length(range.len() as u64);
+            let bytes = format!("[{}-{}]", format_bytes(range.start as f64), format_bytes(range.end as f64));
+            match (attempt, self.max_tries.load(Ordering::SeqCst)) {
+                (1, _) => bar.set_message(format!("chunk {} {}", chunk_id, bytes)),
+                (attempt, max) if attempt <= max => {
+                    bar.set_message(format!("chunk {} {} try {}/{}", chunk_id, bytes, attempt, max))
+                }
+                (attempt, _) => bar.set_message(format!("chunk {} {} try {}", chunk_id, bytes, attempt)),
+            }
+        }
+        self.active.fetch_add(1, Ordering::SeqCst);
+        self.update_status();
+    }
+
+    fn bytes_received(&self, worker: usize, _chunk_id: usize, bytes: usize) {
+        if let Some(bar) = self.workers.get(worker) {
+            bar.inc(bytes as u64);
+        }
+    }
+
+    fn chunk_finished(&self, chunk_id: usize, bytes: usize) {
+        self.release(chunk_id, || "idle".to_string());
+        self.total.inc(bytes as u64);
+        self.active.fetch_sub(1, Ordering::SeqCst);
+        self.update_status();
+    }
+
+    fn chunk_failed(&self, chunk_id: usize, attempt: usize, error: &str, retry_in: Option<Duration>) {
+        // A chunk given up on stays on its bar until the worker starts the next.
+        match retry_in {
+            Some(_) => self.release(chunk_id, || "idle".to_string()),
+            None => self.release(chunk_id, || {
+                format!("chunk {} given up after {} tries: {}", chunk_id, attempt, error)
+            }),
+        }
+        self.active.fetch_sub(1, Ordering::SeqCst);
+        if retry_in.is_some() {
+            self.retries.fetch_add(1, Ordering::SeqCst);
+        }
+        self.update_status();
+        if !self.verbose {
+            return;
+        }
+        self.println(&format!("Error downloading chunk {}: {}", chunk_id, error));
+        self.println(&match retry_in {
+            Some(backoff) => format!("Retrying chunk {} after {}ms", chunk_id, backoff.as_millis()),
+            None => format!("Failed to download chunk {} after {} attempts", chunk_id, attempt),
+        });
+    }
+
+    fn concurrency_changed(&self, limit: usize) {
+        self.total.set_message(format!("{} in flight", limit));
+        if self.verbose {
+            self.println(&format!("Concurrency changed to {}", limit));
+        }
+    }
+
+    fn paused(&self) {
+        if self.total.is_hidden() {
+            self.println("Download paused");
+        }
+        // The elapsed time is written into the template, so it stands still.
+        let elapsed = self.total.elapsed().as_secs();
+        self.total.set_style(total_style(&format!(
+            "[{:02}:{:02}:{:02}] [{{wide_bar:.cyan/blue}}] {{bytes}}/{{total_bytes}} PAUSED {{msg}}",
+            elapsed / 3600, elapsed / 60 % 60, elapsed % 60)));
+    }
+
+    fn resumed(&self) {
+        if self.total.is_hidden() {
+            self.println("Download resumed");
+        }
+        self.total.set_style(total_style(TOTAL_TEMPLATE));
+        // Start the speed estimate over, so the pause doesn't drag it down.
+        self.speed.lock().unwrap().samples.clear();
+    }
+
+    fn restarted(&self, reason: &str) {
+        self.println(&format!("{}, starting over", reason));
+        self.total.set_position(0);
+        for bar in &self.workers {
+            bar.reset();
+        }
+    }
+
+    fn attempt_failed(&self, attempt: usize, refetch: usize, kept_bytes: usize) {
+        self.println(&format!("Checksum verification failed on attempt {}, re-downloading {} chunks", attempt,
+                              refetch));
+        self.total.set_length(self.total.position());
+        self.total.set_position(kept_bytes as u64);
+        for bar in &self.workers {
+            bar.reset();
+        }
+    }
+
+    fn download_finished(&self, _summary: &Summary) {
+        for bar in &self.workers {
+            bar.finish();
+        }
+        if let Some(status) = &self.status {
+            status.finish();
+        }
+        self.total.finish_with_message("Download complete!");
+    }
+}
+
+/// Prints a plain stats line every interval, for logs where bars would turn
+/// into carriage returns and partial renders. Each line is the same
+/// space-separated fields, a Unix timestamp followed by `key=value` pairs,
+/// with `-` for what is not known yet:
+///
+/// ```text
+/// 1760601012.345 bytes=1289728 total=10485760 percent=12 speed=325058 active=4 completed=19 pending=140 failed=0 retries=2
+/// ```
+///
+/// `pending` estimates the chunks still to fetch from the size of the
+/// latest. The last line, printed when the download is finished, gives the
+/// average speed instead of the recent one.
+pub struct PlainProgress {
+    inner: Arc<PlainInner>,
+    interval: Duration,
+    ticker_started: AtomicBool,
+}
+
+struct PlainInner {
+    out: Mutex<Box<dyn Write + Send>>,
+    bytes_done: AtomicUsize,
+    bytes_received: AtomicUsize,
+    total_bytes: AtomicUsize,
+    active: AtomicUsize,
+    completed: AtomicUsize,
+    /// Chunks given up on.
+    failed: AtomicUsize,
+    /// Length of the latest chunk started.
+    chunk_len: AtomicUsize,
+    retries: AtomicUsize,
+    finished: AtomicBool,
+    verbose: bool,
+}
+
+impl PlainProgress {
+    /// Writes a stats line to `out` every `interval`, plus the same verbose
+    /// lines as `TerminalProgress`.
+    pub fn new(out: impl Write + Send + 'static, interval: Duration, verbose: bool) -> Self {
+        PlainProgress {
+            inner: Arc::new(PlainInner {
+                out: Mutex::new(Box::new(out)),
+                bytes_done: AtomicUsize::new(0),
+                bytes_received: AtomicUsize::new(0),
+                total_bytes: AtomicUsize::new(0),
+                active: AtomicUsize::new(0),
+                completed: AtomicUsize::new(0),
+                failed: AtomicUsize::new(0),
+                chunk_len: AtomicUsize::new(0),
+                retries: AtomicUsize::new(0),
+                finished: AtomicBool::new(false),
+                verbose,
+            }),
+            interval,
+            ticker_started: AtomicBool::new(false),
+        }
+    }
+
+    fn start_ticker(&self) {
+        if self.ticker_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let inner = Arc::downgrade(&self.inner);
+        let interval = self.interval;
+        thread::spawn(move || {
+            let mut last_received = 0;
+            let mut last_tick = Instant::now();
+            loop {
+                // Short naps, so the thread ends soon after the download.
+                thread::sleep(interval.saturating_sub(last_tick.elapsed()).min(TICKER_NAP));
+                let inner = match inner.upgrade() {
+                    Some(inner) if !inner.finished.load(Ordering::SeqCst) => inner,
+                    _ => break,
+                };
+                if last_tick.elapsed() < interval {
+                    continue;
+                }
+                let received = inner.bytes_received.load(Ordering::SeqCst);
+                let speed = (received - last_received) as f64 / last_tick.elapsed().as_secs_f64();
+                last_received = received;
+                last_tick = Instant::now();
+                inner.stats_line(speed, false);
+            }
+        });
+    }
+}
+
+impl PlainInner {
+    fn line(&self, text: &str) {
+        let mut out = self.out.lock().unwrap();
+        let _ = writeln!(out, "{}", text);
+        let _ = out.flush();
+    }
+
+    /// Writes a stats line, unless the download finished and this is not
+    /// the `last` one.
+    fn stats_line(&self, speed: f64, last: bool) {
+        let mut out = self.out.lock().unwrap();
+        if self.finished.load(Ordering::SeqCst) && !last {
+            return;
+        }
+        let ts = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0);
+        let done = self.bytes_done.load(Ordering::SeqCst);
+        let active = self.active.load(Ordering::SeqCst);
+        let (total, percent, pending) = match (self.total_bytes.load(Ordering::SeqCst),
+                                               self.chunk_len.load(Ordering::SeqCst)) {
+            (0, _) => ("-".to_string(), "-".to_string(), "-".to_string()),
+            (total, chunk_len) => {
+                let pending = match chunk_len {
+                    0 => "-".to_string(),
+                    _ => (total.saturating_sub(done).div_ceil(chunk_len).saturating_sub(active)).to_string(),
+                };
+                (total.to_string(), (done * 100 / total).to_string(), pending)
+            }
+        };
+        let _ = writeln!(out, "{:.3} bytes={} total={} percent={} speed={:.0} active={} completed={} pending={} \
+                               failed={} retries={}", ts, done, total, percent, speed, active,
+                         self.completed.load(Ordering::SeqCst), pending, self.failed.load(Ordering::SeqCst),
+                         self.retries.load(Ordering::SeqCst));
+        let _ = out.flush();
+    }
+}
+
+/// Longest the stats ticker sleeps before it looks whether the download is
+/// over.
+const TICKER_NAP: Duration = Duration::from_millis(100);
+
+/// Formats a byte count with a binary unit, e.g. `12.3 MiB`.
+pub(crate) fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024.0 {
+        return format!("{:.0} B", bytes);
+    }
+    let mut value = bytes / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+impl DownloadObserver for PlainProgress {
+    fn attempt_started(&self, attempt: usize, max_attempts: usize) {
+        self.start_ticker();
+        if max_attempts > 1 {
+            self.inner.line(&format!("Attempt {} of {}", attempt, max_attempts));
+        }
+    }
+
+    fn size_known(&self, bytes: usize) {
+        self.inner.total_bytes.store(bytes, Ordering::SeqCst);
+    }
+
+    fn continued(&self, bytes: usize) {
+        self.inner.bytes_done.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    fn chunk_started(&self, _worker: usize, _chunk_id: usize, range: Range<usize>, _attempt: usize) {
+        self.inner.active.fetch_add(1, Ordering::SeqCst);
+        self.inner.chunk_len.store(range.len(), Ordering::SeqCst);
+    }
+
+    fn bytes_received(&self, _worker: usize, _chunk_id: usize, bytes: usize) {
+        self.inner.bytes_received.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    fn chunk_finished(&self, _chunk_id: usize, bytes: usize) {
+        self.inner.active.fetch_sub(1, Ordering::SeqCst);
+        self.inner.bytes_done.fetch_add(bytes, Ordering::SeqCst);
+        if bytes > 0 {
+            self.inner.completed.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn chunk_failed(&self, chunk_id: usize, attempt: usize, error: &str, retry_in: Option<Duration>) {
+        self.inner.active.fetch_sub(1, Ordering::SeqCst);
+        match retry_in {
+            Some(_) => self.inner.retries.fetch_add(1, Ordering::SeqCst),
+            None => self.inner.failed.fetch_add(1, Ordering::SeqCst),
+        };
+        if !self.inner.verbose {
+            return;
+        }
+        self.inner.line(&format!("Error downloading chunk {}: {}", chunk_id, error));
+        match retry_in {
+            Some(backoff) => self.inner.line(&format!("Retrying chunk {} after {}ms", chunk_id, backoff.as_millis())),
+            None => self.inner.line(&format!("Failed to download chunk {} after {} attempts", chunk_id, attempt)),
+        }
+    }
+
+    fn concurrency_changed(&self, limit: usize) {
+        if self.inner.verbose {
+            self.inner.line(&format!("Concurrency changed to {}", limit));
+        }
+    }
+
+    fn paused(&self) {
+        self.inner.line("paused");
+    }
+
+    fn resumed(&self) {
+        self.inner.line("resumed");
+    }
+
+    fn restarted(&self, reason: &str) {
+        self.inner.line(&format!("{}, starting over", reason));
+        self.inner.bytes_done.store(0, Ordering::SeqCst);
+    }
+
+    fn attempt_failed(&self, attempt: usize, refetch: usize, kept_bytes: usize) {
+        self.inner.line(&format!("Checksum verification failed on attempt {}, re-downloading {} chunks",
+                                 attempt, refetch));
+        self.inner.total_bytes.store(self.inner.bytes_done.load(Ordering::SeqCst), Ordering::SeqCst);
+        self.inner.bytes_done.store(kept_bytes, Ordering::SeqCst);
+    }
+
+    fn download_finished(&self, summary: &Summary) {
+        self.inner.finished.store(true, Ordering::SeqCst);
+        self.inner.total_bytes.store(summary.bytes + summary.resumed, Ordering::SeqCst);
+        self.inner.stats_line(summary.bytes as f64 / summary.duration.as_secs_f64().max(f64::EPSILON), true);
+    }
+}
+
+/// Writes newline-delimited JSON events instead of drawing bars: one per
+/// chunk start, finish and failure, plus a `progress` event every interval.
+/// Each event carries a `seq` number that increases by one and a `ts` in
+/// seconds since the Unix epoch.
+pub struct JsonProgress {
+    inner: Arc<JsonInner>,
+    interval: Duration,
+    ticker_started: AtomicBool,
+}
+
+struct JsonInner {
+    /// The writer and the next sequence number, locked together so events
+    /// appear in `seq` order.
+    out: Mutex<(Box<dyn Write + Send>, u64)>,
+    bytes_done: AtomicUsize,
+    bytes_received: AtomicUsize,
+    total_bytes: AtomicUsize,
+    active: Mutex<HashSet<usize>>,
+    finished: AtomicBool,
+}
+
+impl JsonProgress {
+    /// Emits events to `out`, with an aggregate `progress` event every `interval`.
+    pub fn new(out: impl Write + Send + 'static, interval: Duration) -> Self {
+        JsonProgress {
+            inner: Arc::new(JsonInner {
+                out: Mutex::new((Box::new(out), 1)),
+                bytes_done: AtomicUsize::new(0),
+                bytes_received: AtomicUsize::new(0),
+                total_bytes: AtomicUsize::new(0),
+                active: Mutex::new(HashSet::new()),
+                finished: AtomicBool::new(false),
+            }),
+            interval,
+            ticker_started: AtomicBool::new(false),
+        }
+    }
+
+    fn start_ticker(&self) {
+        if self.ticker_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // A weak handle lets the ticker die with the observer even when the
+        // download never finishes.
+        let inner = Arc::downgrade(&self.inner);
+        let interval = self.interval;
+        thread::spawn(move || {
+            let mut last_received = 0;
+            let mut last_tick = Instant::now();
+            loop {
+                thread::sleep(interval);
+                let inner = match inner.upgrade() {
+                    Some(inner) if !inner.finished.load(Ordering::SeqCst) => inner,
+                    _ => break,
+                };
+                let received = inner.bytes_received.load(Ordering::SeqCst);
+                let speed = (received - last_received) as f64 / last_tick.elapsed().as_secs_f64();
+                last_received = received;
+                last_tick = Instant::now();
+                inner.progress_event(speed);
+            }
+        });
+    }
+}
+
+impl JsonInner {
+    /// Writes one event; `fields` is the JSON object body after `seq`, `ts`
+    /// and `event`, starting with a comma when not empty.
+    fn emit(&self, event: &str, fields: &str) {
+        let ts = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or(0.0);
+        let mut out = self.out.lock().unwrap();
+        let (writer, seq) = &mut *out;
+        let _ = writeln!(writer, "{{\"seq\":{},\"ts\":{:.3},\"event\":\"{}\"{}}}", seq, ts, event, fields);
+        let _ = writer.flush();
+        *seq += 1;
+    }
+
+    fn progress_event(&self, speed: f64) {
+        let total = match self.total_bytes.load(Ordering::SeqCst) {
+            0 => "null".to_string(),
+            total => total.to_string(),
+        };
+        let active = self.active.lock().unwrap().len();
+        self.emit("progress", &format!(",\"bytes_done\":{},\"total_bytes\":{},\"speed\":{:.0},\"active_workers\":{}",
+                                       self.bytes_done.load(Ordering::SeqCst), total, speed, active));
+    }
+}
+
+impl DownloadObserver for JsonProgress {
+    fn attempt_started(&self, attempt: usize, max_attempts: usize) {
+        self.start_ticker();
+        self.inner.emit("attempt_started", &format!(",\"attempt\":{},\"max_attempts\":{}", attempt, max_attempts));
+    }
+
+    fn size_known(&self, bytes: usize) {
+        self.inner.total_bytes.store(bytes, Ordering::SeqCst);
+        self.inner.emit("size_known", &format!(",\"bytes\":{}", bytes));
+    }
+
+    fn continued(&self, bytes: usize) {
+        self.inner.bytes_done.fetch_add(bytes, Ordering::SeqCst);
+        self.inner.emit("continued", &format!(",\"bytes\":{}", bytes));
+    }
+
+    fn chunk_started(&self, worker: usize, chunk_id: usize, range: Range<usize>, attempt: usize) {
+        self.inner.active.lock().unwrap().insert(chunk_id);
+        self.inner.emit("chunk_started", &format!(",\"worker\":{},\"chunk\":{},\"start\":{},\"end\":{},\"attempt\":{}",
+                                                  worker, chunk_id, range.start, range.end, attempt));
+    }
+
+    fn bytes_received(&self, _worker: usize, _chunk_id: usize, bytes: usize) {
+        self.inner.bytes_received.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    fn chunk_finished(&self, chunk_id: usize, bytes: usize) {
+        self.inner.active.lock().unwrap().remove(&chunk_id);
+        self.inner.bytes_done.fetch_add(bytes, Ordering::SeqCst);
+        self.inner.emit("chunk_finished", &format!(",\"chunk\":{},\"bytes\":{}", chunk_id, bytes));
+    }
+
+    fn chunk_failed(&self, chunk_id: usize, attempt: usize, error: &str, retry_in: Option<Duration>) {
+        self.inner.active.lock().unwrap().remove(&chunk_id);
+        let retry_in = retry_in.map_or("null".to_string(), |backoff| backoff.as_millis().to_string());
+        self.inner.emit("chunk_failed", &format!(",\"chunk\":{},\"attempt\":{},\"error\":{},\"retry_in_ms\":{}",
+                                                 chunk_id, attempt, json_string(error), retry_in));
+    }
+
+    fn concurrency_changed(&self, limit: usize) {
+        self.inner.emit("concurrency_changed", &format!(",\"limit\":{}", limit));
+    }
+
+    fn paused(&self) {
+        self.inner.emit("paused", "");
+    }
+
+    fn resumed(&self) {
+        self.inner.emit("resumed", "");
+    }
+
+    fn restarted(&self, reason: &str) {
+        self.inner.bytes_done.store(0, Ordering::SeqCst);
+        self.inner.emit("restarted", &format!(",\"reason\":{}", json_string(reason)));
+    }
+
+    fn attempt_failed(&self, attempt: usize, refetch: usize, kept_bytes: usize) {
+        // The first pass found the end, so later passes know the total.
+        self.inner.total_bytes.store(self.inner.bytes_done.load(Ordering::SeqCst), Ordering::SeqCst);
+        self.inner.bytes_done.store(kept_bytes, Ordering::SeqCst);
+        self.inner.emit("attempt_failed", &format!(",\"attempt\":{},\"refetch\":{},\"kept_bytes\":{}",
+                                                   attempt, refetch, kept_bytes));
+    }
+
+    fn download_finished(&self, summary: &Summary) {
+        self.inner.finished.store(true, Ordering::SeqCst);
+        self.inner.total_bytes.store(summary.bytes + summary.resumed, Ordering::SeqCst);
+        self.inner.progress_event(summary.bytes as f64 / summary.duration.as_secs_f64().max(f64::EPSILON));
+        let verified = summary.verified.map_or("null".to_string(), |verified| verified.to_string());
+        self.inner.emit("download_finished", &format!(
+            ",\"bytes\":{},\"duration\":{:.3},\"hash_algo\":\"{}\",\"hash\":\"{}\",\"verified\":{},\"errors\":{}",
+            summary.bytes, summary.duration.as_secs_f64(), summary.hash_algo.name(), summary.hash, verified,
+            summary.errors.len()));
+    }
+}
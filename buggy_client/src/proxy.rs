@@ -0,0 +1,263 @@
+//! Picking an HTTP proxy the way curl does: `--proxy`, else the `http_proxy`
+//! or `all_proxy` variables, except for the hosts `no_proxy` lists.
+
+use std::net::IpAddr;
+
+use crate::http::percent_decode;
+use crate::message::format_authority;
+
+/// Port of a proxy URL that names none, as with curl.
+const DEFAULT_PROXY_PORT: u16 = 1080;
+
+/// An HTTP proxy, with the credentials it asks for if any.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Proxy {
+    /// Host name or address of the proxy.
+    pub(crate) host: String,
+    /// Port of the proxy, 1080 if the URL gives none.
+    pub(crate) port: u16,
+    /// User name and password from the URL, percent-decoded.
+    pub(crate) credentials: Option<(String, String)>,
+}
+
+impl Proxy {
+    /// Reads `[http://][user[:password]@]host[:port][/]`, with the
+    /// credentials percent-encoded. Other schemes are refused, as requests
+    /// go to the proxy in plain HTTP.
+    pub(crate) fn parse(url: &str) -> Result<Self, String> {
+        let rest = match url.split_once("://") {
+            None => url,
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => rest,
+            Some((scheme, _)) => {
+                return Err(format!("Unsupported proxy scheme '{}': only http:// proxies are supported", scheme));
+            }
+        };
+        let authority = rest.split_once('/').map_or(rest, |(authority, _)| authority);
+        let (userinfo, address) = match authority.rsplit_once('@') {
+            Some((userinfo, address)) => (Some(userinfo), address),
+            None => (None, authority),
+        };
+        // The credentials stay out of error messages.
+        let invalid = |reason: &str| format!("Invalid proxy '{}': {}", address, reason);
+        let credentials = match userinfo {
+            Some(userinfo) => {
+                let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                match (percent_decode(user), percent_decode(password)) {
+                    (Some(user), Some(password)) => Some((user, password)),
+                    _ => return Err(invalid("bad percent-encoding in the credentials")),
+                }
+            }
+            None => None,
+        };
+        let (host, port) = match address.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, rest) = bracketed.split_once(']').ok_or_else(|| invalid("unclosed '['"))?;
+                (host, rest.strip_prefix(':'))
+            }
+            None => match address.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (address, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse::<u16>().ok().filter(|&port| port != 0)
+                .ok_or_else(|| invalid("expected a port from 1 to 65535"))?,
+            None => DEFAULT_PROXY_PORT,
+        };
+        if host.is_empty() {
+            return Err(invalid("no host"));
+        }
+        Ok(Proxy { host: host.to_string(), port, credentials })
+    }
+
+    /// The proxy as `host:port`, without the credentials.
+    pub(crate) fn authority(&self) -> String {
+        format_authority(&self.host, self.port)
+    }
+
+    /// The `Proxy-Authorization` value for the credentials, if there are any.
+    pub(crate) fn authorization(&self) -> Option<String> {
+        let (user, password) = self.credentials.as_ref()?;
+        Some(format!("Basic {}", base64(format!("{}:{}", user, password).as_bytes())))
+    }
+}
+
+/// Hosts that bypass the proxy: `*` for all of them, names that also cover
+/// their subdomains with or without a leading dot, IP addresses and CIDR
+/// ranges such as `10.0.0.0/8`. Entries are separated by commas or spaces.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct NoProxy {
+    entries: Vec<(String, Rule)>,
+}
+
+#[derive(Clone, Debug)]
+enum Rule {
+    All,
+    Domain(String),
+    Network(IpAddr, u8),
+}
+
+impl NoProxy {
+    /// Parses a list in the format of the `NO_PROXY` variable.
+    pub(crate) fn parse(list: &str) -> Self {
+        let entries = list.split(|c: char| c == ',' || c.is_whitespace()).filter(|entry| !entry.is_empty())
+            .map(|entry| (entry.to_string(), Rule::parse(entry)))
+            .collect();
+        NoProxy { entries }
+    }
+
+    /// The entry that takes `host` off the proxy, if any.
+    pub(crate) fn matching(&self, host: &str) -> Option<&str> {
+        let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let address = host.parse::<IpAddr>().ok();
+        self.entries.iter().find(|(_, rule)| rule.matches(&host, address)).map(|(entry, _)| entry.as_str())
+    }
+}
+
+impl Rule {
+    fn parse(entry: &str) -> Self {
+        if entry == "*" {
+            return Rule::All;
+        }
+        let entry = entry.strip_prefix('[').and_then(|entry| entry.strip_suffix(']')).unwrap_or(entry);
+        if let Some((address, bits)) = entry.split_once('/') {
+            if let (Ok(address), Ok(bits)) = (address.parse::<IpAddr>(), bits.parse::<u8>()) {
+                let width = if address.is_ipv4() { 32 } else { 128 };
+                return Rule::Network(address, bits.min(width));
+            }
+        }
+        match entry.parse::<IpAddr>() {
+            Ok(address) => Rule::Network(address, if address.is_ipv4() { 32 } else { 128 }),
+            Err(_) => Rule::Domain(entry.trim_start_matches('.').trim_end_matches('.').to_ascii_lowercase()),
+        }
+    }
+
+    fn matches(&self, host: &str, address: Option<IpAddr>) -> bool {
+        match self {
+            Rule::All => true,
+            Rule::Domain(domain) => {
+                host == domain || host.strip_suffix(domain.as_str()).is_some_and(|rest| rest.ends_with('.'))
+            }
+            Rule::Network(network, bits) => match (address, network) {
+                (Some(IpAddr::V4(address)), IpAddr::V4(network)) => {
+                    same_prefix(u32::from(address).into(), u32::from(*network).into(), 32, *bits)
+                }
+                (Some(IpAddr::V6(address)), IpAddr::V6(network)) => {
+                    same_prefix(address.into(), (*network).into(), 128, *bits)
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+/// Whether the first `bits` of two `width` bit addresses agree.
+fn same_prefix(address: u128, network: u128, width: u8, bits: u8) -> bool {
+    let shift = u32::from(width - bits);
+    address.checked_shr(shift).unwrap_or(0) == network.checked_shr(shift).unwrap_or(0)
+}
+
+/// Which proxy the requests to each host go through: the one given with
+/// `--proxy` or else the one in the environment, unless the host is in the
+/// `--noproxy` list or else the `no_proxy` one.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ProxySettings {
+    /// The proxy, with where it came from.
+    proxy: Option<(Proxy, String)>,
+    /// The `https_proxy` variable when set, which never applies to plain HTTP.
+    https_proxy: Option<&'static str>,
+    /// Why the proxy in the environment cannot be used, when it cannot.
+    unusable: Option<String>,
+    no_proxy: Option<(NoProxy, String)>,
+}
+
+impl ProxySettings {
+    /// The settings in the process environment.
+    pub(crate) fn from_env() -> Self {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    /// The settings in the variables `var` looks up: `http_proxy`, else
+    /// `all_proxy`, and `no_proxy`, each in lower case before upper case.
+    /// Empty variables count as unset. A proxy that cannot be used, such as
+    /// a `socks5://` one, is left out, with a warning once a host would
+    /// have gone through it.
+    pub(crate) fn from_vars(var: impl Fn(&str) -> Option<String>) -> Self {
+        let lookup = |names: [&'static str; 2]| {
+            names.into_iter().find_map(|name| var(name).filter(|value| !value.is_empty()).map(|value| (name, value)))
+        };
+        let found = lookup(["http_proxy", "HTTP_PROXY"]).or_else(|| lookup(["all_proxy", "ALL_PROXY"]));
+        let (proxy, unusable) = match found {
+            Some((name, url)) => match Proxy::parse(&url) {
+                Ok(proxy) => (Some((proxy, name.to_string())), None),
+                Err(e) => (None, Some(format!("{} in {}", e, name))),
+            },
+            None => (None, None),
+        };
+        ProxySettings {
+            proxy,
+            https_proxy: lookup(["https_proxy", "HTTPS_PROXY"]).map(|(name, _)| name),
+            unusable,
+            no_proxy: lookup(["no_proxy", "NO_PROXY"]).map(|(name, list)| (NoProxy::parse(&list), name.to_string())),
+        }
+    }
+
+    /// Uses `url` whatever the environment says; an empty one turns the
+    /// proxy off.
+    pub(crate) fn proxy(mut self, url: &str) -> Result<Self, String> {
+        self.proxy = match url.is_empty() {
+            true => None,
+            false => Some((Proxy::parse(url)?, "--proxy".to_string())),
+        };
+        self.unusable = None;
+        Ok(self)
+    }
+
+    /// Replaces the `no_proxy` list with `list`.
+    pub(crate) fn no_proxy(mut self, list: &str) -> Self {
+        self.no_proxy = Some((NoProxy::parse(list), "--noproxy".to_string()));
+        self
+    }
+
+    /// The proxy for `host`, if any, and why.
+    pub(crate) fn select(&self, host: &str) -> (Option<&Proxy>, String) {
+        if self.proxy.is_some() || self.unusable.is_some() {
+            if let Some((no_proxy, list)) = &self.no_proxy {
+                if let Some(entry) = no_proxy.matching(host) {
+                    return (None, format!("Connecting to {} directly: it matches '{}' in {}", host, entry, list));
+                }
+            }
+        }
+        let Some((proxy, source)) = &self.proxy else {
+            let reason = match (&self.unusable, self.https_proxy) {
+                (Some(unusable), _) => {
+                    log::warn!("Ignoring the proxy from the environment: {}", unusable);
+                    format!("Connecting to {} directly: the proxy from the environment cannot be used", host)
+                }
+                (None, Some(name)) => {
+                    format!("Connecting to {} directly: {} only applies to https:// servers", host, name)
+                }
+                (None, None) => format!("Connecting to {} directly: no proxy configured", host),
+            };
+            return (None, reason);
+        };
+        (Some(proxy), format!("Connecting to {} through proxy {} from {}", host, proxy.authority(), source))
+    }
+}
+
+/// Standard base64 with padding, as Basic credentials need.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for group in bytes.chunks(3) {
+        let bits = group.iter().enumerate().fold(0u32, |bits, (i, &byte)| bits | u32::from(byte) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= group.len() {
+                true => encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 63) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,56 @@
+//! Aggregate download rate limiting.
+
+use std::sync::Mutex;
+#[cfg(not(feature = "async"))]
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[cfg(not(feature = "async"))]
+use crate::cancel::{CancellationToken, POLL_INTERVAL};
+
+/// Token bucket shared by all workers so the cap applies to the aggregate
+/// throughput regardless of the thread count.
+pub(crate) struct RateLimiter {
+    rate: f64,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: u64) -> Self {
+        RateLimiter {
+            rate: rate as f64,
+            bucket: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    /// Takes `bytes` tokens, sleeping until the bucket has refilled enough
+    /// or `cancel` fires.
+    #[cfg(not(feature = "async"))]
+    pub(crate) fn consume(&self, bytes: usize, cancel: &CancellationToken) {
+        let until = Instant::now() + self.reserve(bytes);
+        while !cancel.is_cancelled() {
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            thread::sleep(left.min(POLL_INTERVAL));
+        }
+    }
+
+    /// Takes `bytes` tokens and returns how long the caller must wait before
+    /// reading more.
+    pub(crate) fn reserve(&self, bytes: usize) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let (tokens, last) = &mut *bucket;
+        let now = Instant::now();
+        // Allow at most a quarter second of burst.
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.rate / 4.0);
+        *last = now;
+        *tokens -= bytes as f64;
+        if *tokens < 0.0 {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! The JSON run report written by `--report`, and the result `--json`
+//! prints.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use buggy_client::{ChunkError, HashAlgo, Summary};
+
+use crate::json::json_string;
+
+/// What one run did, written whether or not the download succeeded.
+pub struct Report<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub path: &'a str,
+    pub chunk_size: usize,
+    pub threads: usize,
+    /// `ok`, `incomplete`, `verification_failed`, `cancelled` or `failed`.
+    pub status: &'a str,
+    /// Missing when the download failed before producing a summary.
+    pub summary: Option<&'a Summary>,
+    /// Why the run failed, if it did.
+    pub error: Option<String>,
+    /// Also list the timing of every chunk.
+    pub chunk_timings: bool,
+}
+
+impl Report<'_> {
+    /// Writes the report to `path` as a single JSON object.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "{{")?;
+        writeln!(out, "  \"status\": {},", json_string(self.status))?;
+        writeln!(out, "  \"host\": {},", json_string(self.host))?;
+        writeln!(out, "  \"port\": {},", self.port)?;
+        writeln!(out, "  \"path\": {},", json_string(self.path))?;
+        writeln!(out, "  \"chunk_size\": {},", self.chunk_size)?;
+        writeln!(out, "  \"threads\": {},", self.threads)?;
+        let error = self.error.as_deref().map_or("null".to_string(), json_string);
+        match self.summary {
+            Some(summary) => {
+                writeln!(out, "  \"error\": {},", error)?;
+                write_summary(&mut out, summary, self.chunk_timings)?;
+            }
+            None => writeln!(out, "  \"error\": {}", error)?,
+        }
+        writeln!(out, "}}")?;
+        out.flush()
+    }
+}
+
+fn write_summary(out: &mut impl Write, summary: &Summary, chunk_timings: bool) -> io::Result<()> {
+    let seconds = summary.duration.as_secs_f64();
+    writeln!(out, "  \"bytes\": {},", summary.bytes)?;
+    writeln!(out, "  \"duration\": {:.3},", seconds)?;
+    writeln!(out, "  \"paused\": {:.3},", summary.paused.as_secs_f64())?;
+    writeln!(out, "  \"average_speed\": {:.0},", summary.bytes as f64 / seconds.max(f64::EPSILON))?;
+    writeln!(out, "  \"peak_speed\": {:.0},", summary.peak_speed)?;
+    writeln!(out, "  \"hash_algo\": \"{}\",", summary.hash_algo.name())?;
+    writeln!(out, "  \"hash\": \"{}\",", summary.hash)?;
+    let verified = summary.verified.map_or("null".to_string(), |verified| verified.to_string());
+    writeln!(out, "  \"verified\": {},", verified)?;
+    writeln!(out, "  \"attempts\": {},", summary.attempts)?;
+    writeln!(out, "  \"restarts\": {},", summary.restarts)?;
+    let missing: Vec<_> = summary.missing.iter().map(|range| format!("[{}, {}]", range.start, range.end)).collect();
+    writeln!(out, "  \"missing\": [{}],", missing.join(", "))?;
+    writeln!(out, "  \"repair_rounds\": {},", summary.repair_rounds)?;
+    writeln!(out, "  \"repaired_chunks\": {},", summary.repaired_chunks)?;
+    let mirrors: Vec<_> = summary.mirrors.iter().map(|mirror| {
+        let blacklisted = mirror.blacklisted.as_deref().map_or("null".to_string(), json_string);
+        format!("{{\"server\": {}, \"bytes\": {}, \"chunks\": {}, \"failures\": {}, \"blacklisted\": {}}}",
+                json_string(&mirror.authority()), mirror.bytes, mirror.chunks, mirror.failures, blacklisted)
+    }).collect();
+    writeln!(out, "  \"mirrors\": [{}],", mirrors.join(", "))?;
+
+    // Every error is one failed try, so counting them per chunk gives its retries.
+    let mut retries = BTreeMap::<usize, usize>::new();
+    for error in &summary.errors {
+        *retries.entry(error.chunk_id).or_default() += 1;
+    }
+    let retries: Vec<_> = retries.iter()
+        .map(|(chunk_id, count)| format!("\"{}\": {}", chunk_id, count))
+        .collect();
+    writeln!(out, "  \"chunk_retries\": {{{}}},", retries.join(", "))?;
+
+    let groups: Vec<_> = summary.error_groups().iter().map(|group| {
+        let ranges: Vec<_> = group.ranges.iter().map(|range| format!("[{}, {}]", range.start, range.end)).collect();
+        format!("{{\"cause\": \"{}\", \"description\": {}, \"count\": {}, \"ranges\": [{}]}}", group.cause.name(),
+                json_string(&group.cause.to_string()), group.count, ranges.join(", "))
+    }).collect();
+    writeln!(out, "  \"error_groups\": [{}],", groups.join(", "))?;
+
+    write!(out, "  \"errors\": [")?;
+    for (index, error) in summary.errors.iter().enumerate() {
+        let separator = if index == 0 { "" } else { "," };
+        write!(out, "{}\n    {{\"chunk\": {}, \"start\": {}, \"end\": {}, \"attempt\": {}, \"phase\": \"{}\", \
+                    \"cause\": \"{}\", \"error\": {}}}",
+               separator, error.chunk_id, error.range.start, error.range.end, error.attempt, error.phase.as_str(),
+               error.cause.name(), json_string(&error.message))?;
+    }
+    if !summary.errors.is_empty() {
+        write!(out, "\n  ")?;
+    }
+    write!(out, "]")?;
+
+    if chunk_timings {
+        write!(out, ",\n  \"chunks\": [")?;
+        for (index, timing) in summary.chunk_timings.iter().enumerate() {
+            let separator = if index == 0 { "" } else { "," };
+            write!(out, "{}\n    {{\"chunk\": {}, \"start\": {}, \"end\": {}, \"worker\": {}, \"started\": {:.3}, \
+                        \"duration\": {:.3}}}",
+                   separator, timing.chunk_id, timing.range.start, timing.range.end, timing.worker,
+                   timing.started.as_secs_f64(), timing.duration.as_secs_f64())?;
+        }
+        if !summary.chunk_timings.is_empty() {
+            write!(out, "\n  ")?;
+        }
+        write!(out, "]")?;
+    }
+    writeln!(out)
+}
+
+/// The one JSON object `--json` prints on stdout when a run is over. Scripts
+/// parse it, so its fields are frozen: new ones may be added, but none is
+/// renamed, removed or given another type.
+#[derive(Clone, Debug, Default)]
+pub struct RunResult {
+    /// As in [`Report::status`].
+    pub status: String,
+    pub exit_code: i32,
+    /// Why the run failed, if it did.
+    pub error: Option<String>,
+    /// Where the data was saved, if it was.
+    pub output: Option<String>,
+    pub bytes: usize,
+    pub duration: Duration,
+    /// The algorithm and digest of the data, unless it was not hashed or
+    /// has holes.
+    pub hash: Option<(HashAlgo, String)>,
+    /// Whether the hash matched the expected one, when one was given.
+    pub verified: Option<bool>,
+    pub chunks: ChunkCounts,
+    /// The last error of every chunk that was given up on.
+    pub errors: Vec<ChunkError>,
+}
+
+/// How the chunks of a download went, counting each chunk once however
+/// often it was tried.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChunkCounts {
+    pub total: usize,
+    /// Chunks received, on the first try or a retry.
+    pub succeeded: usize,
+    /// Chunks received after at least one failed try.
+    pub retried: usize,
+    /// Chunks given up on, which left bytes missing.
+    pub failed: usize,
+}
+
+impl RunResult {
+    /// The result of a run that ended with `summary`; the status, exit code
+    /// and output are left for the caller.
+    pub fn from_summary(summary: &Summary) -> Self {
+        let mut last_errors = BTreeMap::<usize, &ChunkError>::new();
+        for error in &summary.errors {
+            last_errors.insert(error.chunk_id, error);
+        }
+        let missed = |error: &ChunkError| {
+            summary.missing.iter().any(|range| range.start < error.range.end && error.range.start < range.end)
+        };
+        let errors: Vec<_> = last_errors.values().filter(|error| missed(error)).map(|&error| error.clone()).collect();
+        let mut failed: BTreeSet<_> = errors.iter().map(|error| error.chunk_id).collect();
+        // Bytes lost without an error were cut off the end of the response before them.
+        for range in &summary.missing {
+            if !errors.iter().any(|error| range.start < error.range.end && error.range.start < range.end) {
+                failed.extend(summary.chunk_timings.iter().filter(|timing| timing.range.end == range.start)
+                    .map(|timing| timing.chunk_id));
+            }
+        }
+        let succeeded: BTreeSet<_> = summary.chunk_timings.iter().map(|timing| timing.chunk_id)
+            .filter(|chunk_id| !failed.contains(chunk_id))
+            .collect();
+        let retried = succeeded.iter().filter(|chunk_id| last_errors.contains_key(chunk_id)).count();
+        RunResult {
+            bytes: summary.bytes,
+            duration: summary.duration,
+            // The hash of data with holes says nothing.
+            hash: (summary.hash_algo != HashAlgo::None && !summary.hash.is_empty() && summary.missing.is_empty()
+                   && !summary.timed_out)
+                .then(|| (summary.hash_algo, summary.hash.clone())),
+            verified: summary.verified,
+            chunks: ChunkCounts {
+                total: succeeded.len() + failed.len(),
+                succeeded: succeeded.len(),
+                retried,
+                failed: failed.len(),
+            },
+            errors,
+            ..RunResult::default()
+        }
+    }
+
+    /// The result as a single line of JSON.
+    pub fn to_json(&self) -> String {
+        let string_or_null = |value: Option<&str>| value.map_or("null".to_string(), json_string);
+        let seconds = self.duration.as_secs_f64();
+        let average = if seconds > 0.0 { (self.bytes as f64 / seconds).round() as u64 } else { 0 };
+        let verification = match self.verified {
+            Some(true) => "\"passed\"",
+            Some(false) => "\"failed\"",
+            None => "null",
+        };
+        let errors: Vec<_> = self.errors.iter().map(|error| {
+            format!("{{\"chunk\": {}, \"start\": {}, \"end\": {}, \"attempt\": {}, \"phase\": \"{}\", \
+                     \"cause\": \"{}\", \"error\": {}}}",
+                    error.chunk_id, error.range.start, error.range.end, error.attempt, error.phase.as_str(),
+                    error.cause.name(), json_string(&error.message))
+        }).collect();
+        format!("{{\"status\": {}, \"exit_code\": {}, \"error\": {}, \"output\": {}, \"bytes\": {}, \
+                 \"duration_ms\": {}, \"average_bps\": {}, \"hash_algorithm\": {}, \"hash\": {}, \
+                 \"verification\": {}, \"chunks\": {{\"total\": {}, \"succeeded\": {}, \"retried\": {}, \
+                 \"failed\": {}}}, \"errors\": [{}]}}",
+                json_string(&self.status), self.exit_code, string_or_null(self.error.as_deref()),
+                string_or_null(self.output.as_deref()), self.bytes, self.duration.as_millis(), average,
+                string_or_null(self.hash.as_ref().map(|(algo, _)| algo.name())),
+                string_or_null(self.hash.as_ref().map(|(_, hash)| hash.as_str())), verification, self.chunks.total,
+                self.chunks.succeeded, self.chunks.retried, self.chunks.failed, errors.join(", "))
+    }
+}
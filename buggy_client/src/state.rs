@@ -0,0 +1,148 @@
+//! The state file of a download into a part file: which blocks of it are on
+//! disk, so a run that was killed can be resumed without fetching them again.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+
+/// First line of every state file, followed by the format version.
+const MAGIC: &str = "buggy-client-state";
+const VERSION: u32 = 1;
+
+/// What a state file records: the resource it is for and a bitmap of the
+/// `chunk_size` blocks of the part file that hold their bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct DownloadState {
+    pub(crate) size: usize,
+    pub(crate) chunk_size: usize,
+    /// Where the downloaded window starts in the resource.
+    pub(crate) range_start: usize,
+    /// The ETag, else the Last-Modified date, the server sent.
+    pub(crate) validator: Option<String>,
+    pub(crate) blocks: Vec<bool>,
+}
+
+impl DownloadState {
+    /// The state of a download whose part file holds `received`, any ranges
+    /// in any order. Only blocks they cover whole are marked.
+    pub(crate) fn new(size: usize, chunk_size: usize, range_start: usize, validator: Option<String>,
+                      received: impl IntoIterator<Item = Range<usize>>) -> Self {
+        let mut received: Vec<_> = received.into_iter().collect();
+        received.sort_by_key(|range| range.start);
+        let mut covered: Vec<Range<usize>> = Vec::new();
+        for range in received {
+            match covered.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => covered.push(range),
+            }
+        }
+        let blocks = (0..size.div_ceil(chunk_size)).map(|index| {
+            let block = index * chunk_size..((index + 1) * chunk_size).min(size);
+            covered.iter().any(|range| range.start <= block.start && block.end <= range.end)
+        }).collect();
+        DownloadState { size, chunk_size, range_start, validator, blocks }
+    }
+
+    /// The byte ranges the marked blocks make up, merged where they touch.
+    pub(crate) fn ranges(&self) -> Vec<Range<usize>> {
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        for (index, _) in self.blocks.iter().enumerate().filter(|(_, &done)| done) {
+            let block = index * self.chunk_size..((index + 1) * self.chunk_size).min(self.size);
+            match ranges.last_mut() {
+                Some(last) if last.end == block.start => last.end = block.end,
+                _ => ranges.push(block),
+            }
+        }
+        ranges
+    }
+
+    fn render(&self) -> String {
+        let mut bitmap = vec![0_u8; self.blocks.len().div_ceil(8)];
+        for (index, _) in self.blocks.iter().enumerate().filter(|(_, &done)| done) {
+            bitmap[index / 8] |= 1 << (index % 8);
+        }
+        let bitmap: String = bitmap.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let mut text = format!("{} {}\nsize {}\nchunk_size {}\nrange_start {}\n", MAGIC, VERSION, self.size,
+                               self.chunk_size, self.range_start);
+        if let Some(validator) = &self.validator {
+            text.push_str(&format!("validator {}\n", validator));
+        }
+        text.push_str(&format!("blocks {}\n", bitmap));
+        text
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines();
+        match lines.next().and_then(|line| line.split_once(' ')) {
+            Some((MAGIC, version)) if version == VERSION.to_string() => {}
+            Some((MAGIC, version)) => return Err(format!("it has format version {}, not {}", version, VERSION)),
+            _ => return Err("it is not a state file".to_string()),
+        }
+        let fields: Vec<_> = lines.filter_map(|line| line.split_once(' ')).collect();
+        let field = |name: &str| fields.iter().find(|(key, _)| *key == name).map(|(_, value)| *value);
+        let number = |name: &str| {
+            field(name).and_then(|value| value.parse::<usize>().ok()).ok_or_else(|| format!("its {} is missing", name))
+        };
+        let (size, chunk_size, range_start) = (number("size")?, number("chunk_size")?, number("range_start")?);
+        if chunk_size == 0 {
+            return Err("its chunk size is 0".to_string());
+        }
+        let bitmap = field("blocks").ok_or("its blocks are missing")?;
+        let count = size.div_ceil(chunk_size);
+        if bitmap.len() != count.div_ceil(8) * 2 {
+            return Err(format!("it lists {} bytes of blocks for {} blocks", bitmap.len() / 2, count));
+        }
+        let bytes = (0..bitmap.len()).step_by(2)
+            .map(|index| bitmap.get(index..index + 2).and_then(|hex| u8::from_str_radix(hex, 16).ok()))
+            .collect::<Option<Vec<_>>>()
+            .ok_or("its blocks are not hex")?;
+        let blocks = (0..count).map(|index| bytes[index / 8] & (1 << (index % 8)) != 0).collect();
+        Ok(DownloadState { size, chunk_size, range_start, validator: field("validator").map(str::to_string), blocks })
+    }
+
+    /// Writes the state to `path` through a temporary name, synced before
+    /// and after the rename, so a crash or a power cut leaves either the old
+    /// state or the new one.
+    pub(crate) fn save(&self, path: &Path) -> io::Result<()> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".tmp");
+        let mut file = File::create(&partial)?;
+        file.write_all(self.render().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&partial, path)?;
+        sync_dir(path)
+    }
+
+    /// Reads the state at `path`: `None` if there is none, an error saying
+    /// why if it cannot be used.
+    pub(crate) fn load(path: &Path) -> Result<Option<Self>, String> {
+        match fs::read(path) {
+            Ok(bytes) => String::from_utf8(bytes).map_err(|_| "it is not a state file".to_string())
+                .and_then(|text| DownloadState::parse(&text)).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Removes the state file at `path`, if there is one.
+    pub(crate) fn remove(path: &Path) -> io::Result<()> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Syncs the directory holding `path`, so that a rename into it lasts.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    File::open(dir)?.sync_all()
+}
+
+/// Directories cannot be opened to be synced elsewhere.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,107 @@
+//! The connection to the server: TCP, or a Unix domain socket on Unix.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// Runs the same expression on whichever stream `$stream` holds.
+macro_rules! either {
+    ($stream:expr, $inner:ident => $body:expr) => {
+        match $stream {
+            Stream::Tcp($inner) => $body,
+            #[cfg(unix)]
+            Stream::Unix($inner) => $body,
+        }
+    };
+}
+
+impl Stream {
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        either!(self, stream => stream.set_read_timeout(timeout))
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        either!(self, stream => stream.set_write_timeout(timeout))
+    }
+
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        either!(self, stream => stream.set_nonblocking(nonblocking))
+    }
+
+    /// Reads without consuming. `UnixStream::peek` is not stable yet, so
+    /// Unix sockets go through `recv` with `MSG_PEEK`.
+    pub(crate) fn peek(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.peek(buffer),
+            #[cfg(unix)]
+            Stream::Unix(stream) => {
+                use std::os::unix::io::AsRawFd;
+                let read = unsafe {
+                    libc::recv(stream.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len(), libc::MSG_PEEK)
+                };
+                match read {
+                    -1 => Err(io::Error::last_os_error()),
+                    read => Ok(read as usize),
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn try_clone(&self) -> io::Result<Stream> {
+        Ok(either!(self, stream => stream.try_clone()?.into()))
+    }
+}
+
+impl From<TcpStream> for Stream {
+    fn from(stream: TcpStream) -> Self {
+        Stream::Tcp(stream)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixStream> for Stream {
+    fn from(stream: UnixStream) -> Self {
+        Stream::Unix(stream)
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        either!(*self, stream => (&*stream).read(buffer))
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        either!(*self, stream => (&*stream).write(buffer))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        either!(*self, stream => (&*stream).flush())
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buffer)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        (&*self).write(buffer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
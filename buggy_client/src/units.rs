@@ -0,0 +1,32 @@
+//! Sizes and rates with units, as the command line and config files give them.
+
+/// Parses a rate such as `500k` or `2MiB` into bytes per second.
+pub fn parse_rate(raw: &str) -> Result<u64, String> {
+    parse_scaled(raw, "rate")
+}
+
+/// Parses a size such as `512`, `64k` or `1MiB` into bytes. The unit is one
+/// of `b`, `k`, `m` and `g`, in any case, the last three also with `iB`.
+pub fn parse_size(raw: &str) -> Result<usize, String> {
+    parse_scaled(raw, "size").map(|bytes| bytes as usize)
+}
+
+fn parse_scaled(raw: &str, what: &str) -> Result<u64, String> {
+    let raw = raw.trim();
+    let (number, unit) = raw.split_at(raw.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(raw.len()));
+    let multiplier: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1024,
+        "m" | "mib" => 1024 * 1024,
+        "g" | "gib" => 1024 * 1024 * 1024,
+        _ => return Err(format!("Invalid {} '{}': expected a number with an optional k, m or g suffix, like 512, \
+                                 64k or 1MiB", what, raw)),
+    };
+    let value = number.parse::<f64>()
+        .map_err(|_| format!("Invalid {} '{}': expected a number with an optional k, m or g suffix, like 512, 64k \
+                              or 1MiB", what, raw))?;
+    if !value.is_finite() || value <= 0.0 {
+        return Err(format!("Invalid {} '{}': must be greater than zero", what, raw));
+    }
+    Ok(((value * multiplier as f64) as u64).max(1))
+}
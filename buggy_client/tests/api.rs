@@ -0,0 +1,103 @@
+//! Uses the library the way another crate would, through its exports alone:
+//! its own sink and observer, the summary's fields and the error variants.
+
+mod common;
+
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use buggy_client::{CancellationToken, Chunk, DownloadError, DownloadObserver, Downloader, ErrorCause, ErrorPhase,
+                   HashAlgo, SeekSink, Sink, StreamSink};
+use common::{test_data, Behavior, TestServer};
+use sha2::{Digest, Sha256};
+
+/// A sink of the caller's own that keeps each write as it came.
+#[derive(Default)]
+struct Pieces(Vec<(u64, Vec<u8>)>);
+
+impl Sink for Pieces {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        self.0.push((offset, data.to_vec()));
+        Ok(())
+    }
+}
+
+/// Counts the bytes the workers report.
+#[derive(Default)]
+struct Received(AtomicUsize);
+
+impl DownloadObserver for Received {
+    fn bytes_received(&self, _worker: usize, _chunk_id: usize, bytes: usize) {
+        self.0.fetch_add(bytes, Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn downloads_into_sinks_of_the_callers_own() {
+    let data = test_data(100_000);
+    let server = TestServer::start(data.clone(), Behavior::default());
+    let received = Arc::new(Received::default());
+    let downloader = Downloader::builder().port(server.port).chunk_size(16 * 1024).concurrency(3)
+        .hash_algo(HashAlgo::Sha256).observer(received.clone()).build().unwrap();
+
+    let mut pieces = Pieces::default();
+    let summary = downloader.download(&mut pieces).unwrap();
+    let mut assembled = vec![0; data.len()];
+    for (offset, bytes) in &pieces.0 {
+        assembled[*offset as usize..*offset as usize + bytes.len()].copy_from_slice(bytes);
+    }
+    assert_eq!(assembled, data);
+    assert_eq!(summary.bytes, data.len());
+    assert_eq!(summary.hash_algo, HashAlgo::Sha256);
+    assert_eq!(summary.hash, format!("{:x}", Sha256::digest(&data)));
+    assert!(summary.missing.is_empty() && summary.errors.is_empty() && summary.error_groups().is_empty());
+    assert_eq!(summary.mirrors[0].authority(), format!("127.0.0.1:{}", server.port));
+    assert!(received.0.load(Ordering::SeqCst) >= data.len());
+
+    let mut file = SeekSink(Cursor::new(Vec::new()));
+    downloader.download(&mut file).unwrap();
+    assert_eq!(file.0.into_inner(), data);
+    let mut stream = StreamSink::new(Vec::new());
+    assert_eq!(downloader.download(&mut stream).unwrap().hash, summary.hash);
+}
+
+#[test]
+fn yields_chunks_with_their_offsets() {
+    let data = test_data(100_000);
+    let server = TestServer::start(data.clone(), Behavior::default());
+    let downloader = Downloader::builder().port(server.port).chunk_size(16 * 1024).hash_algo(HashAlgo::None)
+        .user_agent("embedder/1.0").build().unwrap();
+
+    let mut chunks = downloader.chunks_ordered();
+    let mut assembled = Vec::new();
+    for chunk in chunks.by_ref() {
+        let Chunk { offset, data, .. } = chunk.unwrap();
+        assert_eq!(offset, assembled.len());
+        assembled.extend(data);
+    }
+    assert_eq!(assembled, data);
+    assert_eq!(chunks.summary().unwrap().bytes, data.len());
+    assert!(server.requests().iter().all(|request| request.contains("\r\nUser-Agent: embedder/1.0\r\n")));
+}
+
+#[test]
+fn errors_are_told_apart_by_variant() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let error = Downloader::builder().port(port).connect_retries(0).fail_fast(true)
+        .build().unwrap().download(&mut Vec::new()).unwrap_err();
+    let failed = error.chunk_error().unwrap();
+    assert_eq!((failed.phase, failed.cause), (ErrorPhase::Connect, ErrorCause::ConnectRefused));
+    match error {
+        DownloadError::ChunkFailed { phase: ErrorPhase::Connect, attempt: 1, .. } => {}
+        error => panic!("{}", error),
+    }
+
+    let cancel = CancellationToken::new();
+    cancel.cancel();
+    let server = TestServer::start(test_data(1000), Behavior::default());
+    let error = Downloader::builder().port(server.port).cancellation(cancel).build().unwrap()
+        .download(&mut Vec::new()).unwrap_err();
+    assert!(matches!(error, DownloadError::Cancelled(_)), "{}", error);
+    assert_eq!(error.cause(), ErrorCause::Other);
+}
@@ -0,0 +1,1008 @@
+//! Runs the command line client: in dry-run mode to check how options are
+//! resolved from the environment and config files, and against failing
+//! servers to check its exit codes. The parsers it keeps out of the library
+//! are compiled in from their sources and tested directly.
+
+mod common;
+#[path = "../src/checkpoint_log.rs"]
+mod checkpoint_log;
+#[path = "../src/checksum.rs"]
+mod checksum;
+#[path = "../src/manifest.rs"]
+mod manifest;
+#[path = "../src/units.rs"]
+mod units;
+
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use buggy_client::Downloader;
+use checkpoint_log::{find_divergence, write_checkpoint_log};
+use checksum::parse_checksum_file;
+use common::{test_data, Behavior, TestServer};
+use manifest::parse_manifest;
+use sha2::{Digest, Sha256};
+use units::{parse_rate, parse_size};
+
+/// An empty directory to run in, so no config file is picked up.
+fn work_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("buggy-client-cli-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn client(dir: &PathBuf) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_buggy_client"));
+    command.current_dir(dir).env("XDG_CONFIG_HOME", dir);
+    for (name, _) in std::env::vars_os() {
+        if name.to_string_lossy().starts_with("BUGGY_CLIENT_") {
+            command.env_remove(name);
+        }
+    }
+    command
+}
+
+fn dry_run(dir: &PathBuf) -> Command {
+    let mut command = client(dir);
+    command.arg("--dry-run");
+    command
+}
+
+fn stdout(output: &Output) -> String {
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn environment_variables_set_options() {
+    let server = TestServer::start(test_data(100_000), Behavior::default());
+    let dir = work_dir("env");
+
+    let output = dry_run(&dir).env("BUGGY_CLIENT_PORT", server.port.to_string())
+        .env("BUGGY_CLIENT_THREADS", "3").env("BUGGY_CLIENT_CHUNK_SIZE", "16").env("BUGGY_CLIENT_FORCE", "true")
+        .output().unwrap();
+
+    let text = stdout(&output);
+    assert!(text.contains("Chunks: 7 of 16384 bytes"), "{}", text);
+    assert!(text.contains("Threads: 3,"), "{}", text);
+    assert!(text.contains("threads = 3 (environment BUGGY_CLIENT_THREADS)"), "{}", text);
+    assert!(text.contains("force = true (environment BUGGY_CLIENT_FORCE)"), "{}", text);
+    assert!(text.contains("min-chunk-size = 16 (default)"), "{}", text);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn the_command_line_beats_the_environment_which_beats_the_config_file() {
+    let server = TestServer::start(test_data(100_000), Behavior::default());
+    let dir = work_dir("precedence");
+    std::fs::write(dir.join("buggy-client.toml"), "threads = 2\nchunk_size = 8\nmax_threads = 6\n").unwrap();
+
+    let output = dry_run(&dir).env("BUGGY_CLIENT_PORT", server.port.to_string())
+        .env("BUGGY_CLIENT_THREADS", "5").env("BUGGY_CLIENT_CHUNK_SIZE", "32").arg("--chunk-size=64")
+        .output().unwrap();
+
+    let text = stdout(&output);
+    assert!(text.contains("chunk-size = 64 (command line)"), "{}", text);
+    assert!(text.contains("threads = 5 (environment BUGGY_CLIENT_THREADS)"), "{}", text);
+    assert!(text.contains("max-threads = 6 (buggy-client.toml:3)"), "{}", text);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn environment_values_are_validated_like_arguments() {
+    let dir = work_dir("invalid");
+
+    let from_env = dry_run(&dir).env("BUGGY_CLIENT_PORT", "notaport").output().unwrap();
+    let from_args = dry_run(&dir).args(["--port", "notaport"]).output().unwrap();
+
+    assert!(!from_env.status.success());
+    assert!(String::from_utf8_lossy(&from_env.stderr)
+        .contains("Invalid value \"notaport\" for '--port <PORT>': expected a port from 1 to 65535"));
+    assert_eq!(from_env.stderr, from_args.stderr);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn rejects_out_of_range_values_naming_the_option() {
+    let dir = work_dir("range");
+    let cases: [(&[&str], &str); 10] = [
+        (&["--threads", "0"], "for '--threads <NUM>': expected a whole number of at least 1"),
+        (&["--chunk-size", "0"], "for '--chunk-size <SIZE>': must be from 1 KiB to 1 GiB"),
+        (&["--chunk-size", "0.5k"], "for '--chunk-size <SIZE>': must be from 1 KiB to 1 GiB"),
+        (&["--chunk-size", "10000000"], "for '--chunk-size <SIZE>': must be from 1 KiB to 1 GiB"),
+        (&["--chunk-size", "64KB7"], "a size like 512k or 1MiB, from 1 KiB to 1 GiB"),
+        (&["--port", "0"], "for '--port <PORT>': expected a port from 1 to 65535"),
+        (&["--stats-interval", "0s"], "for '--stats-interval <SECS>': must be more than zero"),
+        (&["--min-chunk-size", "8"], "--adaptive-chunks"),
+        (&["--range", "2m-1m"], "for '--range <START-END>': the end must come after the start"),
+        (&["--range", "100k"], "for '--range <START-END>': expected START-END"),
+    ];
+    for (args, expected) in cases {
+        let output = dry_run(&dir).args(args).output().unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(2), "{:?}: {}", args, stderr);
+        assert!(stderr.contains(expected), "{:?}: {}", args, stderr);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn reads_sizes_and_durations_with_units() {
+    let server = TestServer::start(test_data(100_000), Behavior::default());
+    let dir = work_dir("units");
+    std::fs::write(dir.join("buggy-client.toml"), "chunk_size = \"32k\"\nstats_interval = \"500ms\"\n\
+                                                     first_byte_timeout = \"none\"\n").unwrap();
+
+    let output = dry_run(&dir).args(["--port", &server.port.to_string()]).output().unwrap();
+
+    let text = stdout(&output);
+    assert!(text.contains("Chunks: 4 of 32768 bytes"), "{}", text);
+    assert!(text.contains("stats-interval = 500ms (buggy-client.toml:2)"), "{}", text);
+    assert!(text.contains("first-byte-timeout = none (buggy-client.toml:3)"), "{}", text);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn reads_every_kind_of_config_value() {
+    let server = TestServer::start(test_data(100_000), Behavior::default());
+    let dir = work_dir("config-values");
+    std::fs::write(dir.join("buggy-client.toml"), "\u{feff}# defaults\n\
+                                                   threads = 8\n\
+                                                   \n\
+                                                   chunk_size = 1_024  # KiB\n\
+                                                   limit-rate = 1.5\n\
+                                                   force = true\n\
+                                                   output = \"out\\\\file \\\"x\\\"\"\n\
+                                                   user-agent = 'C:\\raw'\n\
+                                                   header = [\"A: 1\", 'B: 2', ]\n\
+                                                   \"quoted key\" = false\n").unwrap();
+
+    let output = dry_run(&dir).args(["--port", &server.port.to_string()]).output().unwrap();
+    let text = stdout(&output);
+    for line in ["threads = 8 (buggy-client.toml:2)", "chunk-size = 1024 (buggy-client.toml:4)",
+                 "limit-rate = 1.5 (buggy-client.toml:5)", "force = true (buggy-client.toml:6)",
+                 "output = out\\file \"x\" (buggy-client.toml:7)", "user-agent = C:\\raw (buggy-client.toml:8)",
+                 "header = A: 1, B: 2 (buggy-client.toml:9)"] {
+        assert!(text.contains(line), "{}", text);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("ignoring unknown key 'quoted key' (line 10)"), "{}", stderr);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn reports_the_line_of_each_config_error() {
+    let dir = work_dir("config-errors");
+    let cases = [
+        ("threads = 8\n[download]\n", "line 2: 'download' is a table; tables are not supported"),
+        ("a.b = 1\n", "line 1: 'a' is a table"),
+        ("header = [\"A: 1\",\n  \"B: 2\"]\nproxy = { url = \"x\" }\n", "line 3: 'proxy' is a table"),
+        ("when = 1979-05-27\n", "line 1: 'when' is a date; put it in quotes"),
+        ("threads = 8\nthreads = 9\n", "line 2: duplicate key"),
+        ("output = out.bin\n", "line 1: string values must be quoted"),
+        ("\n\nthreads\n", "line 3: key with no value, expected `=`"),
+        ("output = \"out.bin\n", "line 1: invalid basic string"),
+        ("header = [\"A: 1\",\n", "line 1: unclosed array"),
+        ("output = \"\\q\"\n", "line 1: missing escaped value"),
+    ];
+    for (text, expected) in cases {
+        std::fs::write(dir.join("buggy-client.toml"), text).unwrap();
+        let output = dry_run(&dir).output().unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(2), "{:?} gave {}", text, stderr);
+        assert!(stderr.contains(&format!("buggy-client.toml: {}", expected)), "{:?} gave {}", text, stderr);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn an_empty_config_file_sets_nothing() {
+    let server = TestServer::start(test_data(100_000), Behavior::default());
+    let dir = work_dir("config-empty");
+    for text in ["", "# nothing here\n\n   \n"] {
+        std::fs::write(dir.join("buggy-client.toml"), text).unwrap();
+        let text = stdout(&dry_run(&dir).args(["--port", &server.port.to_string()]).output().unwrap());
+        assert!(!text.contains("(buggy-client.toml:"), "{}", text);
+        assert!(text.contains("threads = 4 (default)"), "{}", text);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+
+/// Downloads from `server` into `download.bin` in `dir` with `args`,
+/// returning the exit code and stderr.
+fn download(dir: &PathBuf, port: u16, args: &[&str]) -> (Option<i32>, String) {
+    let output = client(dir).args(["--port", &port.to_string(), "-o", "download.bin"]).args(args).output().unwrap();
+    (output.status.code(), String::from_utf8_lossy(&output.stderr).into_owned())
+}
+
+#[test]
+fn exits_with_2_for_invalid_options() {
+    let server = TestServer::start(test_data(100_000), Behavior::default());
+    let dir = work_dir("exit-usage");
+    std::fs::write(dir.join("bad.toml"), "threads = \"many\"\n").unwrap();
+
+    assert_eq!(download(&dir, server.port, &["--threads", "0"]).0, Some(2));
+    assert_eq!(download(&dir, server.port, &["--speed-time", "5"]).0, Some(2));
+    assert_eq!(download(&dir, server.port, &["--speed-limit", "100k", "--limit-rate", "10k"]).0, Some(2));
+    assert_eq!(download(&dir, server.port, &["--config", "bad.toml"]).0, Some(2));
+    assert_eq!(download(&dir, server.port, &["--verify", "abcd"]).0, Some(2));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn exits_with_3_when_the_server_is_unreachable() {
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let dir = work_dir("exit-connect");
+
+    let (code, stderr) = download(&dir, port, &["--connect-retries", "0", "--repair-rounds", "0"]);
+    assert_eq!(code, Some(3), "{}", stderr);
+    assert!(stderr.contains("Could not connect to 127.0.0.1:"), "{}", stderr);
+
+    // Running out of retry budget while connecting is the same failure.
+    let (code, stderr) = download(&dir, port, &["--connect-retries", "1", "--retry-budget", "0"]);
+    assert_eq!(code, Some(3), "{}", stderr);
+    assert!(!dir.join("download.bin").exists());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn exits_with_4_when_ranges_are_missing() {
+    let behavior = Behavior { drop_mid_body_every: Some(1), ..Behavior::default() };
+    let server = TestServer::start(test_data(100_000), behavior);
+    let dir = work_dir("exit-incomplete");
+
+    let (code, stderr) = download(&dir, server.port, &["--report", "report.json", "--repair-rounds", "0"]);
+    assert_eq!(code, Some(4), "{}", stderr);
+    assert!(stderr.contains("Download incomplete:"), "{}", stderr);
+    assert!(!dir.join("download.bin").exists());
+    let report = std::fs::read_to_string(dir.join("report.json")).unwrap();
+    assert!(report.contains("\"status\": \"incomplete\""), "{}", report);
+    assert!(report.contains("\"error_groups\": ["), "{}", report);
+    // The missing bytes come with commands that fetch them alone.
+    assert!(stderr.contains("Still missing: "), "{}", stderr);
+    assert!(stderr.contains(&format!(" --host 127.0.0.1:{} --range ", server.port)), "{}", stderr);
+
+    // A chunk that runs out of retries with --fail-fast ends the download at once.
+    let server = TestServer::start(test_data(100_000), Behavior { drop_every: Some(1), ..Behavior::default() });
+    let (code, stderr) = download(&dir, server.port, &["--fail-fast", "--report", "missing/report.json"]);
+    assert_eq!(code, Some(4), "{}", stderr);
+    assert!(stderr.contains("Cannot write report 'missing/report.json'"), "{}", stderr);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn does_not_publish_a_manifest_file_with_missing_ranges() {
+    let data = test_data(100_000);
+    let dir = work_dir("manifest-incomplete");
+    std::fs::write(dir.join("files.txt"), "/a.bin a.bin\n").unwrap();
+
+    let broken = TestServer::start(data.clone(), Behavior { drop_mid_body_every: Some(1), ..Behavior::default() });
+    let output = client(&dir).args(["--port", &broken.port.to_string(), "--manifest", "files.txt",
+                                    "--repair-rounds", "0"]).output().unwrap();
+    let table = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(table.contains("a.bin") && table.contains("FAILED") && table.contains("Download incomplete:"), "{}", table);
+    assert!(!dir.join("a.bin").exists());
+
+    let server = TestServer::start(data.clone(), Behavior::default());
+    let output = client(&dir).args(["--port", &server.port.to_string(), "--manifest", "files.txt"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(std::fs::read(dir.join("a.bin")).unwrap(), data);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn exits_with_5_when_verification_fails() {
+    let server = TestServer::start(test_data(100_000), Behavior::default());
+    let dir = work_dir("exit-verify");
+
+    let (code, stderr) = download(&dir, server.port, &["--verify", &"0".repeat(64)]);
+    assert_eq!(code, Some(5), "{}", stderr);
+    assert!(stderr.contains("Checksum verification failed"), "{}", stderr);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn exits_with_6_when_the_output_cannot_be_written() {
+    let server = TestServer::start(test_data(100_000), Behavior::default());
+    let dir = work_dir("exit-output");
+
+    std::fs::write(dir.join("blocker"), "a file, not a directory").unwrap();
+
+    let output = client(&dir).args(["--port", &server.port.to_string(), "-o", "blocker/download.bin"])
+        .output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(6), "{}", stderr);
+    let resolved = dir.join("blocker/download.bin");
+    assert!(stderr.contains(&format!("Cannot write to '{}'", resolved.display())), "{}", stderr);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn creates_the_directories_of_the_output() {
+    let data = test_data(100_000);
+    let server = TestServer::start(data.clone(), Behavior::default());
+    let dir = work_dir("output-dirs");
+
+    let output = client(&dir).args(["--port", &server.port.to_string(), "-o", "results/today/download.bin"])
+        .output().unwrap();
+    stdout(&output);
+    assert_eq!(std::fs::read(dir.join("results/today/download.bin")).unwrap(), data);
+    // The file that checked the directory takes new files is gone.
+    let left: Vec<_> = std::fs::read_dir(dir.join("results/today")).unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    assert!(left.iter().all(|name| !name.contains(".tmp-")), "{:?}", left);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn keeps_the_part_file_of_a_failed_download_and_resumes_it() {
+    let data = test_data(200_000);
+    let server = TestServer::start(data.clone(), Behavior { slow: Some(Duration::from_millis(20)),
+                                                            ..Behavior::default() });
+    let dir = work_dir("resume");
+
+    let (code, stderr) = download(&dir, server.port, &["--chunk-size", "16k", "--threads", "2", "--max-time", "1"]);
+    assert_eq!(code, Some(4), "{}", stderr);
+    assert!(dir.join("download.bin.part").exists() && dir.join("download.bin.state").exists());
+    assert!(!dir.join("download.bin").exists());
+
+    let output = client(&dir).args(["--port", &server.port.to_string(), "-o", "download.bin", "--chunk-size", "16k"])
+        .output().unwrap();
+    let stdout = stdout(&output);
+    assert!(stdout.contains("Resumed: "), "{}", stdout);
+    assert_eq!(std::fs::read(dir.join("download.bin")).unwrap(), data);
+    assert!(!dir.join("download.bin.part").exists() && !dir.join("download.bin.state").exists());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn sends_the_user_agent_given() {
+    let server = TestServer::start(test_data(50_000), Behavior::default());
+    let dir = work_dir("user-agent");
+    let (code, stderr) = download(&dir, server.port, &["--user-agent", "fetcher/2.1 (ci)"]);
+    assert_eq!(code, Some(0), "{}", stderr);
+    assert!(server.requests().iter().all(|request| request.contains("\r\nUser-Agent: fetcher/2.1 (ci)\r\n")),
+            "{:?}", server.requests());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn checks_the_headers_given_with_h() {
+    let server = TestServer::start(test_data(50_000), Behavior::default());
+    let dir = work_dir("headers");
+    for (header, error) in [
+        ("X-Test: a\r\nInjected: 1", "must not contain CR or LF"),
+        ("X-Test\nInjected: 1", "must not contain CR or LF"),
+        ("X-Test", "expected \"Name: value\""),
+        (": 1", "bad header name"),
+        ("Bad Name: 1", "bad header name"),
+        ("Bad@Name: 1", "Invalid header name 'Bad@Name'"),
+        ("Range: bytes=0-1", "The Range header is set by the client"),
+    ] {
+        let (code, stderr) = download(&dir, server.port, &["-H", header]);
+        assert_eq!(code, Some(2), "{:?}: {}", header, stderr);
+        assert!(stderr.contains(error), "{:?}: {}", header, stderr);
+    }
+    assert!(server.requests().is_empty(), "{:?}", server.requests());
+
+    let (code, stderr) = download(&dir, server.port, &["-H", "Host: override.example", "-H", "Connection: close",
+                                                        "-H", "X-Trace: a:b"]);
+    assert_eq!(code, Some(0), "{}", stderr);
+    assert!(stderr.contains("Overriding default Host header with 'override.example'"), "{}", stderr);
+    assert!(stderr.contains("Overriding default Connection header with 'close'"), "{}", stderr);
+    for request in server.requests() {
+        assert!(request.contains("\r\nHost: override.example\r\n"), "{}", request);
+        assert!(request.contains("\r\nConnection: close\r\n"), "{}", request);
+        assert!(request.contains("\r\nX-Trace: a:b\r\n"), "{}", request);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn a_second_run_on_the_same_output_waits_for_the_first_or_exits_with_75() {
+    let data = test_data(300_000);
+    let slow = Behavior { slow: Some(Duration::from_millis(5)), etag: true, ..Behavior::default() };
+    let server = TestServer::start(data.clone(), slow.clone());
+    let other = TestServer::start(data.clone(), Behavior::default());
+    let dir = work_dir("lock");
+    let args = |port: u16| ["--port".to_string(), port.to_string(), "-o".to_string(), "download.bin".to_string()];
+
+    let alone = TestServer::start(data.clone(), slow);
+    stdout(&client(&dir).args(args(alone.port)).arg("--force").output().unwrap());
+    std::fs::remove_file(dir.join("download.bin")).unwrap();
+
+    let first = client(&dir).args(args(server.port)).stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap();
+    while !std::fs::read_to_string(dir.join("download.bin.lock")).is_ok_and(|text| text.contains("run_id=")) {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let same = client(&dir).args(args(server.port)).stdout(Stdio::piped()).spawn().unwrap();
+    // Another source for the same output is turned away without a request.
+    let output = client(&dir).args(args(other.port)).output().unwrap();
+    assert_eq!(output.status.code(), Some(75), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is locked by another download (pid "));
+    assert!(other.requests().is_empty());
+
+    assert_eq!(first.wait_with_output().unwrap().status.code(), Some(0));
+    let text = stdout(&same.wait_with_output().unwrap());
+    assert!(text.contains("'download.bin' was downloaded by another run of the same source"), "{}", text);
+    assert_eq!(std::fs::read(dir.join("download.bin")).unwrap(), data);
+    // Only the first run transferred anything; the second asked whether
+    // what it published is current.
+    let requests = server.requests();
+    assert_eq!(requests.len(), alone.requests().len() + 1);
+    assert!(requests[0].starts_with("GET / HTTP/1.1\r\n"), "{:?}", requests);
+    assert!(requests.last().unwrap().contains("\r\nIf-None-Match: \"v1\"\r\n"), "{:?}", requests);
+
+    // A held lock is never taken over, even when its pid looks gone, as one
+    // from another pid namespace does.
+    let lock = std::fs::File::create(dir.join("held.bin.lock")).unwrap();
+    lock.try_lock().unwrap();
+    std::fs::write(dir.join("held.bin.lock"), "pid=99999999\nrun_id=elsewhere\nsource=example.com:80/\n").unwrap();
+    let output = client(&dir).args(["--port", &other.port.to_string(), "-o", "held.bin"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(75), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!dir.join("held.bin").exists());
+    drop(lock);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn a_lock_is_only_trusted_once_its_holder_says_who_it_is_and_publishes_the_file() {
+    let data = test_data(50_000);
+    let server = TestServer::start(data.clone(), Behavior::default());
+    let dir = work_dir("lock-holder");
+    let run = |output: &str, wait: &str| {
+        client(&dir).args(["--port", &server.port.to_string(), "-o", output, "--wait-for-lock", wait]).output()
+            .unwrap()
+    };
+
+    // A holder that never writes its metadata is given up on at the deadline.
+    let lock = std::fs::File::create(dir.join("empty.bin.lock")).unwrap();
+    lock.try_lock().unwrap();
+    let started = std::time::Instant::now();
+    let output = run("empty.bin", "1");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(75), "{}", stderr);
+    assert!(stderr.contains("'empty.bin' is locked by another download (which has not written who it is)"),
+            "{}", stderr);
+    assert!(started.elapsed() < Duration::from_secs(10));
+    let output = client(&dir).args(["--port", &server.port.to_string(), "-o", "empty.bin"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(75), "{}", String::from_utf8_lossy(&output.stderr));
+    drop(lock);
+    assert!(server.requests().is_empty());
+
+    // A run of the same source that leaves nothing, or a file that is not
+    // what the server has, is not taken for a published download.
+    let source = format!("source=127.0.0.1:{}/\n", server.port);
+    for (name, left) in [("failed.bin", None), ("wrong.bin", Some(&data[..1000]))] {
+        let lock = std::fs::File::create(dir.join(format!("{}.lock", name))).unwrap();
+        lock.try_lock().unwrap();
+        std::fs::write(dir.join(format!("{}.lock", name)), format!("pid=1\nrun_id=gone\n{}", source)).unwrap();
+        let holder = thread::spawn({
+            let path = dir.join(name);
+            let left = left.map(<[u8]>::to_vec);
+            move || {
+                thread::sleep(Duration::from_millis(500));
+                if let Some(left) = left {
+                    std::fs::write(path, left).unwrap();
+                }
+                drop(lock);
+            }
+        });
+        let output = run(name, "10");
+        holder.join().unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+        assert!(stdout.contains("Another run (pid 1) is downloading the same content"), "{}", stdout);
+        assert!(!stdout.contains("was downloaded by another run"), "{}", stdout);
+        assert_eq!(std::fs::read(dir.join(name)).unwrap(), data);
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn an_unusable_proxy_in_the_environment_is_ignored() {
+    let data = test_data(50_000);
+    let server = TestServer::start(data.clone(), Behavior::default());
+    let dir = work_dir("env-proxy");
+    let run = |args: &[&str]| {
+        client(&dir).args(["--port", &server.port.to_string(), "-o", "download.bin", "--force"]).args(args)
+            .env_remove("http_proxy").env_remove("HTTP_PROXY").env("all_proxy", "socks5://x").output().unwrap()
+    };
+
+    let output = run(&["--noproxy", "*"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr);
+    assert!(!stderr.contains("socks5"), "{}", stderr);
+    assert_eq!(std::fs::read(dir.join("download.bin")).unwrap(), data);
+
+    let output = run(&[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr);
+    assert!(stderr.contains("Ignoring the proxy from the environment: Unsupported proxy scheme 'socks5'"),
+            "{}", stderr);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn takes_the_proxy_from_the_environment_in_curls_order() {
+    let data = test_data(50_000);
+    let proxy = TestServer::start(data, Behavior { proxy_for: Some("http://localhost:1/"), ..Behavior::default() });
+    let dir = work_dir("env-proxy-order");
+    // Nothing listens on port 1, so only a download through the proxy succeeds.
+    let run = |args: &[&str], no_proxy: &str| {
+        let output = client(&dir).args(["-h", "localhost", "-p", "1", "-o", "download.bin", "--force", "--verbose",
+                                        "--connect-retries", "0", "--fail-fast"]).args(args)
+            .env("http_proxy", format!("http://127.0.0.1:{}", proxy.port)).env("HTTP_PROXY", "http://127.0.0.1:1")
+            .env("all_proxy", "http://127.0.0.1:1").env("no_proxy", no_proxy).env_remove("NO_PROXY")
+            .output().unwrap();
+        (output.status.code(), String::from_utf8_lossy(&output.stderr).into_owned())
+    };
+
+    let (code, stderr) = run(&[], "other.example");
+    assert_eq!(code, Some(0), "{}", stderr);
+    assert!(stderr.contains(&format!("through proxy 127.0.0.1:{} from http_proxy", proxy.port)), "{}", stderr);
+    let (code, stderr) = run(&[], "localhost");
+    assert_ne!(code, Some(0), "{}", stderr);
+    assert!(stderr.contains("it matches 'localhost' in no_proxy"), "{}", stderr);
+    // --noproxy replaces no_proxy, and --proxy the variables.
+    assert_eq!(run(&["--noproxy", "other.example"], "localhost").0, Some(0));
+    assert_ne!(run(&["--proxy", ""], "other.example").0, Some(0));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn exits_with_65_when_the_file_is_too_large() {
+    let server = TestServer::start(test_data(100_000), Behavior::default());
+    let dir = work_dir("exit-other");
+    assert_eq!(download(&dir, server.port, &["--max-size", "10k", "--probe-size"]).0, Some(65));
+    // A report that cannot be written leaves the exit code to the download error.
+    let (code, stderr) = download(&dir, server.port, &["--max-size", "10k", "--report", "missing/report.json"]);
+    assert_eq!(code, Some(65), "{}", stderr);
+    assert!(stderr.contains("Cannot write report 'missing/report.json'"), "{}", stderr);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[cfg(unix)]
+#[test]
+fn exits_with_130_when_interrupted() {
+    let dir = work_dir("exit-interrupt");
+    let slow = Behavior { slow: Some(Duration::from_millis(5)), ..Behavior::default() };
+    let server = TestServer::start(test_data(1_000_000), slow);
+    let mut child = client(&dir).args(["--port", &server.port.to_string(), "-o", "download.bin"])
+        .stdout(Stdio::null()).stderr(Stdio::null()).spawn().unwrap();
+    thread::sleep(Duration::from_millis(500));
+    let status = Command::new("kill").args(["-INT", &child.id().to_string()]).status().unwrap();
+    assert!(status.success());
+    assert_eq!(child.wait().unwrap().code(), Some(130));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn merge_assembles_saved_chunks_and_lists_what_is_missing() {
+    let data = test_data(100_000);
+    let server = TestServer::start(data.clone(), Behavior::default());
+    let dir = work_dir("merge");
+    std::fs::write(dir.join("buggy-client.toml"), "hash_algo = \"md5\"\n").unwrap();
+    let port = server.port.to_string();
+    let output = client(&dir).args(["--port", &port, "--chunk-size", "16", "--chunks-dir", "chunks"]).output().unwrap();
+    stdout(&output);
+
+    let output = client(&dir).args(["merge", "chunks", "-o", "merged.bin", "--hash-algo", "sha256", "--verify",
+                                     &"0".repeat(64)]).output().unwrap();
+    assert_eq!(output.status.code(), Some(5), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(!dir.join("merged.bin").exists());
+    // The hash algorithm comes from the config file unless the merge names one.
+    let text = stdout(&client(&dir).args(["merge", "chunks", "-o", "merged.bin"]).output().unwrap());
+    assert!(text.contains("Merged 7 chunks, 100000 bytes") && text.contains("MD5 hash:"), "{}", text);
+    assert_eq!(std::fs::read(dir.join("merged.bin")).unwrap(), data);
+
+    std::fs::remove_file(dir.join("chunks/chunk-4-65536-81920.bin")).unwrap();
+    let output = client(&dir).args(["merge", "chunks", "-o", "again.bin"]).output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(4), "{}", stderr);
+    assert!(stderr.contains("Missing bytes 65536-81920 (16384 bytes)"), "{}", stderr);
+    assert!(stderr.contains(&format!("--host 127.0.0.1:{} --chunks-dir chunks", port)), "{}", stderr);
+    assert!(!dir.join("again.bin").exists());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn skips_an_output_that_is_still_up_to_date() {
+    let server = TestServer::start(test_data(100_000), Behavior { etag: true, ..Behavior::default() });
+    let dir = work_dir("up-to-date");
+
+    assert_eq!(download(&dir, server.port, &[]).0, Some(0));
+    assert_eq!(std::fs::read_to_string(dir.join("download.bin.validators")).unwrap(), "ETag: \"v1\"\n");
+    let output = client(&dir).args(["--port", &server.port.to_string(), "-o", "download.bin"]).output().unwrap();
+    assert!(stdout(&output).contains("'download.bin' is up to date"));
+
+    // A stale validator means a new download, which then needs --force.
+    std::fs::write(dir.join("download.bin.validators"), "ETag: \"v0\"\n").unwrap();
+    let (code, stderr) = download(&dir, server.port, &[]);
+    assert_eq!(code, Some(1), "{}", stderr);
+    assert!(stderr.contains("already exists"), "{}", stderr);
+    let (code, stderr) = download(&dir, server.port, &["--force-download", "--force"]);
+    assert_eq!(code, Some(0), "{}", stderr);
+    assert_eq!(std::fs::read_to_string(dir.join("download.bin.validators")).unwrap(), "ETag: \"v1\"\n");
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn continues_a_partial_output_file() {
+    let data = test_data(100_000);
+    let server = TestServer::start(data.clone(), Behavior::default());
+    let dir = work_dir("continue");
+    std::fs::write(dir.join("download.bin"), &data[..33_333]).unwrap();
+
+    let (code, stderr) = download(&dir, server.port, &["--continue", "--verify",
+                                                       &format!("{:x}", Sha256::digest(&data))]);
+    assert_eq!(code, Some(0), "{}", stderr);
+    assert_eq!(std::fs::read(dir.join("download.bin")).unwrap(), data);
+
+    // Without a file to continue it is a plain download.
+    std::fs::remove_file(dir.join("download.bin")).unwrap();
+    assert_eq!(download(&dir, server.port, &["--continue"]).0, Some(0));
+    assert_eq!(std::fs::read(dir.join("download.bin")).unwrap(), data);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn audits_a_local_copy_and_fixes_what_differs() {
+    let data = test_data(100_000);
+    let server = TestServer::start(data.clone(), Behavior::default());
+    let dir = work_dir("audit");
+    let mut copy = data[..90_000].to_vec();
+    copy[100..104].copy_from_slice(b"XXXX");
+    copy[50_000] ^= 1;
+    std::fs::write(dir.join("copy.bin"), &copy).unwrap();
+    let audit = |args: &[&str]| {
+        let output = client(&dir).args(["--port", &server.port.to_string(), "--chunk-size", "16k"])
+            .args(["--audit", "copy.bin"]).args(args).output().unwrap();
+        let text = String::from_utf8_lossy(&output.stdout) + String::from_utf8_lossy(&output.stderr);
+        (output.status.code(), text.into_owned())
+    };
+
+    let (code, text) = audit(&[]);
+    assert_eq!(code, Some(5), "{}", text);
+    for range in ["bytes 100-104", "bytes 50000-50001", "bytes 90000-100000"] {
+        assert!(text.contains(range), "{}", text);
+    }
+    assert_eq!(std::fs::read(dir.join("copy.bin")).unwrap(), copy);
+
+    let (code, text) = audit(&["--audit-fix"]);
+    assert_eq!(code, Some(0), "{}", text);
+    assert_eq!(std::fs::read(dir.join("copy.bin")).unwrap(), data);
+    let (code, text) = audit(&[]);
+    assert_eq!(code, Some(0), "{}", text);
+    assert!(text.contains("matches the server"), "{}", text);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn max_time_stops_the_run_as_incomplete() {
+    let server = TestServer::start(test_data(200_000), Behavior { slow: Some(Duration::from_millis(20)),
+                                                                  ..Behavior::default() });
+    let dir = work_dir("max-time");
+
+    let (code, stderr) = download(&dir, server.port, &["--max-time", "1", "--probe-size"]);
+    assert_eq!(code, Some(4), "{}", stderr);
+    assert!(stderr.contains("Stopped by --max-time of 1s after downloading"), "{}", stderr);
+    assert!(!dir.join("download.bin").exists());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn stats_interval_prints_a_last_line_in_the_same_format() {
+    let server = TestServer::start(test_data(100_000), Behavior { slow: Some(Duration::from_millis(5)),
+                                                                  ..Behavior::default() });
+    let dir = work_dir("stats");
+
+    let (code, stderr) = download(&dir, server.port, &["--stats-interval", "50ms", "--probe-size"]);
+    assert_eq!(code, Some(0), "{}", stderr);
+    let lines: Vec<_> = stderr.lines().filter(|line| line.contains(" bytes=")).collect();
+    assert!(lines.len() > 1, "{}", stderr);
+    assert!(lines.iter().all(|line| line.split(' ').count() == 10), "{}", stderr);
+    assert!(lines.last().unwrap().contains(" bytes=100000 total=100000 percent=100 "), "{}", stderr);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn stats_lines_name_every_field_in_order() {
+    let server = TestServer::start(test_data(60_000), Behavior { slow: Some(Duration::from_millis(2)),
+                                                                 ..Behavior::default() });
+    let dir = work_dir("stats-fields");
+
+    let (code, stderr) = download(&dir, server.port, &["--stats-interval", "20ms", "--chunk-size", "16"]);
+    assert_eq!(code, Some(0), "{}", stderr);
+    let lines: Vec<_> = stderr.lines().filter(|line| line.contains(" bytes=")).collect();
+    assert!(lines.len() > 1, "{}", stderr);
+    let keys = ["bytes", "total", "percent", "speed", "active", "completed", "pending", "failed", "retries"];
+    for line in &lines {
+        let fields: Vec<_> = line.split(' ').collect();
+        assert!(fields[0].parse::<f64>().is_ok(), "{}", line);
+        let found: Vec<_> = fields[1..].iter().filter_map(|field| field.split_once('=')).map(|(key, _)| key).collect();
+        assert_eq!(found, keys, "{}", line);
+    }
+    let last = lines.last().unwrap();
+    assert!(last.contains(" bytes=60000 total=60000 percent=100 "), "{}", stderr);
+    assert!(last.ends_with(" active=0 completed=4 pending=0 failed=0 retries=0"), "{}", stderr);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn json_progress_writes_numbered_events() {
+    let server = TestServer::start(test_data(60_000), Behavior { slow: Some(Duration::from_millis(2)),
+                                                                 ..Behavior::default() });
+    let dir = work_dir("json-events");
+
+    let (code, stderr) = download(&dir, server.port, &["--progress-format", "json", "--chunk-size", "16"]);
+    assert_eq!(code, Some(0), "{}", stderr);
+    let lines: Vec<_> = stderr.lines().collect();
+    for (index, line) in lines.iter().enumerate() {
+        assert!(line.starts_with(&format!("{{\"seq\":{},\"ts\":", index + 1)), "{}", line);
+        assert!(line.ends_with('}'), "{}", line);
+    }
+    assert!(lines.iter().any(|line| line.contains("\"event\":\"chunk_started\"") && line.ends_with(",\"attempt\":1}")));
+    assert!(lines.iter().any(|line| line.contains("\"event\":\"progress\"")));
+    assert!(lines.last().unwrap().contains("\"event\":\"download_finished\",\"bytes\":60000"), "{}", stderr);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn report_lists_errors_with_their_ranges() {
+    let server = TestServer::start(test_data(100_000), Behavior { drop_every: Some(5), ..Behavior::default() });
+    let dir = work_dir("report");
+
+    let (code, stderr) = download(&dir, server.port, &["--report", "report.json", "--stats", "--chunk-size", "16"]);
+    assert_eq!(code, Some(0), "{}", stderr);
+    let text = std::fs::read_to_string(dir.join("report.json")).unwrap();
+    let report: serde_json::Value = serde_json::from_str(&text).unwrap_or_else(|e| panic!("{}: {}", e, text));
+    assert_eq!(report["status"], "ok");
+    assert_eq!(report["bytes"], 100_000);
+    let errors = report["errors"].as_array().unwrap();
+    assert!(!errors.is_empty(), "{}", text);
+    for error in errors {
+        let start = error["start"].as_u64().unwrap();
+        assert_eq!(start, error["chunk"].as_u64().unwrap() * 16 * 1024, "{}", text);
+        assert!(error["end"].as_u64().unwrap() > start, "{}", text);
+        assert!(error["attempt"].as_u64().unwrap() >= 1, "{}", text);
+    }
+    let groups = report["error_groups"].as_array().unwrap();
+    assert_eq!(groups.iter().map(|group| group["count"].as_u64().unwrap()).sum::<u64>(), errors.len() as u64);
+    for group in groups {
+        let ranges: Vec<_> = group["ranges"].as_array().unwrap().iter()
+            .map(|range| (range[0].as_u64().unwrap(), range[1].as_u64().unwrap()))
+            .collect();
+        assert!(ranges.windows(2).all(|pair| pair[0].1 < pair[1].0), "{}", text);
+    }
+    let chunks = report["chunks"].as_array().unwrap();
+    assert_eq!(chunks.len(), 7, "{}", text);
+    assert!(chunks.iter().all(|chunk| chunk["worker"].is_u64() && chunk["duration"].is_f64()), "{}", text);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn benchmark_repeats_the_download_until_the_target() {
+    let server = TestServer::start(test_data(200_000), Behavior { truncate_to: Some(50_000), ..Behavior::default() });
+    let dir = work_dir("benchmark");
+
+    let output = client(&dir).args(["--port", &server.port.to_string(), "--benchmark", "500000", "--quiet"])
+        .output().unwrap();
+    let text = stdout(&output);
+    assert!(text.contains(", 2 full downloads"), "{}", text);
+    assert!(text.contains("Request time: p50"), "{}", text);
+    assert!(text.contains("Errors: none"), "{}", text);
+    assert!(std::fs::read_dir(&dir).unwrap().next().is_none());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn counts_cache_hits_and_purges_the_cache() {
+    let data = test_data(100_000);
+    let server = TestServer::start(data.clone(), Behavior { etag: true, ..Behavior::default() });
+    let dir = work_dir("cache");
+    let args = ["--cache-dir", "cache", "--chunk-size", "16k", "--force", "--force-download"];
+
+    let output = client(&dir).args(["--port", &server.port.to_string(), "-o", "download.bin"]).args(args)
+        .output().unwrap();
+    assert!(stdout(&output).contains("Cache: 0 hits, 7 misses"));
+    let output = client(&dir).args(["--port", &server.port.to_string(), "-o", "download.bin"]).args(args)
+        .output().unwrap();
+    let text = stdout(&output);
+    assert!(text.contains("Cache: 7 hits, 0 misses"), "{}", text);
+    assert_eq!(std::fs::read(dir.join("download.bin")).unwrap(), data);
+
+    let text = stdout(&client(&dir).args(["cache", "purge", "cache"]).output().unwrap());
+    assert!(text.contains("Removed 7 cached chunks, 100000 bytes"), "{}", text);
+    assert_eq!(std::fs::read_dir(dir.join("cache")).unwrap().count(), 0);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// Runs a download with `--json` and parses the one line it prints on stdout.
+fn json_result(dir: &PathBuf, port: u16, args: &[&str]) -> (Option<i32>, serde_json::Value) {
+    let output = client(dir).args(["--port", &port.to_string(), "-o", "download.bin", "--json"]).args(args)
+        .output().unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stdout.lines().count(), 1, "stdout: {}\nstderr: {}", stdout, stderr);
+    let result: serde_json::Value = serde_json::from_str(&stdout).unwrap_or_else(|e| panic!("{}: {}", e, stdout));
+    // The field names are frozen; scripts rely on every one of them.
+    let mut keys: Vec<_> = result.as_object().unwrap().keys().map(String::as_str).collect();
+    keys.sort_unstable();
+    assert_eq!(keys, ["average_bps", "bytes", "chunks", "duration_ms", "error", "errors", "exit_code", "hash",
+                      "hash_algorithm", "output", "status", "verification"]);
+    let mut chunk_keys: Vec<_> = result["chunks"].as_object().unwrap().keys().map(String::as_str).collect();
+    chunk_keys.sort_unstable();
+    assert_eq!(chunk_keys, ["failed", "retried", "succeeded", "total"]);
+    assert_eq!(result["exit_code"].as_i64().map(|code| code as i32), output.status.code());
+    (output.status.code(), result)
+}
+
+#[test]
+fn json_prints_only_the_result_on_stdout() {
+    let data = test_data(100_000);
+    let server = TestServer::start(data.clone(), Behavior { drop_every: Some(3), ..Behavior::default() });
+    let dir = work_dir("json-ok");
+    let hash = format!("{:x}", Sha256::digest(&data));
+
+    let (code, result) = json_result(&dir, server.port, &["--chunk-size", "16k", "--verify", &hash]);
+    assert_eq!(code, Some(0), "{}", result);
+    assert_eq!(result["status"], "ok");
+    assert_eq!(result["error"], serde_json::Value::Null);
+    assert_eq!(result["output"], "download.bin");
+    assert_eq!(result["bytes"], 100_000);
+    assert_eq!(result["hash_algorithm"], "sha256");
+    assert_eq!(result["hash"], hash.as_str());
+    assert_eq!(result["verification"], "passed");
+    assert_eq!(result["chunks"]["total"], 7);
+    assert_eq!(result["chunks"]["succeeded"], 7);
+    assert_eq!(result["chunks"]["failed"], 0);
+    assert!(result["chunks"]["retried"].as_u64().unwrap() > 0, "{}", result);
+    assert_eq!(result["errors"], serde_json::json!([]));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn json_describes_every_failure() {
+    let dir = work_dir("json-failed");
+
+    let server = TestServer::start(test_data(100_000), Behavior { drop_mid_body_every: Some(1),
+                                                                  ..Behavior::default() });
+    let (code, result) = json_result(&dir, server.port, &["--repair-rounds", "0"]);
+    assert_eq!(code, Some(4), "{}", result);
+    assert_eq!(result["status"], "incomplete");
+    assert!(result["error"].as_str().unwrap().starts_with("Download incomplete:"), "{}", result);
+    assert_eq!(result["output"], serde_json::Value::Null);
+    assert!(result["chunks"]["failed"].as_u64().unwrap() > 0, "{}", result);
+
+    // A chunk that ends the download is the one error.
+    let server = TestServer::start(test_data(100_000), Behavior { drop_every: Some(1), ..Behavior::default() });
+    let (code, result) = json_result(&dir, server.port, &["--fail-fast"]);
+    assert_eq!(code, Some(4), "{}", result);
+    assert_eq!(result["status"], "incomplete");
+    assert_eq!(result["chunks"]["failed"], 1);
+    let errors = result["errors"].as_array().unwrap();
+    assert_eq!(errors.len(), 1, "{}", result);
+    assert_eq!(errors[0]["cause"], "transfer");
+    assert!(errors[0]["end"].as_u64() > errors[0]["start"].as_u64(), "{}", result);
+
+    let server = TestServer::start(test_data(100_000), Behavior::default());
+    let (code, result) = json_result(&dir, server.port, &["--verify", &"0".repeat(64)]);
+    assert_eq!(code, Some(5), "{}", result);
+    assert_eq!(result["status"], "verification_failed");
+    assert_eq!(result["verification"], "failed");
+    assert_eq!(result["bytes"], 100_000);
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let (code, result) = json_result(&dir, port, &["--connect-retries", "0", "--repair-rounds", "0"]);
+    assert_eq!(code, Some(3), "{}", result);
+    assert_eq!(result["status"], "failed");
+    assert!(result["error"].as_str().unwrap().starts_with("Could not connect to 127.0.0.1:"), "{}", result);
+    assert_eq!(result["bytes"], 0);
+    assert_eq!(result["hash"], serde_json::Value::Null);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn puts_the_range_in_query_parameters_with_range_style_query() {
+    let data = test_data(200_000);
+    let server = TestServer::start(data.clone(), Behavior { query_ranges: Some(("from", "count")),
+                                                            ..Behavior::default() });
+    let dir = work_dir("range-style");
+
+    let (code, stderr) = download(&dir, server.port, &["--range-style", "query", "--range-params", "from,count"]);
+    assert_eq!(code, Some(0), "{}", stderr);
+    assert_eq!(std::fs::read(dir.join("download.bin")).unwrap(), data);
+
+    assert_eq!(download(&dir, server.port, &["--range-style", "body"]).0, Some(2));
+    assert_eq!(download(&dir, server.port, &["--range-style", "query", "--range-params", "from"]).0, Some(2));
+    assert_eq!(download(&dir, server.port, &["--range-params", "from,count"]).0, Some(2));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn manifest_lines_parse_into_entries() {
+    let entries = parse_manifest("# artifacts\n/a.bin a.bin ABCD\n\n/b.bin out/b.bin\n").unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!((entries[0].path.as_str(), entries[0].output.as_str()), ("/a.bin", "a.bin"));
+    assert_eq!(entries[0].hash.as_deref(), Some("abcd"));
+    assert_eq!(entries[1].hash, None);
+
+    assert!(parse_manifest("a.bin a.bin").is_err());
+    assert!(parse_manifest("/a.bin").is_err());
+    assert!(parse_manifest("# nothing\n").is_err());
+}
+
+#[test]
+fn finds_the_window_where_the_written_file_diverges() {
+    let data = test_data(100_000);
+    let server = TestServer::start(data.clone(), Behavior::default());
+    let path = std::env::temp_dir().join(format!("buggy-client-divergence-{}.bin", std::process::id()));
+    let summary = Downloader::builder().port(server.port).chunk_size(16 * 1024).crc_window(32 * 1024).build().unwrap()
+        .download(&mut std::fs::File::create(&path).unwrap()).unwrap();
+    let checkpoints = summary.checkpoints.unwrap();
+    let windows: Vec<_> = checkpoints.iter().map(|checkpoint| (checkpoint.start..checkpoint.end,
+                                                               checkpoint.chunk_ids.clone())).collect();
+    assert_eq!(windows, [(0..32768, vec![0, 1]), (32768..65536, vec![2, 3]), (65536..98304, vec![4, 5]),
+                         (98304..100_000, vec![6])]);
+    assert_eq!(find_divergence(&path, &checkpoints).unwrap(), None);
+    let log = format!("{}.crc", path.display());
+    write_checkpoint_log(&log, 32 * 1024, &checkpoints).unwrap();
+    let text = std::fs::read_to_string(&log).unwrap();
+    std::fs::remove_file(&log).unwrap();
+    assert_eq!(text.lines().next(), Some("# crc32c window=32768"));
+    assert_eq!(text.lines().nth(4), Some(format!("98304-100000 {:08x} 6", crc32c::crc32c(&data)).as_str()));
+
+    // A flipped byte points at its window and the chunks that filled it.
+    let corrupt = |offset: usize| {
+        let mut bytes = data.clone();
+        bytes[offset] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+        find_divergence(&path, &checkpoints).unwrap().map(|bad| (bad.start..bad.end, bad.chunk_ids.clone()))
+    };
+    assert_eq!(corrupt(3 * 16384 + 100), Some((32768..65536, vec![2, 3])));
+    assert_eq!(corrupt(0), Some((0..32768, vec![0, 1])));
+    assert_eq!(corrupt(99_999), Some((98304..100_000, vec![6])));
+    // So does a file that ends early.
+    std::fs::write(&path, &data[..99_000]).unwrap();
+    assert_eq!(find_divergence(&path, &checkpoints).unwrap().map(|bad| bad.start), Some(98304));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn parses_sizes_with_units_in_any_case() {
+    for (raw, bytes) in [("512", 512), ("512b", 512), ("64k", 65_536), ("64K", 65_536), ("64KiB", 65_536),
+                         ("64kib", 65_536), ("1M", 1 << 20), ("1MiB", 1 << 20), ("1.5m", 3 << 19), ("2g", 2 << 30),
+                         ("2GiB", 2 << 30), (" 8k ", 8192)] {
+        assert_eq!(parse_size(raw), Ok(bytes), "{}", raw);
+    }
+    for raw in ["", "k", "0", "-1k", "64KB7", "64kb", "64 k", "1e3", "inf", "nan", "12x"] {
+        let error = parse_size(raw).unwrap_err();
+        assert!(error.starts_with("Invalid size"), "{}: {}", raw, error);
+    }
+    assert!(parse_size("64KB7").unwrap_err().contains("like 512, 64k or 1MiB"));
+    assert_eq!(parse_rate("500k"), Ok(500 * 1024));
+    assert!(parse_rate("0").unwrap_err().starts_with("Invalid rate"));
+}
+
+#[test]
+fn picks_the_entry_for_the_output_from_a_checksum_file() {
+    let a = "a".repeat(64);
+    let b = "B".repeat(64);
+    // sha256sum output saved by an editor that adds a BOM, with binary-mode markers.
+    let text = format!("\u{feff}# made by sha256sum\n{} *dist/one.bin\n\n{}  two.bin\n", a, b);
+    assert_eq!(parse_checksum_file(&text, Some("one.bin")), Ok(a.clone()));
+    assert_eq!(parse_checksum_file(&text, Some("out/two.bin")), Ok("b".repeat(64)));
+    assert_eq!(parse_checksum_file(&text, Some("three.bin")), Err("Checksum file has no entry for 'three.bin'".into()));
+    assert!(parse_checksum_file(&text, None).unwrap_err().contains("lists several files"));
+    // A lone entry is taken whatever it names, and so is a bare hash.
+    assert_eq!(parse_checksum_file(&format!("\u{feff}{} *other.bin\r\n", a), Some("one.bin")), Ok(a.clone()));
+    assert_eq!(parse_checksum_file(&format!("{}\n", b), None), Ok("b".repeat(64)));
+    assert_eq!(parse_checksum_file("\u{feff}# nothing\n", None), Err("Checksum file contains no entries".into()));
+}
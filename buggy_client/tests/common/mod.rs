@@ -0,0 +1,446 @@
+//! In-process HTTP server for the integration tests, serving byte ranges of a
+//! fixed buffer the way `buggy_server.py` does.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+/// How the test server misbehaves.
+#[derive(Clone, Default)]
+pub struct Behavior {
+    /// Cut every body to at most this many bytes, still announcing the full
+    /// Content-Length.
+    pub truncate_to: Option<usize>,
+    /// Pause this long after every KiB of body.
+    pub slow: Option<Duration>,
+    /// Dribble the body of every Nth response, starting with the first, a
+    /// byte every 50ms, so reads never time out.
+    pub stall_every: Option<usize>,
+    /// Close every Nth connection without answering, starting with the first.
+    pub drop_every: Option<usize>,
+    /// Close this many connections without answering before serving any.
+    pub refuse_first: usize,
+    /// Stop halfway through the body of every Nth response, starting with
+    /// the first.
+    pub drop_mid_body_every: Option<usize>,
+    /// Send half the body of every Nth response, starting with the first,
+    /// then keep the connection open for a second without sending more.
+    pub hang_mid_body_every: Option<usize>,
+    /// After that second, send the rest of the body after all, so the range
+    /// is served in full a second time if the client asked again meanwhile.
+    pub finish_after_hang: bool,
+    /// Stop halfway through the head of every Nth response, starting with
+    /// the first.
+    pub drop_in_head_every: Option<usize>,
+    /// Start every partial body this many bytes before the requested range,
+    /// repeating bytes the previous range already delivered. The
+    /// Content-Range says where the body really starts.
+    pub overlap: usize,
+    /// Announce a start one byte past the real one in the Content-Range of
+    /// every Nth response, starting with the first.
+    pub bad_content_range_every: Option<usize>,
+    /// Answer ranges that start at or past the end with this status, such as
+    /// 400 or 416, instead of an empty 206.
+    pub past_end_status: Option<u16>,
+    /// Value of a `Content-Disposition` header to send.
+    pub content_disposition: Option<&'static str>,
+    /// Send everything from the start of the range to the end of the data.
+    pub ignore_range_end: bool,
+    /// Answer every request with a 200 and the whole data.
+    pub ignore_range: bool,
+    /// Ignore the Range header and serve the bytes that the query parameters
+    /// with these names give the offset and length of, with a 200 and no
+    /// Content-Range; an empty body past the end.
+    pub query_ranges: Option<(&'static str, &'static str)>,
+    /// Send an ETag, answer a range whose If-Range does not match it with a
+    /// 200 and the whole data, and one whose If-None-Match does with a 304.
+    pub etag: bool,
+    /// From the Nth response on, serve another version of the data, every
+    /// byte inverted, under another ETag.
+    pub change_after: Option<usize>,
+    /// The length of that other version, if not the same.
+    pub changed_len: Option<usize>,
+    /// Keep each connection open for further requests after a complete
+    /// response, answering with `Connection: keep-alive`.
+    pub keep_alive: bool,
+    /// Answer like an HTTP/1.0 server: a 1.0 status line, no Content-Length
+    /// and the end of the body marked by closing the connection.
+    pub http_1_0: bool,
+    /// Answer 400 to requests whose Host header carries a port.
+    pub reject_host_port: bool,
+    /// Wait this long before answering a request, like a round trip on a
+    /// slow link, unless it was already queued behind the one before.
+    pub latency: Option<Duration>,
+    /// Wait this long before answering a request for the range that starts
+    /// at this offset, like a server slow to fetch that part.
+    pub slow_range: Option<(usize, Duration)>,
+    /// Answer a request for several ranges with a multipart/byteranges body,
+    /// leaving out those past the end. Without it only the first is served.
+    pub multi_range: bool,
+    /// Close each connection after this many responses, without a word, once
+    /// the next request is in, as servers do at their keep-alive limits.
+    pub close_after: Option<usize>,
+    /// Value of a `Keep-Alive` header to send on kept-alive connections.
+    pub keep_alive_header: Option<&'static str>,
+    /// Answer 429 Too Many Requests, with a `Retry-After: 0`, to requests
+    /// that come in while this many are being answered.
+    pub max_in_flight: Option<usize>,
+    /// Close connections that come in while this many are open, without
+    /// answering, like a server out of workers.
+    pub max_connections: Option<usize>,
+    /// Act as a proxy in front of the data: answer 400 to requests that do
+    /// not name this URL prefix, such as `http://origin:80/`, in full.
+    pub proxy_for: Option<&'static str>,
+    /// As a proxy, answer 407 to requests without this `Proxy-Authorization`.
+    pub proxy_authorization: Option<&'static str>,
+    /// Send an `X-Chunk-Checksum` with the sha256 of every partial body.
+    pub chunk_checksums: bool,
+    /// Flip the first byte of the body of every Nth response, starting with
+    /// the first, after its `X-Chunk-Checksum` was worked out.
+    pub corrupt_body_every: Option<usize>,
+    /// Compress every body with this `Content-Encoding`, `gzip` or
+    /// `deflate`, whatever the request accepts, as some CDNs do.
+    pub content_encoding: Option<&'static str>,
+    /// Answer requests for paths ending in `.hashes` with this text, or with
+    /// 404 when unset.
+    pub sidecar: Option<String>,
+}
+
+pub struct TestServer {
+    pub port: u16,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+impl TestServer {
+    pub fn start(data: Vec<u8>, behavior: Behavior) -> Self {
+        Self::start_on("127.0.0.1:0", data, behavior).expect("bind test server")
+    }
+
+    /// Starts the server on `address`, failing if it cannot be bound, such
+    /// as `[::1]:0` on a machine without IPv6.
+    pub fn start_on(address: &str, data: Vec<u8>, behavior: Behavior) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        let port = listener.local_addr()?.port();
+        let data = Arc::new(data);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let open = Arc::new(AtomicUsize::new(0));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&requests);
+        thread::spawn(move || {
+            for (index, stream) in listener.incoming().flatten().enumerate() {
+                if index < behavior.refuse_first || behavior.drop_every.is_some_and(|n| index.is_multiple_of(n)) {
+                    continue;
+                }
+                if behavior.max_connections.is_some_and(|max| open.load(Ordering::SeqCst) >= max) {
+                    continue;
+                }
+                open.fetch_add(1, Ordering::SeqCst);
+                let data = Arc::clone(&data);
+                let behavior = behavior.clone();
+                let in_flight = Arc::clone(&in_flight);
+                let open = Arc::clone(&open);
+                let received = Arc::clone(&received);
+                thread::spawn(move || {
+                    serve(stream, &data, &behavior, index, &in_flight, &received);
+                    open.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        Ok(TestServer { port, requests })
+    }
+
+    /// The request line and headers of every request received so far, as
+    /// sent, in the order they came in.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn serve(stream: TcpStream, data: &[u8], behavior: &Behavior, index: usize, in_flight: &AtomicUsize,
+         received: &Mutex<Vec<String>>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut served = 0;
+    while respond(&stream, &mut reader, data, behavior, index, in_flight, received) {
+        served += 1;
+        if behavior.close_after.is_some_and(|n| served >= n) {
+            let _ = reader.fill_buf();
+            return;
+        }
+    }
+}
+
+/// Answers the next request on the connection, returning whether the whole
+/// response was sent and the connection stays open.
+fn respond(mut stream: &TcpStream, reader: &mut BufReader<TcpStream>, data: &[u8], behavior: &Behavior,
+           index: usize, in_flight: &AtomicUsize, received: &Mutex<Vec<String>>) -> bool {
+    let mut ranges = Vec::new();
+    let mut if_range = None;
+    let mut if_none_match = None;
+    let mut host = String::new();
+    let mut proxy_authorization = None;
+    let mut close = false;
+    let pipelined = !reader.buffer().is_empty();
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+        return false;
+    }
+    let mut raw = request_line.clone();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+            break;
+        }
+        raw.push_str(&line);
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("range") {
+                ranges = value.trim().strip_prefix("bytes=").into_iter().flat_map(|r| r.split(','))
+                    .filter_map(|r| r.split_once('-'))
+                    .map(|(start, end)| (start.parse::<usize>().unwrap(), end.parse::<usize>().unwrap()))
+                    .collect();
+            } else if name.eq_ignore_ascii_case("if-range") {
+                if_range = Some(value.trim().to_string());
+            } else if name.eq_ignore_ascii_case("if-none-match") {
+                if_none_match = Some(value.trim().to_string());
+            } else if name.eq_ignore_ascii_case("host") {
+                host = value.trim().to_string();
+            } else if name.eq_ignore_ascii_case("connection") {
+                close = value.trim().eq_ignore_ascii_case("close");
+            } else if name.eq_ignore_ascii_case("proxy-authorization") {
+                proxy_authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    received.lock().unwrap().push(raw);
+    let busy = Busy::enter(in_flight);
+    let mut range = ranges.first().copied();
+    if let Some(latency) = behavior.latency.filter(|_| !pipelined) {
+        thread::sleep(latency);
+    }
+    if let Some((_, delay)) = behavior.slow_range.filter(|&(offset, _)| range.is_some_and(|(start, _)| start == offset)) {
+        thread::sleep(delay);
+    }
+    let changed = behavior.change_after.is_some_and(|n| index >= n);
+    let inverted: Vec<u8>;
+    let data = if changed {
+        let len = behavior.changed_len.unwrap_or(data.len());
+        inverted = data.iter().cycle().take(len).map(|byte| !byte).collect();
+        &inverted[..]
+    } else {
+        data
+    };
+    let etag = if changed { "\"v2\"" } else { "\"v1\"" };
+    let close = close || !behavior.keep_alive || behavior.http_1_0;
+    let connection = if close { "close" } else { "keep-alive" };
+    let version = if behavior.http_1_0 { "1.0" } else { "1.1" };
+    if behavior.reject_host_port && host.rsplit_once(':').is_some_and(|(_, port)| !port.ends_with(']')) {
+        let _ = write!(stream, "HTTP/{} 400 Bad Request\r\nConnection: close\r\n\r\n", version);
+        return false;
+    }
+    let target = request_line.split(' ').nth(1).unwrap_or_default();
+    if behavior.proxy_for.is_some_and(|origin| !target.starts_with(origin)) {
+        let _ = write!(stream, "HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n");
+        return false;
+    }
+    if behavior.proxy_authorization.is_some_and(|expected| proxy_authorization.as_deref() != Some(expected)) {
+        let _ = write!(stream, "HTTP/1.1 407 Proxy Authentication Required\r\nConnection: close\r\n\r\n");
+        return false;
+    }
+    if target.ends_with(".hashes") {
+        let _ = match &behavior.sidecar {
+            Some(text) => write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                                 text.len(), text),
+            None => write!(stream, "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+        };
+        return false;
+    }
+    if let Some((offset, length)) = behavior.query_ranges {
+        let query = target.split_once('?').map_or("", |(_, query)| query);
+        let param = |name: &str| query.split('&').filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.parse::<usize>().unwrap());
+        range = param(offset).zip(param(length)).map(|(offset, length)| (offset, offset + length));
+    }
+    if behavior.etag && if_range.is_some_and(|validator| validator != etag) {
+        range = None;
+    }
+    if behavior.etag && if_none_match.is_some_and(|validator| validator == etag) {
+        let _ = write!(stream, "HTTP/1.1 304 Not Modified\r\nETag: {}\r\nConnection: {}\r\n\r\n", etag, connection);
+        return !close;
+    }
+
+    if behavior.max_in_flight.is_some_and(|max| busy.0 > max) {
+        let body = "Too Many Requests";
+        let _ = write!(stream, "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 0\r\nContent-Length: {}\r\n\
+                               Connection: {}\r\n\r\n{}", body.len(), connection, body);
+        return !close;
+    }
+
+    let nth = |every: Option<usize>| every.is_some_and(|n| index.is_multiple_of(n));
+    if let (Some(status), Some((start, _))) = (behavior.past_end_status, range) {
+        if start >= data.len() && !behavior.ignore_range {
+            let reason = if status == 416 { "Range Not Satisfiable" } else { "Bad Request" };
+            let _ = write!(stream, "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: {}\r\n\r\n", status, reason,
+                         connection);
+            return !close;
+        }
+    }
+
+    if behavior.multi_range && range.is_some() && ranges.len() > 1 && !behavior.ignore_range {
+        let mut body = Vec::new();
+        for &(start, end) in ranges.iter().filter(|(start, _)| *start < data.len()) {
+            let end = end.min(data.len()).max(start);
+            body.extend_from_slice(format!("\r\n--PARTS\r\nContent-Type: application/octet-stream\r\n\
+                                            Content-Range: bytes {}-{}/{}\r\n\r\n", start, end - 1, data.len())
+                                   .as_bytes());
+            body.extend_from_slice(&data[start..end]);
+        }
+        body.extend_from_slice(b"\r\n--PARTS--\r\n");
+        let head = format!("HTTP/1.1 206 Partial Content\r\nContent-Type: multipart/byteranges; boundary=PARTS\r\n\
+                            Content-Length: {}\r\nConnection: {}\r\n\r\n", body.len(), connection);
+        let sent = if nth(behavior.drop_mid_body_every) { body.len() / 2 } else { body.len() };
+        let _ = stream.write_all(head.as_bytes());
+        let _ = stream.write_all(&body[..sent]);
+        return sent == body.len() && !close;
+    }
+
+    // Python slice semantics: the end is exclusive and both ends are clamped.
+    let (start, end) = match range {
+        Some(_) if behavior.ignore_range => (0, data.len()),
+        Some((start, _)) if behavior.ignore_range_end => (start.min(data.len()), data.len()),
+        Some((start, end)) => (start.min(data.len()), end.min(data.len()).max(start.min(data.len()))),
+        None => (0, data.len()),
+    };
+    let start = if start < end { start.saturating_sub(behavior.overlap) } else { start };
+    let mut body = &data[start..end];
+    let partial = body.len() != data.len() && behavior.query_ranges.is_none();
+    let mut extra = String::new();
+    if partial && !body.is_empty() {
+        let announced = if nth(behavior.bad_content_range_every) { start + 1 } else { start };
+        extra.push_str(&format!("Content-Range: bytes {}-{}/{}\r\n", announced, end - 1, data.len()));
+    }
+    if behavior.etag {
+        extra.push_str(&format!("ETag: {}\r\n", etag));
+    }
+    if behavior.chunk_checksums && body.len() != data.len() {
+        extra.push_str(&format!("X-Chunk-Checksum: sha256={:x}\r\n", Sha256::digest(body)));
+    }
+    let corrupted: Vec<u8>;
+    if nth(behavior.corrupt_body_every) && !body.is_empty() {
+        corrupted = [&[!body[0]], &body[1..]].concat();
+        body = &corrupted;
+    }
+    if let Some(value) = behavior.keep_alive_header.filter(|_| !close) {
+        extra.push_str(&format!("Keep-Alive: {}\r\n", value));
+    }
+    if let Some(value) = behavior.content_disposition {
+        extra.push_str(&format!("Content-Disposition: {}\r\n", value));
+    }
+    let encoded: Vec<u8>;
+    if let Some(encoding) = behavior.content_encoding {
+        encoded = encode(encoding, body);
+        body = &encoded;
+        extra.push_str(&format!("Content-Encoding: {}\r\n", encoding));
+    }
+    if !behavior.http_1_0 {
+        extra.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    let status = if partial { "206 Partial Content" } else { "200 OK" };
+    let head = format!("HTTP/{} {}\r\nContent-Type: application/octet-stream\r\n{}Connection: {}\r\n\r\n",
+                       version, status, extra, connection);
+    let mut sent = behavior.truncate_to.map_or(body.len(), |limit| body.len().min(limit));
+    if nth(behavior.drop_mid_body_every) {
+        sent /= 2;
+    }
+    if nth(behavior.drop_in_head_every) {
+        let _ = stream.write_all(&head.as_bytes()[..head.len() / 2]);
+        return false;
+    }
+    let _ = stream.write_all(head.as_bytes());
+    if request_line.starts_with("HEAD ") {
+        return !close;
+    }
+    if nth(behavior.hang_mid_body_every) {
+        let _ = stream.write_all(&body[..sent / 2]);
+        thread::sleep(Duration::from_secs(1));
+        if !behavior.finish_after_hang {
+            return false;
+        }
+        return stream.write_all(&body[sent / 2..sent]).is_ok() && sent == body.len() && !close;
+    }
+    if nth(behavior.stall_every) {
+        for byte in &body[..sent] {
+            if stream.write_all(&[*byte]).is_err() {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        return sent == body.len() && !close;
+    }
+    match behavior.slow {
+        Some(pause) => {
+            for piece in body[..sent].chunks(1024) {
+                if stream.write_all(piece).is_err() {
+                    return false;
+                }
+                thread::sleep(pause);
+            }
+        }
+        None => {
+            let _ = stream.write_all(&body[..sent]);
+        }
+    }
+    sent == body.len() && !close
+}
+
+/// `body` compressed with `encoding`.
+fn encode(encoding: &str, body: &[u8]) -> Vec<u8> {
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use flate2::Compression;
+
+    match encoding {
+        "gzip" => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body).unwrap();
+            encoder.finish().unwrap()
+        }
+        "deflate" => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(body).unwrap();
+            encoder.finish().unwrap()
+        }
+        other => panic!("unknown encoding {}", other),
+    }
+}
+
+/// Counts a request as being answered until dropped, holding how many were
+/// with it.
+struct Busy<'a>(usize, &'a AtomicUsize);
+
+impl<'a> Busy<'a> {
+    fn enter(in_flight: &'a AtomicUsize) -> Self {
+        Busy(in_flight.fetch_add(1, Ordering::SeqCst) + 1, in_flight)
+    }
+}
+
+impl Drop for Busy<'_> {
+    fn drop(&mut self) {
+        self.1.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Deterministic pseudo-random bytes, so failures are reproducible.
+pub fn test_data(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    (0..len).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 24) as u8
+    }).collect()
+}
@@ -0,0 +1,223 @@
+//! Property tests for the response head parser: random heads, split at random
+//! points and mangled at random. proptest shrinks a failing case and keeps its
+//! seed in `message.proptest-regressions`, so it is tried first next time.
+//! The parser is internal to the library, so its source is compiled in here.
+
+// Only the parser is tested; the request side goes unused.
+#[allow(dead_code)]
+#[path = "../src/message.rs"]
+mod message;
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use message::{parse_response_head, Headers, Response};
+use proptest::prelude::*;
+use proptest::sample::{select, Index};
+
+/// A header line as `(name, separator, value)`, with the indent and text of
+/// a continuation line when folded.
+type HeaderLine = (&'static str, &'static str, &'static str, Option<(&'static str, &'static str)>);
+
+fn header_line() -> impl Strategy<Value = HeaderLine> {
+    let name = select(vec!["Content-Length", "X-Thing", "content-range", "ETAG"]);
+    let separator = select(vec![" ", "", "\t "]);
+    let value = select(vec!["1234", "bytes 0-9/10", "a b", "\"quoted\"", ""]);
+    let fold = prop::option::weighted(0.25, (select(vec![" ", "\t", "   "]), select(vec!["more", "and, more"])));
+    (name, separator, value, fold)
+}
+
+prop_compose! {
+    /// A valid response with random status, headers, line endings and
+    /// folding, followed by a body that may itself contain blank lines. Gives
+    /// the bytes and the response they should parse into.
+    fn response()(crlf in any::<bool>(), status in 100_u16..600, version in 0_u8..2,
+                  reason in select(vec!["OK", "", "Partial Content"]),
+                  lines in prop::collection::vec(header_line(), 0..8),
+                  body in prop::collection::vec(select(b"\r\nx".to_vec()), 0..200)) -> (Vec<u8>, Response) {
+        let newline = if crlf { "\r\n" } else { "\n" };
+        let mut text = format!("HTTP/1.{} {} {}{}", version, status, reason, newline);
+        let mut headers = Headers::new();
+        for (index, (name, separator, value, fold)) in lines.into_iter().enumerate() {
+            let name = format!("{}-{}", name, index);
+            match fold.filter(|_| !value.is_empty()) {
+                Some((indent, continued)) => {
+                    text.push_str(&format!("{}:{}{}{}{}{}{}", name, separator, value, newline, indent, continued,
+                                           newline));
+                    headers.append(&name, &format!("{} {}", value, continued));
+                }
+                None => {
+                    text.push_str(&format!("{}: {}{}", name, value, newline));
+                    headers.append(&name, value);
+                }
+            }
+        }
+        text.push_str(newline);
+        let body_offset = text.len();
+        let mut bytes = text.into_bytes();
+        bytes.extend(body);
+        (bytes, Response { status, version, headers, body_offset })
+    }
+}
+
+/// One change to a valid response: overwrite, remove or insert a byte, or
+/// cut the rest off.
+#[derive(Clone, Debug)]
+enum Mangle {
+    Replace(Index, u8),
+    Remove(Index),
+    Insert(Index, u8),
+    Truncate(Index),
+}
+
+fn mangle() -> impl Strategy<Value = Mangle> {
+    prop_oneof![
+        (any::<Index>(), any::<u8>()).prop_map(|(at, byte)| Mangle::Replace(at, byte)),
+        any::<Index>().prop_map(Mangle::Remove),
+        (any::<Index>(), select(b"\r\n: \t".to_vec())).prop_map(|(at, byte)| Mangle::Insert(at, byte)),
+        any::<Index>().prop_map(Mangle::Truncate),
+    ]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(2_000))]
+
+    #[test]
+    fn parses_every_prefix_of_a_valid_response_consistently((bytes, expected) in response(),
+                                                             steps in prop::collection::vec(1_usize..=20, 1..50)) {
+        // Feeding the response in random pieces: nothing is decided until the
+        // blank line has arrived, and then the answer never changes.
+        let mut received = 0;
+        for step in steps.iter().cycle() {
+            if received == bytes.len() {
+                break;
+            }
+            received = (received + step).min(bytes.len());
+            let parsed = parse_response_head(&bytes[..received]).unwrap();
+            if received < expected.body_offset {
+                prop_assert_eq!(parsed, None, "{:?}", String::from_utf8_lossy(&bytes[..received]));
+            } else {
+                prop_assert_eq!(parsed.as_ref(), Some(&expected), "{:?}", String::from_utf8_lossy(&bytes));
+            }
+        }
+    }
+
+    #[test]
+    fn never_panics_on_mangled_responses((mut bytes, _) in response(),
+                                         mangles in prop::collection::vec(mangle(), 1..5)) {
+        for mangle in mangles {
+            match mangle {
+                Mangle::Replace(at, byte) if !bytes.is_empty() => {
+                    let at = at.index(bytes.len());
+                    bytes[at] = byte;
+                }
+                Mangle::Remove(at) if !bytes.is_empty() => {
+                    bytes.remove(at.index(bytes.len()));
+                }
+                Mangle::Insert(at, byte) => bytes.insert(at.index(bytes.len() + 1), byte),
+                Mangle::Truncate(at) => bytes.truncate(at.index(bytes.len() + 1)),
+                _ => {}
+            }
+        }
+        for end in 0..=bytes.len() {
+            let _ = parse_response_head(&bytes[..end]);
+        }
+    }
+}
+
+proptest! {
+    // Long inputs, slow to generate, so fewer of them.
+    #![proptest_config(ProptestConfig::with_cases(16))]
+
+    #[test]
+    fn never_panics_on_noise(noise in prop::collection::vec(any::<u8>(), 0..100_000)) {
+        let _ = parse_response_head(&noise);
+    }
+}
+
+#[test]
+fn accepts_lone_line_feeds_and_folded_headers() {
+    let raw = b"HTTP/1.1 206 Partial Content\nContent-Range: bytes 0-9/\n  100\nX: 1\n\nbody";
+    let parsed = parse_response_head(raw).unwrap().unwrap();
+    assert_eq!(parsed.status, 206);
+    assert_eq!(parsed.headers.get("content-range"), Some("bytes 0-9/ 100"));
+    assert_eq!(parsed.headers.content_range(), Some((0, 9, Some(100))));
+    assert_eq!(parsed.headers.get("X"), Some("1"));
+    assert_eq!(&raw[parsed.body_offset..], b"body");
+}
+
+#[test]
+fn keeps_every_value_of_a_repeated_header() {
+    let parsed = parse_response_head(b"HTTP/1.0 200\r\nSet-Cookie: a\r\nX: 1\r\nset-COOKIE:\tb \r\n\r\n")
+        .unwrap().unwrap();
+    assert_eq!(parsed.headers.get("Set-Cookie"), Some("a"));
+    assert_eq!(parsed.headers.get_all("set-cookie"), ["a", "b"]);
+    assert_eq!(parsed.headers.get_all("missing"), [] as [String; 0]);
+    assert_eq!(parsed.headers.iter().collect::<Vec<_>>(), [("set-cookie", "a"), ("set-cookie", "b"), ("x", "1")]);
+}
+
+#[test]
+fn reads_content_length_and_content_range() {
+    let headers = |lines: &[(&str, &str)]| {
+        let mut headers = Headers::new();
+        for (name, value) in lines {
+            headers.append(name, value);
+        }
+        headers
+    };
+
+    assert_eq!(headers(&[("Content-Length", " 42 ")]).content_length(), Some(42));
+    assert_eq!(headers(&[("content-length", "42"), ("CONTENT-LENGTH", "42")]).content_length(), Some(42));
+    assert_eq!(headers(&[("Content-Length", "42, 42")]).content_length(), Some(42));
+    assert_eq!(headers(&[("Content-Length", "42"), ("Content-Length", "43")]).content_length(), None);
+    assert_eq!(headers(&[("Content-Length", "-1")]).content_length(), None);
+    assert_eq!(headers(&[("Content-Length", &"9".repeat(30))]).content_length(), None);
+    assert_eq!(headers(&[]).content_length(), None);
+
+    assert_eq!(headers(&[("Content-Range", "bytes 0-9/10")]).content_range(), Some((0, 9, Some(10))));
+    assert_eq!(headers(&[("Content-Range", "bytes 5 - 9 / *")]).content_range(), Some((5, 9, None)));
+    assert_eq!(headers(&[("Content-Range", "bytes 9-5/10")]).content_range(), None);
+    assert_eq!(headers(&[("Content-Range", "bytes */10")]).content_range(), None);
+    assert_eq!(headers(&[("Content-Range", "items 0-9/10")]).content_range(), None);
+    assert_eq!(headers(&[("Content-Range", &format!("bytes 0-{}/10", "9".repeat(30)))]).content_range(), None);
+
+    assert_eq!(headers(&[("Keep-Alive", "timeout=5, max=100")]).keep_alive(), (Some(5), Some(100)));
+    let split = headers(&[("Keep-Alive", "MAX=\"3\""), ("Keep-Alive", "timeout = 2")]);
+    assert_eq!(split.keep_alive(), (Some(2), Some(3)));
+    assert_eq!(headers(&[("Keep-Alive", "timeout=soon")]).keep_alive(), (None, None));
+    assert_eq!(headers(&[]).keep_alive(), (None, None));
+
+    let now = UNIX_EPOCH + Duration::from_secs(784_111_777);
+    let retry_after = |value| headers(&[("Retry-After", value)]).retry_after(now);
+    assert_eq!(retry_after("120"), Some(Duration::from_secs(120)));
+    assert_eq!(retry_after("Sun, 06 Nov 1994 08:49:47 GMT"), Some(Duration::from_secs(10)));
+    assert_eq!(retry_after("Tue, 29 Feb 2000 00:00:00 GMT"), Some(Duration::from_secs(951_782_400 - 784_111_777)));
+    assert_eq!(retry_after("Sun, 06 Nov 1994 08:49:00 GMT"), Some(Duration::ZERO));
+    assert_eq!(retry_after("soon"), None);
+    assert_eq!(retry_after("Sun, 06 Nov 1994 08:49:37 PST"), None);
+    assert_eq!(headers(&[]).retry_after(now), None);
+
+    // A value far longer than any real header is kept whole.
+    let long = "x".repeat(60_000);
+    let raw = format!("HTTP/1.1 200 OK\r\nX-Long: {}\r\n\r\n", long);
+    assert_eq!(parse_response_head(raw.as_bytes()).unwrap().unwrap().headers.get("x-long"), Some(long.as_str()));
+}
+
+#[test]
+fn rejects_malformed_heads() {
+    for (raw, error) in [
+        (&b"\r\n\r\n"[..], "Response has no status line"),
+        (b"HTTP/1.1 2000 OK\r\n\r\n", "Malformed status line"),
+        (b"ICY 200 OK\r\n\r\n", "Malformed status line"),
+        (b"HTTP/1.1 200 OK\r\n folded first\r\n\r\n", "continuation line"),
+        (b"HTTP/1.1 200 OK\r\nno colon here\r\n\r\n", "Malformed header line"),
+        (b"HTTP/1.1 200 OK\r\nBad Name: x\r\n\r\n", "Malformed header line"),
+    ] {
+        let result = parse_response_head(raw);
+        assert!(result.as_ref().is_err_and(|e| e.contains(error)), "{:?}: {:?}", String::from_utf8_lossy(raw), result);
+    }
+
+    // A head that never ends is cut off instead of buffered forever.
+    let endless = [b"HTTP/1.1 200 OK\r\n".to_vec(), b"X: y\r\n".repeat(20_000)].concat();
+    assert!(parse_response_head(&endless).unwrap_err().contains("longer than"));
+    assert_eq!(parse_response_head(&endless[..1000]), Ok(None));
+}
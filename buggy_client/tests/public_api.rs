@@ -0,0 +1,268 @@
+//! Keeps the public API from changing by accident: the exports of the crate
+//! root and the signature of every public item behind them are listed from
+//! the sources and compared with `public_api.txt`. After a deliberate change,
+//! run the test with `UPDATE_PUBLIC_API=1` to write the new listing and
+//! review it with the rest of the change.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+
+/// Everything between the start of `lines[i]` and the `{` or `;` that ends
+/// the signature, on one line, and the index of the line it ends on.
+fn signature(lines: &[&str], mut i: usize) -> (String, usize) {
+    let mut text = String::new();
+    loop {
+        let line = lines[i].trim();
+        if let Some(end) = line.find(['{', ';']).filter(|_| !line.starts_with("pub const")) {
+            text.push_str(&line[..end]);
+            break;
+        }
+        if let Some((head, _)) = line.split_once(" = ").filter(|_| line.starts_with("pub const")) {
+            text.push_str(head);
+            break;
+        }
+        text.push_str(line);
+        text.push(' ');
+        i += 1;
+    }
+    (text.split_whitespace().collect::<Vec<_>>().join(" ").replace("( ", "(").replace(" )", ")"), i)
+}
+
+/// The attributes stacked above `lines[i]` that are part of its API.
+fn attributes(lines: &[&str], i: usize) -> String {
+    let mut found = Vec::new();
+    for line in lines[..i].iter().rev().map(|line| line.trim()) {
+        if !line.starts_with("#[") {
+            break;
+        }
+        if ["#[derive", "#[non_exhaustive", "#[cfg"].iter().any(|kind| line.starts_with(kind)) {
+            found.push(line);
+        }
+    }
+    found.reverse();
+    found.iter().map(|attribute| format!("{} ", attribute)).collect()
+}
+
+/// The name of the type or trait an `impl` header is about, and of the trait
+/// it implements if any.
+fn impl_names(header: &str) -> (String, Option<String>) {
+    let name = |path: &str| {
+        let path = path.trim().trim_start_matches('&');
+        let path = path.split('<').next().unwrap_or(path);
+        path.rsplit("::").next().unwrap_or(path).to_string()
+    };
+    let rest = header.strip_prefix("impl").unwrap_or(header);
+    let rest = match rest.strip_prefix('<') {
+        Some(generic) => {
+            let mut depth = 1;
+            let end = generic.char_indices().find(|&(_, c)| {
+                depth += match c { '<' => 1, '>' => -1, _ => 0 };
+                depth == 0
+            }).map_or(generic.len(), |(end, _)| end + 1);
+            &generic[end..]
+        }
+        None => rest,
+    };
+    match rest.split_once(" for ") {
+        Some((trait_name, target)) => (name(target), Some(name(trait_name))),
+        None => (name(rest), None),
+    }
+}
+
+/// An enum variant with the fields it has, on one line.
+fn variant(lines: &[&str], i: usize) -> String {
+    let head = lines[i].trim().trim_end_matches(',');
+    let Some(name) = head.strip_suffix(" {") else {
+        return head.to_string();
+    };
+    let fields: Vec<_> = lines[i + 1..].iter().take_while(|line| !line.trim().starts_with('}'))
+        .map(|line| line.trim()).filter(|line| !line.starts_with("//") && !line.starts_with("#["))
+        .map(|line| line.trim_end_matches(',')).collect();
+    format!("{} {{ {} }}", name, fields.join(", "))
+}
+
+/// The public items of one source file that the crate root exports, and
+/// its trait impls for them or for types of other crates. `private` holds
+/// the types the crate declares without exporting them.
+fn list_module(file: &str, source: &str, exported: &BTreeSet<String>, private: &BTreeSet<String>,
+               api: &mut Vec<String>) {
+    let lines: Vec<&str> = source.lines().collect();
+    // The exported item whose body is being read, and what kind it is.
+    let mut open: Option<(String, String)> = None;
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        if line.starts_with('}') {
+            open = None;
+        } else if line.starts_with("pub ") || line.starts_with("impl") {
+            let (text, end) = signature(&lines, i);
+            let (public, kind) = if text.starts_with("impl") {
+                let (target, trait_name) = impl_names(&text);
+                let foreign = trait_name.is_some_and(|name| exported.contains(&name)) && !private.contains(&target);
+                (exported.contains(&target) || foreign, "impl".to_string())
+            } else {
+                let words: Vec<_> = text.split(['(', '<', ' ', ':']).filter(|word| !word.is_empty()).collect();
+                (words.len() > 2 && exported.contains(words[2]), words[1].to_string())
+            };
+            open = None;
+            if public {
+                api.push(format!("{}: {}{}", file, attributes(&lines, i), text));
+                if lines[end].trim_end().ends_with('{') {
+                    open = Some((text, kind));
+                }
+            }
+            i = end + 1;
+            continue;
+        } else if let Some((parent, kind)) = &open {
+            let member = line.strip_prefix("    ").filter(|member| !member.starts_with(' ')).unwrap_or("");
+            let entry = match kind.as_str() {
+                "struct" if member.starts_with("pub ") => Some(member.trim_end_matches(',').to_string()),
+                "enum" if member.starts_with(|c: char| c.is_ascii_uppercase()) => Some(variant(&lines, i)),
+                "trait" if ["fn ", "type ", "const "].iter().any(|kind| member.starts_with(kind)) => {
+                    Some(signature(&lines, i).0)
+                }
+                "impl" if member.starts_with("pub fn ") || member.starts_with("pub const ")
+                    || parent.contains(" for ") && member.starts_with("fn ") => Some(signature(&lines, i).0),
+                _ => None,
+            };
+            if let Some(entry) = entry {
+                api.push(format!("{}: {} => {}{}", file, parent, attributes(&lines, i), entry));
+            }
+        }
+        i += 1;
+    }
+    // An inherent impl with nothing public in it adds nothing to the API.
+    let listed = std::mem::take(api);
+    for (i, line) in listed.iter().enumerate() {
+        let header = line.split_once(": ").map_or("", |(_, item)| item);
+        let inherent = header.starts_with("impl") && !header.contains(" for ") && !header.contains(" => ");
+        if !inherent || listed.get(i + 1).is_some_and(|next| next.starts_with(&format!("{} =>", line))) {
+            api.push(line.clone());
+        }
+    }
+}
+
+/// The crate's public API, one line per item, as `public_api.txt` holds it.
+fn public_api() -> String {
+    let src = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+    let lib = fs::read_to_string(src.join("lib.rs")).unwrap();
+    let mut exports = Vec::new();
+    let mut statement = String::new();
+    for line in lib.lines().map(str::trim) {
+        if line.starts_with("pub use") || !statement.is_empty() {
+            statement.push_str(line);
+            statement.push(' ');
+            if line.ends_with(';') {
+                exports.push(std::mem::take(&mut statement));
+            }
+        }
+    }
+    let mut api: Vec<String> = exports.iter().map(|export| format!("lib.rs: {}", export.trim())).collect();
+    let mut exported = BTreeSet::new();
+    for export in &exports {
+        let names = export.split_once("::").unwrap().1.trim().trim_end_matches(';');
+        let names = names.trim_start_matches('{').trim_end_matches('}');
+        exported.extend(names.split(',').map(|name| name.trim().to_string()).filter(|name| !name.is_empty()));
+    }
+    // Every module, since one can add methods to a type exported from another.
+    let modules: Vec<_> = lib.lines().filter_map(|line| line.trim().strip_prefix("mod ")?.strip_suffix(';')).collect();
+    let sources: Vec<_> = modules.iter()
+        .map(|module| (format!("{}.rs", module), fs::read_to_string(src.join(format!("{}.rs", module))).unwrap()))
+        .collect();
+    let private = sources.iter().flat_map(|(_, source)| source.lines())
+        .filter_map(|line| {
+            let declared = line.trim_start().trim_start_matches("pub(crate) ").trim_start_matches("pub ");
+            let name = declared.strip_prefix("struct ").or_else(|| declared.strip_prefix("enum "))?;
+            name.split(['<', ' ', '(', ';', '{']).next().map(str::to_string)
+        })
+        .filter(|name| !exported.contains(name))
+        .collect();
+    for (file, source) in &sources {
+        list_module(file, source, &exported, &private, &mut api);
+    }
+    api.push(String::new());
+    api.join("\n")
+}
+
+#[test]
+fn public_api_matches_the_snapshot() {
+    let snapshot = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/public_api.txt");
+    let current = public_api();
+    if std::env::var_os("UPDATE_PUBLIC_API").is_some() {
+        fs::write(&snapshot, &current).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&snapshot).unwrap_or_default();
+    let (old, new): (BTreeSet<_>, BTreeSet<_>) = (expected.lines().collect(), current.lines().collect());
+    let removed: Vec<_> = old.difference(&new).map(|line| format!("- {}", line)).collect();
+    let added: Vec<_> = new.difference(&old).map(|line| format!("+ {}", line)).collect();
+    assert!(removed.is_empty() && added.is_empty(),
+            "The public API differs from tests/public_api.txt; if that is intended, rerun with \
+             UPDATE_PUBLIC_API=1 and commit the new listing:\n{}\n{}", removed.join("\n"), added.join("\n"));
+    assert_eq!(current, expected, "The public API is the same but listed in another order");
+}
+
+#[test]
+fn lists_signatures_with_their_attributes_and_members() {
+    let source = "\
+/// Doc.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Shown {
+    /// Field.
+    pub offset: usize,
+    hidden: usize,
+    pub(crate) internal: usize,
+}
+
+impl Shown {
+    /// Method.
+    pub fn range(&self,
+                 end: usize) -> Range<usize> {
+        self.offset..end
+    }
+
+    fn private(&self) {}
+}
+
+pub enum Kind {
+    #[cfg(feature = \"x\")]
+    Tagged { code: u16 },
+    Plain,
+}
+
+pub struct Hidden;
+
+impl Hidden {
+    pub fn skipped(&self) {}
+}
+
+impl<W: Write> Sink for Wrapper<W> {
+    fn write_at(&mut self) {}
+}
+
+impl Sink for Hidden {
+    fn write_at(&mut self) {}
+}
+
+impl Kind {
+    fn private(&self) {}
+}
+";
+    let exported = ["Shown", "Kind", "Sink"].iter().map(|name| name.to_string()).collect();
+    let private = ["Hidden"].iter().map(|name| name.to_string()).collect();
+    let mut api = Vec::new();
+    list_module("x.rs", source, &exported, &private, &mut api);
+    assert_eq!(api, [
+        "x.rs: #[derive(Clone, Debug)] #[non_exhaustive] pub struct Shown",
+        "x.rs: pub struct Shown => pub offset: usize",
+        "x.rs: impl Shown",
+        "x.rs: impl Shown => pub fn range(&self, end: usize) -> Range<usize>",
+        "x.rs: pub enum Kind",
+        "x.rs: pub enum Kind => #[cfg(feature = \"x\")] Tagged { code: u16 }",
+        "x.rs: pub enum Kind => Plain",
+        "x.rs: impl<W: Write> Sink for Wrapper<W>",
+        "x.rs: impl<W: Write> Sink for Wrapper<W> => fn write_at(&mut self)",
+    ]);
+}